@@ -0,0 +1,54 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use proxy_filter::server::{handle, ProxyClient};
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use tokio::runtime::Runtime;
+
+const BODY_SIZES: [(&str, usize); 3] = [("1KB", 1024), ("64KB", 64 * 1024), ("1MB", 1024 * 1024)];
+
+/// Starts an upstream that answers every request with `size` bytes.
+fn serve_upstream(runtime: &Runtime, size: usize) -> SocketAddr {
+    let _guard = runtime.enter();
+    let body = vec![b'a'; size];
+    let make_service = make_service_fn(move |_conn| {
+        let body = body.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_req| {
+                let body = body.clone();
+                async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+            }))
+        }
+    });
+    let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+    let addr = server.local_addr();
+    runtime.spawn(server);
+    addr
+}
+
+fn bench_handle(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("runtime");
+    let remote_addr: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+    let mut group = c.benchmark_group("handle");
+    for (name, size) in BODY_SIZES {
+        let upstream = serve_upstream(&runtime, size);
+        let proxy = Arc::new(ProxyClient::new(
+            "127.0.0.1:0".parse().unwrap(),
+            format!("http://{}", upstream),
+        ));
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.to_async(&runtime).iter(|| async {
+                let req = Request::get("/bench").body(Body::empty()).unwrap();
+                let resp = handle(req, Arc::clone(&proxy), remote_addr).await.unwrap();
+                hyper::body::to_bytes(resp.into_body()).await.unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_handle);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+use std::env;
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(ossl110h)");
+    // Set by openssl-sys for the crates depending on it, as a hex number.
+    // LibreSSL reports a fixed OpenSSL version that says nothing about
+    // which options it supports.
+    if env::var_os("DEP_OPENSSL_LIBRESSL_VERSION_NUMBER").is_some() {
+        return;
+    }
+    if let Ok(version) = env::var("DEP_OPENSSL_VERSION_NUMBER") {
+        let version = u64::from_str_radix(&version, 16).expect("OpenSSL version number");
+        // SSL_OP_NO_RENEGOTIATION arrived in 1.1.0h.
+        if version >= 0x1010_0080 {
+            println!("cargo:rustc-cfg=ossl110h");
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use hyper::{
+    header::{HeaderMap, AUTHORIZATION},
+    Method,
+};
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Whether a request was let through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Allowed,
+    Denied,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    timestamp: String,
+    client_ip: IpAddr,
+    method: &'a str,
+    path: &'a str,
+    result: Decision,
+    /// What made the decision, e.g. `pre_connect_hook`.
+    decided_by: &'a str,
+    auth_method: &'static str,
+    identity: Option<String>,
+}
+
+/// Records access decisions as JSON lines appended to a file. The file is
+/// only ever appended to.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(
+        &self,
+        client_ip: IpAddr,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+        result: Decision,
+        decided_by: &str,
+    ) {
+        let (auth_method, identity) = credentials(headers);
+        let entry = Entry {
+            timestamp: rfc3339(SystemTime::now()),
+            client_ip,
+            method: method.as_str(),
+            path,
+            result,
+            decided_by,
+            auth_method,
+            identity,
+        };
+        let mut line = serde_json::to_vec(&entry).expect("audit entry serializes");
+        line.push(b'\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            tracing::error!("Failed to write audit log: {}", e);
+        }
+    }
+}
+
+/// The kind of credentials in `Authorization` and who they claim to be. JWTs
+/// are not verified, their `sub` claim is only reported.
+fn credentials(headers: &HeaderMap) -> (&'static str, Option<String>) {
+    let authorization = match headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+        Some(authorization) => authorization,
+        None => return ("none", None),
+    };
+    let (scheme, credentials) = authorization.split_once(' ').unwrap_or((authorization, ""));
+    let credentials = credentials.trim();
+    if scheme.eq_ignore_ascii_case("basic") {
+        let username = base64::decode(credentials)
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| decoded.split(':').next().map(String::from));
+        ("basic", username)
+    } else if scheme.eq_ignore_ascii_case("bearer") {
+        let mut parts = credentials.split('.');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(_), Some(payload), Some(_), None) => ("jwt", jwt_subject(payload)),
+            _ => ("bearer", None),
+        }
+    } else {
+        ("other", None)
+    }
+}
+
+fn jwt_subject(payload: &str) -> Option<String> {
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    claims.get("sub")?.as_str().map(String::from)
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with milliseconds.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    // Converts days since 1970-01-01 to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use std::time::Duration;
+
+    #[test]
+    fn test_credentials() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(credentials(&headers), ("none", None));
+
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("Basic YWxpY2U6c2VjcmV0"),
+        );
+        assert_eq!(credentials(&headers), ("basic", Some("alice".to_string())));
+
+        // {"alg":"none"}.{"sub":"bob"}.signature
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("Bearer eyJhbGciOiJub25lIn0.eyJzdWIiOiJib2IifQ.c2ln"),
+        );
+        assert_eq!(credentials(&headers), ("jwt", Some("bob".to_string())));
+
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer opaque"));
+        assert_eq!(credentials(&headers), ("bearer", None));
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(rfc3339(time), "2024-02-29T12:34:56.789Z");
+    }
+}
@@ -0,0 +1,108 @@
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use std::str::FromStr;
+
+/// Basic authentication required for requests under a path prefix, parsed
+/// from `PREFIX:REALM:USERNAME:PASSWORD`. The password may contain `:`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathAuth {
+    prefix: String,
+    realm: String,
+    username: String,
+    password: String,
+}
+
+impl PathAuth {
+    /// The `WWW-Authenticate` value asking for credentials for the realm.
+    pub fn challenge(&self) -> HeaderValue {
+        HeaderValue::from_str(&format!("Basic realm=\"{}\"", self.realm))
+            .expect("realm is a valid header value")
+    }
+
+    /// Whether `headers` carry basic credentials for this realm's user.
+    pub fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let credentials = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+            .and_then(|(_, credentials)| base64::decode(credentials.trim()).ok());
+        let expected = format!("{}:{}", self.username, self.password);
+        credentials.is_some_and(|credentials| constant_time_eq(&credentials, expected.as_bytes()))
+    }
+}
+
+impl FromStr for PathAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(4, ':');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(prefix), Some(realm), Some(username), Some(password))
+                if prefix.starts_with('/') && !realm.is_empty() && !username.is_empty() =>
+            {
+                if realm.contains('"') || HeaderValue::from_str(realm).is_err() {
+                    return Err(format!("invalid realm '{}'", realm));
+                }
+                Ok(PathAuth {
+                    prefix: prefix.to_string(),
+                    realm: realm.to_string(),
+                    username: username.to_string(),
+                    password: password.to_string(),
+                })
+            }
+            _ => Err(format!(
+                "expected /PREFIX:REALM:USERNAME:PASSWORD, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+/// Finds the realm protecting `path`, the one with the longest matching
+/// prefix.
+pub fn find<'a>(auths: &'a [PathAuth], path: &str) -> Option<&'a PathAuth> {
+    auths
+        .iter()
+        .filter(|auth| path.starts_with(auth.prefix.as_str()))
+        .max_by_key(|auth| auth.prefix.len())
+}
+
+/// Compares without returning early, so the time taken doesn't reveal how
+/// much of a guessed password was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_auth() {
+        let auths: Vec<PathAuth> = vec![
+            "/admin:admin-realm:adminuser:admin:pass".parse().unwrap(),
+            "/admin/reports:reports:reporter:secret".parse().unwrap(),
+        ];
+        assert!(find(&auths, "/public").is_none());
+        let admin = find(&auths, "/admin/users").unwrap();
+        assert_eq!(admin.challenge(), "Basic realm=\"admin-realm\"");
+        assert_eq!(find(&auths, "/admin/reports/1").unwrap().realm, "reports");
+
+        let mut headers = HeaderMap::new();
+        assert!(!admin.is_authorized(&headers));
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Basic {}", base64::encode("adminuser:admin:pass")))
+                .unwrap(),
+        );
+        assert!(admin.is_authorized(&headers));
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Basic {}", base64::encode("adminuser:wrong"))).unwrap(),
+        );
+        assert!(!admin.is_authorized(&headers));
+
+        assert!("admin:realm:user:pass".parse::<PathAuth>().is_err());
+        assert!("/admin:realm:user".parse::<PathAuth>().is_err());
+    }
+}
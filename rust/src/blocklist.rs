@@ -0,0 +1,118 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+/// Request path patterns loaded from a file with one glob per line, where `*`
+/// matches any run of characters and `?` any single one. Blank lines and
+/// lines starting with `#` are ignored.
+#[derive(Debug)]
+pub struct PathBlocklist {
+    file: PathBuf,
+    patterns: RwLock<Vec<String>>,
+}
+
+impl PathBlocklist {
+    pub fn load(file: PathBuf) -> io::Result<PathBlocklist> {
+        let patterns = read(&file)?;
+        Ok(PathBlocklist {
+            file,
+            patterns: RwLock::new(patterns),
+        })
+    }
+
+    /// Reads the file again, keeping the current patterns if it can't be
+    /// loaded. Returns the number of patterns loaded.
+    pub fn reload(&self) -> io::Result<usize> {
+        let patterns = read(&self.file)?;
+        let count = patterns.len();
+        *self.patterns.write().unwrap() = patterns;
+        Ok(count)
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn is_blocked(&self, path: &str) -> bool {
+        self.patterns
+            .read()
+            .unwrap()
+            .iter()
+            .any(|pattern| matches(pattern, path))
+    }
+}
+
+fn read(file: &Path) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(file)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Whether `path` matches the glob `pattern`.
+pub(crate) fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, path) = (pattern.as_bytes(), path.as_bytes());
+    let (mut p, mut s) = (0, 0);
+    // Where the last `*` was seen, and how much of `path` it has taken.
+    let mut star: Option<(usize, usize)> = None;
+    while s < path.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, s));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == path[s] => {
+                p += 1;
+                s += 1;
+            }
+            _ => match star {
+                Some((star_p, star_s)) => {
+                    p = star_p + 1;
+                    s = star_s + 1;
+                    star = Some((star_p, star_s + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_and_reload() {
+        let file = std::env::temp_dir().join(format!("blocklist-{}.txt", std::process::id()));
+        fs::write(&file, "# admin pages\n/admin/*\n\n/*.php\n").unwrap();
+        let blocklist = PathBlocklist::load(file.clone()).unwrap();
+        assert!(blocklist.is_blocked("/admin/users"));
+        assert!(blocklist.is_blocked("/wp/login.php"));
+        assert!(!blocklist.is_blocked("/api/users"));
+
+        fs::write(&file, "/api/*\n").unwrap();
+        assert_eq!(blocklist.reload().unwrap(), 1);
+        assert!(blocklist.is_blocked("/api/users"));
+        assert!(!blocklist.is_blocked("/admin/users"));
+
+        fs::remove_file(&file).unwrap();
+        assert!(blocklist.reload().is_err());
+        assert!(blocklist.is_blocked("/api/users"));
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("/admin/*", "/admin/"));
+        assert!(matches("/admin/*", "/admin/users/1"));
+        assert!(matches("/*/secret?", "/a/b/secret2"));
+        assert!(matches("*", ""));
+        assert!(!matches("/admin/*", "/administrator"));
+        assert!(!matches("/*.php", "/index.phps"));
+        assert!(!matches("/user?", "/user"));
+    }
+}
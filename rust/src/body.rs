@@ -0,0 +1,624 @@
+use http_body::{combinators::UnsyncBoxBody, SizeHint};
+use hyper::{
+    body::{Bytes, HttpBody, Sender},
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Body,
+};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::{Instant, Interval, MissedTickBehavior, Sleep};
+
+/// A body that can be wrapped any number of times, keeping the trailers and
+/// size hint of the body it wraps, unlike `Body::wrap_stream`.
+pub type BoxBody = UnsyncBoxBody<Bytes, Box<dyn Error + Send + Sync>>;
+
+/// Boxes `body` so it can be wrapped.
+pub fn boxed<B>(body: B) -> BoxBody
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    body.map_err(Into::into).boxed_unsync()
+}
+
+/// Ends `body` early if it yields neither data nor EOF within `timeout`, for
+/// upstreams that send their headers but never finish an empty body.
+pub fn with_empty_body_timeout(body: BoxBody, timeout: Duration) -> BoxBody {
+    boxed(EmptyBodyTimeout {
+        body,
+        timer: Some(Box::pin(tokio::time::sleep(timeout))),
+        timed_out: false,
+    })
+}
+
+/// Fails `body` if, once it has yielded its first chunk, nothing more arrives
+/// within `timeout` of the last one, for upstreams that stall mid-body.
+pub fn with_stall_timeout(body: BoxBody, timeout: Duration) -> BoxBody {
+    boxed(StallTimeout {
+        body,
+        timeout,
+        timer: None,
+    })
+}
+
+/// Fails `body` with `TooSlow` if, over any `window` once the first `window`
+/// has passed, it yields fewer than `bytes_per_sec` bytes per second.
+pub fn with_min_speed(body: BoxBody, bytes_per_sec: u64, window: Duration) -> BoxBody {
+    let start = Instant::now();
+    // Checking ten times a window keeps the window close to sliding.
+    let period = (window / 10).max(Duration::from_millis(1));
+    let mut check = tokio::time::interval_at(start + window, period);
+    check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    boxed(MinSpeed {
+        body,
+        bytes_per_sec,
+        window,
+        chunks: VecDeque::new(),
+        check,
+    })
+}
+
+/// Reads `body` until it ends or more than `limit` bytes have been read,
+/// giving what was read and, if the body didn't end, the body to send in its
+/// place, yielding what was read before the rest.
+pub async fn read_prefix(
+    mut body: BoxBody,
+    limit: usize,
+) -> Result<(Bytes, Option<BoxBody>), Box<dyn Error + Send + Sync>> {
+    let mut prefix = Vec::new();
+    while let Some(chunk) = body.data().await {
+        prefix.extend_from_slice(&chunk?);
+        if prefix.len() > limit {
+            let prefix = Bytes::from(prefix);
+            let rest = Prefixed {
+                prefix: Some(prefix.clone()),
+                body,
+            };
+            return Ok((prefix, Some(boxed(rest))));
+        }
+    }
+    Ok((Bytes::from(prefix), None))
+}
+
+/// Fails `body` with `TooLarge` once it has yielded more than `limit` bytes.
+pub fn with_size_limit(body: BoxBody, limit: u64) -> BoxBody {
+    boxed(SizeLimit {
+        body,
+        limit,
+        read: 0,
+    })
+}
+
+/// Forwards `body` and its trailers, calling `done` with the number of bytes
+/// it had once it has been read in full.
+pub fn on_complete<B, F>(mut body: B, done: F) -> Body
+where
+    B: HttpBody<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: fmt::Display + Send,
+    F: FnOnce(u64) + Send + 'static,
+{
+    let (sender, forwarded) = Body::channel();
+    tokio::spawn(async move {
+        let Some((length, mut sender)) = forward_data(&mut body, sender).await else {
+            return;
+        };
+        match body.trailers().await {
+            Ok(Some(trailers)) => {
+                if sender.send_trailers(trailers).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::warn!("Failed to read body trailers: {}", e);
+                sender.abort();
+                return;
+            }
+        }
+        done(length);
+    });
+    forwarded
+}
+
+/// Forwards `body`, adding a `name` trailer with the number of bytes it had
+/// to its own trailers once it has been read in full.
+pub fn with_length_trailer<B>(mut body: B, name: HeaderName) -> Body
+where
+    B: HttpBody<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: fmt::Display + Send,
+{
+    let (sender, forwarded) = Body::channel();
+    tokio::spawn(async move {
+        let Some((length, mut sender)) = forward_data(&mut body, sender).await else {
+            return;
+        };
+        let mut trailers = match body.trailers().await {
+            Ok(trailers) => trailers.unwrap_or_default(),
+            Err(e) => {
+                tracing::warn!("Failed to read body trailers: {}", e);
+                sender.abort();
+                return;
+            }
+        };
+        trailers.insert(name, HeaderValue::from(length));
+        let _ = sender.send_trailers(trailers).await;
+    });
+    forwarded
+}
+
+/// Sends the data of `body` through `sender`, returning how many bytes it
+/// had and `sender` for the trailers, or `None` if either side failed.
+async fn forward_data<B>(body: &mut B, mut sender: Sender) -> Option<(u64, Sender)>
+where
+    B: HttpBody<Data = Bytes> + Unpin,
+    B::Error: fmt::Display,
+{
+    let mut length = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                tracing::warn!("Failed to read body: {}", e);
+                sender.abort();
+                return None;
+            }
+        };
+        length += chunk.len() as u64;
+        sender.send_data(chunk).await.ok()?;
+    }
+    Some((length, sender))
+}
+
+/// The error of a body that went over its size limit.
+#[derive(Debug)]
+pub struct TooLarge {
+    pub limit: u64,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "body is larger than {} bytes", self.limit)
+    }
+}
+
+impl Error for TooLarge {}
+
+/// Whether `err` was caused by a body going over its size limit.
+pub fn is_too_large(err: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if e.is::<TooLarge>() {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
+
+struct SizeLimit<B> {
+    body: B,
+    limit: u64,
+    read: u64,
+}
+
+impl<B> HttpBody for SizeLimit<B>
+where
+    B: HttpBody<Data = Bytes> + Unpin,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Data = Bytes;
+    type Error = Box<dyn Error + Send + Sync>;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.body).poll_data(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                this.read += chunk.len() as u64;
+                if this.read > this.limit {
+                    Poll::Ready(Some(Err(TooLarge { limit: this.limit }.into())))
+                } else {
+                    Poll::Ready(Some(Ok(chunk)))
+                }
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().body)
+            .poll_trailers(cx)
+            .map_err(Into::into)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// `body` with the `prefix` already read from it put back in front.
+struct Prefixed<B> {
+    prefix: Option<Bytes>,
+    body: B,
+}
+
+impl<B: HttpBody<Data = Bytes> + Unpin> HttpBody for Prefixed<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        match this.prefix.take() {
+            Some(prefix) => Poll::Ready(Some(Ok(prefix))),
+            None => Pin::new(&mut this.body).poll_data(cx),
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.prefix.is_none() && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let prefix = self.prefix.as_ref().map_or(0, |prefix| prefix.len() as u64);
+        let rest = self.body.size_hint();
+        let mut hint = SizeHint::new();
+        hint.set_lower(rest.lower() + prefix);
+        if let Some(upper) = rest.upper() {
+            hint.set_upper(upper + prefix);
+        }
+        hint
+    }
+}
+
+/// The error of a body that stopped yielding data partway through.
+#[derive(Debug)]
+pub struct Stalled {
+    pub timeout: Duration,
+}
+
+impl fmt::Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "body stalled for {:?}", self.timeout)
+    }
+}
+
+impl Error for Stalled {}
+
+struct StallTimeout<B> {
+    body: B,
+    timeout: Duration,
+    /// Started by the first chunk and restarted by every one after it.
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl<B> StallTimeout<B> {
+    fn poll_stalled(&mut self, cx: &mut Context<'_>) -> Poll<Box<dyn Error + Send + Sync>> {
+        match self.timer.as_mut().map(|timer| timer.as_mut().poll(cx)) {
+            Some(Poll::Ready(())) => {
+                tracing::warn!("Upstream stalled mid-body, aborting it");
+                self.timer = None;
+                Poll::Ready(
+                    Stalled {
+                        timeout: self.timeout,
+                    }
+                    .into(),
+                )
+            }
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl<B> HttpBody for StallTimeout<B>
+where
+    B: HttpBody + Unpin,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Data = B::Data;
+    type Error = Box<dyn Error + Send + Sync>;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.body).poll_data(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let deadline = Instant::now() + this.timeout;
+                match &mut this.timer {
+                    Some(timer) => timer.as_mut().reset(deadline),
+                    None => this.timer = Some(Box::pin(tokio::time::sleep_until(deadline))),
+                }
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => this.poll_stalled(cx).map(|e| Some(Err(e))),
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = self.get_mut();
+        // Trailers stall a body as much as data does.
+        match Pin::new(&mut this.body).poll_trailers(cx) {
+            Poll::Ready(trailers) => Poll::Ready(trailers.map_err(Into::into)),
+            Poll::Pending => this.poll_stalled(cx).map(Err),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// The error of a body that yielded data too slowly.
+#[derive(Debug)]
+pub struct TooSlow {
+    pub bytes_per_sec: u64,
+    pub window: Duration,
+}
+
+impl fmt::Display for TooSlow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "body was slower than {} bytes per second over {:?}",
+            self.bytes_per_sec, self.window
+        )
+    }
+}
+
+impl Error for TooSlow {}
+
+struct MinSpeed<B> {
+    body: B,
+    bytes_per_sec: u64,
+    window: Duration,
+    /// When each chunk within the last `window` arrived, and its size.
+    chunks: VecDeque<(Instant, u64)>,
+    check: Interval,
+}
+
+impl<B> MinSpeed<B> {
+    fn too_slow(&mut self) -> bool {
+        let now = Instant::now();
+        while let Some((at, _)) = self.chunks.front() {
+            if now.duration_since(*at) <= self.window {
+                break;
+            }
+            self.chunks.pop_front();
+        }
+        let read: u64 = self.chunks.iter().map(|(_, len)| len).sum();
+        (read as f64) < self.bytes_per_sec as f64 * self.window.as_secs_f64()
+    }
+}
+
+impl<B> HttpBody for MinSpeed<B>
+where
+    B: HttpBody<Data = Bytes> + Unpin,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Data = Bytes;
+    type Error = Box<dyn Error + Send + Sync>;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.body).poll_data(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                this.chunks.push_back((Instant::now(), chunk.len() as u64));
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                while this.check.poll_tick(cx).is_ready() {
+                    if this.too_slow() {
+                        tracing::warn!("Upstream response is too slow, aborting it");
+                        return Poll::Ready(Some(Err(TooSlow {
+                            bytes_per_sec: this.bytes_per_sec,
+                            window: this.window,
+                        }
+                        .into())));
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().body)
+            .poll_trailers(cx)
+            .map_err(Into::into)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+struct EmptyBodyTimeout<B> {
+    body: B,
+    /// Dropped once the body has yielded anything.
+    timer: Option<Pin<Box<Sleep>>>,
+    /// Set once the body has been ended early, when it has no trailers.
+    timed_out: bool,
+}
+
+impl<B: HttpBody + Unpin> HttpBody for EmptyBodyTimeout<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        if this.timed_out {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut this.body).poll_data(cx) {
+            Poll::Ready(item) => {
+                this.timer = None;
+                Poll::Ready(item)
+            }
+            Poll::Pending => match this.timer.as_mut().map(|timer| timer.as_mut().poll(cx)) {
+                Some(Poll::Ready(())) => {
+                    tracing::warn!("Upstream sent no body, closing it");
+                    this.timed_out = true;
+                    Poll::Ready(None)
+                }
+                _ => Poll::Pending,
+            },
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = self.get_mut();
+        if this.timed_out {
+            return Poll::Ready(Ok(None));
+        }
+        Pin::new(&mut this.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.timed_out || self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        if self.timed_out {
+            SizeHint::with_exact(0)
+        } else {
+            self.body.size_hint()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A body of `ok` with a `grpc-status` trailer.
+    fn body_with_trailers() -> BoxBody {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from("ok")).await.unwrap();
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            sender.send_trailers(trailers).await.unwrap();
+        });
+        boxed(body)
+    }
+
+    async fn read(mut body: BoxBody) -> (Vec<u8>, Option<HeaderMap>) {
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        (data, body.trailers().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_empty_body_timeout_keeps_trailers() {
+        let timeout = Duration::from_secs(60);
+        let (data, trailers) = read(with_empty_body_timeout(body_with_trailers(), timeout)).await;
+        assert_eq!(data, b"ok");
+        assert_eq!(trailers.unwrap()["grpc-status"], "0");
+
+        let body = with_empty_body_timeout(boxed(Body::from("ok")), timeout);
+        assert_eq!(body.size_hint().exact(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_stall_timeout_keeps_trailers() {
+        let timeout = Duration::from_secs(60);
+        let (data, trailers) = read(with_stall_timeout(body_with_trailers(), timeout)).await;
+        assert_eq!(data, b"ok");
+        assert_eq!(trailers.unwrap()["grpc-status"], "0");
+
+        let body = with_stall_timeout(boxed(Body::from("ok")), timeout);
+        assert_eq!(body.size_hint().exact(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_min_speed_keeps_trailers() {
+        let window = Duration::from_secs(60);
+        let (data, trailers) = read(with_min_speed(body_with_trailers(), 1, window)).await;
+        assert_eq!(data, b"ok");
+        assert_eq!(trailers.unwrap()["grpc-status"], "0");
+
+        let body = with_min_speed(boxed(Body::from("ok")), 1, window);
+        assert_eq!(body.size_hint().exact(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_size_limit_keeps_trailers() {
+        let (data, trailers) = read(with_size_limit(body_with_trailers(), 2)).await;
+        assert_eq!(data, b"ok");
+        assert_eq!(trailers.unwrap()["grpc-status"], "0");
+
+        let body = with_size_limit(boxed(Body::from("ok")), 2);
+        assert_eq!(body.size_hint().exact(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_read_prefix_keeps_trailers() {
+        let (prefix, rest) = read_prefix(body_with_trailers(), 1).await.unwrap();
+        assert_eq!(prefix, "ok");
+        let (data, trailers) = read(rest.unwrap()).await;
+        assert_eq!(data, b"ok");
+        assert_eq!(trailers.unwrap()["grpc-status"], "0");
+
+        let (prefix, rest) = read_prefix(boxed(Body::from("ok")), 5).await.unwrap();
+        assert_eq!(prefix, "ok");
+        assert!(rest.is_none());
+    }
+}
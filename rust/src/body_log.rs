@@ -0,0 +1,123 @@
+use std::{fmt::Write, str::FromStr};
+
+/// How the bytes of a logged request body are written to the log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyLogFormat {
+    /// As text, with bytes that aren't UTF-8 escaped as `\xNN`.
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl BodyLogFormat {
+    pub fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            BodyLogFormat::Utf8 => {
+                let mut text = String::with_capacity(bytes.len());
+                for chunk in bytes.utf8_chunks() {
+                    text.push_str(chunk.valid());
+                    for b in chunk.invalid() {
+                        let _ = write!(text, "\\x{:02x}", b);
+                    }
+                }
+                text
+            }
+            BodyLogFormat::Hex => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+            BodyLogFormat::Base64 => base64::encode(bytes),
+        }
+    }
+}
+
+impl FromStr for BodyLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(BodyLogFormat::Utf8),
+            "hex" => Ok(BodyLogFormat::Hex),
+            "base64" => Ok(BodyLogFormat::Base64),
+            _ => Err(format!(
+                "invalid body log format '{}', expected utf8, hex or base64",
+                s
+            )),
+        }
+    }
+}
+
+/// The format for bodies whose content type starts with `content_type`, or
+/// for every body when it is `None`. Parsed from `FORMAT` or
+/// `CONTENT_TYPE_PREFIX=FORMAT`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BodyLogFormatRule {
+    pub content_type: Option<String>,
+    pub format: BodyLogFormat,
+}
+
+impl FromStr for BodyLogFormatRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('=') {
+            Some((prefix, format)) if !prefix.trim().is_empty() => Ok(BodyLogFormatRule {
+                content_type: Some(prefix.trim().to_ascii_lowercase()),
+                format: format.trim().parse()?,
+            }),
+            Some(_) => Err(format!("expected CONTENT_TYPE_PREFIX=FORMAT, got '{}'", s)),
+            None => Ok(BodyLogFormatRule {
+                content_type: None,
+                format: s.trim().parse()?,
+            }),
+        }
+    }
+}
+
+/// The format for a body of `content_type`: that of the rule with the
+/// longest matching prefix, else of the last rule without one, else UTF-8.
+pub fn format_for(rules: &[BodyLogFormatRule], content_type: Option<&str>) -> BodyLogFormat {
+    let content_type = content_type.unwrap_or_default().to_ascii_lowercase();
+    rules
+        .iter()
+        .filter_map(|rule| match &rule.content_type {
+            Some(prefix) if content_type.starts_with(prefix.as_str()) => {
+                Some((prefix.len() + 1, rule.format))
+            }
+            Some(_) => None,
+            None => Some((0, rule.format)),
+        })
+        .max_by_key(|&(specificity, _)| specificity)
+        .map_or(BodyLogFormat::Utf8, |(_, format)| format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let bytes = b"ok\xff\n";
+        assert_eq!(BodyLogFormat::Utf8.encode(bytes), "ok\\xff\n");
+        assert_eq!(BodyLogFormat::Hex.encode(bytes), "6f6bff0a");
+        assert_eq!(BodyLogFormat::Base64.encode(bytes), "b2v/Cg==");
+    }
+
+    #[test]
+    fn test_format_for() {
+        let rules: Vec<BodyLogFormatRule> = ["hex", "application/=base64", "application/json=utf8"]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect();
+        let format = |content_type| format_for(&rules, content_type);
+        assert_eq!(
+            format(Some("Application/JSON; charset=utf-8")),
+            BodyLogFormat::Utf8
+        );
+        assert_eq!(format(Some("application/protobuf")), BodyLogFormat::Base64);
+        assert_eq!(format(Some("image/png")), BodyLogFormat::Hex);
+        assert_eq!(format(None), BodyLogFormat::Hex);
+        assert_eq!(format_for(&[], Some("image/png")), BodyLogFormat::Utf8);
+
+        assert!("binary".parse::<BodyLogFormatRule>().is_err());
+        assert!("=hex".parse::<BodyLogFormatRule>().is_err());
+        assert!("image/=octal".parse::<BodyLogFormatRule>().is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use crate::body::BoxBody;
+use crate::connector::{PoolStats, UpstreamConnector};
+use crate::proxy_protocol;
+use crate::resolver::{self, AddressFamily, UpstreamResolver};
+use hyper::{client::HttpConnector, Client};
+use hyper_openssl::HttpsConnector;
+use ipnet::IpNet;
+use openssl::{
+    error::ErrorStack,
+    nid::Nid,
+    ssl::{self, SslConnector, SslMethod, SslOptions, SslVerifyMode},
+    x509::X509Ref,
+};
+use regex::Regex;
+use socket2::TcpKeepalive;
+use std::{
+    error::Error,
+    fs::File,
+    io::Write,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+pub type HttpClient = Client<HttpsConnector<UpstreamConnector>, BoxBody>;
+
+/// Hyper's default HTTP/2 stream window, 2MB.
+pub const DEFAULT_HTTP2_STREAM_WINDOW: u32 = 2 * 1024 * 1024;
+
+/// Hyper's default HTTP/2 connection window, 5MB.
+pub const DEFAULT_HTTP2_CONNECTION_WINDOW: u32 = 5 * 1024 * 1024;
+
+/// The HTTP version spoken to an upstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Http1,
+    Http2,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "h1" => Ok(Protocol::Http1),
+            "h2" => Ok(Protocol::Http2),
+            _ => Err(format!("invalid protocol '{}', expected h1 or h2", s)),
+        }
+    }
+}
+
+/// Whether `TlsRenegotiation::Reject` is available, which needs OpenSSL
+/// 1.1.0h or newer.
+pub const REJECT_RENEGOTIATION_SUPPORTED: bool = cfg!(ossl110h);
+
+/// What to do when an upstream asks to renegotiate a TLS 1.2 session. TLS
+/// 1.3 has no renegotiation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsRenegotiation {
+    /// Renegotiate, even with upstreams that don't support secure
+    /// renegotiation (RFC 5746).
+    Allow,
+    /// Leave it to OpenSSL, which only renegotiates securely.
+    Ignore,
+    /// Refuse to renegotiate. Needs OpenSSL 1.1.0h or newer, see
+    /// `REJECT_RENEGOTIATION_SUPPORTED`.
+    Reject,
+}
+
+impl FromStr for TlsRenegotiation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(TlsRenegotiation::Allow),
+            "ignore" => Ok(TlsRenegotiation::Ignore),
+            "reject" => Ok(TlsRenegotiation::Reject),
+            _ => Err(format!(
+                "invalid TLS renegotiation '{}', expected allow, ignore or reject",
+                s
+            )),
+        }
+    }
+}
+
+/// How the HTTP version spoken to upstreams is agreed on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpVersionNegotiation {
+    /// Always speak HTTP/1.1.
+    None,
+    /// Offer HTTP/2 and HTTP/1.1 through TLS ALPN, speaking HTTP/1.1 without
+    /// TLS.
+    Alpn,
+}
+
+impl FromStr for HttpVersionNegotiation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(HttpVersionNegotiation::None),
+            "alpn" => Ok(HttpVersionNegotiation::Alpn),
+            // hyper's client has no h2c upgrade, which RFC 9113 deprecated.
+            "upgrade" => Err(
+                "HTTP/2 upgrade is not supported, speak HTTP/2 with prior knowledge \
+                 through --upstream-protocol HOST:h2 instead"
+                    .to_string(),
+            ),
+            _ => Err(format!(
+                "invalid HTTP version negotiation '{}', expected none or alpn",
+                s
+            )),
+        }
+    }
+}
+
+/// The case of the header names sent to HTTP/1 upstreams. HTTP/2 header
+/// names are always lowercase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderCase {
+    Lowercase,
+    /// Title case, `Content-Type`, as in the HTTP specifications.
+    HttpStandard,
+}
+
+impl FromStr for HeaderCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lowercase" => Ok(HeaderCase::Lowercase),
+            "http-standard" => Ok(HeaderCase::HttpStandard),
+            _ => Err(format!(
+                "invalid header case '{}', expected lowercase or http-standard",
+                s
+            )),
+        }
+    }
+}
+
+/// Speaks `protocol` to `host`. Parsed from `HOST:h1` or `HOST:h2`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamProtocol {
+    pub host: String,
+    pub protocol: Protocol,
+}
+
+impl FromStr for UpstreamProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, protocol) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected HOST:PROTOCOL, got '{}'", s))?;
+        if host.is_empty() {
+            return Err(format!("no host in '{}'", s));
+        }
+        Ok(UpstreamProtocol {
+            host: host.to_ascii_lowercase(),
+            protocol: protocol.parse()?,
+        })
+    }
+}
+
+/// Settings applied when building the client used to reach upstreams.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    /// Address ranges upstreams must not resolve to or be given as.
+    pub denied_ip_ranges: Vec<IpNet>,
+    /// Disable TLS session tickets so resumed sessions keep forward secrecy.
+    pub disable_tls_session_tickets: bool,
+    /// What to do when an upstream asks to renegotiate TLS.
+    pub tls_renegotiation: TlsRenegotiation,
+    /// Only accept upstream certificates with a common name or DNS subject
+    /// alternative name matching this, besides the upstream's hostname.
+    pub tls_hostname_pattern: Option<Regex>,
+    /// Announce the downstream client with a PROXY protocol header on every
+    /// upstream connection. Connections then can't be shared between clients,
+    /// so pooling and HTTP/2 are turned off.
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+    /// Only connect to upstream addresses of this IP version.
+    pub address_family: Option<AddressFamily>,
+    /// How many times to retry a failed upstream connection attempt.
+    pub connect_retries: u32,
+    /// How many connections may be open to each upstream host at once.
+    pub max_connections_per_host: Option<usize>,
+    /// How many connections may be open to all upstream hosts together.
+    pub max_connections: Option<usize>,
+    /// Send HTTP/2 PING frames this often on upstream connections that
+    /// negotiated HTTP/2, such as gRPC streams.
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// Close HTTP/2 connections whose PING isn't acknowledged within this.
+    pub http2_keep_alive_timeout: Duration,
+    /// HTTP/2 flow control window, in bytes, of each upstream stream.
+    pub http2_initial_stream_window_size: u32,
+    /// HTTP/2 flow control window, in bytes, shared by the streams of an
+    /// upstream connection.
+    pub http2_initial_connection_window_size: u32,
+    /// Give up on upstream DNS lookups that take longer than this.
+    pub resolver_timeout: Option<Duration>,
+    /// Append the secrets of upstream TLS sessions to this file in the NSS
+    /// key log format, so captured traffic can be decrypted.
+    pub tls_key_log: Option<Arc<Mutex<File>>>,
+    /// Open upstream connections with TCP Fast Open.
+    pub tcp_fast_open: bool,
+    /// Send HTTP/1 header names title-cased, for upstreams that wrongly treat
+    /// them as case-sensitive.
+    pub http1_title_case_headers: bool,
+    /// Write HTTP/1 messages with vectored writes rather than flattening them
+    /// into one buffer first.
+    pub http1_writev: bool,
+    /// Close every upstream connection after its request instead of keeping
+    /// it for reuse.
+    pub disable_pooling: bool,
+    /// Close HTTP/1 upstream connections open for longer than this once they
+    /// are between requests, even if they keep being reused.
+    pub max_connection_age: Option<Duration>,
+    /// Send TCP keepalive probes this often on idle upstream connections,
+    /// the first after the connection has been idle this long.
+    pub tcp_keepalive_interval: Option<Duration>,
+    /// Close upstream connections after this many unanswered keepalive
+    /// probes, instead of the OS default.
+    pub tcp_keepalive_probes: Option<u32>,
+    /// Buffer this many bytes written to each upstream connection before
+    /// sending them.
+    pub write_buffer_size: Option<usize>,
+    /// Give up connecting to each address of an upstream host after this,
+    /// moving on to the next.
+    pub connect_attempt_timeout: Option<Duration>,
+    /// Give up connecting to an upstream host after this, however many of
+    /// its addresses are left to try.
+    pub connect_total_timeout: Option<Duration>,
+    /// Only speak this HTTP version to upstreams rather than negotiating it.
+    /// HTTP/2 is then used without TLS too, with prior knowledge.
+    pub protocol: Option<Protocol>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            denied_ip_ranges: resolver::default_denied_ranges(),
+            disable_tls_session_tickets: false,
+            tls_renegotiation: TlsRenegotiation::Ignore,
+            tls_hostname_pattern: None,
+            proxy_protocol: None,
+            address_family: None,
+            connect_retries: 0,
+            max_connections_per_host: None,
+            max_connections: None,
+            http2_keep_alive_interval: None,
+            http2_keep_alive_timeout: Duration::from_secs(20),
+            http2_initial_stream_window_size: DEFAULT_HTTP2_STREAM_WINDOW,
+            http2_initial_connection_window_size: DEFAULT_HTTP2_CONNECTION_WINDOW,
+            resolver_timeout: None,
+            tls_key_log: None,
+            tcp_fast_open: false,
+            http1_title_case_headers: false,
+            http1_writev: true,
+            disable_pooling: false,
+            max_connection_age: None,
+            tcp_keepalive_interval: None,
+            tcp_keepalive_probes: None,
+            write_buffer_size: None,
+            connect_attempt_timeout: None,
+            connect_total_timeout: None,
+            protocol: None,
+        }
+    }
+}
+
+/// Whether the common name or a DNS subject alternative name of `cert`
+/// matches `pattern`.
+fn certificate_matches(cert: &X509Ref, pattern: &Regex) -> bool {
+    let alt_names = cert
+        .subject_alt_names()
+        .into_iter()
+        .flatten()
+        .filter_map(|name| name.dnsname().map(str::to_string));
+    let common_names = cert
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|entry| entry.data().as_utf8().ok().map(|name| name.to_string()));
+    alt_names
+        .chain(common_names)
+        .any(|name| pattern.is_match(&name))
+}
+
+/// Whether `err` was caused by a failed TLS handshake or TLS session with the
+/// upstream.
+pub fn is_tls_error(err: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if e.is::<ssl::Error>() || e.is::<ErrorStack>() {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
+
+/// Builds the upstream client, along with counts of the upstream connections
+/// it has open.
+pub fn build(options: &ClientOptions) -> (HttpClient, Arc<PoolStats>) {
+    let pool_stats = Arc::new(PoolStats::default());
+    (
+        build_with_pool_stats(options, Arc::clone(&pool_stats)),
+        pool_stats,
+    )
+}
+
+/// Builds an upstream client counting its connections in `pool_stats`.
+pub fn build_with_pool_stats(options: &ClientOptions, pool_stats: Arc<PoolStats>) -> HttpClient {
+    let mut resolver =
+        UpstreamResolver::new(options.denied_ip_ranges.clone(), options.address_family);
+    if let Some(timeout) = options.resolver_timeout {
+        resolver = resolver.with_timeout(timeout);
+    }
+    let direct_resolver = resolver.clone();
+    let denies_ips = !options.denied_ip_ranges.is_empty();
+    let mut http = HttpConnector::new_with_resolver(resolver);
+    http.enforce_http(false);
+
+    let mut connector =
+        UpstreamConnector::new(http, options.proxy_protocol, options.connect_retries)
+            .with_pool_stats(pool_stats);
+    if denies_ips {
+        connector = connector.with_ip_literal_check(direct_resolver.clone());
+    }
+    if let Some(max_per_host) = options.max_connections_per_host {
+        connector = connector.with_max_connections_per_host(max_per_host);
+    }
+    if let Some(max) = options.max_connections {
+        connector = connector.with_max_connections(max);
+    }
+    if let Some(max_age) = options.max_connection_age {
+        connector = connector.with_max_age(max_age);
+    }
+    if options.tcp_fast_open {
+        connector = connector.with_fast_open(direct_resolver.clone());
+    }
+    if let Some(timeout) = options.connect_attempt_timeout {
+        connector = connector.with_attempt_timeout(direct_resolver, timeout);
+    }
+    if let Some(timeout) = options.connect_total_timeout {
+        connector = connector.with_total_connect_timeout(timeout);
+    }
+    if let Some(interval) = options.tcp_keepalive_interval {
+        let mut keepalive = TcpKeepalive::new()
+            .with_time(interval)
+            .with_interval(interval);
+        if let Some(probes) = options.tcp_keepalive_probes {
+            keepalive = keepalive.with_retries(probes);
+        }
+        connector = connector.with_tcp_keepalive(keepalive);
+    }
+    if let Some(size) = options.write_buffer_size {
+        connector = connector.with_write_buffer(size);
+    }
+
+    let mut ssl = SslConnector::builder(SslMethod::tls()).expect("ssl connector");
+    let alpn_protos: &[u8] = match options.protocol {
+        Some(Protocol::Http2) => b"\x02h2",
+        Some(Protocol::Http1) => b"\x08http/1.1",
+        None if options.proxy_protocol.is_some() => b"\x08http/1.1",
+        None => b"\x02h2\x08http/1.1",
+    };
+    ssl.set_alpn_protos(alpn_protos).expect("alpn protocols");
+    if options.disable_tls_session_tickets {
+        ssl.set_options(SslOptions::NO_TICKET);
+    }
+    match options.tls_renegotiation {
+        TlsRenegotiation::Allow => {
+            ssl.set_options(SslOptions::ALLOW_UNSAFE_LEGACY_RENEGOTIATION);
+        }
+        TlsRenegotiation::Ignore => {}
+        #[cfg(ossl110h)]
+        TlsRenegotiation::Reject => {
+            ssl.set_options(SslOptions::NO_RENEGOTIATION);
+        }
+        #[cfg(not(ossl110h))]
+        TlsRenegotiation::Reject => {
+            panic!("rejecting TLS renegotiation needs OpenSSL 1.1.0h or newer")
+        }
+    }
+    if let Some(pattern) = &options.tls_hostname_pattern {
+        let pattern = pattern.clone();
+        ssl.set_verify_callback(SslVerifyMode::PEER, move |verified, ctx| {
+            // Only the upstream's own certificate is checked, not its issuers.
+            if !verified || ctx.error_depth() > 0 {
+                return verified;
+            }
+            match ctx.current_cert() {
+                Some(cert) if certificate_matches(cert, &pattern) => true,
+                _ => {
+                    tracing::warn!("Upstream certificate doesn't match '{}'", pattern);
+                    false
+                }
+            }
+        });
+    }
+    if let Some(key_log) = &options.tls_key_log {
+        let key_log = Arc::clone(key_log);
+        ssl.set_keylog_callback(move |_, line| {
+            let mut file = key_log.lock().unwrap();
+            if let Err(e) = writeln!(file, "{}", line) {
+                tracing::warn!("Failed to write TLS key log: {}", e);
+            }
+        });
+    }
+    let https = HttpsConnector::with_connector(connector, ssl).expect("https connector");
+
+    let mut builder = Client::builder();
+    builder
+        .http1_title_case_headers(options.http1_title_case_headers)
+        .http1_writev(options.http1_writev)
+        .http2_initial_stream_window_size(options.http2_initial_stream_window_size)
+        .http2_initial_connection_window_size(options.http2_initial_connection_window_size);
+    if options.proxy_protocol.is_some() || options.disable_pooling {
+        builder.pool_max_idle_per_host(0);
+    }
+    if let Some(interval) = options.http2_keep_alive_interval {
+        builder
+            .http2_keep_alive_interval(interval)
+            .http2_keep_alive_timeout(options.http2_keep_alive_timeout);
+    }
+    if options.protocol == Some(Protocol::Http2) {
+        builder.http2_only(true);
+    }
+    builder.build::<_, BoxBody>(https)
+}
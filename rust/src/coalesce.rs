@@ -0,0 +1,106 @@
+use futures::future::{BoxFuture, FutureExt, Shared};
+use hyper::{
+    body::{self, Bytes},
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Body, Request, Response, StatusCode,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Requests are only coalesced when they would get the same response. Any
+/// header may change the response, from `Accept-Encoding` and `Range` to
+/// credentials, so every forwarded header is part of the key, sorted by name.
+type Key = (String, Vec<(HeaderName, HeaderValue)>);
+
+type UpstreamResult = Result<Response<Body>, Arc<hyper::Error>>;
+
+#[derive(Clone)]
+struct Buffered {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+type Pending = Shared<BoxFuture<'static, Result<Buffered, Arc<hyper::Error>>>>;
+
+/// Shares one upstream request between identical GET requests arriving
+/// within `window` of the first one. Shared responses are buffered in full.
+#[derive(Debug)]
+pub struct Coalescer {
+    window: Duration,
+    pending: Arc<Mutex<HashMap<Key, Pending>>>,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Coalescer {
+        Coalescer {
+            window,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The key `req` is coalesced under, if it can be.
+    pub fn key<B>(req: &Request<B>) -> Option<Key> {
+        if req.method() != hyper::Method::GET {
+            return None;
+        }
+        let mut headers: Vec<_> = req
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        // Stable, so the values of a repeated header keep their order.
+        headers.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        Some((req.uri().to_string(), headers))
+    }
+
+    /// Waits for the upstream request already made for `key`, or makes it
+    /// with `send`.
+    pub async fn request<F>(&self, key: Key, send: F) -> UpstreamResult
+    where
+        F: Future<Output = hyper::Result<Response<Body>>> + Send + 'static,
+    {
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&key) {
+                Some(shared) => {
+                    tracing::debug!("Coalescing request for {}", key.0);
+                    shared.clone()
+                }
+                None => {
+                    let shared = buffer(send).boxed().shared();
+                    pending.insert(key.clone(), shared.clone());
+                    let expire = Arc::clone(&self.pending);
+                    let window = self.window;
+                    tokio::spawn(async move {
+                        tokio::time::sleep(window).await;
+                        expire.lock().unwrap().remove(&key);
+                    });
+                    shared
+                }
+            }
+        };
+        let buffered = pending.await?;
+        let mut response = Response::new(Body::from(buffered.body));
+        *response.status_mut() = buffered.status;
+        *response.headers_mut() = buffered.headers;
+        Ok(response)
+    }
+}
+
+async fn buffer<F>(send: F) -> Result<Buffered, Arc<hyper::Error>>
+where
+    F: Future<Output = hyper::Result<Response<Body>>>,
+{
+    let (parts, body) = send.await?.into_parts();
+    let body = body::to_bytes(body).await?;
+    Ok(Buffered {
+        status: parts.status,
+        headers: parts.headers,
+        body,
+    })
+}
@@ -0,0 +1,88 @@
+use async_compression::tokio::bufread::GzipEncoder;
+use futures::{stream, TryStreamExt};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{HeaderMap, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, TRAILER},
+    Body,
+};
+use std::{error::Error, io, pin::Pin};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Whether the client lists gzip (or `*`) in `Accept-Encoding` without
+/// refusing it with `q=0`.
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+/// Whether an upstream response with `headers` should be compressed. Bodies
+/// of unknown length are compressed, already encoded ones never are. Nor are
+/// bodies that announce trailers, since the gzip stream would drop them.
+pub fn should_compress(headers: &HeaderMap, min_bytes: u64) -> bool {
+    if headers.contains_key(CONTENT_ENCODING) || headers.contains_key(TRAILER) {
+        return false;
+    }
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .is_none_or(|len| len > min_bytes)
+}
+
+/// Gzips `body` as it streams through.
+pub fn gzip<B>(mut body: B) -> Body
+where
+    B: HttpBody<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    let data = stream::poll_fn(move |cx| Pin::new(&mut body).poll_data(cx));
+    let reader = StreamReader::new(data.map_err(io::Error::other));
+    Body::wrap_stream(ReaderStream::new(GzipEncoder::new(reader)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_accepts_gzip() {
+        let accepts = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+            accepts_gzip(&headers)
+        };
+        assert!(accepts("gzip"));
+        assert!(accepts("deflate, GZIP;q=0.5"));
+        assert!(accepts("*"));
+        assert!(!accepts("br"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let mut headers = HeaderMap::new();
+        assert!(should_compress(&headers, 1024));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("512"));
+        assert!(!should_compress(&headers, 1024));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("2048"));
+        assert!(should_compress(&headers, 1024));
+        headers.insert(TRAILER, HeaderValue::from_static("grpc-status"));
+        assert!(!should_compress(&headers, 1024));
+    }
+}
@@ -0,0 +1,595 @@
+use crate::auth::PathAuth;
+use crate::body_log::BodyLogFormatRule;
+use crate::client::{self, HeaderCase, HttpVersionNegotiation, TlsRenegotiation, UpstreamProtocol};
+use crate::content_type::ContentTypeOverride;
+use crate::cookies::InsecureCookies;
+use crate::filter::FilterRule;
+use crate::grpc;
+use crate::path::PathCase;
+use crate::priority::PathPriority;
+use crate::proxy_protocol;
+use crate::rate_limit::{PathRateLimit, RateLimitAlgorithm};
+use crate::reload::ReloadSignal;
+use crate::status_ranges::StatusRanges;
+use crate::timeouts::{MethodTimeouts, PathTimeout};
+use crate::validation::ResponseValidation;
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    Method, StatusCode,
+};
+use openssl::sha::sha256;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Everything the proxy can be configured with, loaded from a TOML file
+/// whose keys are the names of the command line flags, e.g.
+/// `base-endpoint = "http://127.0.0.1:8080"`. Keys that aren't given take
+/// the flags' defaults. See `--help` for what each option does.
+///
+/// Per-path timeouts are given in a `[timeouts]` table of path globs to
+/// milliseconds instead, e.g. `"/reports/*" = 30000`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub base_endpoint: String,
+    pub listen: SocketAddr,
+    pub upstream_authority: Option<String>,
+    pub upstream_path_prefix: Option<String>,
+    pub restrict_upstream_ip_ranges: bool,
+    pub upstream_ipv4_only: bool,
+    pub upstream_ipv6_only: bool,
+    pub upstream_connect_retry: u32,
+    pub upstream_connection_timeout_per_attempt_ms: Option<u64>,
+    pub upstream_connection_timeout_total_ms: Option<u64>,
+    pub upstream_tcp_fast_open: bool,
+    pub upstream_tcp_keepalive_interval: Option<NonZeroU64>,
+    pub upstream_tcp_keepalive_probes: Option<u32>,
+    pub upstream_request_write_buf_size: Option<usize>,
+    pub upstream_title_case_headers: bool,
+    #[serde(deserialize_with = "parse")]
+    pub request_header_case: HeaderCase,
+    pub upstream_http1_writev: bool,
+    pub force_close_upstream_connection: bool,
+    pub upstream_idle_connection_check: bool,
+    pub idle_connection_revalidate_after_ms: u64,
+    pub pool_pre_check: bool,
+    pub upstream_pool_health_check_path: String,
+    pub max_concurrent_upstream_connections_per_host: Option<usize>,
+    pub max_host_connections: Option<usize>,
+    pub upstream_max_idle_connection_age_secs: Option<u64>,
+    pub upstream_preemptive_refresh_connections: bool,
+    pub grpc_keepalive_interval_secs: Option<u64>,
+    pub grpc_keepalive_timeout_secs: u64,
+    pub upstream_h2_initial_stream_window: u32,
+    pub upstream_h2_initial_connection_window: u32,
+    pub upstream_resolver_timeout_ms: Option<u64>,
+    pub tls_key_log_file: Option<PathBuf>,
+    pub debug_mode: bool,
+    pub upstream_tls_no_session_tickets: bool,
+    #[serde(deserialize_with = "parse")]
+    pub upstream_tls_renegotiation: TlsRenegotiation,
+    #[serde(deserialize_with = "parse")]
+    pub upstream_http_version_negotiation: HttpVersionNegotiation,
+    #[serde(deserialize_with = "parse_option")]
+    pub upstream_tls_hostname_pattern: Option<Regex>,
+    pub slow_client_abort_threshold_ms: Option<u64>,
+    pub min_client_bandwidth_bps: u64,
+    pub upstream_pre_connect_hook_url: Option<String>,
+    pub pre_connect_cache_secs: u64,
+    pub remove_response_cookies: bool,
+    pub remove_cookies_matching: Vec<String>,
+    #[serde(deserialize_with = "parse_option")]
+    pub strip_response_set_cookie_for_non_https: Option<InsecureCookies>,
+    #[serde(deserialize_with = "parse_option")]
+    pub inject_response_timing_header: Option<HeaderName>,
+    pub upstream_auth_token_refresh_url: Option<String>,
+    pub upstream_auth_client_credentials: Option<String>,
+    pub upstream_auth_google_iam: bool,
+    pub upstream_auth_google_audience: Option<String>,
+    pub aws_region: Option<String>,
+    pub aws_service: Option<String>,
+    #[serde(deserialize_with = "parse_option")]
+    pub upstream_proxy_protocol: Option<proxy_protocol::Version>,
+    #[serde(deserialize_with = "parse_option")]
+    pub listen_proxy_protocol: Option<proxy_protocol::Version>,
+    pub listen_recv_buf_size: Option<usize>,
+    pub listen_send_buf_size: Option<usize>,
+    pub listen_defer_accept: bool,
+    pub listen_tcp_quickack: bool,
+    pub listen_h2_max_frame_size: Option<u32>,
+    pub workers: Option<NonZeroUsize>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_session_cache_size: Option<u32>,
+    pub tls_session_timeout_secs: Option<u64>,
+    pub client_cert_optional: Option<PathBuf>,
+    pub upstream_timeout: u64,
+    pub max_req_body: Option<u64>,
+    pub max_resp_body: Option<u64>,
+    pub grpc_max_recv_message_size: u64,
+    pub grpc_max_send_message_size: Option<u64>,
+    pub upstream_grpc_status_mapping: bool,
+    #[serde(deserialize_with = "parse_option")]
+    pub upstream_timeout_per_method: Option<MethodTimeouts>,
+    pub upstream_timeout_jitter_ms: u64,
+    #[serde(rename = "timeouts", deserialize_with = "parse_path_timeouts")]
+    pub upstream_per_path_timeout: Vec<PathTimeout>,
+    pub gzip_compress_response_above_bytes: Option<u64>,
+    pub drain_timeout_secs: u64,
+    #[serde(deserialize_with = "parse")]
+    pub reload_signal: ReloadSignal,
+    pub upstream_empty_body_timeout_ms: Option<u64>,
+    pub upstream_response_timeout_after_first_byte_ms: Option<u64>,
+    pub upstream_response_min_speed_bps: Option<u64>,
+    pub upstream_slow_speed_window_secs: u64,
+    pub request_coalesce_window_ms: Option<u64>,
+    #[serde(deserialize_with = "parse_option")]
+    pub upstream_response_hash_header: Option<HeaderName>,
+    pub request_log_sampling_rate: Option<f64>,
+    pub log_request_body_for: Vec<String>,
+    #[serde(deserialize_with = "parse_vec")]
+    pub request_body_log_format: Vec<BodyLogFormatRule>,
+    pub upstream_happy_path_only: bool,
+    #[serde(deserialize_with = "parse")]
+    pub upstream_error_status: StatusCode,
+    pub upstream_error_body: String,
+    #[serde(deserialize_with = "parse_option")]
+    pub upstream_valid_status_range: Option<StatusRanges>,
+    #[serde(deserialize_with = "parse")]
+    pub upstream_response_validation_mode: ResponseValidation,
+    pub upstream_latency_sla_warn_ms: Option<u64>,
+    pub upstream_response_size_sla_warn_bytes: Option<u64>,
+    pub rate_limit_capacity: Option<NonZeroU32>,
+    pub rate_limit_rps: Option<f64>,
+    #[serde(deserialize_with = "parse")]
+    pub rate_limit_algorithm: RateLimitAlgorithm,
+    #[serde(deserialize_with = "parse_vec")]
+    pub rate_limit_path: Vec<PathRateLimit>,
+    pub upstream_burst_limit: Option<NonZeroU32>,
+    pub upstream_queue_depth: usize,
+    #[serde(deserialize_with = "parse_option")]
+    pub log_correlation_id_header: Option<HeaderName>,
+    #[serde(deserialize_with = "parse_vec")]
+    pub trace_request_headers: Vec<HeaderName>,
+    pub trace_sensitive_headers: bool,
+    pub abort_on_upstream_tls_error: bool,
+    pub request_normalize_path: bool,
+    pub request_normalize_path_strict: bool,
+    #[serde(deserialize_with = "parse")]
+    pub request_path_case_normalization: PathCase,
+    pub upstream_base64_encode_path: bool,
+    #[serde(deserialize_with = "parse_vec")]
+    pub response_vary_header: Vec<HeaderName>,
+    pub response_download_header: Option<String>,
+    pub force_download_for: Vec<String>,
+    pub force_download_override: bool,
+    #[serde(deserialize_with = "parse_vec")]
+    pub override_content_type: Vec<ContentTypeOverride>,
+    pub upstream_response_minify_json: bool,
+    pub debug_headers: bool,
+    pub upstream_connection_count_header: bool,
+    pub debug_headers_strip_prefix: Option<String>,
+    #[serde(deserialize_with = "parse_vec")]
+    pub strip_req_header: Vec<HeaderName>,
+    #[serde(deserialize_with = "parse_vec")]
+    pub allow_request_headers: Vec<HeaderName>,
+    #[serde(deserialize_with = "parse_vec")]
+    pub strip_resp_header: Vec<HeaderName>,
+    #[serde(deserialize_with = "parse_vec")]
+    pub allow_response_headers: Vec<HeaderName>,
+    pub upstream_metadata_header: bool,
+    pub upstream_send_proxy_info_header: bool,
+    pub upstream_strip_port_from_host_header: bool,
+    pub metrics_addr: Option<SocketAddr>,
+    pub response_add_etag: bool,
+    pub add_path_segment: Vec<String>,
+    pub after_segment: Vec<usize>,
+    pub handle_options_locally: bool,
+    #[serde(deserialize_with = "parse_vec")]
+    pub allowed_methods: Vec<Method>,
+    pub request_path_blocklist_file: Option<PathBuf>,
+    pub audit_log_file: Option<PathBuf>,
+    #[serde(deserialize_with = "parse_vec")]
+    pub filter: Vec<FilterRule>,
+    pub upstream_expect_content_type: Option<String>,
+    #[serde(deserialize_with = "parse_header_option")]
+    pub connection_draining_header: Option<(HeaderName, HeaderValue)>,
+    pub allow_get_body: bool,
+    pub allow_method_override: bool,
+    #[serde(deserialize_with = "parse")]
+    pub request_method_override_header: HeaderName,
+    pub no_forwarding_headers: bool,
+    pub upstream_pool_warm_up: Option<usize>,
+    pub health_path: String,
+    pub geo_block: Vec<String>,
+    pub geoip_db_path: Option<PathBuf>,
+    pub serve_favicon_file: Option<PathBuf>,
+    pub request_body_encoding_validation: bool,
+    pub request_body_type_validation: bool,
+    pub body_validation_max_bytes: usize,
+    pub body_passthrough_above_bytes: Option<u64>,
+    pub request_body_transform_remove_fields: Vec<String>,
+    pub request_transform_graphql_operation_name_header: bool,
+    #[serde(deserialize_with = "parse_option")]
+    pub body_size_reporting_header: Option<HeaderName>,
+    #[serde(deserialize_with = "parse_option")]
+    pub response_body_size_header: Option<HeaderName>,
+    #[serde(deserialize_with = "parse_vec")]
+    pub path_auth: Vec<PathAuth>,
+    pub route: Vec<String>,
+    pub version_upstream: Vec<String>,
+    pub host_route: Vec<String>,
+    pub feature_route: Vec<String>,
+    pub upstream_hostname_from_path_segment: Option<usize>,
+    pub upstream_template: Option<String>,
+    #[serde(deserialize_with = "parse_vec")]
+    pub upstream_h2_priority: Vec<PathPriority>,
+    #[serde(deserialize_with = "parse_vec")]
+    pub upstream_protocol: Vec<UpstreamProtocol>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            base_endpoint: "http://127.0.0.1:8080".to_string(),
+            listen: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            upstream_authority: None,
+            upstream_path_prefix: None,
+            restrict_upstream_ip_ranges: true,
+            upstream_ipv4_only: false,
+            upstream_ipv6_only: false,
+            upstream_connect_retry: 0,
+            upstream_connection_timeout_per_attempt_ms: None,
+            upstream_connection_timeout_total_ms: None,
+            upstream_tcp_fast_open: false,
+            upstream_tcp_keepalive_interval: None,
+            upstream_tcp_keepalive_probes: None,
+            upstream_request_write_buf_size: None,
+            upstream_title_case_headers: false,
+            request_header_case: HeaderCase::Lowercase,
+            upstream_http1_writev: true,
+            force_close_upstream_connection: false,
+            upstream_idle_connection_check: false,
+            idle_connection_revalidate_after_ms: 30_000,
+            pool_pre_check: false,
+            upstream_pool_health_check_path: "/healthz".to_string(),
+            max_concurrent_upstream_connections_per_host: None,
+            max_host_connections: None,
+            upstream_max_idle_connection_age_secs: None,
+            upstream_preemptive_refresh_connections: false,
+            grpc_keepalive_interval_secs: None,
+            grpc_keepalive_timeout_secs: 5,
+            upstream_h2_initial_stream_window: client::DEFAULT_HTTP2_STREAM_WINDOW,
+            upstream_h2_initial_connection_window: client::DEFAULT_HTTP2_CONNECTION_WINDOW,
+            upstream_resolver_timeout_ms: None,
+            tls_key_log_file: None,
+            debug_mode: false,
+            upstream_tls_no_session_tickets: false,
+            upstream_tls_renegotiation: TlsRenegotiation::Ignore,
+            upstream_http_version_negotiation: HttpVersionNegotiation::Alpn,
+            upstream_tls_hostname_pattern: None,
+            slow_client_abort_threshold_ms: None,
+            min_client_bandwidth_bps: 1024,
+            upstream_pre_connect_hook_url: None,
+            pre_connect_cache_secs: 0,
+            remove_response_cookies: false,
+            remove_cookies_matching: Vec::new(),
+            strip_response_set_cookie_for_non_https: None,
+            inject_response_timing_header: None,
+            upstream_auth_token_refresh_url: None,
+            upstream_auth_client_credentials: None,
+            upstream_auth_google_iam: false,
+            upstream_auth_google_audience: None,
+            aws_region: None,
+            aws_service: None,
+            upstream_proxy_protocol: None,
+            listen_proxy_protocol: None,
+            listen_recv_buf_size: None,
+            listen_send_buf_size: None,
+            listen_defer_accept: false,
+            listen_tcp_quickack: false,
+            listen_h2_max_frame_size: None,
+            workers: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_session_cache_size: None,
+            tls_session_timeout_secs: None,
+            client_cert_optional: None,
+            upstream_timeout: 30,
+            max_req_body: None,
+            max_resp_body: None,
+            grpc_max_recv_message_size: grpc::DEFAULT_MAX_RECV_MESSAGE_SIZE,
+            grpc_max_send_message_size: None,
+            upstream_grpc_status_mapping: false,
+            upstream_timeout_per_method: None,
+            upstream_timeout_jitter_ms: 0,
+            upstream_per_path_timeout: Vec::new(),
+            gzip_compress_response_above_bytes: None,
+            drain_timeout_secs: 10,
+            reload_signal: ReloadSignal::Hangup,
+            upstream_empty_body_timeout_ms: None,
+            upstream_response_timeout_after_first_byte_ms: None,
+            upstream_response_min_speed_bps: None,
+            upstream_slow_speed_window_secs: 10,
+            request_coalesce_window_ms: None,
+            upstream_response_hash_header: None,
+            request_log_sampling_rate: None,
+            log_request_body_for: Vec::new(),
+            request_body_log_format: Vec::new(),
+            upstream_happy_path_only: false,
+            upstream_error_status: StatusCode::BAD_GATEWAY,
+            upstream_error_body: String::new(),
+            upstream_valid_status_range: None,
+            upstream_response_validation_mode: ResponseValidation::Lenient,
+            upstream_latency_sla_warn_ms: None,
+            upstream_response_size_sla_warn_bytes: None,
+            rate_limit_capacity: None,
+            rate_limit_rps: None,
+            rate_limit_algorithm: RateLimitAlgorithm::TokenBucket,
+            rate_limit_path: Vec::new(),
+            upstream_burst_limit: None,
+            upstream_queue_depth: 0,
+            log_correlation_id_header: None,
+            trace_request_headers: Vec::new(),
+            trace_sensitive_headers: false,
+            abort_on_upstream_tls_error: false,
+            request_normalize_path: false,
+            request_normalize_path_strict: false,
+            request_path_case_normalization: PathCase::None,
+            upstream_base64_encode_path: false,
+            response_vary_header: Vec::new(),
+            response_download_header: None,
+            force_download_for: Vec::new(),
+            force_download_override: false,
+            override_content_type: Vec::new(),
+            upstream_response_minify_json: false,
+            debug_headers: false,
+            upstream_connection_count_header: false,
+            debug_headers_strip_prefix: None,
+            strip_req_header: Vec::new(),
+            allow_request_headers: Vec::new(),
+            strip_resp_header: Vec::new(),
+            allow_response_headers: Vec::new(),
+            upstream_metadata_header: false,
+            upstream_send_proxy_info_header: false,
+            upstream_strip_port_from_host_header: false,
+            metrics_addr: None,
+            response_add_etag: false,
+            add_path_segment: Vec::new(),
+            after_segment: Vec::new(),
+            handle_options_locally: false,
+            allowed_methods: vec![
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::DELETE,
+                Method::OPTIONS,
+                Method::HEAD,
+            ],
+            request_path_blocklist_file: None,
+            audit_log_file: None,
+            filter: Vec::new(),
+            upstream_expect_content_type: None,
+            connection_draining_header: None,
+            allow_get_body: false,
+            allow_method_override: false,
+            request_method_override_header: HeaderName::from_static("x-http-method-override"),
+            no_forwarding_headers: false,
+            upstream_pool_warm_up: None,
+            health_path: "/_proxy/health".to_string(),
+            geo_block: Vec::new(),
+            geoip_db_path: None,
+            serve_favicon_file: None,
+            request_body_encoding_validation: false,
+            request_body_type_validation: false,
+            body_validation_max_bytes: 1024 * 1024,
+            body_passthrough_above_bytes: None,
+            request_body_transform_remove_fields: Vec::new(),
+            request_transform_graphql_operation_name_header: false,
+            body_size_reporting_header: None,
+            response_body_size_header: None,
+            path_auth: Vec::new(),
+            route: Vec::new(),
+            version_upstream: Vec::new(),
+            host_route: Vec::new(),
+            feature_route: Vec::new(),
+            upstream_hostname_from_path_segment: None,
+            upstream_template: None,
+            upstream_h2_priority: Vec::new(),
+            upstream_protocol: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&contents).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+
+    /// A SHA-256 hash of the configuration, changing whenever it does.
+    pub fn hash(&self) -> String {
+        sha256(format!("{:?}", self).as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// The keys, as in the configuration file, whose values differ between
+    /// this configuration and `other`.
+    pub fn changed_keys(&self, other: &Config) -> Vec<String> {
+        // One entry per field of the pretty-printed `Debug` output, whose
+        // nested values are indented further.
+        let fields = |config: &Config| {
+            let mut fields: Vec<(String, String)> = Vec::new();
+            for line in format!("{:#?}", config).lines() {
+                let field = line
+                    .strip_prefix("    ")
+                    .and_then(|field| field.split_once(": "))
+                    .filter(|(name, _)| name.chars().all(|c| c.is_ascii_lowercase() || c == '_'));
+                match (field, fields.last_mut()) {
+                    (Some((name, value)), _) => fields.push((name.to_string(), value.to_string())),
+                    (None, Some((_, value))) => value.push_str(line),
+                    (None, None) => {}
+                }
+            }
+            fields
+        };
+        fields(self)
+            .into_iter()
+            .zip(fields(other))
+            .filter(|(old, new)| old.1 != new.1)
+            .map(|((name, _), _)| name.replace('_', "-"))
+            .collect()
+    }
+}
+
+/// Parses a header given as `NAME: VALUE`.
+pub fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected NAME: VALUE, got '{}'", s))?;
+    let name = name
+        .trim()
+        .parse::<HeaderName>()
+        .map_err(|e| format!("invalid header name '{}': {}", name, e))?;
+    let value = HeaderValue::from_str(value.trim())
+        .map_err(|e| format!("invalid header value '{}': {}", value, e))?;
+    Ok((name, value))
+}
+
+// Options without a serde representation are written as on the command line.
+
+fn parse<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+fn parse_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    parse(deserializer).map(Some)
+}
+
+fn parse_vec<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+fn parse_path_timeouts<'de, D>(deserializer: D) -> Result<Vec<PathTimeout>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    BTreeMap::<String, u64>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(pattern, millis)| {
+            PathTimeout::new(&pattern, Duration::from_millis(millis)).map_err(de::Error::custom)
+        })
+        .collect()
+}
+
+fn parse_header_option<'de, D>(
+    deserializer: D,
+) -> Result<Option<(HeaderName, HeaderValue)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_header(&String::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_example() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("proxy-filter.example.toml");
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.base_endpoint, "https://api.internal:8443");
+        assert_eq!(config.listen, SocketAddr::from(([0, 0, 0, 0], 8000)));
+        assert_eq!(config.upstream_timeout, 10);
+        assert_eq!(config.route.len(), 2);
+        assert_eq!(config.strip_req_header, [HeaderName::from_static("cookie")]);
+        assert_eq!(config.filter.len(), 1);
+        assert_eq!(
+            config.upstream_per_path_timeout,
+            ["/reports/*=30000".parse().unwrap()]
+        );
+        assert_eq!(
+            config.connection_draining_header.unwrap().0,
+            HeaderName::from_static("x-draining")
+        );
+        // Keys that aren't given keep their defaults.
+        assert_eq!(config.health_path, "/_proxy/health");
+        assert_eq!(config.upstream_error_status, StatusCode::BAD_GATEWAY);
+        assert_eq!(config.upstream_tls_renegotiation, TlsRenegotiation::Ignore);
+
+        assert!(toml::from_str::<Config>("upstream-timout = 10").is_err());
+        assert!(toml::from_str::<Config>("upstream-tls-renegotiation = \"never\"").is_err());
+        assert!(
+            toml::from_str::<Config>("upstream-http-version-negotiation = \"upgrade\"").is_err()
+        );
+        assert!(toml::from_str::<Config>("request-header-case = \"title\"").is_err());
+        assert!(toml::from_str::<Config>("strip-req-header = [\"bad header\"]").is_err());
+        assert!(toml::from_str::<Config>("[timeouts]\n\"reports\" = 100").is_err());
+    }
+
+    #[test]
+    fn test_changed_keys() {
+        let config = Config::default();
+        assert!(config.changed_keys(&config.clone()).is_empty());
+        let changed = Config {
+            base_endpoint: "http://localhost:9000".to_string(),
+            route: vec!["/api/*=http://localhost:9001".to_string()],
+            tls_cert: Some(PathBuf::from("cert.pem")),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.changed_keys(&changed),
+            ["base-endpoint", "tls-cert", "route"]
+        );
+    }
+
+    #[test]
+    fn test_hash_ignores_method_timeout_order() {
+        let config = |timeouts: &str| Config {
+            upstream_timeout_per_method: Some(timeouts.parse().unwrap()),
+            ..Config::default()
+        };
+        let (a, b) = (
+            config("GET:1,POST:2,PUT:3,DELETE:4,PATCH:5"),
+            config("PATCH:5,DELETE:4,PUT:3,POST:2,GET:1"),
+        );
+        assert_eq!(a.hash(), b.hash());
+        assert!(a.changed_keys(&b).is_empty());
+        assert_ne!(a.hash(), config("GET:1").hash());
+    }
+}
@@ -0,0 +1,877 @@
+use crate::body;
+use crate::proxy_protocol;
+use crate::resolver::{self, UpstreamResolver};
+use futures::future::BoxFuture;
+use hyper::{
+    client::{
+        connect::{dns::Name, Connected, Connection},
+        HttpConnector,
+    },
+    service::Service,
+    Body, Response, Uri, Version,
+};
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf},
+    net::{TcpSocket, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Sleep,
+};
+
+/// How long to wait before retrying a failed upstream connection.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+tokio::task_local! {
+    static DOWNSTREAM: Downstream;
+}
+
+/// The inbound connection an upstream request is made on behalf of.
+#[derive(Clone, Copy, Debug)]
+pub struct Downstream {
+    pub remote_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+}
+
+/// Runs `f` with `downstream` visible to any upstream connection it opens.
+pub async fn scope<F: Future>(downstream: Downstream, f: F) -> F::Output {
+    DOWNSTREAM.scope(downstream, f).await
+}
+
+/// Returned when a host, or all upstreams together when `None`, already
+/// have as many open connections as allowed.
+#[derive(Debug)]
+pub struct ConnectionLimitReached(Option<String>);
+
+impl fmt::Display for ConnectionLimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(host) => write!(f, "connection limit reached for '{}'", host),
+            None => write!(f, "connection limit reached for all upstreams"),
+        }
+    }
+}
+
+impl Error for ConnectionLimitReached {}
+
+/// Whether `err` was caused by reaching the connection limit for a host or
+/// for all upstreams.
+pub fn is_connection_limit_reached(err: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if e.is::<ConnectionLimitReached>() {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
+
+/// Caps the number of open connections to each upstream host.
+#[derive(Debug)]
+struct HostLimits {
+    max_per_host: usize,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostLimits {
+    fn try_acquire(&self, host: &str) -> Result<OwnedSemaphorePermit, ConnectionLimitReached> {
+        let semaphore = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let semaphore = hosts
+                .entry(host.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(self.max_per_host)));
+            Arc::clone(semaphore)
+        };
+        semaphore
+            .try_acquire_owned()
+            .map_err(|_| ConnectionLimitReached(Some(host.to_string())))
+    }
+}
+
+/// Opens TCP connections to upstreams, retrying failed connection attempts
+/// up to `connect_retries` times.
+#[derive(Clone, Debug)]
+pub struct UpstreamConnector {
+    http: HttpConnector<UpstreamResolver>,
+    proxy_protocol: Option<proxy_protocol::Version>,
+    connect_retries: u32,
+    host_limits: Option<Arc<HostLimits>>,
+    /// Caps the number of open connections to all hosts together.
+    limit: Option<Arc<Semaphore>>,
+    pool_stats: Arc<PoolStats>,
+    direct: Option<DirectConnect>,
+    total_connect_timeout: Option<Duration>,
+    keepalive: Option<TcpKeepalive>,
+    write_buffer_size: usize,
+    max_age: Option<Duration>,
+    ip_literal_check: Option<UpstreamResolver>,
+}
+
+impl UpstreamConnector {
+    pub fn new(
+        http: HttpConnector<UpstreamResolver>,
+        proxy_protocol: Option<proxy_protocol::Version>,
+        connect_retries: u32,
+    ) -> UpstreamConnector {
+        UpstreamConnector {
+            http,
+            proxy_protocol,
+            connect_retries,
+            host_limits: None,
+            limit: None,
+            pool_stats: Arc::new(PoolStats::default()),
+            direct: None,
+            total_connect_timeout: None,
+            keepalive: None,
+            write_buffer_size: 0,
+            max_age: None,
+            ip_literal_check: None,
+        }
+    }
+
+    /// Refuses to connect to hosts given as IP literals that `resolver`
+    /// denies, which the resolver itself never sees.
+    pub fn with_ip_literal_check(mut self, resolver: UpstreamResolver) -> Self {
+        self.ip_literal_check = Some(resolver);
+        self
+    }
+
+    /// Buffers up to `size` bytes written to each connection before sending
+    /// them, so that small writes of a large body take fewer syscalls at the
+    /// cost of copying them.
+    pub fn with_write_buffer(mut self, size: usize) -> Self {
+        self.write_buffer_size = size;
+        self
+    }
+
+    /// Closes HTTP/1 connections open for longer than `max_age` once they are
+    /// between requests, which needs responses passed through
+    /// `release_after`. HTTP/2 connections are left open.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Sends TCP keepalive probes on idle connections, so ones dropped
+    /// silently along the way are noticed.
+    pub fn with_tcp_keepalive(mut self, keepalive: TcpKeepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Opens connections with TCP Fast Open, resolving hostnames with
+    /// `resolver` rather than through the `HttpConnector`.
+    pub fn with_fast_open(mut self, resolver: UpstreamResolver) -> Self {
+        if !FAST_OPEN_SUPPORTED {
+            tracing::warn!("TCP Fast Open is not supported on this OS, connecting without it");
+            return self;
+        }
+        self.direct(resolver).fast_open = true;
+        self
+    }
+
+    /// Gives up connecting to each address of a host after `timeout`, moving
+    /// on to the next, resolving hostnames with `resolver` rather than
+    /// through the `HttpConnector`.
+    pub fn with_attempt_timeout(mut self, resolver: UpstreamResolver, timeout: Duration) -> Self {
+        self.direct(resolver).attempt_timeout = Some(timeout);
+        self
+    }
+
+    /// Gives up connecting to a host after `timeout`, however many of its
+    /// addresses are left to try. Each retry gets its own `timeout`.
+    pub fn with_total_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.total_connect_timeout = Some(timeout);
+        self
+    }
+
+    fn direct(&mut self, resolver: UpstreamResolver) -> &mut DirectConnect {
+        self.direct.get_or_insert(DirectConnect {
+            resolver,
+            fast_open: false,
+            attempt_timeout: None,
+        })
+    }
+
+    /// Counts of the upstream connections currently open.
+    pub fn pool_stats(&self) -> Arc<PoolStats> {
+        Arc::clone(&self.pool_stats)
+    }
+
+    /// Counts connections in `pool_stats`, shared with other connectors.
+    pub fn with_pool_stats(mut self, pool_stats: Arc<PoolStats>) -> Self {
+        self.pool_stats = pool_stats;
+        self
+    }
+
+    /// Keeps at most `max_per_host` connections open to each host. Further
+    /// connection attempts fail with `ConnectionLimitReached`.
+    pub fn with_max_connections_per_host(mut self, max_per_host: usize) -> Self {
+        self.host_limits = Some(Arc::new(HostLimits {
+            max_per_host,
+            hosts: Mutex::new(HashMap::new()),
+        }));
+        self
+    }
+
+    /// Keeps at most `max` connections open across all hosts. Further
+    /// connection attempts fail with `ConnectionLimitReached`.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.limit = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+}
+
+/// The upstream connections currently open, in total and per host, and the
+/// requests per host waiting on a response.
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    open: AtomicUsize,
+    hosts: Mutex<HashMap<String, HostStats>>,
+    newest: Mutex<HashMap<String, (Uri, Instant)>>,
+    released: Mutex<HashMap<String, Instant>>,
+}
+
+/// The connections open to one upstream host.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HostStats {
+    pub open: usize,
+    pub active: usize,
+}
+
+impl HostStats {
+    /// Open connections not serving a request. HTTP/2 requests share a
+    /// connection, so this undercounts for upstreams speaking it.
+    pub fn idle(&self) -> usize {
+        self.open.saturating_sub(self.active)
+    }
+}
+
+impl PoolStats {
+    /// The number of connections open to any upstream, in use or idle.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// The connections open to the host of `uri`.
+    pub fn host(&self, uri: &Uri) -> HostStats {
+        let hosts = self.hosts.lock().unwrap();
+        hosts.get(&host_key(uri)).copied().unwrap_or_default()
+    }
+
+    /// Counts a request to the host of `uri` as active until the returned
+    /// guard is dropped.
+    pub fn start_request(self: &Arc<Self>, uri: &Uri) -> ActiveRequest {
+        let host = host_key(uri);
+        self.update(&host, |stats| stats.active += 1);
+        ActiveRequest {
+            stats: Arc::clone(self),
+            host,
+        }
+    }
+
+    /// How long the host of `uri` has had no request counted by
+    /// `start_request` end and no connection open, if it has an idle
+    /// connection. The pool reuses the connection idle for the shortest
+    /// time, so this is how long the next request's connection has been
+    /// idle.
+    pub fn idle_for(&self, uri: &Uri) -> Option<Duration> {
+        if self.host(uri).idle() == 0 {
+            return None;
+        }
+        let host = host_key(uri);
+        let opened = self.newest.lock().unwrap().get(&host).map(|(_, at)| *at);
+        let released = self.released.lock().unwrap().get(&host).copied();
+        opened.max(released).map(|since| since.elapsed())
+    }
+
+    /// The hosts whose newest open connection is between `min_age` and
+    /// `max_age` old, with a URI to connect to them by and their connections.
+    pub fn aging_hosts(&self, min_age: Duration, max_age: Duration) -> Vec<(Uri, HostStats)> {
+        let newest = self.newest.lock().unwrap();
+        let hosts = self.hosts.lock().unwrap();
+        newest
+            .iter()
+            .filter(|(_, (_, opened))| (min_age..max_age).contains(&opened.elapsed()))
+            .map(|(host, (uri, _))| {
+                let stats = hosts.get(host).copied().unwrap_or_default();
+                (uri.clone(), stats)
+            })
+            .collect()
+    }
+
+    fn update(&self, host: &str, f: impl FnOnce(&mut HostStats)) -> HostStats {
+        let mut hosts = self.hosts.lock().unwrap();
+        let stats = hosts.entry(host.to_string()).or_default();
+        f(stats);
+        let updated = *stats;
+        if updated == HostStats::default() {
+            hosts.remove(host);
+        }
+        updated
+    }
+}
+
+/// The `host:port` connections to `uri` are pooled by.
+fn host_key(uri: &Uri) -> String {
+    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    });
+    format!("{}:{}", uri.host().unwrap_or_default(), port)
+}
+
+/// A request counted as active by `PoolStats::start_request`.
+#[derive(Debug)]
+pub struct ActiveRequest {
+    stats: Arc<PoolStats>,
+    host: String,
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        let host = self.stats.update(&self.host, |stats| {
+            stats.active = stats.active.saturating_sub(1)
+        });
+        if host.open > 0 {
+            let mut released = self.stats.released.lock().unwrap();
+            released.insert(self.host.clone(), Instant::now());
+        }
+    }
+}
+
+/// Counts a connection as open until dropped.
+#[derive(Debug)]
+struct OpenConnection {
+    stats: Arc<PoolStats>,
+    host: String,
+    peer: String,
+}
+
+impl OpenConnection {
+    fn start(stats: &Arc<PoolStats>, uri: &Uri, stream: &TcpStream) -> OpenConnection {
+        let peer = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string());
+        let host = host_key(uri);
+        stats.update(&host, |stats| stats.open += 1);
+        let mut newest = stats.newest.lock().unwrap();
+        newest.insert(host.clone(), (uri.clone(), Instant::now()));
+        drop(newest);
+        let count = stats.open.fetch_add(1, Ordering::SeqCst) + 1;
+        tracing::debug!("Opened upstream connection to {} ({} open)", peer, count);
+        OpenConnection {
+            stats: Arc::clone(stats),
+            host,
+            peer,
+        }
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        let host = self.stats.update(&self.host, |stats| {
+            stats.open = stats.open.saturating_sub(1)
+        });
+        if host.open == 0 {
+            self.stats.newest.lock().unwrap().remove(&self.host);
+            self.stats.released.lock().unwrap().remove(&self.host);
+        }
+        let count = self.stats.open.fetch_sub(1, Ordering::SeqCst) - 1;
+        tracing::debug!(
+            "Closed upstream connection to {} ({} open)",
+            self.peer,
+            count
+        );
+    }
+}
+
+/// An upstream connection, holding its host's connection permit and its
+/// permit under the limit for all hosts, if any, until it is closed.
+#[derive(Debug)]
+pub struct UpstreamStream {
+    // Writes at least as large as the buffer, and so every write when it is
+    // empty, go straight to the socket.
+    stream: BufWriter<TcpStream>,
+    _permit: Option<OwnedSemaphorePermit>,
+    _global_permit: Option<OwnedSemaphorePermit>,
+    _open: OpenConnection,
+    max_age: Option<MaxAge>,
+}
+
+/// When a connection gets too old to be reused, and whether it is between
+/// requests.
+#[derive(Debug)]
+struct MaxAge {
+    age: Duration,
+    expired: Pin<Box<Sleep>>,
+    reuse: ConnectionReuse,
+}
+
+/// Whether an upstream connection is between requests, given in the
+/// extensions of responses from connections with a maximum age.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionReuse(Arc<ReuseState>);
+
+#[derive(Debug, Default)]
+struct ReuseState {
+    idle: AtomicBool,
+    /// The task to wake when the connection becomes idle after expiring.
+    waker: Mutex<Option<Waker>>,
+}
+
+impl ConnectionReuse {
+    fn set_idle(&self, idle: bool) {
+        self.0.idle.store(idle, Ordering::SeqCst);
+        if idle {
+            if let Some(waker) = self.0.waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Counts the HTTP/1 connection `response` came over as between requests
+/// once its body has been read in full, so that it can be closed if it is
+/// past its maximum age.
+pub fn release_after(response: Response<Body>) -> Response<Body> {
+    match response.extensions().get::<ConnectionReuse>() {
+        Some(reuse) if response.version() < Version::HTTP_2 => {
+            let reuse = reuse.clone();
+            let (parts, body) = response.into_parts();
+            let body = body::on_complete(body, move |_| reuse.set_idle(true));
+            Response::from_parts(parts, body)
+        }
+        _ => response,
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if let Poll::Ready(result) = Pin::new(&mut this.stream).poll_read(cx, buf) {
+            return Poll::Ready(result);
+        }
+        if let Some(max_age) = &mut this.max_age {
+            if max_age.expired.as_mut().poll(cx).is_ready() {
+                *max_age.reuse.0.waker.lock().unwrap() = Some(cx.waker().clone());
+                if max_age.reuse.0.idle.load(Ordering::SeqCst) {
+                    tracing::debug!(
+                        "Closing upstream connection open for over {:?}",
+                        max_age.age
+                    );
+                    // hyper takes reading nothing from an idle connection as
+                    // the upstream closing it, and drops it from the pool.
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Some(max_age) = &self.max_age {
+            max_age.reuse.set_idle(false);
+        }
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        let connected = self.stream.get_ref().connected();
+        match &self.max_age {
+            Some(max_age) => connected.extra(max_age.reuse.clone()),
+            None => connected,
+        }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        // Hyper starts connecting from within the task of the request that
+        // needs the connection, so the downstream can be read here.
+        let proxy_header = self.proxy_protocol.and_then(|version| {
+            DOWNSTREAM
+                .try_with(|downstream| {
+                    proxy_protocol::encode(version, downstream.remote_addr, downstream.local_addr)
+                })
+                .ok()
+        });
+        let proxy_protocol = self.proxy_protocol;
+        let connect_retries = self.connect_retries;
+        let mut http = self.http.clone();
+        let mut direct = self.direct.clone();
+        let total_connect_timeout = self.total_connect_timeout;
+        let pool_stats = Arc::clone(&self.pool_stats);
+        let keepalive = self.keepalive.clone();
+        let write_buffer_size = self.write_buffer_size;
+        let max_age = self.max_age;
+        let ip_literal_check = match (&self.ip_literal_check, uri.host()) {
+            (Some(resolver), Some(host)) => resolver.check_ip_literal(host),
+            _ => Ok(()),
+        };
+        let permit = match (&self.host_limits, uri.host()) {
+            (Some(limits), Some(host)) => Some(limits.try_acquire(host)),
+            _ => None,
+        };
+        let global_permit = self.limit.as_ref().map(|limit| {
+            Arc::clone(limit)
+                .try_acquire_owned()
+                .map_err(|_| ConnectionLimitReached(None))
+        });
+        Box::pin(async move {
+            ip_literal_check?;
+            let permit = permit.transpose()?;
+            let global_permit = global_permit.transpose()?;
+            let mut attempt = 0;
+            let mut stream = loop {
+                let connect = async {
+                    match &mut direct {
+                        Some(direct) => direct.connect(&uri).await,
+                        None => http.call(uri.clone()).await.map_err(Into::into),
+                    }
+                };
+                let connected = match total_connect_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, connect)
+                        .await
+                        .unwrap_or_else(|_| Err(timed_out(&uri, timeout).into())),
+                    None => connect.await,
+                };
+                match connected {
+                    Ok(stream) => break stream,
+                    // Denied addresses won't be allowed on a retry either.
+                    Err(e)
+                        if attempt < connect_retries
+                            && resolver::find_resolve_error(e.as_ref()).is_none() =>
+                    {
+                        attempt += 1;
+                        tracing::debug!(
+                            "Connecting to {} failed, retrying (attempt {}): {}",
+                            uri,
+                            attempt,
+                            e
+                        );
+                        tokio::time::sleep(CONNECT_RETRY_DELAY).await;
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            if let Some(keepalive) = &keepalive {
+                if let Err(e) = SockRef::from(&stream).set_tcp_keepalive(keepalive) {
+                    tracing::warn!("Failed to enable TCP keepalive to {}: {}", uri, e);
+                }
+            }
+            match (proxy_protocol, proxy_header) {
+                (Some(_), Some(header)) => stream.write_all(&header).await?,
+                (Some(version), None) => {
+                    return Err(
+                        format!("no downstream connection to send PROXY {} for", version).into(),
+                    )
+                }
+                _ => {}
+            }
+            Ok(UpstreamStream {
+                _open: OpenConnection::start(&pool_stats, &uri, &stream),
+                stream: BufWriter::with_capacity(write_buffer_size, stream),
+                _permit: permit,
+                _global_permit: global_permit,
+                max_age: max_age.map(|age| MaxAge {
+                    age,
+                    expired: Box::pin(tokio::time::sleep(age)),
+                    reuse: ConnectionReuse::default(),
+                }),
+            })
+        })
+    }
+}
+
+const FAST_OPEN_SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "macos"));
+
+fn timed_out(target: &dyn fmt::Display, timeout: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("connecting to {} timed out after {:?}", target, timeout),
+    )
+}
+
+/// Connects to the addresses of a host one by one rather than through the
+/// `HttpConnector`.
+#[derive(Clone, Debug)]
+struct DirectConnect {
+    resolver: UpstreamResolver,
+    /// Sends the request with the SYN to upstreams that allow it.
+    fast_open: bool,
+    attempt_timeout: Option<Duration>,
+}
+
+impl DirectConnect {
+    /// Connects to the first reachable address of the `uri`'s host.
+    async fn connect(&mut self, uri: &Uri) -> Result<TcpStream, Box<dyn Error + Send + Sync>> {
+        let host = uri
+            .host()
+            .ok_or_else(|| format!("no host in '{}'", uri))?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = uri
+            .port_u16()
+            .unwrap_or(if uri.scheme_str() == Some("https") {
+                443
+            } else {
+                80
+            });
+        let addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => self
+                .resolver
+                .call(Name::from_str(host)?)
+                .await?
+                .map(|addr| SocketAddr::new(addr.ip(), port))
+                .collect(),
+        };
+        let mut last_error = None;
+        for addr in addrs {
+            match self.connect_addr(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    tracing::debug!("Failed to connect to {}: {}", addr, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error
+            .map(Into::into)
+            .unwrap_or_else(|| format!("no addresses for '{}'", host).into()))
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if self.fast_open {
+            fast_open_socket(addr)?
+        } else if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        match self.attempt_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, socket.connect(addr))
+                .await
+                .unwrap_or_else(|_| Err(timed_out(&addr, timeout))),
+            None => socket.connect(addr).await,
+        }
+    }
+}
+
+fn fast_open_socket(addr: SocketAddr) -> io::Result<TcpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    socket.set_nonblocking(true)?;
+    if let Err(e) = enable_fast_open(&socket) {
+        tracing::warn!("Connecting to {} without TCP Fast Open: {}", addr, e);
+    }
+    Ok(TcpSocket::from_std_stream(socket.into()))
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn enable_fast_open(socket: &socket2::Socket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Linux sends data given to the first write with the SYN, macOS needs
+    // TCP_FASTOPEN set on the client socket.
+    #[cfg(target_os = "linux")]
+    let option = libc::TCP_FASTOPEN_CONNECT;
+    #[cfg(target_os = "macos")]
+    let option = libc::TCP_FASTOPEN;
+    let enable: libc::c_int = 1;
+    // SAFETY: the socket is open and `enable` outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            option,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn enable_fast_open(_socket: &socket2::Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP Fast Open is not supported on this OS",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    #[test]
+    fn test_pool_stats() {
+        let stats = Arc::new(PoolStats::default());
+        let uri = "http://example.com/path".parse::<Uri>().unwrap();
+        stats.update(&host_key(&uri), |host| host.open += 2);
+        let active = stats.start_request(&uri);
+        let explicit_port = "http://example.com:80/other".parse::<Uri>().unwrap();
+        assert_eq!(stats.host(&explicit_port), HostStats { open: 2, active: 1 });
+        assert_eq!(stats.host(&explicit_port).idle(), 1);
+        drop(active);
+        assert_eq!(stats.host(&uri).idle(), 2);
+        assert_eq!(
+            stats.host(&"https://example.com".parse().unwrap()),
+            HostStats::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_aging_hosts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let uri = format!("http://{}", addr).parse::<Uri>().unwrap();
+        let stats = Arc::new(PoolStats::default());
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let open = OpenConnection::start(&stats, &uri, &stream);
+        let max_age = Duration::from_secs(60);
+        assert_eq!(stats.aging_hosts(Duration::ZERO, max_age).len(), 1);
+        assert_eq!(stats.aging_hosts(Duration::from_secs(30), max_age), vec![]);
+        drop(open);
+        assert_eq!(stats.aging_hosts(Duration::ZERO, max_age), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_idle_for() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let uri = format!("http://{}", addr).parse::<Uri>().unwrap();
+        let stats = Arc::new(PoolStats::default());
+        assert_eq!(stats.idle_for(&uri), None);
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let _open = OpenConnection::start(&stats, &uri, &stream);
+        let active = stats.start_request(&uri);
+        assert_eq!(stats.idle_for(&uri), None);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(active);
+        assert!(stats.idle_for(&uri).unwrap() < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_write_buffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap())
+            .parse::<Uri>()
+            .unwrap();
+        let resolver = UpstreamResolver::new(Vec::new(), None);
+        let mut connector =
+            UpstreamConnector::new(HttpConnector::new_with_resolver(resolver), None, 0)
+                .with_write_buffer(1024);
+        let mut stream = connector.call(uri).await.unwrap();
+        let (mut upstream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        let read = tokio::time::timeout(Duration::from_millis(100), upstream.read(&mut buf));
+        assert!(read.await.is_err(), "write should be buffered");
+        stream.flush().await.unwrap();
+        upstream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_tcp_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap())
+            .parse::<Uri>()
+            .unwrap();
+        let resolver = UpstreamResolver::new(Vec::new(), None);
+        let mut connector =
+            UpstreamConnector::new(HttpConnector::new_with_resolver(resolver), None, 0)
+                .with_tcp_keepalive(
+                    TcpKeepalive::new()
+                        .with_time(Duration::from_secs(15))
+                        .with_interval(Duration::from_secs(5))
+                        .with_retries(4),
+                );
+        let stream = connector.call(uri).await.unwrap();
+        let socket = SockRef::from(stream.stream.get_ref());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(15));
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+        assert_eq!(socket.keepalive_retries().unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeouts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap())
+            .parse::<Uri>()
+            .unwrap();
+        let resolver = UpstreamResolver::new(Vec::new(), None);
+        let mut connector =
+            UpstreamConnector::new(HttpConnector::new_with_resolver(resolver.clone()), None, 0)
+                .with_attempt_timeout(resolver, Duration::from_secs(1))
+                .with_total_connect_timeout(Duration::from_millis(200));
+        connector.call(uri).await.unwrap();
+
+        // Connections to a listener with a full accept queue hang.
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let full = socket.listen(0).unwrap();
+        let addr = full.local_addr().unwrap();
+        let _queued = TcpStream::connect(addr).await.unwrap();
+        let uri = format!("http://{}", addr).parse::<Uri>().unwrap();
+        let started = std::time::Instant::now();
+        let err = connector.call(uri).await.err().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(err.to_string().contains("timed out after 200ms"), "{}", err);
+    }
+}
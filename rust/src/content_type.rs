@@ -0,0 +1,114 @@
+use crate::server::media_type_matches;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use std::str::FromStr;
+
+/// Content types that say nothing about the body, which upstreams fall back
+/// to when they don't know better.
+const GENERIC_TYPES: &[&str] = &["text/plain", "application/octet-stream"];
+
+/// Serves responses to requests for paths ending in `.extension` as
+/// `content_type` when the upstream gave them a generic type. Parsed from
+/// `.EXTENSION=TYPE`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContentTypeOverride {
+    pub extension: String,
+    pub content_type: HeaderValue,
+}
+
+impl FromStr for ContentTypeOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (extension, content_type) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected .EXTENSION=TYPE, got '{}'", s))?;
+        let extension = extension
+            .strip_prefix('.')
+            .filter(|extension| !extension.is_empty() && !extension.contains(['.', '/']))
+            .ok_or_else(|| format!("invalid extension '{}', expected e.g. .js", extension))?;
+        if !content_type.contains('/') {
+            return Err(format!("invalid content type '{}'", content_type));
+        }
+        let content_type = HeaderValue::from_str(content_type)
+            .map_err(|_| format!("invalid content type '{}'", content_type))?;
+        Ok(ContentTypeOverride {
+            extension: extension.to_ascii_lowercase(),
+            content_type,
+        })
+    }
+}
+
+/// Replaces a missing or generic `Content-Type` in response `headers` with
+/// the one for the extension of the request's `path`.
+pub fn apply(overrides: &[ContentTypeOverride], path: &str, headers: &mut HeaderMap) {
+    let generic = match headers.get(CONTENT_TYPE) {
+        Some(value) => value.to_str().is_ok_and(|content_type| {
+            GENERIC_TYPES
+                .iter()
+                .any(|generic| media_type_matches(content_type, generic))
+        }),
+        None => true,
+    };
+    if !generic {
+        return;
+    }
+    let file = path.rsplit('/').next().unwrap_or(path);
+    let Some((_, extension)) = file.rsplit_once('.') else {
+        return;
+    };
+    if let Some(found) = overrides
+        .iter()
+        .find(|found| found.extension.eq_ignore_ascii_case(extension))
+    {
+        headers.insert(CONTENT_TYPE, found.content_type.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let overrides: Vec<ContentTypeOverride> = vec![
+            ".js=application/javascript".parse().unwrap(),
+            ".CSS=text/css".parse().unwrap(),
+        ];
+        let applied = |path: &str, content_type: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(content_type) = content_type {
+                headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            }
+            apply(&overrides, path, &mut headers);
+            headers.get(CONTENT_TYPE).cloned()
+        };
+        assert_eq!(
+            applied("/static/app.js", Some("text/plain; charset=utf-8")).unwrap(),
+            "application/javascript"
+        );
+        assert_eq!(
+            applied("/static/site.css", Some("application/octet-stream")).unwrap(),
+            "text/css"
+        );
+        assert_eq!(
+            applied("/static/APP.JS", None).unwrap(),
+            "application/javascript"
+        );
+        assert_eq!(
+            applied("/static/app.js", Some("text/html")).unwrap(),
+            "text/html"
+        );
+        assert_eq!(
+            applied("/static/app.txt", Some("text/plain")).unwrap(),
+            "text/plain"
+        );
+        assert_eq!(applied("/static.js/app", None), None);
+
+        assert!("js=application/javascript"
+            .parse::<ContentTypeOverride>()
+            .is_err());
+        assert!(".=text/css".parse::<ContentTypeOverride>().is_err());
+        assert!(".css".parse::<ContentTypeOverride>().is_err());
+        assert!(".css=css".parse::<ContentTypeOverride>().is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use hyper::header::HeaderValue;
+use std::str::FromStr;
+
+/// Which `Set-Cookie` headers to drop from upstream responses.
+#[derive(Clone, Debug)]
+pub enum CookieFilter {
+    All,
+    NamePrefixes(Vec<String>),
+}
+
+impl CookieFilter {
+    pub fn removes(&self, set_cookie: &HeaderValue) -> bool {
+        match self {
+            CookieFilter::All => true,
+            CookieFilter::NamePrefixes(prefixes) => {
+                let name = cookie_name(set_cookie);
+                prefixes
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix.as_str()))
+            }
+        }
+    }
+}
+
+/// Which `Set-Cookie` headers to drop from responses to clients that
+/// connected over plain HTTP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InsecureCookies {
+    /// Cookies with the `Secure` attribute, which browsers must only
+    /// receive over HTTPS.
+    Secure,
+    All,
+}
+
+impl InsecureCookies {
+    pub fn removes(&self, set_cookie: &HeaderValue) -> bool {
+        match self {
+            InsecureCookies::Secure => is_secure(set_cookie),
+            InsecureCookies::All => true,
+        }
+    }
+}
+
+impl FromStr for InsecureCookies {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "secure" => Ok(InsecureCookies::Secure),
+            "all" => Ok(InsecureCookies::All),
+            other => Err(format!("expected 'secure' or 'all', got '{}'", other)),
+        }
+    }
+}
+
+/// Whether a `Set-Cookie` header value has the `Secure` attribute.
+pub fn is_secure(set_cookie: &HeaderValue) -> bool {
+    set_cookie
+        .as_bytes()
+        .split(|&b| b == b';')
+        .skip(1)
+        .any(|attribute| attribute.trim_ascii().eq_ignore_ascii_case(b"secure"))
+}
+
+/// Returns the cookie name of a `Set-Cookie` header value.
+pub fn cookie_name(set_cookie: &HeaderValue) -> &str {
+    let value = set_cookie.to_str().unwrap_or_default();
+    value.split_once('=').map_or(value, |(name, _)| name).trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie_filter_name_prefixes() {
+        let filter = CookieFilter::NamePrefixes(vec!["session".to_string()]);
+        assert!(filter.removes(&HeaderValue::from_static("session_id=abc; Path=/")));
+        assert!(!filter.removes(&HeaderValue::from_static("theme=dark; Path=/")));
+        assert!(CookieFilter::All.removes(&HeaderValue::from_static("theme=dark")));
+    }
+
+    #[test]
+    fn test_insecure_cookies() {
+        let secure = HeaderValue::from_static("session_id=abc; Path=/; SECURE; HttpOnly");
+        let insecure = HeaderValue::from_static("secure=yes; Path=/secure");
+        assert!(InsecureCookies::Secure.removes(&secure));
+        assert!(!InsecureCookies::Secure.removes(&insecure));
+        assert!(InsecureCookies::All.removes(&insecure));
+        assert_eq!("all".parse(), Ok(InsecureCookies::All));
+        assert!("none".parse::<InsecureCookies>().is_err());
+    }
+}
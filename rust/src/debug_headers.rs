@@ -0,0 +1,70 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use std::time::Duration;
+
+const UPSTREAM: &str = "x-proxy-upstream";
+const LATENCY_MS: &str = "x-proxy-latency-ms";
+const REQUEST_ID: &str = "x-proxy-request-id";
+const VERSION: &str = "x-proxy-version";
+const POOL_ACTIVE: &str = "x-proxy-pool-active";
+const POOL_IDLE: &str = "x-proxy-pool-idle";
+
+/// Adds headers describing how the proxy handled the request: the upstream
+/// URL, the upstream latency, a unique request ID and the proxy's version.
+pub fn insert(headers: &mut HeaderMap, upstream: &str, latency: Duration) {
+    if let Ok(upstream) = HeaderValue::from_str(upstream) {
+        headers.insert(UPSTREAM, upstream);
+    }
+    headers.insert(LATENCY_MS, HeaderValue::from(latency.as_millis() as u64));
+    let request_id = uuid::Uuid::new_v4().to_string();
+    headers.insert(
+        REQUEST_ID,
+        HeaderValue::from_str(&request_id).expect("uuid is a valid header value"),
+    );
+    headers.insert(VERSION, HeaderValue::from_static(env!("CARGO_PKG_VERSION")));
+}
+
+/// Adds the number of active and idle connections open to the upstream.
+pub fn insert_pool_stats(headers: &mut HeaderMap, active: usize, idle: usize) {
+    headers.insert(POOL_ACTIVE, HeaderValue::from(active));
+    headers.insert(POOL_IDLE, HeaderValue::from(idle));
+}
+
+/// Removes every header whose name starts with `prefix`, ignoring case.
+pub fn strip_prefix(headers: &mut HeaderMap, prefix: &str) {
+    let prefix = prefix.to_ascii_lowercase();
+    let names: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with(&prefix))
+        .cloned()
+        .collect();
+    for name in names {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_strip_prefix() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("text/plain"));
+        insert(
+            &mut headers,
+            "http://127.0.0.1:8080/path",
+            Duration::from_millis(42),
+        );
+        assert_eq!(headers[UPSTREAM], "http://127.0.0.1:8080/path");
+        assert_eq!(headers[LATENCY_MS], "42");
+        assert_eq!(headers[REQUEST_ID].len(), 36);
+        assert_eq!(headers[VERSION], env!("CARGO_PKG_VERSION"));
+        insert_pool_stats(&mut headers, 1, 2);
+        assert_eq!(headers[POOL_ACTIVE], "1");
+        assert_eq!(headers[POOL_IDLE], "2");
+
+        strip_prefix(&mut headers, "X-Proxy-");
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("content-type"));
+    }
+}
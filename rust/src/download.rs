@@ -0,0 +1,94 @@
+use crate::server::media_type_matches;
+use hyper::header::{HeaderMap, HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE};
+
+/// Makes browsers save responses of the given media types as a file instead
+/// of displaying them.
+#[derive(Clone, Debug)]
+pub struct ForcedDownload {
+    disposition: HeaderValue,
+    media_types: Vec<String>,
+    override_existing: bool,
+}
+
+impl ForcedDownload {
+    pub fn new(filename: &str, media_types: Vec<String>) -> Result<ForcedDownload, String> {
+        if filename.is_empty() || filename.contains(['"', '\\']) {
+            return Err(format!("invalid download filename '{}'", filename));
+        }
+        let disposition = HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename))
+            .map_err(|_| format!("invalid download filename '{}'", filename))?;
+        Ok(ForcedDownload {
+            disposition,
+            media_types,
+            override_existing: false,
+        })
+    }
+
+    /// Replaces a `Content-Disposition` the upstream already set.
+    pub fn overriding(mut self) -> Self {
+        self.override_existing = true;
+        self
+    }
+
+    /// Sets `Content-Disposition` on response `headers` with a matching
+    /// `Content-Type`.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if headers.contains_key(CONTENT_DISPOSITION) && !self.override_existing {
+            return;
+        }
+        let matches = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|content_type| {
+                self.media_types
+                    .iter()
+                    .any(|media_type| media_type_matches(content_type, media_type))
+            });
+        if matches {
+            headers.insert(CONTENT_DISPOSITION, self.disposition.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let download = ForcedDownload::new(
+            "report.csv",
+            vec!["text/csv".into(), "application/pdf".into()],
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/csv; charset=utf-8"),
+        );
+        download.apply(&mut headers);
+        assert_eq!(
+            headers[CONTENT_DISPOSITION],
+            "attachment; filename=\"report.csv\""
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        download.apply(&mut headers);
+        assert!(!headers.contains_key(CONTENT_DISPOSITION));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/pdf"));
+        headers.insert(CONTENT_DISPOSITION, HeaderValue::from_static("inline"));
+        download.apply(&mut headers);
+        assert_eq!(headers[CONTENT_DISPOSITION], "inline");
+        download.clone().overriding().apply(&mut headers);
+        assert_eq!(
+            headers[CONTENT_DISPOSITION],
+            "attachment; filename=\"report.csv\""
+        );
+
+        assert!(ForcedDownload::new("a\"b.csv", vec![]).is_err());
+        assert!(ForcedDownload::new("", vec![]).is_err());
+    }
+}
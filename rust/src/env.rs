@@ -0,0 +1,52 @@
+use std::env;
+
+/// Replaces every `${VAR}` in `s` with the value of the environment variable
+/// `VAR`, failing if it is not set.
+pub fn expand(s: &str) -> Result<String, String> {
+    expand_with(s, |name| env::var(name).ok())
+}
+
+fn expand_with<F>(s: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unterminated '${{' in '{}'", s))?;
+        let name = &rest[start + 2..start + end];
+        let value =
+            lookup(name).ok_or_else(|| format!("environment variable '{}' is not set", name))?;
+        expanded.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_with() {
+        let lookup = |name: &str| match name {
+            "UPSTREAM_HOST" => Some("example.com".to_string()),
+            "UPSTREAM_PORT" => Some("8443".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_with("https://${UPSTREAM_HOST}:${UPSTREAM_PORT}/api", lookup),
+            Ok("https://example.com:8443/api".to_string())
+        );
+        assert_eq!(
+            expand_with("http://127.0.0.1:8080", lookup),
+            Ok("http://127.0.0.1:8080".to_string())
+        );
+        assert!(expand_with("https://${MISSING}", lookup).is_err());
+        assert!(expand_with("https://${UPSTREAM_HOST", lookup).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+use std::{fmt, sync::Arc, time::Duration};
+
+/// Why the proxy answered a request itself rather than with the upstream's
+/// response.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The request can't be forwarded as it is.
+    BadRequest,
+    /// The method override header names an invalid or disallowed method.
+    InvalidMethodOverride(String),
+    /// A JSON request body is not valid UTF-8.
+    InvalidEncoding(String),
+    /// A request body is not well-formed for its content type.
+    InvalidBody(String),
+    /// The request body is larger than `limit` bytes.
+    RequestBodyTooLarge { limit: u64 },
+    /// The upstream resolved to an address the proxy may not connect to.
+    UpstreamDenied,
+    /// The upstream's name couldn't be resolved in time.
+    UpstreamResolveTimeout,
+    /// The upstream sent no response within the timeout.
+    UpstreamTimeout(Duration),
+    /// No more connections may be opened to the upstream.
+    UpstreamConnectionLimit,
+    /// The upstream couldn't be reached.
+    UpstreamConnectFailed(String),
+    /// The TLS handshake or session with the upstream failed.
+    UpstreamTlsError(String),
+    /// The upstream response body is larger than `limit` bytes.
+    UpstreamResponseTooLarge { limit: u64 },
+    /// The upstream response doesn't match what was expected of it.
+    UpstreamVerificationFailed,
+    /// The upstream response body couldn't be read in full.
+    UpstreamBodyFailed,
+    /// The upstream response can't be passed on to the client.
+    InvalidUpstreamResponse(String),
+    /// The client's connection is closed instead of being answered. As a
+    /// response, it is an empty 502.
+    Aborted(Arc<hyper::Error>),
+}
+
+impl ProxyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::BadRequest
+            | ProxyError::InvalidMethodOverride(_)
+            | ProxyError::InvalidEncoding(_)
+            | ProxyError::InvalidBody(_) => StatusCode::BAD_REQUEST,
+            ProxyError::RequestBodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ProxyError::UpstreamDenied => StatusCode::FORBIDDEN,
+            ProxyError::UpstreamResolveTimeout | ProxyError::UpstreamTimeout(_) => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            ProxyError::UpstreamConnectionLimit => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::UpstreamConnectFailed(_)
+            | ProxyError::UpstreamTlsError(_)
+            | ProxyError::UpstreamResponseTooLarge { .. }
+            | ProxyError::UpstreamVerificationFailed
+            | ProxyError::UpstreamBodyFailed
+            | ProxyError::InvalidUpstreamResponse(_)
+            | ProxyError::Aborted(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// The machine-readable code given in the JSON body of the response, for
+    /// errors answered with one.
+    pub fn code(&self) -> Option<&'static str> {
+        match self {
+            ProxyError::InvalidMethodOverride(_) => Some("invalid_method_override"),
+            ProxyError::InvalidEncoding(_) => Some("invalid_encoding"),
+            ProxyError::InvalidBody(_) => Some("invalid_body"),
+            ProxyError::RequestBodyTooLarge { .. } => Some("request_too_large"),
+            ProxyError::UpstreamTimeout(_) => Some("upstream_timeout"),
+            ProxyError::UpstreamConnectFailed(_) => Some("upstream_unavailable"),
+            ProxyError::UpstreamTlsError(_) => Some("upstream_tls_error"),
+            ProxyError::UpstreamResponseTooLarge { .. } => Some("response_too_large"),
+            ProxyError::InvalidUpstreamResponse(_) => Some("invalid_upstream_response"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::BadRequest => write!(f, "bad request"),
+            ProxyError::InvalidMethodOverride(e) | ProxyError::InvalidBody(e) => write!(f, "{}", e),
+            ProxyError::InvalidEncoding(e) => {
+                write!(f, "JSON request body is not valid UTF-8: {}", e)
+            }
+            ProxyError::RequestBodyTooLarge { limit } => {
+                write!(f, "request body is larger than {} bytes", limit)
+            }
+            ProxyError::UpstreamDenied => write!(f, "upstream address is not allowed"),
+            ProxyError::UpstreamResolveTimeout => write!(f, "upstream name lookup timed out"),
+            ProxyError::UpstreamTimeout(timeout) => {
+                write!(f, "no response from upstream within {:?}", timeout)
+            }
+            ProxyError::UpstreamConnectionLimit => write!(f, "upstream connection limit reached"),
+            ProxyError::UpstreamConnectFailed(e)
+            | ProxyError::UpstreamTlsError(e)
+            | ProxyError::InvalidUpstreamResponse(e) => write!(f, "{}", e),
+            ProxyError::UpstreamResponseTooLarge { limit } => {
+                write!(f, "upstream response body is larger than {} bytes", limit)
+            }
+            ProxyError::UpstreamVerificationFailed => {
+                write!(f, "upstream response failed verification")
+            }
+            ProxyError::UpstreamBodyFailed => write!(f, "failed to read upstream response body"),
+            ProxyError::Aborted(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<ProxyError> for Response<Body> {
+    /// A response with the error's status and, for errors with a code, a
+    /// JSON body giving the machine-readable `error` code and a
+    /// human-readable `detail`.
+    fn from(err: ProxyError) -> Response<Body> {
+        let mut response = match err.code() {
+            Some(code) => {
+                let body = serde_json::json!({ "error": code, "detail": err.to_string() });
+                let mut response = Response::new(Body::from(body.to_string()));
+                response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                response
+            }
+            None => Response::new(Body::empty()),
+        };
+        *response.status_mut() = err.status();
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_into_response() {
+        let response = Response::from(ProxyError::RequestBodyTooLarge { limit: 10 });
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "request_too_large");
+        assert_eq!(body["detail"], "request body is larger than 10 bytes");
+
+        let response = Response::from(ProxyError::UpstreamConnectionLimit);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().get(CONTENT_TYPE).is_none());
+    }
+}
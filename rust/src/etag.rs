@@ -0,0 +1,52 @@
+use hyper::header::HeaderValue;
+use openssl::sha::sha256;
+
+/// An entity tag for `body` made from its SHA-256 digest. Weak tags are used
+/// for bodies the proxy re-encodes, since the bytes sent differ.
+pub fn generate(body: &[u8], weak: bool) -> HeaderValue {
+    let hex: String = sha256(body).iter().map(|b| format!("{:02x}", b)).collect();
+    let tag = if weak {
+        format!("W/\"{}\"", hex)
+    } else {
+        format!("\"{}\"", hex)
+    };
+    HeaderValue::from_str(&tag).expect("etag is a valid header value")
+}
+
+/// Whether an `If-None-Match` header matches `etag`, using the weak
+/// comparison `If-None-Match` calls for.
+pub fn matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let (Ok(if_none_match), Ok(etag)) = (if_none_match.to_str(), etag.to_str()) else {
+        return false;
+    };
+    if if_none_match.trim() == "*" {
+        return true;
+    }
+    let etag = opaque(etag);
+    if_none_match.split(',').any(|tag| opaque(tag) == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_and_match() {
+        let etag = generate(b"hello", false);
+        assert_eq!(
+            etag,
+            "\"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\""
+        );
+        assert!(generate(b"hello", true)
+            .to_str()
+            .unwrap()
+            .starts_with("W/\""));
+
+        let if_none_match =
+            HeaderValue::from_str(&format!("\"other\", W/{}", etag.to_str().unwrap())).unwrap();
+        assert!(matches(&if_none_match, &etag));
+        assert!(matches(&HeaderValue::from_static("*"), &etag));
+        assert!(!matches(&HeaderValue::from_static("\"other\""), &etag));
+    }
+}
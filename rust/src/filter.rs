@@ -0,0 +1,258 @@
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    Method, Request, StatusCode,
+};
+use regex::Regex;
+use std::str::FromStr;
+
+/// What to do with a request a rule matches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Forward the request, skipping any later rules.
+    Allow,
+    /// Answer with the status, and body if any, without contacting the
+    /// upstream.
+    Block(StatusCode, Option<String>),
+    /// Answer with a 302 redirect to the URL.
+    Redirect(String),
+}
+
+#[derive(Clone, Debug)]
+enum PathMatcher {
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl PathMatcher {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathMatcher::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            PathMatcher::Regex(regex) => regex.is_match(path),
+        }
+    }
+}
+
+/// A rule matching requests by method, path and headers. Conditions that
+/// aren't set match every request.
+#[derive(Clone, Debug)]
+pub struct FilterRule {
+    method: Option<Method>,
+    path: Option<PathMatcher>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    action: Action,
+}
+
+impl FilterRule {
+    pub fn new(action: Action) -> FilterRule {
+        FilterRule {
+            method: None,
+            path: None,
+            headers: Vec::new(),
+            action,
+        }
+    }
+
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    pub fn path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.path = Some(PathMatcher::Prefix(prefix.into()));
+        self
+    }
+
+    pub fn path_regex(mut self, regex: Regex) -> Self {
+        self.path = Some(PathMatcher::Regex(regex));
+        self
+    }
+
+    /// Only matches requests with a `name` header equal to `value`. May be
+    /// given several times, all headers must match.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn action(&self) -> &Action {
+        &self.action
+    }
+
+    pub fn matches<B>(&self, req: &Request<B>) -> bool {
+        self.method
+            .as_ref()
+            .is_none_or(|method| req.method() == method)
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|path| path.matches(req.uri().path()))
+            && self.headers.iter().all(|(name, value)| {
+                req.headers()
+                    .get_all(name)
+                    .iter()
+                    .any(|actual| actual == value)
+            })
+    }
+}
+
+/// Parses rules like `method=GET;path-prefix=/admin;action=block:403`. The
+/// keys are `method`, `path-prefix`, `path-regex`, `header` (as
+/// `NAME:VALUE`, may be repeated) and `action`, which is `allow`,
+/// `block:STATUS[:BODY]` with a 4xx or 5xx status, or `redirect:URL`, and
+/// must be given.
+impl FromStr for FilterRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rule = FilterRule::new(Action::Allow);
+        let mut action = None;
+        for entry in s.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", entry))?;
+            match key.trim() {
+                "method" => {
+                    let method = Method::from_bytes(value.trim().to_ascii_uppercase().as_bytes())
+                        .map_err(|_| format!("invalid method '{}'", value))?;
+                    rule = rule.method(method);
+                }
+                "path-prefix" => rule = rule.path_prefix(value.trim()),
+                "path-regex" => {
+                    let regex = Regex::new(value.trim())
+                        .map_err(|e| format!("invalid path regex '{}': {}", value, e))?;
+                    rule = rule.path_regex(regex);
+                }
+                "header" => {
+                    let (name, header_value) = value
+                        .split_once(':')
+                        .ok_or_else(|| format!("expected NAME:VALUE, got '{}'", value))?;
+                    let name = HeaderName::from_str(name.trim())
+                        .map_err(|_| format!("invalid header name '{}'", name))?;
+                    let header_value = HeaderValue::from_str(header_value.trim())
+                        .map_err(|_| format!("invalid header value '{}'", header_value))?;
+                    rule = rule.header(name, header_value);
+                }
+                "action" => action = Some(parse_action(value.trim())?),
+                key => return Err(format!("unknown filter key '{}'", key)),
+            }
+        }
+        rule.action = action.ok_or_else(|| "filter has no action".to_string())?;
+        Ok(rule)
+    }
+}
+
+fn parse_action(s: &str) -> Result<Action, String> {
+    let (kind, argument) = s.split_once(':').unwrap_or((s, ""));
+    match kind {
+        "allow" => Ok(Action::Allow),
+        "block" => {
+            let (status, body) = match argument.split_once(':') {
+                Some((status, body)) => (status, Some(body.to_string())),
+                None => (argument, None),
+            };
+            let status = status
+                .parse::<StatusCode>()
+                .ok()
+                .filter(|status| status.is_client_error() || status.is_server_error())
+                .ok_or_else(|| format!("invalid block status '{}', expected 4xx or 5xx", status))?;
+            Ok(Action::Block(status, body))
+        }
+        "redirect" if HeaderValue::from_str(argument).is_ok() && !argument.is_empty() => {
+            Ok(Action::Redirect(argument.to_string()))
+        }
+        _ => Err(format!("invalid action '{}'", s)),
+    }
+}
+
+/// Rules evaluated in order, the first one matching a request decides what
+/// happens to it. Requests no rule matches are forwarded.
+#[derive(Clone, Debug, Default)]
+pub struct FilterChain(Vec<FilterRule>);
+
+impl FilterChain {
+    pub fn new(rules: Vec<FilterRule>) -> FilterChain {
+        FilterChain(rules)
+    }
+
+    pub fn evaluate<B>(&self, req: &Request<B>) -> Option<&Action> {
+        self.0
+            .iter()
+            .find(|rule| rule.matches(req))
+            .map(FilterRule::action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, uri: &str) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("x-env", "staging")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_first_matching_rule_decides() {
+        let chain = FilterChain::new(vec![
+            FilterRule::new(Action::Allow)
+                .path_prefix("/admin/health")
+                .header(
+                    HeaderName::from_static("x-env"),
+                    HeaderValue::from_static("staging"),
+                ),
+            FilterRule::new(Action::Block(StatusCode::FORBIDDEN, None)).path_prefix("/admin"),
+            FilterRule::new(Action::Block(StatusCode::TOO_MANY_REQUESTS, None))
+                .method(Method::POST)
+                .path_regex(Regex::new(r"^/api/v\d+/upload$").unwrap()),
+        ]);
+        assert_eq!(
+            chain.evaluate(&request(Method::GET, "/admin/health")),
+            Some(&Action::Allow)
+        );
+        assert_eq!(
+            chain.evaluate(&request(Method::GET, "/admin/users")),
+            Some(&Action::Block(StatusCode::FORBIDDEN, None))
+        );
+        assert_eq!(
+            chain.evaluate(&request(Method::POST, "/api/v2/upload")),
+            Some(&Action::Block(StatusCode::TOO_MANY_REQUESTS, None))
+        );
+        assert_eq!(
+            chain.evaluate(&request(Method::GET, "/api/v2/upload")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_filter_rule() {
+        let rule: FilterRule = "method=post; path-regex=^/upload; header=x-env:staging; \
+            action=block:429:slow down"
+            .parse()
+            .unwrap();
+        assert!(rule.matches(&request(Method::POST, "/upload/file")));
+        assert!(!rule.matches(&request(Method::GET, "/upload/file")));
+        assert_eq!(
+            rule.action(),
+            &Action::Block(StatusCode::TOO_MANY_REQUESTS, Some("slow down".to_string()))
+        );
+
+        let rule: FilterRule = "path-prefix=/old;action=redirect:https://example.com/new"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            rule.action(),
+            &Action::Redirect("https://example.com/new".to_string())
+        );
+
+        assert!("path-prefix=/admin".parse::<FilterRule>().is_err());
+        assert!("action=block:forbidden".parse::<FilterRule>().is_err());
+        assert!("action=block:200".parse::<FilterRule>().is_err());
+        assert!("action=block:302".parse::<FilterRule>().is_err());
+        assert!("action=block:999".parse::<FilterRule>().is_err());
+        assert!("colour=blue;action=allow".parse::<FilterRule>().is_err());
+    }
+}
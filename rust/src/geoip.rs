@@ -0,0 +1,59 @@
+use std::{fmt, net::IpAddr, path::Path};
+
+use maxminddb::{geoip2, Reader};
+
+/// Looks up the country of IP addresses in a MaxMind DB file, such as
+/// GeoLite2-Country or GeoLite2-City.
+pub struct GeoIpDb {
+    reader: Reader<Vec<u8>>,
+}
+
+impl fmt::Debug for GeoIpDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeoIpDb")
+            .field("database_type", &self.reader.metadata.database_type)
+            .field("build_epoch", &self.reader.metadata.build_epoch)
+            .finish()
+    }
+}
+
+impl GeoIpDb {
+    pub fn open(path: &Path) -> Result<GeoIpDb, String> {
+        let reader = Reader::open_readfile(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Ok(GeoIpDb { reader })
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<GeoIpDb, String> {
+        let reader = Reader::from_source(data).map_err(|e| e.to_string())?;
+        Ok(GeoIpDb { reader })
+    }
+
+    /// The ISO 3166-1 code of the country `ip` is in, or of the country it
+    /// is registered in when that isn't known.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .or_else(|| record.registered_country?.iso_code)
+            .map(str::to_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_invalid_database() {
+        assert!(GeoIpDb::from_bytes(b"not a database".to_vec()).is_err());
+        assert!(GeoIpDb::from_bytes(Vec::new()).is_err());
+        let err = GeoIpDb::open(Path::new("/nonexistent/GeoLite2-Country.mmdb")).unwrap_err();
+        assert!(
+            err.contains("/nonexistent/GeoLite2-Country.mmdb"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,31 @@
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct Operation {
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+}
+
+/// The `operationName` of a GraphQL request body, if it is a single JSON
+/// request naming its operation.
+pub fn operation_name(body: &[u8]) -> Option<String> {
+    serde_json::from_slice::<Operation>(body)
+        .ok()?
+        .operation_name
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operation_name() {
+        let body = br#"{"query":"query GetUser { user { id } }","operationName":"GetUser"}"#;
+        assert_eq!(operation_name(body), Some("GetUser".to_string()));
+        assert_eq!(operation_name(br#"{"query":"{ user { id } }"}"#), None);
+        assert_eq!(operation_name(br#"{"operationName":null}"#), None);
+        assert_eq!(operation_name(br#"[{"operationName":"GetUser"}]"#), None);
+        assert_eq!(operation_name(b"operationName=GetUser"), None);
+    }
+}
@@ -0,0 +1,361 @@
+use futures::Stream;
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+use std::{
+    error::Error,
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The gRPC status of a message larger than allowed.
+pub const RESOURCE_EXHAUSTED: u32 = 8;
+
+/// The default limit on messages received from clients, as in gRPC itself.
+pub const DEFAULT_MAX_RECV_MESSAGE_SIZE: u64 = 4 * 1024 * 1024;
+
+const MESSAGE_TOO_LARGE: &str = "message too large";
+
+/// Whether a request or response with `headers` carries gRPC messages.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/grpc"))
+}
+
+/// A response ending the call with `code` and `message` and no body.
+pub fn status_response(code: u32, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::OK;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    headers.extend(status_trailers(code, message));
+    response
+}
+
+fn status_trailers(code: u32, message: &str) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from(code));
+    if let Ok(message) = HeaderValue::from_str(message) {
+        trailers.insert("grpc-message", message);
+    }
+    trailers
+}
+
+/// The error of a body with a message over its size limit.
+#[derive(Debug)]
+pub struct MessageTooLarge {
+    pub limit: u64,
+}
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "gRPC message is larger than {} bytes", self.limit)
+    }
+}
+
+impl Error for MessageTooLarge {}
+
+/// Whether `err` was caused by a gRPC message going over its size limit.
+pub fn is_message_too_large(err: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if e.is::<MessageTooLarge>() {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
+
+/// Follows the length-prefixed framing of gRPC messages, a compression flag
+/// byte and a 4 byte big-endian length before each, across body chunks.
+#[derive(Debug)]
+struct Framing {
+    limit: u64,
+    prefix: [u8; 5],
+    prefix_len: usize,
+    /// Bytes left of the current message once its prefix is read.
+    remaining: u64,
+}
+
+impl Framing {
+    fn new(limit: u64) -> Framing {
+        Framing {
+            limit,
+            prefix: [0; 5],
+            prefix_len: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Reads `chunk`, returning the offset in it of the first message over
+    /// the limit, or where its prefix was cut off by the previous chunk.
+    fn check(&mut self, chunk: &[u8]) -> Result<(), usize> {
+        let mut offset = 0;
+        while offset < chunk.len() {
+            if self.remaining > 0 {
+                let skipped = self.remaining.min((chunk.len() - offset) as u64);
+                self.remaining -= skipped;
+                offset += skipped as usize;
+                continue;
+            }
+            let start = offset;
+            let needed = (5 - self.prefix_len).min(chunk.len() - offset);
+            self.prefix[self.prefix_len..self.prefix_len + needed]
+                .copy_from_slice(&chunk[offset..offset + needed]);
+            self.prefix_len += needed;
+            offset += needed;
+            if self.prefix_len == 5 {
+                self.prefix_len = 0;
+                let length = u32::from_be_bytes(self.prefix[1..5].try_into().unwrap()) as u64;
+                if length > self.limit {
+                    return Err(start.saturating_sub(5 - needed));
+                }
+                self.remaining = length;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fails `body` with `MessageTooLarge` once it starts a message longer than
+/// `limit` bytes, for request bodies sent upstream.
+pub fn with_message_limit(body: Body, limit: u64) -> Body {
+    Body::wrap_stream(MessageLimit {
+        body,
+        framing: Framing::new(limit),
+    })
+}
+
+struct MessageLimit {
+    body: Body,
+    framing: Framing,
+}
+
+impl Stream for MessageLimit {
+    type Item = Result<Bytes, Box<dyn Error + Send + Sync>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.body).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => match this.framing.check(&chunk) {
+                Ok(()) => Poll::Ready(Some(Ok(chunk))),
+                Err(_) => Poll::Ready(Some(Err(MessageTooLarge {
+                    limit: this.framing.limit,
+                }
+                .into()))),
+            },
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Forwards `body` and its trailers until it starts a message longer than
+/// `limit` bytes, then ends the call with `RESOURCE_EXHAUSTED` instead, for
+/// response bodies sent to clients.
+pub fn with_message_limit_status<B>(mut body: B, limit: u64) -> Body
+where
+    B: HttpBody<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: fmt::Display + Send,
+{
+    let (mut sender, limited) = Body::channel();
+    tokio::spawn(async move {
+        let mut framing = Framing::new(limit);
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    tracing::warn!("Failed to read gRPC response: {}", e);
+                    sender.abort();
+                    return;
+                }
+            };
+            if let Err(offset) = framing.check(&chunk) {
+                tracing::info!("gRPC response message is larger than {} bytes", limit);
+                if offset > 0 && sender.send_data(chunk.slice(..offset)).await.is_err() {
+                    return;
+                }
+                let trailers = status_trailers(RESOURCE_EXHAUSTED, MESSAGE_TOO_LARGE);
+                let _ = sender.send_trailers(trailers).await;
+                return;
+            }
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        match body.trailers().await {
+            Ok(Some(trailers)) => {
+                let _ = sender.send_trailers(trailers).await;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::warn!("Failed to read gRPC response trailers: {}", e);
+                sender.abort();
+            }
+        }
+    });
+    limited
+}
+
+/// The gRPC status of a response with `headers`, set when the call ended
+/// without sending any messages.
+pub fn status_code(headers: &HeaderMap) -> Option<u32> {
+    headers
+        .get("grpc-status")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// The HTTP status matching gRPC status `code`, as in the mapping used by
+/// gRPC-HTTP gateways.
+pub fn http_status(code: u32) -> StatusCode {
+    match code {
+        0 => StatusCode::OK,
+        // CANCELLED, nginx's "client closed request".
+        1 => StatusCode::from_u16(499).expect("499 is a valid status"),
+        3 | 9 | 11 => StatusCode::BAD_REQUEST,
+        4 => StatusCode::GATEWAY_TIMEOUT,
+        5 => StatusCode::NOT_FOUND,
+        6 | 10 => StatusCode::CONFLICT,
+        7 => StatusCode::FORBIDDEN,
+        RESOURCE_EXHAUSTED => StatusCode::TOO_MANY_REQUESTS,
+        12 => StatusCode::NOT_IMPLEMENTED,
+        14 => StatusCode::SERVICE_UNAVAILABLE,
+        16 => StatusCode::UNAUTHORIZED,
+        // UNKNOWN, INTERNAL, DATA_LOSS and codes from newer versions.
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Forwards `body` and its trailers, calling `done` with the gRPC status in
+/// the trailers once the body has been read in full.
+pub fn on_status<B, F>(mut body: B, done: F) -> Body
+where
+    B: HttpBody<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: fmt::Display + Send,
+    F: FnOnce(Option<u32>) + Send + 'static,
+{
+    let (mut sender, forwarded) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    tracing::warn!("Failed to read gRPC response: {}", e);
+                    sender.abort();
+                    return;
+                }
+            };
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        match body.trailers().await {
+            Ok(Some(trailers)) => {
+                let code = status_code(&trailers);
+                if sender.send_trailers(trailers).await.is_ok() {
+                    done(code);
+                }
+            }
+            Ok(None) => done(None),
+            Err(e) => {
+                tracing::warn!("Failed to read gRPC response trailers: {}", e);
+                sender.abort();
+            }
+        }
+    });
+    forwarded
+}
+
+/// The response for a request whose message was too large to forward.
+pub fn message_too_large_response() -> Response<Body> {
+    status_response(RESOURCE_EXHAUSTED, MESSAGE_TOO_LARGE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(length: u32) -> Vec<u8> {
+        let mut message = vec![0];
+        message.extend_from_slice(&length.to_be_bytes());
+        message.resize(5 + length as usize, b'x');
+        message
+    }
+
+    #[test]
+    fn test_framing() {
+        let mut framing = Framing::new(4);
+        let mut chunk = message(4);
+        chunk.extend(message(0));
+        assert_eq!(framing.check(&chunk), Ok(()));
+        // A prefix split across chunks.
+        let large = message(5);
+        assert_eq!(framing.check(&large[..2]), Ok(()));
+        assert_eq!(framing.check(&large[2..]), Err(0));
+
+        let mut framing = Framing::new(4);
+        let mut chunk = message(3);
+        chunk.extend(message(5));
+        assert_eq!(framing.check(&chunk), Err(8));
+    }
+
+    #[test]
+    fn test_http_status() {
+        assert_eq!(http_status(0), StatusCode::OK);
+        assert_eq!(http_status(14), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(http_status(16), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            http_status(RESOURCE_EXHAUSTED),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(http_status(99), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_on_status() {
+        let (mut sender, body) = Body::channel();
+        let (code_tx, code_rx) = tokio::sync::oneshot::channel();
+        let mut observed = on_status(body, move |code| {
+            let _ = code_tx.send(code);
+        });
+        sender.send_data(Bytes::from(message(2))).await.unwrap();
+        sender
+            .send_trailers(status_trailers(14, "unavailable"))
+            .await
+            .unwrap();
+        drop(sender);
+        assert_eq!(
+            observed.data().await.unwrap().unwrap(),
+            Bytes::from(message(2))
+        );
+        assert!(observed.data().await.is_none());
+        assert_eq!(
+            observed.trailers().await.unwrap().unwrap()["grpc-status"],
+            "14"
+        );
+        assert_eq!(code_rx.await.unwrap(), Some(14));
+    }
+
+    #[tokio::test]
+    async fn test_with_message_limit_status() {
+        let (mut sender, body) = Body::channel();
+        let mut chunk = message(2);
+        chunk.extend(message(10));
+        sender.send_data(Bytes::from(chunk)).await.unwrap();
+        let mut limited = with_message_limit_status(body, 4);
+        let data = limited.data().await.unwrap();
+        assert_eq!(data.unwrap(), Bytes::from(message(2)));
+        assert!(limited.data().await.is_none());
+        let trailers = limited.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["grpc-status"], "8");
+        assert_eq!(trailers["grpc-message"], MESSAGE_TOO_LARGE);
+    }
+}
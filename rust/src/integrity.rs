@@ -0,0 +1,36 @@
+use hyper::header::HeaderValue;
+use openssl::sha::sha256;
+
+/// Checks `body` against a SHA-256 digest given in hex or base64.
+pub fn verify(expected: &HeaderValue, body: &[u8]) -> Result<(), String> {
+    let expected = expected
+        .to_str()
+        .map_err(|_| "digest is not ASCII".to_string())?
+        .trim();
+    let digest = sha256(body);
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    if expected.eq_ignore_ascii_case(&hex) || expected == base64::encode(digest) {
+        Ok(())
+    } else {
+        Err(format!("expected SHA-256 {}, got {}", expected, hex))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let hex = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(verify(&HeaderValue::from_static(hex), b"hello").is_ok());
+        assert!(verify(
+            &HeaderValue::from_str(&hex.to_uppercase()).unwrap(),
+            b"hello"
+        )
+        .is_ok());
+        let b64 = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+        assert!(verify(&HeaderValue::from_static(b64), b"hello").is_ok());
+        assert!(verify(&HeaderValue::from_static(hex), b"hello!").is_err());
+    }
+}
@@ -0,0 +1,43 @@
+pub mod audit;
+pub mod auth;
+pub mod aws;
+pub mod blocklist;
+mod body;
+pub mod body_log;
+pub mod client;
+mod coalesce;
+mod compression;
+pub mod config;
+pub mod connector;
+pub mod content_type;
+pub mod cookies;
+mod debug_headers;
+pub mod download;
+pub mod env;
+pub mod error;
+mod etag;
+pub mod filter;
+pub mod geoip;
+mod graphql;
+pub mod grpc;
+mod integrity;
+pub mod listener;
+mod mdns;
+pub mod metrics;
+mod minify;
+pub mod oauth;
+pub mod path;
+pub mod pre_connect;
+pub mod priority;
+pub mod proxy_protocol;
+pub mod rate_limit;
+mod redact;
+pub mod reload;
+pub mod resolver;
+pub mod route;
+pub mod server;
+pub mod status_ranges;
+pub mod timeouts;
+pub mod validation;
+mod vary;
+pub mod workers;
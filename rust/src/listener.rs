@@ -0,0 +1,1031 @@
+use crate::proxy_protocol;
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+use hyper::server::{
+    accept::Accept,
+    conn::{AddrIncoming, AddrStream},
+};
+use openssl::{
+    error::ErrorStack,
+    pkey::{PKeyRef, Private},
+    ssl::{self, AlpnError, Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode},
+    x509::{X509NameRef, X509Ref, X509},
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    cmp, fmt,
+    future::Future,
+    io::{self, IoSlice},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::TcpListener,
+    time::{Instant, Sleep},
+};
+use tokio_openssl::SslStream;
+
+/// Settings applied to every inbound connection.
+#[derive(Clone, Debug, Default)]
+pub struct ListenerOptions {
+    /// Abort connections whose clients do not keep up with the response.
+    pub slow_client: Option<SlowClientPolicy>,
+    /// Expect every connection to start with a PROXY protocol header and take
+    /// the client address from it.
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+    /// Size of the receive buffer of every accepted socket. It is set on the
+    /// listening socket before `listen`, since the TCP window scale is agreed
+    /// during the handshake and bounds the largest window the kernel can
+    /// advertise later.
+    pub recv_buffer_size: Option<usize>,
+    /// Size of the send buffer of every accepted socket.
+    pub send_buffer_size: Option<usize>,
+    /// Have the kernel hold connections back from `accept` until the client
+    /// has sent data, sparing a wakeup for connections that never send a
+    /// request. Linux only.
+    pub defer_accept: bool,
+    /// Set TCP_QUICKACK on every accepted socket, so the kernel acknowledges
+    /// the client's first segments right away instead of delaying the ACK.
+    /// The kernel may fall back to delayed ACKs later in the connection.
+    /// Linux only.
+    pub tcp_quickack: bool,
+    /// Largest HTTP/2 frame payload clients may send, from 16384 to
+    /// 16777215 bytes. hyper's default is 16384.
+    pub h2_max_frame_size: Option<u32>,
+    /// Set SO_REUSEPORT on the listening socket, so several listeners, one
+    /// per worker, can share the address.
+    pub reuse_port: bool,
+    /// Terminate TLS on every connection, after the PROXY protocol header if
+    /// one is expected.
+    pub tls: Option<TlsAcceptor>,
+}
+
+/// How long a client has to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client has to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the kernel holds back a connection with no data before passing
+/// it on anyway, with `defer_accept`.
+const DEFER_ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Protocols offered to clients through ALPN, in order of preference.
+const ALPN_PROTOCOLS: &[u8] = b"\x02h2\x08http/1.1";
+
+/// Accepts TLS connections with a certificate and its private key. Clones
+/// share the certificate, `replace` changes it for all of them.
+#[derive(Clone)]
+pub struct TlsAcceptor(Arc<RwLock<SslAcceptor>>);
+
+impl TlsAcceptor {
+    pub fn new(cert: &X509Ref, key: &PKeyRef<Private>) -> Result<TlsAcceptor, ErrorStack> {
+        let mut builder = acceptor_builder()?;
+        builder.set_certificate(cert)?;
+        builder.set_private_key(key)?;
+        builder.check_private_key()?;
+        Ok(TlsAcceptor::wrap(builder.build()))
+    }
+
+    /// Loads the certificate chain and private key from PEM files.
+    pub fn from_pem_files(cert: &Path, key: &Path) -> Result<TlsAcceptor, ErrorStack> {
+        TlsAcceptor::from_pem_files_with_session_cache(cert, key, &SessionCache::default())
+    }
+
+    /// Accepts TLS connections with a certificate and its private key, asking
+    /// clients for a certificate issued by one of `client_cas` without
+    /// requiring one. Connections with a certificate that doesn't verify are
+    /// refused.
+    pub fn new_with_optional_client_certs(
+        cert: &X509Ref,
+        key: &PKeyRef<Private>,
+        client_cas: &[X509],
+    ) -> Result<TlsAcceptor, ErrorStack> {
+        let mut builder = acceptor_builder()?;
+        builder.set_certificate(cert)?;
+        builder.set_private_key(key)?;
+        builder.check_private_key()?;
+        request_client_certs(&mut builder, client_cas)?;
+        Ok(TlsAcceptor::wrap(builder.build()))
+    }
+
+    /// Loads the certificate chain and private key from PEM files, caching
+    /// sessions for resumption as set in `cache`.
+    pub fn from_pem_files_with_session_cache(
+        cert: &Path,
+        key: &Path,
+        cache: &SessionCache,
+    ) -> Result<TlsAcceptor, ErrorStack> {
+        let builder = pem_files_builder(cert, key, cache)?;
+        Ok(TlsAcceptor::wrap(builder.build()))
+    }
+
+    /// Loads the certificate chain and private key from PEM files, caching
+    /// sessions for resumption as set in `cache` and asking clients for a
+    /// certificate issued by one of the CAs in the `client_ca` PEM file
+    /// without requiring one.
+    pub fn from_pem_files_with_client_ca(
+        cert: &Path,
+        key: &Path,
+        cache: &SessionCache,
+        client_ca: &Path,
+    ) -> Result<TlsAcceptor, io::Error> {
+        let mut builder = pem_files_builder(cert, key, cache)?;
+        let cas = X509::stack_from_pem(&std::fs::read(client_ca)?)?;
+        if cas.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no certificates in {}", client_ca.display()),
+            ));
+        }
+        request_client_certs(&mut builder, &cas)?;
+        Ok(TlsAcceptor::wrap(builder.build()))
+    }
+
+    fn wrap(acceptor: SslAcceptor) -> TlsAcceptor {
+        TlsAcceptor(Arc::new(RwLock::new(acceptor)))
+    }
+
+    /// Accepts connections from now on with the certificate and settings of
+    /// `other`, here and in every clone. Handshakes already under way finish
+    /// with the previous ones.
+    pub fn replace(&self, other: &TlsAcceptor) {
+        if Arc::ptr_eq(&self.0, &other.0) {
+            return;
+        }
+        let acceptor = other.0.read().unwrap().clone();
+        *self.0.write().unwrap() = acceptor;
+    }
+
+    /// Whether clients are asked for a certificate.
+    pub fn requests_client_certs(&self) -> bool {
+        self.0
+            .read()
+            .unwrap()
+            .context()
+            .verify_mode()
+            .contains(SslVerifyMode::PEER)
+    }
+
+    async fn accept(&self, stream: PrefixedStream) -> io::Result<SslStream<PrefixedStream>> {
+        let ssl = Ssl::new(self.0.read().unwrap().context()).map_err(io::Error::other)?;
+        let mut stream = SslStream::new(ssl, stream).map_err(io::Error::other)?;
+        Pin::new(&mut stream)
+            .accept()
+            .await
+            .map_err(|e| match e.into_io_error() {
+                Ok(e) => e,
+                Err(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+            })?;
+        Ok(stream)
+    }
+}
+
+fn pem_files_builder(
+    cert: &Path,
+    key: &Path,
+    cache: &SessionCache,
+) -> Result<ssl::SslAcceptorBuilder, ErrorStack> {
+    let mut builder = acceptor_builder()?;
+    builder.set_certificate_chain_file(cert)?;
+    builder.set_private_key_file(key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    cache.apply(&mut builder);
+    Ok(builder)
+}
+
+fn acceptor_builder() -> Result<ssl::SslAcceptorBuilder, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    builder.set_alpn_select_callback(|_, client| {
+        ssl::select_next_proto(ALPN_PROTOCOLS, client).ok_or(AlpnError::NOACK)
+    });
+    Ok(builder)
+}
+
+/// Asks clients for a certificate issued by one of `cas`, verifying any that
+/// is sent but going on without one.
+fn request_client_certs(
+    builder: &mut ssl::SslAcceptorBuilder,
+    cas: &[X509],
+) -> Result<(), ErrorStack> {
+    for ca in cas {
+        builder.cert_store_mut().add_cert(ca.clone())?;
+        builder.add_client_ca(ca)?;
+    }
+    builder.set_verify(SslVerifyMode::PEER);
+    // Sessions of verified clients can only be resumed within a context.
+    builder.set_session_id_context(env!("CARGO_PKG_NAME").as_bytes())?;
+    Ok(())
+}
+
+/// Settings of the cache of TLS sessions that returning clients resume,
+/// skipping a full handshake.
+#[derive(Clone, Debug, Default)]
+pub struct SessionCache {
+    /// Most sessions kept, instead of OpenSSL's default of 20480. 0 keeps
+    /// every session until it expires.
+    pub size: Option<u32>,
+    /// How long a session can be resumed for, instead of OpenSSL's default
+    /// of 5 minutes. Also the lifetime of TLS 1.3 session tickets.
+    pub timeout: Option<Duration>,
+}
+
+extern "C" {
+    // Not wrapped by the openssl crate.
+    fn SSL_CTX_set_timeout(ctx: *mut libc::c_void, t: libc::c_long) -> libc::c_long;
+}
+
+impl SessionCache {
+    fn apply(&self, builder: &mut ssl::SslAcceptorBuilder) {
+        if let Some(size) = self.size {
+            builder.set_session_cache_size(size.min(i32::MAX as u32) as i32);
+        }
+        if let Some(timeout) = self.timeout {
+            let secs = timeout.as_secs().min(libc::c_long::MAX as u64) as libc::c_long;
+            // SAFETY: the context is owned by `builder`, which outlives the call.
+            unsafe {
+                SSL_CTX_set_timeout(builder.as_ptr().cast(), secs);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsAcceptor").finish_non_exhaustive()
+    }
+}
+
+/// A client is considered slow once it has kept the proxy waiting to write
+/// for longer than `threshold` while reading less than `min_bytes_per_sec`.
+#[derive(Clone, Debug)]
+pub struct SlowClientPolicy {
+    pub min_bytes_per_sec: u64,
+    pub threshold: Duration,
+}
+
+pub struct Incoming {
+    inner: AddrIncoming,
+    options: ListenerOptions,
+    /// Connections still sending their PROXY protocol header or completing
+    /// the TLS handshake.
+    handshakes: FuturesUnordered<BoxFuture<'static, io::Result<ClientStream>>>,
+}
+
+impl Incoming {
+    pub fn bind(addr: &SocketAddr, mut options: ListenerOptions) -> io::Result<Incoming> {
+        if options.tcp_quickack && !cfg!(target_os = "linux") {
+            tracing::warn!("TCP_QUICKACK is only supported on Linux, accepting without it");
+            options.tcp_quickack = false;
+        }
+        let inner = if options.recv_buffer_size.is_none()
+            && options.send_buffer_size.is_none()
+            && !options.defer_accept
+            && !options.reuse_port
+        {
+            AddrIncoming::bind(addr)
+        } else {
+            AddrIncoming::from_listener(TcpListener::from_std(bind_socket(addr, &options)?)?)
+        }
+        .map_err(io::Error::other)?;
+        Ok(Incoming {
+            inner,
+            options,
+            handshakes: FuturesUnordered::new(),
+        })
+    }
+}
+
+/// Binds a listening socket with the socket options in `options`, which
+/// accepted sockets inherit.
+fn bind_socket(addr: &SocketAddr, options: &ListenerOptions) -> io::Result<std::net::TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    if options.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if options.defer_accept {
+        if let Err(e) = enable_defer_accept(&socket) {
+            tracing::warn!("Listening on {} without TCP_DEFER_ACCEPT: {}", addr, e);
+        }
+    }
+    socket.bind(&(*addr).into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(target_os = "linux")]
+fn enable_defer_accept(socket: &Socket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let timeout = DEFER_ACCEPT_TIMEOUT.as_secs() as libc::c_int;
+    // SAFETY: the socket is open and `timeout` outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_DEFER_ACCEPT,
+            &timeout as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_defer_accept(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP_DEFER_ACCEPT is only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn enable_quickack(stream: &AddrStream) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let enabled: libc::c_int = 1;
+    // SAFETY: the socket is open and `enabled` outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_QUICKACK,
+            &enabled as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_quickack(_stream: &AddrStream) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TCP_QUICKACK is only supported on Linux",
+    ))
+}
+
+impl Accept for Incoming {
+    type Conn = ClientStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            let stream = match Pin::new(&mut this.inner).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => stream,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => break,
+            };
+            if this.options.tcp_quickack {
+                if let Err(e) = enable_quickack(&stream) {
+                    tracing::debug!(
+                        "Failed to set TCP_QUICKACK for {}: {}",
+                        stream.remote_addr(),
+                        e
+                    );
+                }
+            }
+            if this.options.proxy_protocol.is_none() && this.options.tls.is_none() {
+                let remote_addr = stream.remote_addr();
+                let stream = Transport::Plain(PrefixedStream::new(stream));
+                return Poll::Ready(Some(Ok(ClientStream::new(
+                    stream,
+                    remote_addr,
+                    &this.options,
+                ))));
+            }
+            this.handshakes
+                .push(Box::pin(handshake(stream, this.options.clone())));
+        }
+        // A bad header or handshake only drops its own connection, an error
+        // returned from here would stop the server.
+        while let Poll::Ready(Some(handshake)) = this.handshakes.poll_next_unpin(cx) {
+            match handshake {
+                Ok(stream) => return Poll::Ready(Some(Ok(stream))),
+                Err(e) => tracing::warn!("Dropping connection: {}", e),
+            }
+        }
+        Poll::Pending
+    }
+}
+
+async fn handshake(stream: AddrStream, options: ListenerOptions) -> io::Result<ClientStream> {
+    let peer = stream.remote_addr();
+    let mut stream = PrefixedStream::new(stream);
+    let remote_addr = match options.proxy_protocol {
+        Some(version) => read_proxy_header(&mut stream, version)
+            .await?
+            .unwrap_or(peer),
+        None => peer,
+    };
+    let stream = match &options.tls {
+        Some(tls) => {
+            let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no TLS handshake"))
+                .and_then(|stream| stream)
+                .map_err(|e| io::Error::new(e.kind(), format!("TLS from {}: {}", peer, e)))?;
+            Transport::Tls(Box::new(stream))
+        }
+        None => Transport::Plain(stream),
+    };
+    Ok(ClientStream::new(stream, remote_addr, &options))
+}
+
+/// Reads the PROXY protocol header from `stream` and returns the client
+/// address it declares.
+async fn read_proxy_header(
+    stream: &mut PrefixedStream,
+    version: proxy_protocol::Version,
+) -> io::Result<Option<SocketAddr>> {
+    let peer = stream.inner.remote_addr();
+    let mut buf = Vec::with_capacity(128);
+    let read = async {
+        loop {
+            if stream.inner.read_buf(&mut buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let parsed = proxy_protocol::parse(version, &buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if let Some(header) = parsed {
+                return Ok(header);
+            }
+        }
+    };
+    let header = tokio::time::timeout(PROXY_HEADER_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no PROXY header"))
+        .and_then(|header| header)
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("PROXY {} header from {}: {}", version, peer, e),
+            )
+        })?;
+
+    // Anything read past the header belongs to the TLS handshake or the
+    // first request.
+    buf.drain(..header.len);
+    stream.buffered = buf;
+    Ok(header.source)
+}
+
+/// An accepted socket.
+struct PrefixedStream {
+    inner: AddrStream,
+    /// Bytes read ahead while looking for the PROXY protocol header, read
+    /// again before the socket.
+    buffered: Vec<u8>,
+}
+
+impl PrefixedStream {
+    fn new(inner: AddrStream) -> PrefixedStream {
+        PrefixedStream {
+            inner,
+            buffered: Vec::new(),
+        }
+    }
+}
+
+impl AsyncRead for PrefixedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.buffered.is_empty() {
+            let n = cmp::min(this.buffered.len(), buf.remaining());
+            buf.put_slice(&this.buffered[..n]);
+            this.buffered.drain(..n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PrefixedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+enum Transport {
+    Plain(PrefixedStream),
+    Tls(Box<SslStream<PrefixedStream>>),
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Transport::Plain(stream) => stream.is_write_vectored(),
+            Transport::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// An accepted inbound connection.
+pub struct ClientStream {
+    inner: Transport,
+    remote_addr: SocketAddr,
+    slow_client: Option<SlowClientMonitor>,
+}
+
+impl ClientStream {
+    fn new(inner: Transport, remote_addr: SocketAddr, options: &ListenerOptions) -> ClientStream {
+        let slow_client = options
+            .slow_client
+            .as_ref()
+            .filter(|policy| policy.min_bytes_per_sec > 0)
+            .map(|policy| SlowClientMonitor::new(policy.clone()));
+        ClientStream {
+            inner,
+            remote_addr,
+            slow_client,
+        }
+    }
+
+    /// The client's address, as declared in the PROXY protocol header if
+    /// there was one.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Whether the client resumed an earlier TLS session, or `None` without
+    /// TLS.
+    pub fn tls_session_reused(&self) -> Option<bool> {
+        match &self.inner {
+            Transport::Plain(_) => None,
+            Transport::Tls(stream) => Some(stream.ssl().session_reused()),
+        }
+    }
+
+    /// The subject of the verified certificate the client sent over TLS, as
+    /// an RFC 4514 distinguished name, e.g. `CN=client,O=Example`.
+    pub fn client_cert_subject(&self) -> Option<String> {
+        match &self.inner {
+            Transport::Plain(_) => None,
+            Transport::Tls(stream) => stream
+                .ssl()
+                .peer_certificate()
+                .map(|cert| distinguished_name(cert.subject_name())),
+        }
+    }
+
+    fn poll_written(
+        &mut self,
+        cx: &mut Context<'_>,
+        written: Poll<io::Result<usize>>,
+    ) -> Poll<io::Result<usize>> {
+        let monitor = match self.slow_client.as_mut() {
+            Some(monitor) => monitor,
+            None => return written,
+        };
+        match written {
+            Poll::Ready(Ok(n)) => {
+                monitor.on_written(n);
+                Poll::Ready(Ok(n))
+            }
+            Poll::Pending => match monitor.poll_blocked(cx) {
+                Poll::Ready(Err(e)) => {
+                    tracing::warn!("Aborting slow client {}: {}", self.remote_addr, e);
+                    Poll::Ready(Err(e))
+                }
+                _ => Poll::Pending,
+            },
+            other => other,
+        }
+    }
+}
+
+/// Formats `name` as in RFC 4514, most specific attribute first.
+fn distinguished_name(name: &X509NameRef) -> String {
+    let entries: Vec<_> = name.entries().collect();
+    entries
+        .iter()
+        .rev()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("UNKNOWN");
+            let value = match entry.data().as_utf8() {
+                Ok(value) => value.to_string(),
+                Err(_) => String::from_utf8_lossy(entry.data().as_slice()).into_owned(),
+            };
+            let last = value.chars().count().saturating_sub(1);
+            let mut escaped = String::with_capacity(value.len());
+            for (i, c) in value.chars().enumerate() {
+                let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+                    || (i == 0 && matches!(c, ' ' | '#'))
+                    || (i == last && c == ' ');
+                if special {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+            format!("{}={}", key, escaped)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl fmt::Debug for ClientStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientStream")
+            .field("remote_addr", &self.remote_addr)
+            .finish()
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let read = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(monitor)) = (&read, this.slow_client.as_mut()) {
+            // A new request starts a new measurement.
+            if buf.filled().len() > filled {
+                monitor.reset();
+            }
+        }
+        read
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.poll_written(cx, written)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        this.poll_written(cx, written)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Tracks how long writes to a client have been blocked against how much the
+/// client has read. Time spent waiting on the upstream is never counted, only
+/// time the proxy had data ready that the client would not accept.
+struct SlowClientMonitor {
+    policy: SlowClientPolicy,
+    blocked: Duration,
+    blocked_since: Option<Instant>,
+    bytes: u64,
+    timer: Pin<Box<Sleep>>,
+}
+
+impl SlowClientMonitor {
+    fn new(policy: SlowClientPolicy) -> SlowClientMonitor {
+        SlowClientMonitor {
+            policy,
+            blocked: Duration::ZERO,
+            blocked_since: None,
+            bytes: 0,
+            timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.blocked = Duration::ZERO;
+        self.blocked_since = None;
+        self.bytes = 0;
+    }
+
+    fn on_written(&mut self, n: usize) {
+        // Writes before the first block only fill socket buffers, they say
+        // nothing about how fast the client reads.
+        if let Some(since) = self.blocked_since.take() {
+            self.blocked += since.elapsed();
+        } else if self.blocked.is_zero() {
+            return;
+        }
+        self.bytes += n as u64;
+    }
+
+    /// How long writes may stay blocked before the client's throughput drops
+    /// below the policy's minimum.
+    fn allowance(&self) -> Duration {
+        let drain_time =
+            Duration::from_secs_f64(self.bytes as f64 / self.policy.min_bytes_per_sec as f64);
+        cmp::max(self.policy.threshold, drain_time)
+    }
+
+    fn poll_blocked(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let since = *self.blocked_since.get_or_insert_with(Instant::now);
+        let remaining = self.allowance().saturating_sub(self.blocked);
+        self.timer.as_mut().reset(since + remaining);
+        match self.timer.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "read {} bytes in {:?} of blocked writes",
+                    self.bytes,
+                    self.blocked + since.elapsed()
+                ),
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server,
+    };
+    use std::convert::Infallible;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    #[test]
+    fn test_session_cache() {
+        extern "C" {
+            fn SSL_CTX_get_timeout(ctx: *const libc::c_void) -> libc::c_long;
+        }
+
+        let mut builder = acceptor_builder().unwrap();
+        SessionCache {
+            size: Some(1024),
+            timeout: Some(Duration::from_secs(3600)),
+        }
+        .apply(&mut builder);
+        // SAFETY: the context is owned by `builder`, which outlives the call.
+        let timeout = unsafe { SSL_CTX_get_timeout(builder.as_ptr().cast()) };
+        assert_eq!(timeout, 3600);
+        assert_eq!(builder.build().context().session_cache_size(), 1024);
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_is_aborted() {
+        const BODY_SIZE: usize = 32 * 1024 * 1024;
+        let options = ListenerOptions {
+            slow_client: Some(SlowClientPolicy {
+                min_bytes_per_sec: 1024 * 1024,
+                threshold: Duration::from_millis(200),
+            }),
+            ..Default::default()
+        };
+        let incoming = Incoming::bind(&"127.0.0.1:0".parse().unwrap(), options).unwrap();
+        let addr = incoming.inner.local_addr();
+        let make_service = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|_req| async {
+                Ok::<_, Infallible>(Response::new(Body::from(vec![b'a'; BODY_SIZE])))
+            }))
+        });
+        tokio::spawn(Server::builder(incoming).serve(make_service));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let mut received = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut received));
+        let _ = read.await.expect("connection should be closed");
+        assert!(
+            received.len() < BODY_SIZE,
+            "connection should be aborted before the full body is sent"
+        );
+    }
+
+    #[test]
+    fn test_bind_with_buffers() {
+        let options = ListenerOptions {
+            recv_buffer_size: Some(262144),
+            send_buffer_size: Some(131072),
+            ..Default::default()
+        };
+        let listener = bind_socket(&"127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let socket = socket2::SockRef::from(&listener);
+        // Linux doubles the requested size to account for bookkeeping.
+        assert!(socket.recv_buffer_size().unwrap() >= 262144);
+        assert!(socket.send_buffer_size().unwrap() >= 131072);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_bind_with_defer_accept() {
+        use std::os::unix::io::AsRawFd;
+
+        let options = ListenerOptions {
+            defer_accept: true,
+            ..Default::default()
+        };
+        let listener = bind_socket(&"127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let mut timeout: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: the socket is open and `timeout` and `len` outlive the call.
+        let result = unsafe {
+            libc::getsockopt(
+                listener.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_DEFER_ACCEPT,
+                &mut timeout as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(result, 0);
+        // Linux rounds the timeout to a number of SYN-ACK retransmissions.
+        assert!(timeout > 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_enable_quickack() {
+        use std::os::unix::io::AsRawFd;
+
+        let mut incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let _client = std::net::TcpStream::connect(incoming.local_addr()).unwrap();
+        let stream = futures::future::poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        enable_quickack(&stream).unwrap();
+        let mut enabled: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: the socket is open and `enabled` and `len` outlive the call.
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_QUICKACK,
+                &mut enabled as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(result, 0);
+        assert_eq!(enabled, 1);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_header_sets_remote_addr() {
+        let options = ListenerOptions {
+            proxy_protocol: Some(proxy_protocol::Version::V2),
+            ..Default::default()
+        };
+        let incoming = Incoming::bind(&"127.0.0.1:0".parse().unwrap(), options).unwrap();
+        let addr = incoming.inner.local_addr();
+        let make_service = make_service_fn(|conn: &ClientStream| {
+            let remote_addr = conn.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_req| async move {
+                    Ok::<_, Infallible>(Response::new(Body::from(remote_addr.to_string())))
+                }))
+            }
+        });
+        tokio::spawn(Server::builder(incoming).serve(make_service));
+
+        // A connection without a header is dropped without stopping the server.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let client = "203.0.113.7:40000".parse().unwrap();
+        let mut request = proxy_protocol::encode(proxy_protocol::Version::V2, client, addr);
+        request
+            .extend_from_slice(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n");
+        stream.write_all(&request).await.unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).await.unwrap();
+        assert!(received.ends_with("203.0.113.7:40000"), "{}", received);
+    }
+}
@@ -1,34 +1,1575 @@
-mod server;
-
-use crate::server::ProxyClient;
-use clap::Parser;
-use std::net::SocketAddr;
-use tracing::info;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use hyper::{
+    client::HttpConnector,
+    header::{HeaderName, HeaderValue},
+    Client, Method, StatusCode, Uri,
+};
+use hyper_openssl::HttpsConnector;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use proxy_filter::{
+    auth::PathAuth,
+    blocklist::PathBlocklist,
+    body_log::BodyLogFormatRule,
+    client::{self, HeaderCase, HttpVersionNegotiation, TlsRenegotiation, UpstreamProtocol},
+    config::{self, Config},
+    content_type::ContentTypeOverride,
+    cookies::InsecureCookies,
+    env,
+    filter::FilterRule,
+    grpc,
+    metrics::{self, MetricsRecorder},
+    oauth::{self, TokenSource},
+    path::PathCase,
+    priority::PathPriority,
+    proxy_protocol,
+    rate_limit::{PathRateLimit, RateLimitAlgorithm},
+    reload::ReloadSignal,
+    server::{ProxyClient, ReloadHandle, ServerBuilder},
+    status_ranges::StatusRanges,
+    timeouts::{MethodTimeouts, PathTimeout},
+    validation::ResponseValidation,
+    workers::WorkerPool,
+};
+use regex::Regex;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{signal, sync::oneshot};
+use tracing::{info, warn};
 
 #[derive(clap::Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    // Base endpoint to send data to
+    /// TOML file to load options from, keyed by the long flag names. Flags
+    /// given on the command line take precedence
+    #[clap(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Instead of serving, GET this path from the proxy listening at
+    /// --listen, over HTTPS if --tls-cert is given, and exit with 0 if it
+    /// answers 200 and 1 otherwise. Meant for container health checks, e.g.
+    /// "/_proxy/health"
+    #[clap(long, value_name = "PATH")]
+    container_healthcheck_path: Option<String>,
+
+    // Base endpoint to send data to, ${VAR} is replaced with the value of the
+    // environment variable VAR
     #[clap(short, long, default_value = "http://127.0.0.1:8080")]
     base_endpoint: String,
+
+    /// Address and port to accept connections on
+    #[clap(short, long, default_value = "0.0.0.0:3000", value_name = "ADDR")]
+    listen: SocketAddr,
+
+    /// Replace the host and port of --base-endpoint, also sending them as
+    /// the upstream Host header
+    #[clap(long, alias = "rewrite-upstream-authority", value_name = "HOST:PORT")]
+    upstream_authority: Option<String>,
+
+    /// Replace the path of --base-endpoint
+    #[clap(long, value_name = "PATH")]
+    upstream_path_prefix: Option<String>,
+
+    /// Refuse to connect to upstreams named by the request through
+    /// --upstream-template that are, or resolve to, private, loopback or
+    /// unspecified addresses. Configured backends are always trusted
+    #[clap(long, default_value_t = true, parse(try_from_str), value_name = "BOOL")]
+    restrict_upstream_ip_ranges: bool,
+
+    /// Only connect to the IPv4 addresses upstream hostnames resolve to
+    #[clap(long, conflicts_with = "upstream-ipv6-only")]
+    upstream_ipv4_only: bool,
+
+    /// Only connect to the IPv6 addresses upstream hostnames resolve to
+    #[clap(long)]
+    upstream_ipv6_only: bool,
+
+    /// Number of times to retry a failed upstream connection attempt before
+    /// answering with 502
+    #[clap(long, default_value_t = 0, value_name = "RETRIES")]
+    upstream_connect_retry: u32,
+
+    /// Give up connecting to each address of an upstream host after this
+    /// many milliseconds and try the next one it resolves to
+    #[clap(long, value_name = "MS")]
+    upstream_connection_timeout_per_attempt_ms: Option<u64>,
+
+    /// Give up connecting to an upstream host after this many milliseconds,
+    /// however many of its addresses are left to try. Each retry from
+    /// --upstream-connect-retry gets the full timeout
+    #[clap(long, value_name = "MS")]
+    upstream_connection_timeout_total_ms: Option<u64>,
+
+    /// Open upstream connections with TCP Fast Open, saving a round trip on
+    /// connections to upstreams that support it
+    #[clap(long)]
+    upstream_tcp_fast_open: bool,
+
+    /// Seconds an upstream connection may be idle before, and then between,
+    /// TCP keepalive probes. Notices connections dropped silently along the
+    /// way, e.g. by a firewall, sooner than a timeout would
+    #[clap(long, value_name = "SECS")]
+    upstream_tcp_keepalive_interval: Option<NonZeroU64>,
+
+    /// Unanswered TCP keepalive probes after which an upstream connection is
+    /// closed
+    #[clap(
+        long,
+        value_name = "COUNT",
+        requires = "upstream-tcp-keepalive-interval"
+    )]
+    upstream_tcp_keepalive_probes: Option<u32>,
+
+    /// Buffer up to this many bytes written to each upstream connection
+    /// before sending them. Larger buffers send large request bodies with
+    /// fewer syscalls but copy every write, smaller ones the reverse. By
+    /// default writes go straight to the socket
+    #[clap(long, value_name = "BYTES", alias = "upstream-request-buffer-size")]
+    upstream_request_write_buf_size: Option<usize>,
+
+    /// Send title-cased header names (Content-Type rather than content-type)
+    /// to HTTP/1 upstreams that are case-sensitive about them
+    #[clap(long)]
+    upstream_title_case_headers: bool,
+
+    /// Case of the header names sent to HTTP/1 upstreams, "lowercase" or
+    /// "http-standard" title case. "http-standard" is the same as
+    /// --upstream-title-case-headers
+    #[clap(
+        long,
+        alias = "header-case-normalization",
+        default_value = "lowercase",
+        value_name = "lowercase|http-standard"
+    )]
+    request_header_case: HeaderCase,
+
+    /// Write HTTP/1 requests to upstreams with vectored (scatter-gather)
+    /// writes. Set to false where that causes issues
+    #[clap(long, default_value_t = true, parse(try_from_str), value_name = "BOOL")]
+    upstream_http1_writev: bool,
+
+    /// Open a new upstream connection for every request and close it after,
+    /// to debug connection-level issues. Connection opens and closes are
+    /// logged at debug level. Not meant for production
+    #[clap(long)]
+    force_close_upstream_connection: bool,
+
+    /// Before sending a request over a pooled upstream connection idle for
+    /// longer than --idle-connection-revalidate-after-ms, check that the
+    /// connection is still alive with an OPTIONS * request, so requests
+    /// aren't sent on connections dropped along the way. Best-effort: under
+    /// concurrency the request may not get the connection that was checked
+    #[clap(long, conflicts_with = "pool-pre-check")]
+    upstream_idle_connection_check: bool,
+
+    /// How long an upstream connection may be idle and still be reused
+    /// without a check, with --upstream-idle-connection-check or
+    /// --pool-pre-check
+    #[clap(long, default_value_t = 30_000, value_name = "MS")]
+    idle_connection_revalidate_after_ms: u64,
+
+    /// Before sending a request over a pooled upstream connection idle for
+    /// longer than --idle-connection-revalidate-after-ms, check that the
+    /// connection is still alive with a HEAD request for
+    /// --upstream-pool-health-check-path. Adds a round trip to such requests.
+    /// Best-effort: under concurrency the request may not get the connection
+    /// that was checked
+    #[clap(long)]
+    pool_pre_check: bool,
+
+    /// Path requested by --pool-pre-check
+    #[clap(long, default_value = "/healthz", value_name = "PATH")]
+    upstream_pool_health_check_path: String,
+
+    /// Maximum number of connections open to each upstream host at once.
+    /// Requests that would need another connection are answered with 503
+    #[clap(long, value_name = "CONNECTIONS")]
+    max_concurrent_upstream_connections_per_host: Option<usize>,
+
+    /// Maximum number of connections open to all upstream hosts together,
+    /// bounding the file descriptors they use. Requests that would need
+    /// another connection are answered with 503
+    #[clap(long, value_name = "CONNECTIONS")]
+    max_host_connections: Option<usize>,
+
+    /// Close upstream connections open for longer than this many seconds
+    /// once they are between requests, even if they are reused often enough
+    /// never to go idle, so they don't outlive firewall timeouts. HTTP/2
+    /// connections are left open
+    #[clap(long, value_name = "SECS")]
+    upstream_max_idle_connection_age_secs: Option<u64>,
+
+    /// Open a new upstream connection once the newest one to an upstream is
+    /// three quarters of the way to --upstream-max-idle-connection-age-secs,
+    /// so a fresh connection is ready when the old ones are closed
+    #[clap(long, requires = "upstream-max-idle-connection-age-secs")]
+    upstream_preemptive_refresh_connections: bool,
+
+    /// Send HTTP/2 PING frames this often on upstream connections that
+    /// negotiated HTTP/2, keeping long-lived gRPC streams open through NAT
+    #[clap(long, value_name = "SECS")]
+    grpc_keepalive_interval_secs: Option<u64>,
+
+    /// Close upstream HTTP/2 connections whose PING is not acknowledged within
+    /// this long
+    #[clap(long, default_value_t = 5, value_name = "SECS")]
+    grpc_keepalive_timeout_secs: u64,
+
+    /// HTTP/2 flow control window of each upstream stream, in bytes. Large
+    /// windows speed up bulk transfers, small ones share a connection more
+    /// evenly between many concurrent small requests
+    #[clap(long, default_value_t = client::DEFAULT_HTTP2_STREAM_WINDOW, value_name = "BYTES")]
+    upstream_h2_initial_stream_window: u32,
+
+    /// HTTP/2 flow control window shared by all streams of an upstream
+    /// connection, in bytes
+    #[clap(
+        long,
+        default_value_t = client::DEFAULT_HTTP2_CONNECTION_WINDOW,
+        value_name = "BYTES"
+    )]
+    upstream_h2_initial_connection_window: u32,
+
+    /// Answer with 504 when resolving the upstream hostname takes longer than
+    /// this many milliseconds
+    #[clap(long, value_name = "MS")]
+    upstream_resolver_timeout_ms: Option<u64>,
+
+    /// Append the secrets of upstream TLS sessions to this file in the NSS
+    /// key log format (like SSLKEYLOGFILE). Requires --debug-mode
+    #[clap(long, value_name = "PATH", requires = "debug-mode")]
+    tls_key_log_file: Option<PathBuf>,
+
+    /// Allow options that weaken security, for debugging only
+    #[clap(long)]
+    debug_mode: bool,
+
+    /// Disable TLS session tickets on upstream connections
+    #[clap(long)]
+    upstream_tls_no_session_tickets: bool,
+
+    /// What to do when an upstream asks to renegotiate a TLS 1.2 session:
+    /// "allow" also renegotiates with old upstreams that don't support
+    /// secure renegotiation, "ignore" leaves it to OpenSSL, which only
+    /// renegotiates securely, and "reject" refuses, failing the request
+    #[clap(long, default_value = "ignore", value_name = "allow|ignore|reject")]
+    upstream_tls_renegotiation: TlsRenegotiation,
+
+    /// How to agree on the HTTP version spoken to upstreams: "none" always
+    /// speaks HTTP/1.1, for upstreams with broken ALPN, and "alpn" offers
+    /// HTTP/2 through TLS ALPN. HTTP/2 upgrade from HTTP/1.1 isn't
+    /// supported, --upstream-protocol speaks HTTP/2 to a host without
+    /// negotiating and takes precedence
+    #[clap(long, default_value = "alpn", value_name = "none|alpn")]
+    upstream_http_version_negotiation: HttpVersionNegotiation,
+
+    /// Regex that the common name or a DNS subject alternative name of
+    /// upstream TLS certificates must match, checked on top of the usual
+    /// hostname verification, e.g. ".*\.example\.com$". Requests to
+    /// upstreams whose certificate doesn't match are answered with 502
+    #[clap(long, value_name = "REGEX", alias = "upstream-hostname-validation")]
+    upstream_tls_hostname_pattern: Option<Regex>,
+
+    /// Abort clients that keep the proxy waiting to write for longer than
+    /// this while reading slower than --min-client-bandwidth-bps
+    #[clap(long, value_name = "MS")]
+    slow_client_abort_threshold_ms: Option<u64>,
+
+    /// Minimum rate, in bytes per second, a client must read responses at
+    #[clap(long, default_value_t = 1024, value_name = "BPS")]
+    min_client_bandwidth_bps: u64,
+
+    /// Authorization service asked to approve each request before it is
+    /// forwarded
+    #[clap(long, value_name = "URL")]
+    upstream_pre_connect_hook_url: Option<String>,
+
+    /// How long to cache pre-connect hook decisions for
+    #[clap(long, default_value_t = 0, value_name = "SECS")]
+    pre_connect_cache_secs: u64,
+
+    /// Remove every Set-Cookie header from upstream responses
+    #[clap(long)]
+    remove_response_cookies: bool,
+
+    /// Remove Set-Cookie headers for cookies whose name starts with this
+    /// prefix, may be repeated
+    #[clap(long, value_name = "NAME_PREFIX")]
+    remove_cookies_matching: Vec<String>,
+
+    /// Remove Set-Cookie headers with the Secure attribute from responses to
+    /// clients that didn't connect over TLS, or every Set-Cookie header with
+    /// "all"
+    #[clap(
+        long,
+        value_name = "secure|all",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "secure"
+    )]
+    strip_response_set_cookie_for_non_https: Option<InsecureCookies>,
+
+    /// Response header to report upstream latency in, e.g. Server-Timing
+    #[clap(long, value_name = "HEADER")]
+    inject_response_timing_header: Option<HeaderName>,
+
+    /// OAuth token endpoint used to authenticate upstream requests with the
+    /// client credentials grant
+    #[clap(
+        long,
+        value_name = "URL",
+        requires = "upstream-auth-client-credentials"
+    )]
+    upstream_auth_token_refresh_url: Option<String>,
+
+    /// Client credentials for the token endpoint, as CLIENT_ID:CLIENT_SECRET
+    #[clap(
+        long,
+        value_name = "CREDENTIALS",
+        requires = "upstream-auth-token-refresh-url"
+    )]
+    upstream_auth_client_credentials: Option<String>,
+
+    /// Authenticate upstream requests with Google Cloud ID tokens of the
+    /// instance's service account, fetched from the metadata server (or
+    /// GCE_METADATA_HOST) and refreshed before they expire
+    #[clap(long, conflicts_with = "upstream-auth-token-refresh-url")]
+    upstream_auth_google_iam: bool,
+
+    /// Audience of the Google Cloud ID tokens, by default the scheme and
+    /// authority of the base endpoint
+    #[clap(long, value_name = "URL", requires = "upstream-auth-google-iam")]
+    upstream_auth_google_audience: Option<String>,
+
+    /// Sign upstream requests for an AWS service in this region, e.g.
+    /// us-east-1, with Signature Version 4, using the credentials in
+    /// AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN.
+    /// Request bodies are read in full before forwarding, and Host is set to
+    /// the upstream's unless given with --upstream-authority
+    #[clap(
+        long,
+        value_name = "REGION",
+        requires = "aws-service",
+        alias = "upstream-auth-aws-sigv4"
+    )]
+    aws_region: Option<String>,
+
+    /// AWS service to sign upstream requests for with --aws-region, e.g.
+    /// execute-api for API Gateway
+    #[clap(long, value_name = "SERVICE", requires = "aws-region")]
+    aws_service: Option<String>,
+
+    /// Send a PROXY protocol header (v1 or v2) with the client's address on
+    /// each upstream connection. Disables upstream connection reuse
+    #[clap(long, value_name = "VERSION")]
+    upstream_proxy_protocol: Option<proxy_protocol::Version>,
+
+    /// Expect a PROXY protocol header (v1 or v2) at the start of each inbound
+    /// connection and treat its source as the client's address
+    #[clap(long, value_name = "VERSION")]
+    listen_proxy_protocol: Option<proxy_protocol::Version>,
+
+    /// Receive buffer size, in bytes, of inbound sockets. Buffers above 64KB
+    /// only help if the OS has TCP window scaling enabled, and the kernel
+    /// may cap the size (net.core.rmem_max on Linux)
+    #[clap(long, value_name = "BYTES")]
+    listen_recv_buf_size: Option<usize>,
+
+    /// Send buffer size, in bytes, of inbound sockets. The kernel may cap the
+    /// size (net.core.wmem_max on Linux)
+    #[clap(long, value_name = "BYTES")]
+    listen_send_buf_size: Option<usize>,
+
+    /// Set TCP_DEFER_ACCEPT on the listening socket so connections only wake
+    /// the proxy once the client sends data, sparing port scanners and
+    /// connection checks that never send a request. Linux only
+    #[clap(long)]
+    listen_defer_accept: bool,
+
+    /// Set TCP_QUICKACK on every accepted connection, turning off delayed
+    /// ACKs at its start to save up to 200ms for request-response clients
+    /// that wait on an ACK. Linux only
+    #[clap(long)]
+    listen_tcp_quickack: bool,
+
+    /// Largest HTTP/2 frame payload clients may send, from 16384 to 16777215
+    /// bytes. Larger frames mean fewer of them for large bodies, at the cost
+    /// of a larger read buffer per connection
+    #[clap(long, alias = "listen-max-frame-size", value_name = "BYTES")]
+    listen_h2_max_frame_size: Option<u32>,
+
+    /// Serve on this many worker threads, each with its own single-threaded
+    /// runtime and its own listening socket on the address through
+    /// SO_REUSEPORT, instead of on one multi-threaded runtime. Connections
+    /// stay on the worker that accepted them
+    #[clap(long, value_name = "N")]
+    workers: Option<NonZeroUsize>,
+
+    /// PEM certificate chain to accept HTTPS connections with, instead of
+    /// plain HTTP
+    #[clap(long, value_name = "PATH", requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the --tls-cert certificate
+    #[clap(long, value_name = "PATH", requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Most TLS sessions kept for clients to resume, skipping a full
+    /// handshake. 0 keeps every session until it expires. Defaults to
+    /// OpenSSL's 20480
+    #[clap(long, value_name = "SESSIONS", requires = "tls-cert")]
+    tls_session_cache_size: Option<u32>,
+
+    /// How long clients can resume a TLS session for. Defaults to OpenSSL's
+    /// 300 seconds
+    #[clap(long, value_name = "SECS", requires = "tls-cert")]
+    tls_session_timeout_secs: Option<u64>,
+
+    /// Ask TLS clients for a certificate issued by a CA in this PEM file,
+    /// without requiring one, and send the subject of any they present
+    /// upstream in X-Client-Cert-Subject, e.g. "CN=client,O=Example".
+    /// Certificates that don't verify fail the handshake
+    #[clap(
+        long,
+        value_name = "CA_PATH",
+        requires = "tls-cert",
+        alias = "listen-client-certificate-optional"
+    )]
+    client_cert_optional: Option<PathBuf>,
+
+    /// Seconds to wait for an upstream response before answering with 504
+    #[clap(long, default_value_t = 30, value_name = "SECS")]
+    upstream_timeout: u64,
+
+    /// Largest request body to accept, in bytes. Larger requests are
+    /// answered with 413. Unlimited by default
+    #[clap(long, value_name = "BYTES")]
+    max_req_body: Option<u64>,
+
+    /// Largest upstream response body to pass on, in bytes. Larger
+    /// responses are answered with 502, or cut off if they don't declare
+    /// their length. Unlimited by default
+    #[clap(long, value_name = "BYTES")]
+    max_resp_body: Option<u64>,
+
+    /// Largest message in gRPC requests to forward, in bytes. Requests with
+    /// larger messages are answered with status RESOURCE_EXHAUSTED
+    #[clap(
+        long,
+        default_value_t = grpc::DEFAULT_MAX_RECV_MESSAGE_SIZE,
+        value_name = "BYTES"
+    )]
+    grpc_max_recv_message_size: u64,
+
+    /// Largest message in gRPC responses to pass on, in bytes. The call ends
+    /// with status RESOURCE_EXHAUSTED at the first larger message. Unlimited
+    /// by default
+    #[clap(long, value_name = "BYTES")]
+    grpc_max_send_message_size: Option<u64>,
+
+    /// Map the grpc-status of gRPC responses to its HTTP equivalent, e.g.
+    /// UNAVAILABLE to 503, logging both and counting them in the
+    /// proxy_grpc_responses_total metric. Responses sent to clients are
+    /// unchanged
+    #[clap(long)]
+    upstream_grpc_status_mapping: bool,
+
+    /// Per-method upstream response timeouts in milliseconds, e.g.
+    /// "POST:5000,GET:500". Requests that time out are answered with 504
+    #[clap(long, value_name = "METHOD:MILLIS,...")]
+    upstream_timeout_per_method: Option<MethodTimeouts>,
+
+    /// Add a random delay of up to this many milliseconds to each upstream
+    /// timeout, whether from --upstream-timeout, --upstream-timeout-per-method
+    /// or --upstream-per-path-timeout
+    #[clap(long, default_value_t = 0, value_name = "MS")]
+    upstream_timeout_jitter_ms: u64,
+
+    /// Upstream response timeout in milliseconds for request paths matching
+    /// a glob, as "GLOB=MILLIS", e.g. "/reports/*=30000". May be given
+    /// several times, the most specific matching glob applies. Takes
+    /// precedence over --upstream-timeout-per-method and --upstream-timeout.
+    /// In the config file, given as a [timeouts] table of globs to
+    /// milliseconds
+    #[clap(long, value_name = "GLOB=MILLIS")]
+    upstream_per_path_timeout: Vec<PathTimeout>,
+
+    /// Gzip responses larger than this many bytes for clients that send
+    /// "Accept-Encoding: gzip"
+    #[clap(long, value_name = "BYTES")]
+    gzip_compress_response_above_bytes: Option<u64>,
+
+    /// On SIGINT or SIGTERM, stop accepting connections and wait this long for
+    /// in-flight requests before exiting. Connections still open after that
+    /// are closed
+    #[clap(
+        long,
+        alias = "drain-timeout",
+        default_value_t = 10,
+        value_name = "SECS"
+    )]
+    drain_timeout_secs: u64,
+
+    /// Signal that reads the --config file, the TLS certificate and the
+    /// path blocklist again and rebuilds the upstream clients, without
+    /// dropping connections. The listen address and other listener settings
+    /// only change on restart
+    #[clap(long, default_value = "SIGHUP", value_name = "SIGHUP|SIGUSR1")]
+    reload_signal: ReloadSignal,
+
+    /// End upstream response bodies that send neither data nor EOF within
+    /// this many milliseconds, keeping the status and headers
+    #[clap(long, value_name = "MS")]
+    upstream_empty_body_timeout_ms: Option<u64>,
+
+    /// Abort upstream responses that, once their body has started, send
+    /// nothing more for this many milliseconds. The client's connection is
+    /// closed, as the response can't be completed
+    #[clap(long, value_name = "MS")]
+    upstream_response_timeout_after_first_byte_ms: Option<u64>,
+
+    /// Abort upstream responses whose body arrives slower than this many
+    /// bytes per second, measured over --upstream-slow-speed-window-secs.
+    /// The client's connection is closed, as the response can't be completed
+    #[clap(long, value_name = "BYTES")]
+    upstream_response_min_speed_bps: Option<u64>,
+
+    /// Window over which --upstream-response-min-speed-bps is measured
+    #[clap(long, default_value_t = 10, value_name = "SECS")]
+    upstream_slow_speed_window_secs: u64,
+
+    /// Send identical GET requests arriving within this many milliseconds of
+    /// each other upstream only once, sharing the buffered response
+    #[clap(long, value_name = "MS")]
+    request_coalesce_window_ms: Option<u64>,
+
+    /// Verify upstream responses that carry this header against the SHA-256
+    /// digest (hex or base64) it holds, answering 502 on a mismatch
+    #[clap(long, value_name = "HEADER")]
+    upstream_response_hash_header: Option<HeaderName>,
+
+    /// Fraction of requests (0.0 to 1.0) to write access log entries for.
+    /// 4xx and 5xx responses and denied requests are always logged
+    #[clap(long, value_name = "RATE", validator = validate_sampling_rate)]
+    request_log_sampling_rate: Option<f64>,
+
+    /// Log the bodies of requests to paths starting with these
+    /// comma-separated prefixes, e.g. "/debug,/trace", up to 4 KiB each
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        value_name = "PREFIX,...",
+        alias = "request-log-body-paths"
+    )]
+    log_request_body_for: Vec<String>,
+
+    /// How to write logged request bodies: utf8, hex or base64. Given as
+    /// CONTENT_TYPE_PREFIX=FORMAT, e.g. "image/=base64", it applies to
+    /// bodies of matching content types only. May be given several times
+    #[clap(long, value_name = "[PREFIX=]FORMAT")]
+    request_body_log_format: Vec<BodyLogFormatRule>,
+
+    /// Replace every invalid upstream response, by default any non-2xx one,
+    /// with a static one
+    #[clap(long)]
+    upstream_happy_path_only: bool,
+
+    /// Status of the response sent in place of upstream errors
+    #[clap(long, default_value = "502", value_name = "STATUS")]
+    upstream_error_status: StatusCode,
+
+    /// Body of the response sent in place of upstream errors
+    #[clap(long, default_value = "", value_name = "BODY")]
+    upstream_error_body: String,
+
+    /// Upstream response statuses and ranges considered valid, e.g.
+    /// "200-299,302". Other responses are logged as errors, and replaced if
+    /// --upstream-happy-path-only is set
+    #[clap(
+        long,
+        value_name = "STATUS[-STATUS],...",
+        alias = "upstream-validate-status-range"
+    )]
+    upstream_valid_status_range: Option<StatusRanges>,
+
+    /// "strict" answers with 502 in place of upstream responses with a
+    /// status outside 100-599, several Content-Length headers, both
+    /// Content-Length and Transfer-Encoding, or non-ASCII header values.
+    /// "lenient" passes them on. Malformed status lines and header names
+    /// are refused either way
+    #[clap(long, default_value = "lenient", value_name = "strict|lenient")]
+    upstream_response_validation_mode: ResponseValidation,
+
+    /// Log a warning and count an SLA violation whenever the upstream takes
+    /// longer than this many milliseconds to send response headers
+    #[clap(long, value_name = "MS")]
+    upstream_latency_sla_warn_ms: Option<u64>,
+
+    /// Log a warning whenever an upstream response body is larger than this
+    /// many bytes
+    #[clap(long, value_name = "BYTES")]
+    upstream_response_size_sla_warn_bytes: Option<u64>,
+
+    /// Number of requests each client IP address may send in a burst before
+    /// being limited to --rate-limit-rps
+    #[clap(long, value_name = "REQUESTS", requires = "rate-limit-rps")]
+    rate_limit_capacity: Option<NonZeroU32>,
+
+    /// Requests per second each client IP address may send once its burst
+    /// is used up. Requests over the limit are answered with 429
+    #[clap(
+        long,
+        value_name = "REQUESTS",
+        requires = "rate-limit-capacity",
+        validator = validate_rate_limit_rps
+    )]
+    rate_limit_rps: Option<f64>,
+
+    /// How --rate-limit-capacity and --rate-limit-rps apply: token-bucket
+    /// allows bursts of up to the capacity, leaky-bucket delays requests to
+    /// the rate with up to the capacity waiting, sliding-window allows the
+    /// capacity in any window of capacity / rps seconds
+    #[clap(
+        long,
+        default_value = "token-bucket",
+        value_name = "ALGORITHM",
+        alias = "request-rate-limit-algorithm"
+    )]
+    rate_limit_algorithm: RateLimitAlgorithm,
+
+    /// "PREFIX:RATErps", e.g. "/api:100rps". Requests across all clients to
+    /// paths starting with PREFIX over RATE a second are answered with 429.
+    /// May be given several times, the longest matching prefix applies
+    #[clap(long, value_name = "PREFIX:RATErps")]
+    rate_limit_path: Vec<PathRateLimit>,
+
+    /// Maximum number of requests per second sent upstream across all
+    /// clients. Requests over the limit are answered with 503
+    #[clap(long, value_name = "REQUESTS")]
+    upstream_burst_limit: Option<NonZeroU32>,
+
+    /// Number of requests over --upstream-burst-limit that wait for their
+    /// turn instead of being answered with 503
+    #[clap(long, default_value_t = 0, value_name = "REQUESTS")]
+    upstream_queue_depth: usize,
+
+    /// Request header holding an ID to add to every log line for the request
+    /// as correlation_id, e.g. X-Correlation-Id
+    #[clap(long, value_name = "HEADER")]
+    log_correlation_id_header: Option<HeaderName>,
+
+    /// Comma-separated request headers to add to every log line for the
+    /// request, e.g. "Authorization,X-Api-Key,Content-Type"
+    #[clap(long, use_value_delimiter = true, value_name = "HEADER,...")]
+    trace_request_headers: Vec<HeaderName>,
+
+    /// Log the values of sensitive headers listed in --trace-request-headers
+    /// instead of [REDACTED]
+    #[clap(long)]
+    trace_sensitive_headers: bool,
+
+    /// Close the client connection without a response, instead of answering
+    /// with 502, when the upstream request fails with a TLS error
+    #[clap(long)]
+    abort_on_upstream_tls_error: bool,
+
+    /// Remove repeated slashes and "." and ".." components from request
+    /// paths before forwarding them
+    #[clap(long)]
+    request_normalize_path: bool,
+
+    /// Normalize request paths like --request-normalize-path, answering 400
+    /// to paths that go above the root
+    #[clap(long)]
+    request_normalize_path_strict: bool,
+
+    /// Convert request paths to "lowercase" or "uppercase" before
+    /// forwarding them, for case-sensitive upstreams. The query is left as is
+    #[clap(long, default_value = "none", value_name = "lowercase|uppercase|none")]
+    request_path_case_normalization: PathCase,
+
+    /// Send the request path and query upstream base64url-encoded, without
+    /// padding, as a single segment after --base-endpoint, for gateways that
+    /// expect encoded paths. The upstream must decode it
+    #[clap(long)]
+    upstream_base64_encode_path: bool,
+
+    /// Comma-separated request headers to add to the Vary header of every
+    /// response, merged with any the upstream sent
+    #[clap(long, use_value_delimiter = true, value_name = "HEADER,...")]
+    response_vary_header: Vec<HeaderName>,
+
+    /// Filename to have browsers save --force-download-for responses as,
+    /// sent as Content-Disposition: attachment
+    #[clap(long, value_name = "FILENAME", requires = "force-download-for")]
+    response_download_header: Option<String>,
+
+    /// Comma-separated media types of responses to send with
+    /// --response-download-header
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        value_name = "TYPE,...",
+        requires = "response-download-header"
+    )]
+    force_download_for: Vec<String>,
+
+    /// Replace the Content-Disposition of upstream responses with
+    /// --response-download-header instead of keeping it
+    #[clap(long, requires = "response-download-header")]
+    force_download_override: bool,
+
+    /// Replace the generic Content-Type (text/plain or
+    /// application/octet-stream) upstreams give some files, or a missing
+    /// one, based on the extension of the request path, as ".EXT=TYPE",
+    /// e.g. ".js=application/javascript". May be given for several
+    /// extensions
+    #[clap(
+        long,
+        value_name = ".EXT=TYPE",
+        alias = "upstream-response-content-type-override"
+    )]
+    override_content_type: Vec<ContentTypeOverride>,
+
+    /// Remove the whitespace from "application/json" responses, updating
+    /// Content-Length. Responses that aren't valid JSON are sent as they are
+    #[clap(long, alias = "minify-json-responses")]
+    upstream_response_minify_json: bool,
+
+    /// Add X-Proxy-Upstream, X-Proxy-Latency-Ms, X-Proxy-Request-Id and
+    /// X-Proxy-Version headers to every response
+    #[clap(long, alias = "response-include-debug-headers")]
+    debug_headers: bool,
+
+    /// Add X-Proxy-Pool-Active and X-Proxy-Pool-Idle headers with the number
+    /// of connections open to the upstream that served the response
+    #[clap(long, requires = "debug-headers")]
+    upstream_connection_count_header: bool,
+
+    /// Remove response headers whose name starts with this prefix, e.g.
+    /// X-Proxy-
+    #[clap(long, value_name = "PREFIX")]
+    debug_headers_strip_prefix: Option<String>,
+
+    /// Request header to remove before forwarding, may be given several
+    /// times
+    #[clap(long, value_name = "HEADER")]
+    strip_req_header: Vec<HeaderName>,
+
+    /// Comma-separated request headers to forward, e.g.
+    /// "Authorization,Content-Type". Every other header the client sends is
+    /// dropped
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        value_name = "HEADER,...",
+        alias = "request-header-whitelist"
+    )]
+    allow_request_headers: Vec<HeaderName>,
+
+    /// Response header to remove before returning the response, may be
+    /// given several times
+    #[clap(long, value_name = "HEADER")]
+    strip_resp_header: Vec<HeaderName>,
+
+    /// Comma-separated upstream response headers to return, e.g.
+    /// "Content-Type,Cache-Control". Every other header the upstream sends
+    /// is dropped
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        value_name = "HEADER,...",
+        alias = "response-header-whitelist"
+    )]
+    allow_response_headers: Vec<HeaderName>,
+
+    /// Add an X-Proxy-Meta header with the proxy version and a hash of its
+    /// command line arguments to upstream requests
+    #[clap(long)]
+    upstream_metadata_header: bool,
+
+    /// Add an X-Forwarded-By header with the proxy's name and version, e.g.
+    /// "proxy-filter/0.1.0", to upstream requests, so upstreams can tell
+    /// proxied traffic from direct traffic
+    #[clap(long)]
+    upstream_send_proxy_info_header: bool,
+
+    /// Send only the host name in the upstream Host header, dropping any
+    /// port, e.g. "example.com" rather than "example.com:8443", for
+    /// upstreams that reject a port in Host
+    #[clap(long)]
+    upstream_strip_port_from_host_header: bool,
+
+    /// Serve Prometheus metrics of requests, latency and upstream errors on
+    /// this address, e.g. 127.0.0.1:9090
+    #[clap(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Add an ETag made from a hash of the body to successful GET responses
+    /// that lack one, answering 304 to requests whose If-None-Match matches
+    #[clap(long)]
+    response_add_etag: bool,
+
+    /// Path segment to insert into request paths after the number of
+    /// segments given by the matching --after-segment, may be repeated.
+    /// Insertions are applied in order
+    #[clap(
+        long,
+        value_name = "SEGMENT",
+        requires = "after-segment",
+        alias = "request-transform-add-path-segment"
+    )]
+    add_path_segment: Vec<String>,
+
+    /// Number of leading path segments to insert the matching
+    /// --add-path-segment after
+    #[clap(long, value_name = "COUNT", requires = "add-path-segment")]
+    after_segment: Vec<usize>,
+
+    /// Answer OPTIONS requests with 200 and an Allow header instead of
+    /// forwarding them
+    #[clap(long)]
+    handle_options_locally: bool,
+
+    /// Comma-separated methods listed in the Allow header of OPTIONS
+    /// responses sent by --handle-options-locally
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        default_value = "GET,POST,PUT,DELETE,OPTIONS,HEAD",
+        value_name = "METHOD,..."
+    )]
+    allowed_methods: Vec<Method>,
+
+    /// File of request path globs, one per line, to answer with 403. The file
+    /// is read again on --reload-signal
+    #[clap(long, value_name = "PATH")]
+    request_path_blocklist_file: Option<PathBuf>,
+
+    /// Append a JSON line for every request a --filter rule, the path
+    /// blocklist or the pre-connect hook allows or denies to this file
+    #[clap(long, value_name = "PATH")]
+    audit_log_file: Option<PathBuf>,
+
+    /// Rule deciding what to do with matching requests, e.g.
+    /// `method=POST;path-prefix=/admin;header=X-Env:prod;action=block:403`.
+    /// Rules are tried in the order given and the first match wins. The
+    /// action is `allow`, `block:STATUS[:BODY]` with a 4xx or 5xx status or
+    /// `redirect:URL`, and `path-regex` may be used instead of `path-prefix`
+    #[clap(long, value_name = "RULE")]
+    filter: Vec<FilterRule>,
+
+    /// Media type upstream responses must have, e.g. application/json.
+    /// Responses with any other Content-Type are answered with 502
+    #[clap(long, value_name = "TYPE")]
+    upstream_expect_content_type: Option<String>,
+
+    /// Header added to upstream requests sent while draining on shutdown,
+    /// e.g. "X-Draining: true"
+    #[clap(long, parse(try_from_str = config::parse_header), value_name = "NAME: VALUE")]
+    connection_draining_header: Option<(HeaderName, HeaderValue)>,
+
+    /// Forward the bodies of GET requests instead of dropping them
+    #[clap(long, alias = "request-body-passthrough-for-get")]
+    allow_get_body: bool,
+
+    /// Forward POST requests with the method given in
+    /// --request-method-override-header, for clients that can only send GET
+    /// and POST
+    #[clap(long)]
+    allow_method_override: bool,
+
+    /// Header read by --allow-method-override
+    #[clap(long, default_value = "X-HTTP-Method-Override", value_name = "NAME")]
+    request_method_override_header: HeaderName,
+
+    /// Don't add X-Forwarded-For, X-Forwarded-Host, X-Forwarded-Proto and
+    /// Via headers to upstream requests
+    #[clap(long)]
+    no_forwarding_headers: bool,
+
+    /// After startup, open this many connections to the upstream with HEAD
+    /// requests to the base endpoint so the first requests don't wait for
+    /// them
+    #[clap(long, value_name = "COUNT")]
+    upstream_pool_warm_up: Option<usize>,
+
+    /// Path answered by the proxy itself with 200 and its version, for
+    /// health checks. An empty value forwards every path
+    #[clap(long, default_value = "/_proxy/health", value_name = "PATH")]
+    health_path: String,
+
+    /// Answer requests from clients in these countries with 403, as
+    /// comma-separated ISO 3166-1 codes, e.g. "CN,RU". Countries are looked
+    /// up in --geoip-db-path
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        value_name = "COUNTRY,...",
+        alias = "request-geo-block",
+        requires = "geoip-db-path"
+    )]
+    geo_block: Vec<String>,
+
+    /// MaxMind DB file, such as GeoLite2-Country.mmdb, to look up the
+    /// country of clients in. The country is logged with every request
+    #[clap(long, value_name = "PATH")]
+    geoip_db_path: Option<PathBuf>,
+
+    /// Answer GET /favicon.ico with this file instead of forwarding it, for
+    /// upstreams that don't serve one. Browsers are told to cache it for a
+    /// day
+    #[clap(long, value_name = "PATH")]
+    serve_favicon_file: Option<PathBuf>,
+
+    /// Answer requests whose application/json body isn't valid UTF-8 with
+    /// 400 instead of forwarding them
+    #[clap(long)]
+    request_body_encoding_validation: bool,
+
+    /// Answer requests whose application/json or
+    /// application/x-www-form-urlencoded body is malformed with 400 instead
+    /// of forwarding them. Bodies larger than --body-validation-max-bytes
+    /// are forwarded unchecked
+    #[clap(long)]
+    request_body_type_validation: bool,
+
+    /// Largest request body checked by --request-body-type-validation
+    #[clap(long, default_value_t = 1024 * 1024, value_name = "BYTES")]
+    body_validation_max_bytes: usize,
+
+    /// Forward request bodies larger than this many bytes as they are,
+    /// skipping the validation, transformation, logging and size reporting
+    /// that read bodies in full, e.g. 10485760 for 10MB. Logged as a warning
+    #[clap(long, value_name = "BYTES")]
+    body_passthrough_above_bytes: Option<u64>,
+
+    /// Remove members with these comma-separated names, at any depth, from
+    /// application/json request bodies before forwarding them, e.g.
+    /// "password,ssn". Bodies that aren't valid JSON are answered with 400
+    #[clap(long, use_value_delimiter = true, value_name = "FIELD,...")]
+    request_body_transform_remove_fields: Vec<String>,
+
+    /// Send the operationName of GraphQL requests, POSTed as
+    /// application/json, upstream in X-GraphQL-Operation-Name, and match
+    /// --route prefixes against the path followed by the operation name,
+    /// e.g. "/graphql/GetUser". Such bodies are read in full before
+    /// forwarding
+    #[clap(long)]
+    request_transform_graphql_operation_name_header: bool,
+
+    /// Request header to report the size of request bodies to upstreams in,
+    /// e.g. X-Request-Body-Bytes. Bodies are read in full before forwarding.
+    /// The size of response bodies is logged once they are sent
+    #[clap(long, value_name = "HEADER")]
+    body_size_reporting_header: Option<HeaderName>,
+
+    /// Response header to report the size of response bodies to clients in,
+    /// e.g. X-Response-Size. Bodies of unknown size are reported in a
+    /// trailer to HTTP/2 clients and not at all to HTTP/1 clients. The size
+    /// is logged once the body has been sent
+    #[clap(long, value_name = "HEADER")]
+    response_body_size_header: Option<HeaderName>,
+
+    /// Require basic authentication for requests under a path prefix, as
+    /// "/PREFIX:REALM:USERNAME:PASSWORD". May be given for several paths,
+    /// the longest matching prefix applies
+    #[clap(long, alias = "proxy-auth-realm", value_name = "AUTH")]
+    path_auth: Vec<PathAuth>,
+
+    /// Send requests under a path prefix to another backend, as
+    /// "PREFIX=BACKEND". May be given several times, the longest matching
+    /// prefix applies and other requests go to --base-endpoint. ${VAR} in
+    /// BACKEND is replaced as in --base-endpoint
+    #[clap(long, value_name = "PREFIX=BACKEND")]
+    route: Vec<String>,
+
+    /// Send requests asking for an API version through a vendor media type
+    /// in Accept, e.g. "application/vnd.example.v2+json", to another
+    /// backend, as "VERSION:BACKEND", e.g. "v2:https://api-v2.internal".
+    /// May be given for several versions and takes precedence over --route.
+    /// ${VAR} in BACKEND is replaced as in --base-endpoint
+    #[clap(
+        long,
+        value_name = "VERSION:BACKEND",
+        alias = "request-schema-version-routing"
+    )]
+    version_upstream: Vec<String>,
+
+    /// Send requests for a virtual host, named in Host, to another backend,
+    /// as "HOST:BACKEND", e.g. "api.example.com:https://api.internal". May
+    /// be given for several hosts and takes precedence over
+    /// --version-upstream and --route. ${VAR} in BACKEND is replaced as in
+    /// --base-endpoint
+    #[clap(
+        long,
+        value_name = "HOST:BACKEND",
+        alias = "request-host-header-routing"
+    )]
+    host_route: Vec<String>,
+
+    /// Send requests with a feature flag header to another backend, as
+    /// "HEADER:VALUE:BACKEND", e.g. "X-Feature:new-ui:https://new-ui.internal".
+    /// The header may carry several comma-separated values. May be given for
+    /// several features, the first matching one applies, and takes
+    /// precedence over --host-route, --version-upstream and --route. ${VAR}
+    /// in BACKEND is replaced as in --base-endpoint
+    #[clap(
+        long,
+        value_name = "HEADER:VALUE:BACKEND",
+        alias = "feature-flag-header"
+    )]
+    feature_route: Vec<String>,
+
+    /// Send requests to the upstream named by this path segment, counting
+    /// from 1, put into --upstream-template. The segment is taken out of the
+    /// forwarded path, so with 1, "/tenantA/api/users" goes to
+    /// "/api/users" on the tenantA upstream. Takes precedence over
+    /// --feature-route, --host-route, --version-upstream and --route
+    #[clap(long, value_name = "N", requires = "upstream-template")]
+    upstream_hostname_from_path_segment: Option<usize>,
+
+    /// Upstream URL for --upstream-hostname-from-path-segment, with
+    /// "{segment}" standing for the segment, e.g. "https://{segment}.internal"
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        requires = "upstream-hostname-from-path-segment"
+    )]
+    upstream_template: Option<String>,
+
+    /// Comma-separated "PREFIX:LEVEL" priorities, e.g.
+    /// "/assets:low,/api:high", sent upstream in an RFC 9218 Priority header
+    /// on requests to paths starting with PREFIX. LEVEL is high, normal, low
+    /// or an urgency from 0 (most urgent) to 7. The longest matching prefix
+    /// applies
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        value_name = "PREFIX:LEVEL,...",
+        alias = "upstream-set-priority"
+    )]
+    upstream_h2_priority: Vec<PathPriority>,
+
+    /// Comma-separated "HOST:PROTOCOL" overrides, e.g.
+    /// "grpc.example.com:h2,legacy.example.com:h1", to only speak HTTP/1.1
+    /// (h1) or HTTP/2 (h2) to an upstream host instead of negotiating it.
+    /// HTTP/2 is also spoken without TLS
+    #[clap(long, use_value_delimiter = true, value_name = "HOST:PROTOCOL,...")]
+    upstream_protocol: Vec<UpstreamProtocol>,
+}
+
+impl Args {
+    /// Overrides the options in `config` with those given on the command
+    /// line. Without a config file every option comes from the command line,
+    /// defaults included.
+    fn merge_into(self, matches: &ArgMatches, mut config: Config) -> Config {
+        let all = self.config.is_none();
+        macro_rules! merge {
+            ($($field:ident,)*) => {
+                $(
+                    if all || matches.occurrences_of(&*stringify!($field).replace('_', "-")) > 0 {
+                        config.$field = self.$field;
+                    }
+                )*
+            };
+        }
+        merge!(
+            base_endpoint,
+            listen,
+            upstream_authority,
+            upstream_path_prefix,
+            restrict_upstream_ip_ranges,
+            upstream_ipv4_only,
+            upstream_ipv6_only,
+            upstream_connect_retry,
+            upstream_connection_timeout_per_attempt_ms,
+            upstream_connection_timeout_total_ms,
+            upstream_tcp_fast_open,
+            upstream_tcp_keepalive_interval,
+            upstream_tcp_keepalive_probes,
+            upstream_request_write_buf_size,
+            upstream_title_case_headers,
+            request_header_case,
+            upstream_http1_writev,
+            force_close_upstream_connection,
+            upstream_idle_connection_check,
+            idle_connection_revalidate_after_ms,
+            pool_pre_check,
+            upstream_pool_health_check_path,
+            max_concurrent_upstream_connections_per_host,
+            max_host_connections,
+            upstream_max_idle_connection_age_secs,
+            upstream_preemptive_refresh_connections,
+            grpc_keepalive_interval_secs,
+            grpc_keepalive_timeout_secs,
+            upstream_h2_initial_stream_window,
+            upstream_h2_initial_connection_window,
+            upstream_resolver_timeout_ms,
+            tls_key_log_file,
+            debug_mode,
+            upstream_tls_no_session_tickets,
+            upstream_tls_renegotiation,
+            upstream_http_version_negotiation,
+            upstream_tls_hostname_pattern,
+            slow_client_abort_threshold_ms,
+            min_client_bandwidth_bps,
+            upstream_pre_connect_hook_url,
+            pre_connect_cache_secs,
+            remove_response_cookies,
+            remove_cookies_matching,
+            strip_response_set_cookie_for_non_https,
+            inject_response_timing_header,
+            upstream_auth_token_refresh_url,
+            upstream_auth_client_credentials,
+            upstream_auth_google_iam,
+            upstream_auth_google_audience,
+            aws_region,
+            aws_service,
+            upstream_proxy_protocol,
+            listen_proxy_protocol,
+            listen_recv_buf_size,
+            listen_send_buf_size,
+            listen_defer_accept,
+            listen_tcp_quickack,
+            listen_h2_max_frame_size,
+            workers,
+            tls_cert,
+            tls_key,
+            tls_session_cache_size,
+            tls_session_timeout_secs,
+            client_cert_optional,
+            upstream_timeout,
+            max_req_body,
+            max_resp_body,
+            grpc_max_recv_message_size,
+            grpc_max_send_message_size,
+            upstream_grpc_status_mapping,
+            upstream_timeout_per_method,
+            upstream_timeout_jitter_ms,
+            upstream_per_path_timeout,
+            gzip_compress_response_above_bytes,
+            drain_timeout_secs,
+            reload_signal,
+            upstream_empty_body_timeout_ms,
+            upstream_response_timeout_after_first_byte_ms,
+            upstream_response_min_speed_bps,
+            upstream_slow_speed_window_secs,
+            request_coalesce_window_ms,
+            upstream_response_hash_header,
+            request_log_sampling_rate,
+            log_request_body_for,
+            request_body_log_format,
+            upstream_happy_path_only,
+            upstream_error_status,
+            upstream_error_body,
+            upstream_valid_status_range,
+            upstream_response_validation_mode,
+            upstream_latency_sla_warn_ms,
+            upstream_response_size_sla_warn_bytes,
+            rate_limit_capacity,
+            rate_limit_rps,
+            rate_limit_algorithm,
+            rate_limit_path,
+            upstream_burst_limit,
+            upstream_queue_depth,
+            log_correlation_id_header,
+            trace_request_headers,
+            trace_sensitive_headers,
+            abort_on_upstream_tls_error,
+            request_normalize_path,
+            request_normalize_path_strict,
+            request_path_case_normalization,
+            upstream_base64_encode_path,
+            response_vary_header,
+            response_download_header,
+            force_download_for,
+            force_download_override,
+            override_content_type,
+            upstream_response_minify_json,
+            debug_headers,
+            upstream_connection_count_header,
+            debug_headers_strip_prefix,
+            strip_req_header,
+            allow_request_headers,
+            strip_resp_header,
+            allow_response_headers,
+            upstream_metadata_header,
+            upstream_send_proxy_info_header,
+            upstream_strip_port_from_host_header,
+            metrics_addr,
+            response_add_etag,
+            add_path_segment,
+            after_segment,
+            handle_options_locally,
+            allowed_methods,
+            request_path_blocklist_file,
+            audit_log_file,
+            filter,
+            upstream_expect_content_type,
+            connection_draining_header,
+            allow_get_body,
+            allow_method_override,
+            request_method_override_header,
+            no_forwarding_headers,
+            upstream_pool_warm_up,
+            health_path,
+            geo_block,
+            geoip_db_path,
+            serve_favicon_file,
+            request_body_encoding_validation,
+            request_body_type_validation,
+            body_validation_max_bytes,
+            body_passthrough_above_bytes,
+            request_body_transform_remove_fields,
+            request_transform_graphql_operation_name_header,
+            body_size_reporting_header,
+            response_body_size_header,
+            path_auth,
+            route,
+            version_upstream,
+            host_route,
+            feature_route,
+            upstream_hostname_from_path_segment,
+            upstream_template,
+            upstream_h2_priority,
+            upstream_protocol,
+        );
+        config
+    }
+}
+
+fn validate_sampling_rate(rate: &str) -> Result<(), String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(()),
+        _ => Err("must be a number between 0.0 and 1.0".to_string()),
+    }
+}
+
+fn validate_rate_limit_rps(rate: &str) -> Result<(), String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(()),
+        _ => Err("must be a number above 0".to_string()),
+    }
 }
 
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt::init();
-    let args = Args::parse();
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    let forward_addr = args.base_endpoint;
-    info!("Starting server at '{}'", addr);
-
-    let proxy_client = ProxyClient::new(
-        addr,
-        forward_addr,
-        // SocketAddr::from(([192, 168, 64, 8], 8080)),
-    );
-
-    let server = server::new!(proxy_client);
-    if let Err(e) = server.await {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let healthcheck_path = args.container_healthcheck_path.clone();
+    let config = load_config(&matches, args).unwrap_or_else(|e| {
+        eprintln!("failed to load --config: {}", e);
+        std::process::exit(1);
+    });
+    if let Some(path) = healthcheck_path {
+        match healthcheck(&config, &path).await {
+            Ok(StatusCode::OK) => std::process::exit(0),
+            Ok(status) => eprintln!("health check answered {}", status),
+            Err(e) => eprintln!("health check failed: {}", e),
+        }
+        std::process::exit(1);
+    }
+    info!("Starting server at '{}'", config.listen);
+
+    let mut proxy_client = ProxyClient::from_config(config.clone()).unwrap_or_else(|e| {
+        eprintln!("invalid configuration: {}", e);
+        std::process::exit(1);
+    });
+    let mut blocklist = None;
+    if let Some(file) = &config.request_path_blocklist_file {
+        match PathBlocklist::load(file.clone()) {
+            Ok(loaded) => {
+                let loaded = Arc::new(loaded);
+                proxy_client = proxy_client.with_path_blocklist(Arc::clone(&loaded));
+                blocklist = Some(loaded);
+            }
+            Err(e) => {
+                eprintln!("failed to load --request-path-blocklist-file: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let (Some(url), Some(credentials)) = (
+        config.upstream_auth_token_refresh_url.clone(),
+        &config.upstream_auth_client_credentials,
+    ) {
+        match TokenSource::start(url, credentials).await {
+            Ok(source) => proxy_client = proxy_client.with_token_source(source),
+            Err(e) => {
+                eprintln!("failed to obtain upstream auth token: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if config.upstream_auth_google_iam {
+        let audience = match &config.upstream_auth_google_audience {
+            Some(audience) => Ok(audience.clone()),
+            None => endpoint_origin(&config.base_endpoint),
+        };
+        let metadata_host = std::env::var("GCE_METADATA_HOST")
+            .unwrap_or_else(|_| oauth::GOOGLE_METADATA_HOST.to_string());
+        let source = match audience {
+            Ok(audience) => TokenSource::start_google_iam(&metadata_host, &audience).await,
+            Err(e) => Err(e),
+        };
+        match source {
+            Ok(source) => proxy_client = proxy_client.with_token_source(source),
+            Err(e) => {
+                eprintln!("failed to obtain Google Cloud ID token: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(connections) = config.upstream_pool_warm_up {
+        tokio::spawn(proxy_client.warm_up(connections));
+    }
+    let (metrics_shutdown_tx, metrics_shutdown_rx) = oneshot::channel::<()>();
+    let mut metrics_task = None;
+    if let Some(metrics_addr) = config.metrics_addr {
+        let recorder = Arc::new(MetricsRecorder::new());
+        proxy_client = proxy_client.with_metrics(Arc::clone(&recorder));
+        info!("Serving metrics at '{}'", metrics_addr);
+        metrics_task = Some(tokio::spawn(metrics::serve(
+            metrics_addr,
+            recorder,
+            async {
+                let _ = metrics_shutdown_rx.await;
+            },
+        )));
+    }
+    let in_flight = proxy_client.in_flight();
+    let draining = proxy_client.draining();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let mut server = ServerBuilder::new(proxy_client);
+    if let Some(workers) = config.workers {
+        server = server.with_workers(WorkerPool::new(workers));
+    }
+    tokio::spawn(reload_on_signal(
+        config.clone(),
+        matches,
+        blocklist,
+        server.reload_handle(),
+    ));
+    if config.upstream_preemptive_refresh_connections {
+        tokio::spawn(server.reload_handle().refresh_connections());
+    }
+    let server = server
+        .with_graceful_shutdown(async {
+            let _ = shutdown_rx.await;
+        })
+        .serve();
+    tokio::pin!(server);
+    let result = tokio::select! {
+        result = &mut server => result,
+        _ = shutdown_signal() => {
+            info!("Shutting down, draining in-flight requests");
+            draining.store(true, Ordering::SeqCst);
+            let _ = shutdown_tx.send(());
+            let drain_timeout = Duration::from_secs(config.drain_timeout_secs);
+            match tokio::time::timeout(drain_timeout, &mut server).await {
+                Ok(result) => result,
+                Err(_) => {
+                    warn!(
+                        "Drain timeout expired with {} requests still in flight",
+                        in_flight.load(Ordering::SeqCst)
+                    );
+                    Ok(())
+                }
+            }
+        }
+    };
+    if let Err(e) = result {
         eprintln!("server error: {}", e);
     }
+    if let Some(metrics_task) = metrics_task {
+        let _ = metrics_shutdown_tx.send(());
+        match metrics_task.await {
+            Ok(Err(e)) => eprintln!("metrics server error: {}", e),
+            Err(e) => eprintln!("metrics server failed: {}", e),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
+/// GETs `path` from the proxy `config` listens on, going through loopback
+/// when it listens on every address. The proxy's certificate isn't verified,
+/// it is usually issued for a name other than the loopback address.
+async fn healthcheck(config: &Config, path: &str) -> Result<StatusCode, String> {
+    if !path.starts_with('/') {
+        return Err(format!("path '{}' must start with /", path));
+    }
+    let mut addr = config.listen;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    let scheme = if config.tls_cert.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let uri: Uri = format!("{}://{}{}", scheme, addr, path)
+        .parse()
+        .map_err(|e| format!("invalid path '{}': {}", path, e))?;
+    let mut ssl = SslConnector::builder(SslMethod::tls()).map_err(|e| e.to_string())?;
+    ssl.set_verify(SslVerifyMode::NONE);
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let https = HttpsConnector::with_connector(http, ssl).map_err(|e| e.to_string())?;
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let resp = client.get(uri).await.map_err(|e| e.to_string())?;
+    Ok(resp.status())
+}
+
+/// The scheme and authority of the upstream `endpoint`.
+fn endpoint_origin(endpoint: &str) -> Result<String, String> {
+    let endpoint = env::expand(endpoint)?;
+    let uri: Uri = endpoint
+        .parse()
+        .map_err(|e| format!("invalid base endpoint '{}': {}", endpoint, e))?;
+    match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme), Some(authority)) => Ok(format!("{}://{}", scheme, authority)),
+        _ => Err(format!("invalid base endpoint '{}'", endpoint)),
+    }
+}
+
+/// Reads the --config file, if any, with the arguments given in `matches`
+/// taking precedence.
+fn load_config(matches: &ArgMatches, args: Args) -> Result<Config, String> {
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    Ok(args.merge_into(matches, config))
+}
+
+/// Reads the path blocklist and the configuration again on every
+/// `config.reload_signal`, replacing the client of `proxy` with one built from
+/// the configuration and logging the keys that changed.
+async fn reload_on_signal(
+    mut config: Config,
+    matches: ArgMatches,
+    blocklist: Option<Arc<PathBlocklist>>,
+    proxy: ReloadHandle,
+) {
+    let reload_signal = config.reload_signal;
+    let mut signals = signal::unix::signal(reload_signal.kind()).expect("reload signal handler");
+    while signals.recv().await.is_some() {
+        info!("Received {}, reloading", reload_signal);
+        if let Some(blocklist) = &blocklist {
+            match blocklist.reload() {
+                Ok(count) => info!(
+                    "Reloaded {} patterns from {}",
+                    count,
+                    blocklist.file().display()
+                ),
+                Err(e) => warn!(
+                    "Keeping current blocklist, failed to reload {}: {}",
+                    blocklist.file().display(),
+                    e
+                ),
+            }
+        }
+        let reloaded = Args::from_arg_matches(&matches)
+            .map_err(|e| e.to_string())
+            .and_then(|args| load_config(&matches, args));
+        let reloaded = match reloaded {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                warn!("Keeping current configuration, failed to load it: {}", e);
+                continue;
+            }
+        };
+        let changed = config.changed_keys(&reloaded);
+        if let Err(e) = proxy.reload(reloaded.clone()) {
+            warn!("Keeping current configuration, failed to apply it: {}", e);
+            continue;
+        }
+        for key in [
+            "listen",
+            "reload-signal",
+            "request-path-blocklist-file",
+            "upstream-preemptive-refresh-connections",
+            "workers",
+        ] {
+            if changed.iter().any(|changed| changed == key) {
+                warn!("Ignoring the new {}, it only changes on restart", key);
+            }
+        }
+        if changed.is_empty() {
+            info!("Reloaded, the configuration is unchanged");
+        } else {
+            info!("Reloaded, changed: {}", changed.join(", "));
+        }
+        config = reloaded;
+    }
+}
+
+/// Completes on the first SIGINT (Ctrl-C) or SIGTERM. Later signals are not
+/// handled, the drain timeout bounds how long shutdown takes.
+async fn shutdown_signal() {
+    let mut terminate =
+        signal::unix::signal(signal::unix::SignalKind::terminate()).expect("SIGTERM handler");
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(argv: &[&str], config: Config) -> Config {
+        let matches = Args::command().get_matches_from(argv);
+        Args::from_arg_matches(&matches)
+            .unwrap()
+            .merge_into(&matches, config)
+    }
+
+    #[test]
+    fn test_merge_into() {
+        // The flags' defaults are the same as the config file's.
+        let config = merged(&["proxy-filter"], Config::default());
+        assert_eq!(format!("{:?}", config), format!("{:?}", Config::default()));
+
+        let file = Config {
+            upstream_timeout: 10,
+            health_path: "/health".to_string(),
+            ..Config::default()
+        };
+        let config = merged(
+            &[
+                "proxy-filter",
+                "--config",
+                "proxy-filter.toml",
+                "--upstream-timeout",
+                "20",
+                "--listen",
+                "127.0.0.1:4000",
+            ],
+            file,
+        );
+        assert_eq!(config.upstream_timeout, 20);
+        assert_eq!(config.listen, SocketAddr::from(([127, 0, 0, 1], 4000)));
+        assert_eq!(config.health_path, "/health");
+
+        let config = merged(
+            &["proxy-filter", "--strip-response-set-cookie-for-non-https"],
+            Config::default(),
+        );
+        assert_eq!(
+            config.strip_response_set_cookie_for_non_https,
+            Some(InsecureCookies::Secure)
+        );
+    }
 }
@@ -0,0 +1,166 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+const MDNS_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::new(224, 0, 0, 251), 5353);
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+/// Asks responders to answer with unicast rather than multicast.
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+/// How long to wait for an mDNS responder before giving up.
+pub const QUERY_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub fn is_local(host: &str) -> bool {
+    host.trim_end_matches('.')
+        .to_ascii_lowercase()
+        .ends_with(".local")
+}
+
+/// Resolves a `.local` hostname with a one-shot multicast DNS query.
+pub async fn resolve(host: &str, timeout: Duration) -> io::Result<Vec<IpAddr>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.send_to(&query(host)?, MDNS_ADDR).await?;
+
+    let mut buf = [0u8; 9000];
+    tokio::time::timeout(timeout, async {
+        loop {
+            let (len, _) = socket.recv_from(&mut buf).await?;
+            let addrs = parse_response(&buf[..len], host);
+            if !addrs.is_empty() {
+                return Ok(addrs);
+            }
+        }
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no mDNS response"))?
+}
+
+pub fn with_port(addrs: Vec<IpAddr>) -> Vec<SocketAddr> {
+    addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect()
+}
+
+fn query(host: &str) -> io::Result<Vec<u8>> {
+    // Header: id 0, no flags, two questions.
+    let mut packet = vec![0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0];
+    for qtype in [TYPE_A, TYPE_AAAA] {
+        for label in host.trim_end_matches('.').split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid hostname '{}'", host),
+                ));
+            }
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&(CLASS_IN | UNICAST_RESPONSE).to_be_bytes());
+    }
+    Ok(packet)
+}
+
+/// Returns the A and AAAA records for `host` found in a DNS response, ignoring
+/// anything malformed.
+fn parse_response(packet: &[u8], host: &str) -> Vec<IpAddr> {
+    let host = host.trim_end_matches('.');
+    let mut addrs = Vec::new();
+    let counts = |i: usize| read_u16(packet, 4 + i * 2).unwrap_or(0) as usize;
+    let (questions, records) = (counts(0), counts(1) + counts(2) + counts(3));
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        match read_name(packet, pos) {
+            Some((_, next)) => pos = next + 4,
+            None => return addrs,
+        }
+    }
+    for _ in 0..records {
+        let (name, next) = match read_name(packet, pos) {
+            Some(name) => name,
+            None => break,
+        };
+        let (rtype, len) = match (read_u16(packet, next), read_u16(packet, next + 8)) {
+            (Some(rtype), Some(len)) => (rtype, len as usize),
+            _ => break,
+        };
+        let data = match packet.get(next + 10..next + 10 + len) {
+            Some(data) => data,
+            None => break,
+        };
+        pos = next + 10 + len;
+        if !name.eq_ignore_ascii_case(host) {
+            continue;
+        }
+        match (rtype, <[u8; 4]>::try_from(data), <[u8; 16]>::try_from(data)) {
+            (TYPE_A, Ok(octets), _) => addrs.push(IpAddr::V4(Ipv4Addr::from(octets))),
+            (TYPE_AAAA, _, Ok(octets)) => addrs.push(IpAddr::V6(Ipv6Addr::from(octets))),
+            _ => {}
+        }
+    }
+    addrs
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    packet
+        .get(pos..pos + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a possibly compressed name, returning it with the offset just past
+/// it in the original position.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bound the number of pointers followed so crafted packets can't loop.
+    for _ in 0..packet.len() {
+        let len = *packet.get(pos)? as usize;
+        match len {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(pos + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = (read_u16(packet, pos)? & 0x3fff) as usize;
+                end.get_or_insert(pos + 2);
+                pos = pointer;
+            }
+            len => {
+                let label = packet.get(pos + 1..pos + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response_with_compressed_names() {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+        // printer.local A 192.168.1.20
+        packet.extend_from_slice(b"\x07printer\x05local\x00");
+        packet.extend_from_slice(&[0, 1, 0x80, 1, 0, 0, 0, 120, 0, 4, 192, 168, 1, 20]);
+        // Pointer back to printer.local, AAAA fe80::1
+        packet.extend_from_slice(&[0xc0, 12, 0, 28, 0x80, 1, 0, 0, 0, 120, 0, 16]);
+        packet.extend_from_slice(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+        assert_eq!(
+            parse_response(&packet, "printer.local"),
+            vec![
+                "192.168.1.20".parse::<IpAddr>().unwrap(),
+                "fe80::1".parse::<IpAddr>().unwrap(),
+            ]
+        );
+        assert!(parse_response(&packet, "scanner.local").is_empty());
+        assert!(is_local("Printer.LOCAL."));
+    }
+}
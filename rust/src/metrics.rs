@@ -0,0 +1,258 @@
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Response, Server, StatusCode,
+};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds, in seconds, of the request duration histogram buckets.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative, plus one for `+Inf`.
+    buckets: [u64; DURATION_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+/// Counts requests, upstream errors and TLS handshakes and observes request
+/// durations, rendered in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct MetricsRecorder {
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    grpc_responses: Mutex<BTreeMap<(u32, u16), u64>>,
+    sla_violations: Mutex<BTreeMap<String, u64>>,
+    duration: Mutex<Histogram>,
+    upstream_errors: AtomicU64,
+    tls_handshakes: AtomicU64,
+    tls_resumed: AtomicU64,
+}
+
+impl MetricsRecorder {
+    pub fn new() -> MetricsRecorder {
+        MetricsRecorder::default()
+    }
+
+    /// Records a request answered with `status` after `duration`.
+    pub fn record_request(&self, method: &Method, status: StatusCode, duration: Duration) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.to_string(), status.as_u16()))
+            .or_insert(0) += 1;
+        let seconds = duration.as_secs_f64();
+        let mut histogram = self.duration.lock().unwrap();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        histogram.buckets[bucket] += 1;
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    /// Records a gRPC call the upstream ended with status `code`, mapped to
+    /// the HTTP `status`.
+    pub fn record_grpc_status(&self, code: u32, status: StatusCode) {
+        *self
+            .grpc_responses
+            .lock()
+            .unwrap()
+            .entry((code, status.as_u16()))
+            .or_insert(0) += 1;
+    }
+
+    /// Records a response from `upstream` slower than the latency SLA.
+    pub fn record_sla_violation(&self, upstream: &str) {
+        *self
+            .sla_violations
+            .lock()
+            .unwrap()
+            .entry(upstream.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Records a request the upstream failed to answer.
+    pub fn record_upstream_error(&self) {
+        self.upstream_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a TLS handshake with a client, `resumed` if it picked up an
+    /// earlier session.
+    pub fn record_tls_handshake(&self, resumed: bool) {
+        let counter = if resumed {
+            &self.tls_resumed
+        } else {
+            &self.tls_handshakes
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP proxy_requests_total Requests handled by the proxy.\n");
+        out.push_str("# TYPE proxy_requests_total counter\n");
+        for ((method, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "proxy_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                method, status, count
+            );
+        }
+
+        out.push_str(
+            "# HELP proxy_request_duration_seconds Time until response headers were sent.\n",
+        );
+        out.push_str("# TYPE proxy_request_duration_seconds histogram\n");
+        let histogram = self.duration.lock().unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "proxy_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "proxy_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            histogram.count
+        );
+        let _ = writeln!(out, "proxy_request_duration_seconds_sum {}", histogram.sum);
+        let _ = writeln!(
+            out,
+            "proxy_request_duration_seconds_count {}",
+            histogram.count
+        );
+
+        let grpc_responses = self.grpc_responses.lock().unwrap();
+        if !grpc_responses.is_empty() {
+            out.push_str(
+                "# HELP proxy_grpc_responses_total gRPC calls by status and its HTTP equivalent.\n",
+            );
+            out.push_str("# TYPE proxy_grpc_responses_total counter\n");
+            for ((code, status), count) in grpc_responses.iter() {
+                let _ = writeln!(
+                    out,
+                    "proxy_grpc_responses_total{{grpc_status=\"{}\",http_status=\"{}\"}} {}",
+                    code, status, count
+                );
+            }
+        }
+
+        let sla_violations = self.sla_violations.lock().unwrap();
+        if !sla_violations.is_empty() {
+            out.push_str(
+                "# HELP proxy_sla_violation_total Upstream responses slower than the latency SLA.\n",
+            );
+            out.push_str("# TYPE proxy_sla_violation_total counter\n");
+            for (upstream, count) in sla_violations.iter() {
+                let _ = writeln!(
+                    out,
+                    "proxy_sla_violation_total{{upstream=\"{}\"}} {}",
+                    upstream, count
+                );
+            }
+        }
+
+        out.push_str(
+            "# HELP proxy_upstream_errors_total Upstream requests that failed or timed out.\n",
+        );
+        out.push_str("# TYPE proxy_upstream_errors_total counter\n");
+        let _ = writeln!(
+            out,
+            "proxy_upstream_errors_total {}",
+            self.upstream_errors.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP proxy_tls_handshakes_total TLS handshakes with clients.\n");
+        out.push_str("# TYPE proxy_tls_handshakes_total counter\n");
+        let _ = writeln!(
+            out,
+            "proxy_tls_handshakes_total{{resumed=\"false\"}} {}",
+            self.tls_handshakes.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "proxy_tls_handshakes_total{{resumed=\"true\"}} {}",
+            self.tls_resumed.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+/// Serves the metrics of `recorder` on `addr` until `shutdown` completes.
+pub async fn serve(
+    addr: SocketAddr,
+    recorder: Arc<MetricsRecorder>,
+    shutdown: impl Future<Output = ()>,
+) -> hyper::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let recorder = Arc::clone(&recorder);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_req| {
+                let mut response = Response::new(Body::from(recorder.render()));
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    Server::try_bind(&addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let recorder = MetricsRecorder::new();
+        recorder.record_request(&Method::GET, StatusCode::OK, Duration::from_millis(20));
+        recorder.record_request(&Method::GET, StatusCode::OK, Duration::from_secs(30));
+        recorder.record_request(&Method::POST, StatusCode::BAD_GATEWAY, Duration::ZERO);
+        recorder.record_upstream_error();
+        recorder.record_grpc_status(14, StatusCode::SERVICE_UNAVAILABLE);
+        recorder.record_sla_violation("api.internal:8080");
+        recorder.record_sla_violation("api.internal:8080");
+        recorder.record_tls_handshake(false);
+        recorder.record_tls_handshake(true);
+        recorder.record_tls_handshake(true);
+
+        let rendered = recorder.render();
+        assert!(rendered.contains("proxy_requests_total{method=\"GET\",status=\"200\"} 2\n"));
+        assert!(rendered.contains("proxy_requests_total{method=\"POST\",status=\"502\"} 1\n"));
+        assert!(rendered.contains("proxy_request_duration_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(rendered.contains("proxy_request_duration_seconds_bucket{le=\"0.025\"} 2\n"));
+        assert!(rendered.contains("proxy_request_duration_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(rendered.contains("proxy_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(rendered.contains("proxy_request_duration_seconds_count 3\n"));
+        assert!(rendered.contains("proxy_upstream_errors_total 1\n"));
+        assert!(rendered
+            .contains("proxy_grpc_responses_total{grpc_status=\"14\",http_status=\"503\"} 1\n"));
+        assert!(rendered.contains("proxy_sla_violation_total{upstream=\"api.internal:8080\"} 2\n"));
+        assert!(rendered.contains("proxy_tls_handshakes_total{resumed=\"false\"} 1\n"));
+        assert!(rendered.contains("proxy_tls_handshakes_total{resumed=\"true\"} 2\n"));
+    }
+}
@@ -0,0 +1,39 @@
+/// Removes the whitespace between the tokens of the JSON document `bytes`,
+/// keeping the order of object keys and the spelling of numbers and strings.
+pub fn json(bytes: &[u8]) -> Result<Vec<u8>, serde_json::Error> {
+    serde_json::from_slice::<serde::de::IgnoredAny>(bytes)?;
+    let mut minified = Vec::with_capacity(bytes.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for &b in bytes {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+        } else if matches!(b, b' ' | b'\t' | b'\n' | b'\r') {
+            continue;
+        } else if b == b'"' {
+            in_string = true;
+        }
+        minified.push(b);
+    }
+    Ok(minified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json() {
+        let pretty = b"{\n  \"b\": [1, 2.50],\n  \"a\": \"x \\\" y\\\\\",\n  \"c\": null\n}\n";
+        assert_eq!(
+            json(pretty).unwrap(),
+            b"{\"b\":[1,2.50],\"a\":\"x \\\" y\\\\\",\"c\":null}"
+        );
+        assert!(json(b"{\"a\": }").is_err());
+    }
+}
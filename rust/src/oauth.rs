@@ -0,0 +1,169 @@
+use crate::aws;
+use hyper::{
+    body,
+    client::HttpConnector,
+    header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Request,
+};
+use hyper_openssl::HttpsConnector;
+use serde::Deserialize;
+use std::{
+    fmt,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const METADATA_FLAVOR: &str = "metadata-flavor";
+
+/// Delay before retrying a failed refresh.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// The Google Cloud metadata server, unless `GCE_METADATA_HOST` names another.
+pub const GOOGLE_METADATA_HOST: &str = "metadata.google.internal";
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// How tokens are obtained.
+enum Grant {
+    /// The OAuth 2.0 client credentials grant, with these HTTP basic
+    /// credentials.
+    ClientCredentials(HeaderValue),
+    /// Google Cloud ID tokens of the instance's service account, from the
+    /// metadata server.
+    GoogleIdToken,
+}
+
+/// Obtains bearer tokens with the OAuth 2.0 client credentials grant, or from
+/// the Google Cloud metadata server, and keeps them fresh in the background.
+pub struct TokenSource {
+    url: String,
+    grant: Grant,
+    token: RwLock<Option<HeaderValue>>,
+    http_client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl TokenSource {
+    /// Fetches the first token and spawns the refresh task. `credentials` is
+    /// `client_id:client_secret`, sent with HTTP basic authentication.
+    pub async fn start(url: String, credentials: &str) -> Result<Arc<TokenSource>, String> {
+        let credentials = HeaderValue::from_str(&format!("Basic {}", base64::encode(credentials)))
+            .map_err(|e| format!("invalid client credentials: {}", e))?;
+        TokenSource::spawn(url, Grant::ClientCredentials(credentials)).await
+    }
+
+    /// Fetches Google Cloud ID tokens for `audience`, usually the upstream's
+    /// URL, from the metadata server at `metadata_host`, refreshing them
+    /// before they expire.
+    pub async fn start_google_iam(
+        metadata_host: &str,
+        audience: &str,
+    ) -> Result<Arc<TokenSource>, String> {
+        let url = format!(
+            "http://{}/computeMetadata/v1/instance/service-accounts/default/identity?audience={}",
+            metadata_host,
+            aws::encode(audience, true)
+        );
+        TokenSource::spawn(url, Grant::GoogleIdToken).await
+    }
+
+    async fn spawn(url: String, grant: Grant) -> Result<Arc<TokenSource>, String> {
+        let ssl = HttpsConnector::new().expect("https connector");
+        let source = Arc::new(TokenSource {
+            url,
+            grant,
+            token: RwLock::new(None),
+            http_client: Client::builder().build::<_, Body>(ssl),
+        });
+
+        let mut delay = refresh_delay(source.refresh().await?);
+        let refresher = Arc::clone(&source);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(delay).await;
+                delay = match refresher.refresh().await {
+                    Ok(expires_in) => refresh_delay(expires_in),
+                    Err(e) => {
+                        tracing::error!("Failed to refresh upstream auth token: {}", e);
+                        RETRY_DELAY
+                    }
+                };
+            }
+        });
+        Ok(source)
+    }
+
+    /// The `Authorization` header value to send upstream.
+    pub fn authorization(&self) -> Option<HeaderValue> {
+        self.token.read().unwrap().clone()
+    }
+
+    async fn refresh(&self) -> Result<Duration, String> {
+        let req = match &self.grant {
+            Grant::ClientCredentials(credentials) => Request::post(&self.url)
+                .header(AUTHORIZATION, credentials.clone())
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from("grant_type=client_credentials")),
+            Grant::GoogleIdToken => Request::get(&self.url)
+                .header(METADATA_FLAVOR, "Google")
+                .body(Body::empty()),
+        }
+        .map_err(|e| e.to_string())?;
+        let resp = self
+            .http_client
+            .request(req)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !resp.status().is_success() {
+            return Err(format!("token endpoint returned {}", resp.status()));
+        }
+        let bytes = body::to_bytes(resp.into_body())
+            .await
+            .map_err(|e| e.to_string())?;
+        let (token, expires_in) = match self.grant {
+            Grant::ClientCredentials(_) => {
+                let token: TokenResponse =
+                    serde_json::from_slice(&bytes).map_err(|e| e.to_string())?;
+                (token.access_token, token.expires_in)
+            }
+            Grant::GoogleIdToken => {
+                let token = String::from_utf8_lossy(&bytes).trim().to_string();
+                let expiry = jwt_expiry(&token).ok_or("ID token without expiry")?;
+                let expires_in = expiry
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+                    .as_secs();
+                (token, expires_in)
+            }
+        };
+        let header =
+            HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|e| e.to_string())?;
+        *self.token.write().unwrap() = Some(header);
+        tracing::debug!("Refreshed upstream auth token, expires in {}s", expires_in);
+        Ok(Duration::from_secs(expires_in))
+    }
+}
+
+/// The `exp` claim of a JWT, unverified.
+fn jwt_expiry(token: &str) -> Option<SystemTime> {
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(claims.get("exp")?.as_u64()?))
+}
+
+/// Refresh once 80% of the token's lifetime has passed.
+fn refresh_delay(expires_in: Duration) -> Duration {
+    expires_in.mul_f64(0.8).max(Duration::from_secs(1))
+}
+
+impl fmt::Debug for TokenSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenSource")
+            .field("url", &self.url)
+            .finish()
+    }
+}
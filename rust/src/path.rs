@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+/// How request paths are cleaned up before being forwarded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathNormalization {
+    /// `..` components that would go above the root are dropped.
+    Lenient,
+    /// Paths that would go above the root are rejected.
+    Strict,
+}
+
+/// Collapses repeated slashes and resolves `.` and `..` components of
+/// `path`, keeping a trailing slash. Returns whether any `..` went above the
+/// root, which the normalized path stops at.
+pub fn normalize(path: &str) -> (String, bool) {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut above_root = false;
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => above_root |= segments.pop().is_none(),
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if !segments.is_empty() && path.ends_with('/') {
+        normalized.push('/');
+    }
+    (normalized, above_root)
+}
+
+/// The case request paths are converted to before being forwarded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathCase {
+    None,
+    Lowercase,
+    Uppercase,
+}
+
+impl FromStr for PathCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(PathCase::None),
+            "lowercase" => Ok(PathCase::Lowercase),
+            "uppercase" => Ok(PathCase::Uppercase),
+            _ => Err(format!(
+                "invalid path case '{}', expected lowercase, uppercase or none",
+                s
+            )),
+        }
+    }
+}
+
+impl PathCase {
+    /// Converts the ASCII letters of `path` to this case, leaving the hex
+    /// digits of percent-encoded octets alone.
+    pub fn apply(self, path: &str) -> String {
+        let mut converted = String::with_capacity(path.len());
+        let mut escape = 0;
+        for c in path.chars() {
+            if escape > 0 {
+                escape -= 1;
+                converted.push(c);
+                continue;
+            }
+            if c == '%' {
+                escape = 2;
+            }
+            converted.push(match self {
+                PathCase::None => c,
+                PathCase::Lowercase => c.to_ascii_lowercase(),
+                PathCase::Uppercase => c.to_ascii_uppercase(),
+            });
+        }
+        converted
+    }
+}
+
+/// A path segment inserted after the first `after` segments of request
+/// paths, e.g. `v2` after 1 turns `/api/users` into `/api/v2/users`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentInsertion {
+    pub segment: String,
+    pub after: usize,
+}
+
+/// Applies `insertions` to `path` in order. Segments are appended to paths
+/// with fewer than `after` segments.
+pub fn insert_segments(path: &str, insertions: &[SegmentInsertion]) -> String {
+    let trimmed = path.trim_start_matches('/');
+    let mut segments: Vec<&str> = if trimmed.is_empty() {
+        Vec::new()
+    } else {
+        trimmed.split('/').collect()
+    };
+    for insertion in insertions {
+        // Keep a trailing slash at the end.
+        let end = match segments.last() {
+            Some(&"") => segments.len() - 1,
+            _ => segments.len(),
+        };
+        segments.insert(insertion.after.min(end), &insertion.segment);
+    }
+    format!("/{}", segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_case() {
+        assert_eq!(
+            PathCase::Lowercase.apply("/Users/%2FAlice"),
+            "/users/%2Falice"
+        );
+        assert_eq!(PathCase::Uppercase.apply("/users/%2fbob"), "/USERS/%2fBOB");
+        assert_eq!(PathCase::None.apply("/Users"), "/Users");
+        assert!("title".parse::<PathCase>().is_err());
+    }
+
+    #[test]
+    fn test_insert_segments() {
+        let v2 = SegmentInsertion {
+            segment: "v2".to_string(),
+            after: 1,
+        };
+        let internal = SegmentInsertion {
+            segment: "internal".to_string(),
+            after: 0,
+        };
+        let only_v2 = [v2.clone()];
+        assert_eq!(insert_segments("/api/users", &only_v2), "/api/v2/users");
+        assert_eq!(insert_segments("/api/", &only_v2), "/api/v2/");
+        assert_eq!(insert_segments("/", &only_v2), "/v2");
+        assert_eq!(
+            insert_segments("/api/users", &[v2, internal]),
+            "/internal/api/v2/users"
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize("//api/v1/../v2/./users"),
+            ("/api/v2/users".to_string(), false)
+        );
+        assert_eq!(
+            normalize("/api//users/"),
+            ("/api/users/".to_string(), false)
+        );
+        assert_eq!(normalize("/"), ("/".to_string(), false));
+        assert_eq!(normalize("/api/../../etc"), ("/etc".to_string(), true));
+    }
+}
@@ -0,0 +1,120 @@
+use hyper::{
+    client::HttpConnector,
+    header::{HeaderValue, AUTHORIZATION},
+    Body, Client, Method, Request, StatusCode,
+};
+use hyper_openssl::HttpsConnector;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Request headers passed on to the authorization service.
+const FORWARDED_HEADERS: [hyper::header::HeaderName; 1] = [AUTHORIZATION];
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct CacheKey {
+    method: Method,
+    path: String,
+    authorization: Option<HeaderValue>,
+}
+
+/// Asks an external authorization service whether a request may be forwarded
+/// before the upstream is contacted.
+///
+/// The service receives a `GET` with the original method and URI in
+/// `X-Original-Method` and `X-Original-Uri` and must answer `200` to allow the
+/// request. Any other status, or failing to reach the service, denies it.
+pub struct PreConnectHook {
+    url: String,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<CacheKey, (Instant, bool)>>,
+    http_client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl PreConnectHook {
+    pub fn new(url: String, cache_ttl: Duration) -> PreConnectHook {
+        let ssl = HttpsConnector::new().expect("https connector");
+        PreConnectHook {
+            url,
+            cache_ttl,
+            cache: Mutex::new(HashMap::new()),
+            http_client: Client::builder().build::<_, Body>(ssl),
+        }
+    }
+
+    pub async fn is_allowed(&self, req: &Request<Body>) -> bool {
+        let key = CacheKey {
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            authorization: req.headers().get(AUTHORIZATION).cloned(),
+        };
+        if let Some(allowed) = self.cached(&key) {
+            return allowed;
+        }
+
+        let mut hook_req = Request::get(&self.url)
+            .header("X-Original-Method", req.method().as_str())
+            .header("X-Original-Uri", req.uri().to_string());
+        for name in FORWARDED_HEADERS.iter() {
+            for value in req.headers().get_all(name) {
+                hook_req = hook_req.header(name, value);
+            }
+        }
+        let hook_req = match hook_req.body(Body::empty()) {
+            Ok(hook_req) => hook_req,
+            Err(e) => {
+                tracing::error!("Invalid pre-connect hook request: {}", e);
+                return false;
+            }
+        };
+        match self.http_client.request(hook_req).await {
+            Ok(resp) => {
+                let allowed = resp.status() == StatusCode::OK;
+                if !allowed {
+                    tracing::info!(
+                        "Pre-connect hook denied {} {}: {}",
+                        key.method,
+                        key.path,
+                        resp.status()
+                    );
+                }
+                self.store(key, allowed);
+                allowed
+            }
+            Err(e) => {
+                tracing::error!("Pre-connect hook {} failed: {}", self.url, e);
+                false
+            }
+        }
+    }
+
+    fn cached(&self, key: &CacheKey) -> Option<bool> {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(key)
+            .filter(|(expires, _)| *expires > Instant::now())
+            .map(|(_, allowed)| *allowed)
+    }
+
+    fn store(&self, key: CacheKey, allowed: bool) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (expires, _)| *expires > now);
+        cache.insert(key, (now + self.cache_ttl, allowed));
+    }
+}
+
+impl fmt::Debug for PreConnectHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PreConnectHook")
+            .field("url", &self.url)
+            .field("cache_ttl", &self.cache_ttl)
+            .finish()
+    }
+}
@@ -0,0 +1,84 @@
+use hyper::header::HeaderValue;
+use std::str::FromStr;
+
+/// The request header carrying the urgency of a request, from RFC 9218.
+pub const PRIORITY: &str = "priority";
+
+/// Asks upstreams to serve requests whose path starts with `path_prefix`
+/// with `urgency`, from 0 (most urgent) to 7. Parsed from `PREFIX:LEVEL`,
+/// where LEVEL is `high`, `normal`, `low` or an urgency.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathPriority {
+    pub path_prefix: String,
+    pub urgency: u8,
+}
+
+impl FromStr for PathPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path_prefix, level) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected PREFIX:LEVEL, got '{}'", s))?;
+        if !path_prefix.starts_with('/') {
+            return Err(format!(
+                "priority prefix '{}' must start with /",
+                path_prefix
+            ));
+        }
+        let urgency = match level {
+            "high" => 1,
+            "normal" => 3,
+            "low" => 5,
+            level => level
+                .parse::<u8>()
+                .ok()
+                .filter(|&urgency| urgency <= 7)
+                .ok_or_else(|| {
+                    format!(
+                        "invalid priority '{}', expected high, normal, low or 0-7",
+                        level
+                    )
+                })?,
+        };
+        Ok(PathPriority {
+            path_prefix: path_prefix.to_string(),
+            urgency,
+        })
+    }
+}
+
+/// Finds the `Priority` header value for `path`, from the longest matching
+/// prefix.
+pub fn find(priorities: &[PathPriority], path: &str) -> Option<HeaderValue> {
+    priorities
+        .iter()
+        .filter(|priority| path.starts_with(priority.path_prefix.as_str()))
+        .max_by_key(|priority| priority.path_prefix.len())
+        .map(|priority| {
+            HeaderValue::from_str(&format!("u={}", priority.urgency))
+                .expect("urgency is a valid header value")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_longest_prefix() {
+        let priorities: Vec<PathPriority> = vec![
+            "/assets:low".parse().unwrap(),
+            "/api:high".parse().unwrap(),
+            "/api/batch:7".parse().unwrap(),
+        ];
+        assert_eq!(find(&priorities, "/assets/app.js").unwrap(), "u=5");
+        assert_eq!(find(&priorities, "/api/users").unwrap(), "u=1");
+        assert_eq!(find(&priorities, "/api/batch/1").unwrap(), "u=7");
+        assert_eq!(find(&priorities, "/"), None);
+
+        assert!("/api:urgent".parse::<PathPriority>().is_err());
+        assert!("/api:8".parse::<PathPriority>().is_err());
+        assert!("api:low".parse::<PathPriority>().is_err());
+    }
+}
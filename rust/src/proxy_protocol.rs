@@ -0,0 +1,227 @@
+use std::{
+    cmp, fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::{self, FromStr},
+};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest possible v1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Version of the HAProxy PROXY protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Version::V1),
+            "v2" => Ok(Version::V2),
+            other => Err(format!("unknown PROXY protocol version '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Version::V1 => f.write_str("v1"),
+            Version::V2 => f.write_str("v2"),
+        }
+    }
+}
+
+/// Encodes the header announcing a connection from `src` to `dst`.
+pub fn encode(version: Version, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    // Both addresses must be of the same family.
+    let (src_ip, dst_ip) = match (canonical(src.ip()), canonical(dst.ip())) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => (IpAddr::V4(s), IpAddr::V4(d)),
+        (IpAddr::V4(s), IpAddr::V6(_)) => (IpAddr::V4(s), IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        (IpAddr::V6(s), IpAddr::V6(d)) => (IpAddr::V6(s), IpAddr::V6(d)),
+        (IpAddr::V6(s), IpAddr::V4(d)) => (IpAddr::V6(s), IpAddr::V6(d.to_ipv6_mapped())),
+    };
+    match version {
+        Version::V1 => {
+            let family = if src_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                src_ip,
+                dst_ip,
+                src.port(),
+                dst.port()
+            )
+            .into_bytes()
+        }
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY command.
+            header.push(0x21);
+            let mut addresses = Vec::with_capacity(36);
+            match (src_ip, dst_ip) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => {
+                    header.push(0x11);
+                    addresses.extend_from_slice(&s.octets());
+                    addresses.extend_from_slice(&d.octets());
+                }
+                (IpAddr::V6(s), IpAddr::V6(d)) => {
+                    header.push(0x21);
+                    addresses.extend_from_slice(&s.octets());
+                    addresses.extend_from_slice(&d.octets());
+                }
+                _ => unreachable!("addresses have the same family"),
+            }
+            addresses.extend_from_slice(&src.port().to_be_bytes());
+            addresses.extend_from_slice(&dst.port().to_be_bytes());
+            header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+            header.extend_from_slice(&addresses);
+            header
+        }
+    }
+}
+
+/// A header read from the start of an inbound connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// The client the connection was made for. `None` when the sender does
+    /// not relay a client, e.g. for its own health checks.
+    pub source: Option<SocketAddr>,
+    /// Length of the header in bytes.
+    pub len: usize,
+}
+
+/// Parses a header at the start of `buf`. Returns `Ok(None)` while `buf` does
+/// not hold the complete header yet.
+pub fn parse(version: Version, buf: &[u8]) -> Result<Option<Header>, String> {
+    match version {
+        Version::V1 => parse_v1(buf),
+        Version::V2 => parse_v2(buf),
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<Header>, String> {
+    let prefix = cmp::min(buf.len(), 6);
+    if buf[..prefix] != b"PROXY "[..prefix] {
+        return Err("missing PROXY v1 header".to_string());
+    }
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        None if buf.len() < V1_MAX_LEN => return Ok(None),
+        _ => return Err("PROXY v1 header is too long".to_string()),
+    };
+    let line = str::from_utf8(&buf[..end]).map_err(|_| "PROXY v1 header is not ASCII")?;
+    let source = match line.split(' ').collect::<Vec<_>>().as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", src, _, port, _] => match (src.parse(), port.parse()) {
+            (Ok(ip), Ok(port)) => Some(SocketAddr::new(ip, port)),
+            _ => return Err(format!("malformed PROXY v1 header '{}'", line)),
+        },
+        _ => return Err(format!("malformed PROXY v1 header '{}'", line)),
+    };
+    Ok(Some(Header {
+        source,
+        len: end + 2,
+    }))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<Header>, String> {
+    let prefix = cmp::min(buf.len(), V2_SIGNATURE.len());
+    if buf[..prefix] != V2_SIGNATURE[..prefix] {
+        return Err("missing PROXY v2 header".to_string());
+    }
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    if buf[12] >> 4 != 2 {
+        return Err(format!("unsupported PROXY v2 version {}", buf[12] >> 4));
+    }
+    let addresses = &buf[16..len];
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    let source = match (buf[12] & 0x0f, buf[13]) {
+        // LOCAL
+        (0x0, _) => None,
+        (0x1, 0x11) if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8)))
+        }
+        (0x1, 0x21) if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32)))
+        }
+        // Unspecified or UNIX socket addresses say nothing usable.
+        (0x1, 0x00 | 0x31 | 0x32) => None,
+        (0x1, family) => {
+            return Err(format!(
+                "malformed PROXY v2 address block for family {:#x}",
+                family
+            ))
+        }
+        (command, _) => return Err(format!("unknown PROXY v2 command {:#x}", command)),
+    };
+    Ok(Some(Header { source, len }))
+}
+
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let src = "192.168.0.1:56324".parse().unwrap();
+        let dst = "10.0.0.2:443".parse().unwrap();
+        assert_eq!(
+            encode(Version::V1, src, dst),
+            b"PROXY TCP4 192.168.0.1 10.0.0.2 56324 443\r\n".to_vec()
+        );
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 168, 0, 1, 10, 0, 0, 2]);
+        expected.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(encode(Version::V2, src, dst), expected);
+    }
+
+    #[test]
+    fn test_parse() {
+        let src: SocketAddr = "192.168.0.1:56324".parse().unwrap();
+        let dst = "10.0.0.2:443".parse().unwrap();
+        for version in [Version::V1, Version::V2] {
+            let mut buf = encode(version, src, dst);
+            let len = buf.len();
+            assert_eq!(parse(version, &buf[..len - 1]), Ok(None));
+            buf.extend_from_slice(b"GET / HTTP/1.1\r\n");
+            assert_eq!(
+                parse(version, &buf),
+                Ok(Some(Header {
+                    source: Some(src),
+                    len
+                }))
+            );
+        }
+
+        assert_eq!(
+            parse(Version::V1, b"PROXY UNKNOWN\r\n"),
+            Ok(Some(Header {
+                source: None,
+                len: 15
+            }))
+        );
+        assert!(parse(Version::V1, b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse(Version::V2, b"PROXY TCP4 ").is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How many clients to track before forgetting those whose buckets have
+/// refilled.
+const MAX_IDLE_CLIENTS: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, per_sec: f64, capacity: f64) {
+        let refilled = now.duration_since(self.updated).as_secs_f64() * per_sec;
+        self.tokens = (self.tokens + refilled).min(capacity);
+        self.updated = now;
+    }
+}
+
+/// A token bucket shared by every request to the upstream, refilled at
+/// `per_sec` tokens a second up to a burst of `per_sec`.
+///
+/// Requests that find the bucket empty may borrow up to `queue_depth` tokens
+/// ahead and wait for them to be refilled. Past that they are rejected.
+#[derive(Debug)]
+pub struct UpstreamRateLimiter {
+    per_sec: f64,
+    queue_depth: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl UpstreamRateLimiter {
+    pub fn new(per_sec: u32, queue_depth: usize) -> UpstreamRateLimiter {
+        UpstreamRateLimiter {
+            per_sec: per_sec as f64,
+            queue_depth: queue_depth as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: per_sec as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Whether `other` enforces the same limit, so this one, with the
+    /// requests it has already counted, can stand in for it.
+    pub fn same_limit(&self, other: &UpstreamRateLimiter) -> bool {
+        self.per_sec == other.per_sec && self.queue_depth == other.queue_depth
+    }
+
+    /// Takes a token, waiting for one if the request fits in the queue.
+    /// If the queue is full, returns how long until a request would fit.
+    pub async fn acquire(&self) -> Result<(), Duration> {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.refill(Instant::now(), self.per_sec, self.per_sec);
+            let missing = 1.0 - self.queue_depth - bucket.tokens;
+            if missing > 0.0 {
+                return Err(Duration::from_secs_f64(missing / self.per_sec));
+            }
+            bucket.tokens -= 1.0;
+            if bucket.tokens >= 0.0 {
+                return Ok(());
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.per_sec)
+        };
+        tokio::time::sleep(wait).await;
+        Ok(())
+    }
+}
+
+/// At most `per_sec` requests a second to paths starting with `path_prefix`.
+/// Parsed from `PREFIX:RATErps`, e.g. `/api:100rps`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathRateLimit {
+    pub path_prefix: String,
+    pub per_sec: u32,
+}
+
+impl FromStr for PathRateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path_prefix, rate) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected PREFIX:RATErps, got '{}'", s))?;
+        if !path_prefix.starts_with('/') {
+            return Err(format!(
+                "rate limit prefix '{}' must start with /",
+                path_prefix
+            ));
+        }
+        let per_sec = rate
+            .strip_suffix("rps")
+            .unwrap_or(rate)
+            .parse::<u32>()
+            .ok()
+            .filter(|&per_sec| per_sec > 0)
+            .ok_or_else(|| format!("invalid rate '{}', expected e.g. 100rps", rate))?;
+        Ok(PathRateLimit {
+            path_prefix: path_prefix.to_string(),
+            per_sec,
+        })
+    }
+}
+
+/// Finds the limiter for `path`, the one with the longest matching prefix.
+pub fn find_path_limiter<'a>(
+    limiters: &'a HashMap<String, Arc<UpstreamRateLimiter>>,
+    path: &str,
+) -> Option<&'a UpstreamRateLimiter> {
+    limiters
+        .iter()
+        .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, limiter)| limiter.as_ref())
+}
+
+/// How `ClientRateLimiter` decides which requests are over the limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RateLimitAlgorithm {
+    /// Allows bursts of up to `capacity` requests, refilled at `per_sec` a
+    /// second.
+    #[default]
+    TokenBucket,
+    /// Delays requests so they are sent at most `per_sec` a second, with up
+    /// to `capacity` waiting. Smooths out bursts rather than allowing them.
+    LeakyBucket,
+    /// Allows `capacity` requests in any window of `capacity / per_sec`
+    /// seconds, estimated from the counts of the current and previous fixed
+    /// windows. Bursts are bounded per window rather than refilled steadily.
+    SlidingWindow,
+}
+
+impl FromStr for RateLimitAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "token-bucket" => Ok(RateLimitAlgorithm::TokenBucket),
+            "leaky-bucket" => Ok(RateLimitAlgorithm::LeakyBucket),
+            "sliding-window" => Ok(RateLimitAlgorithm::SlidingWindow),
+            other => Err(format!(
+                "unknown rate limit algorithm '{}', expected token-bucket, leaky-bucket or sliding-window",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    current: f64,
+    previous: f64,
+}
+
+impl Window {
+    /// Moves the window forward to the one `now` falls in.
+    fn advance(&mut self, now: Instant, length: Duration) {
+        let elapsed = now.duration_since(self.start);
+        if elapsed >= length * 2 {
+            self.previous = 0.0;
+            self.current = 0.0;
+            self.start = now;
+        } else if elapsed >= length {
+            self.previous = self.current;
+            self.current = 0.0;
+            self.start += length;
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ClientState {
+    /// Tokens left for a token bucket, requests waiting for a leaky one.
+    Bucket(Bucket),
+    Window(Window),
+}
+
+/// A rate limit per client IP address of `per_sec` requests a second, with
+/// bursts of up to `capacity` requests depending on the algorithm.
+#[derive(Debug)]
+pub struct ClientRateLimiter {
+    capacity: f64,
+    per_sec: f64,
+    algorithm: RateLimitAlgorithm,
+    clients: Mutex<HashMap<IpAddr, ClientState>>,
+}
+
+impl ClientRateLimiter {
+    pub fn new(capacity: u32, per_sec: f64, algorithm: RateLimitAlgorithm) -> ClientRateLimiter {
+        ClientRateLimiter {
+            capacity: capacity as f64,
+            per_sec,
+            algorithm,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `other` enforces the same limit, so this one, with the
+    /// requests it has already counted, can stand in for it.
+    pub fn same_limit(&self, other: &ClientRateLimiter) -> bool {
+        self.capacity == other.capacity
+            && self.per_sec == other.per_sec
+            && self.algorithm == other.algorithm
+    }
+
+    /// Admits a request from `client`, returning how long to delay it, or
+    /// how long until it would be admitted if it is over the limit.
+    pub fn try_acquire(&self, client: IpAddr) -> Result<Duration, Duration> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_IDLE_CLIENTS && !clients.contains_key(&client) {
+            clients.retain(|_, state| !self.is_idle(state, now));
+        }
+        let state = clients.entry(client).or_insert_with(|| self.new_state(now));
+        match state {
+            ClientState::Bucket(bucket) if self.algorithm == RateLimitAlgorithm::LeakyBucket => {
+                self.leak(bucket, now)
+            }
+            ClientState::Bucket(bucket) => self.take_token(bucket, now),
+            ClientState::Window(window) => self.count(window, now),
+        }
+    }
+
+    fn new_state(&self, now: Instant) -> ClientState {
+        match self.algorithm {
+            RateLimitAlgorithm::TokenBucket => ClientState::Bucket(Bucket {
+                tokens: self.capacity,
+                updated: now,
+            }),
+            RateLimitAlgorithm::LeakyBucket => ClientState::Bucket(Bucket {
+                tokens: 0.0,
+                updated: now,
+            }),
+            RateLimitAlgorithm::SlidingWindow => ClientState::Window(Window {
+                start: now,
+                current: 0.0,
+                previous: 0.0,
+            }),
+        }
+    }
+
+    /// Whether `state` is back to how a new client's starts.
+    fn is_idle(&self, state: &mut ClientState, now: Instant) -> bool {
+        match state {
+            ClientState::Bucket(bucket) if self.algorithm == RateLimitAlgorithm::LeakyBucket => {
+                self.drain(bucket, now);
+                bucket.tokens <= 0.0
+            }
+            ClientState::Bucket(bucket) => {
+                bucket.refill(now, self.per_sec, self.capacity);
+                bucket.tokens >= self.capacity
+            }
+            ClientState::Window(window) => now.duration_since(window.start) >= self.window() * 2,
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs_f64(self.capacity / self.per_sec)
+    }
+
+    fn take_token(&self, bucket: &mut Bucket, now: Instant) -> Result<Duration, Duration> {
+        bucket.refill(now, self.per_sec, self.capacity);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(Duration::ZERO)
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_sec,
+            ))
+        }
+    }
+
+    fn drain(&self, bucket: &mut Bucket, now: Instant) {
+        let drained = now.duration_since(bucket.updated).as_secs_f64() * self.per_sec;
+        bucket.tokens = (bucket.tokens - drained).max(0.0);
+        bucket.updated = now;
+    }
+
+    fn leak(&self, bucket: &mut Bucket, now: Instant) -> Result<Duration, Duration> {
+        self.drain(bucket, now);
+        if bucket.tokens + 1.0 > self.capacity {
+            return Err(Duration::from_secs_f64(
+                (bucket.tokens + 1.0 - self.capacity) / self.per_sec,
+            ));
+        }
+        let delay = Duration::from_secs_f64(bucket.tokens / self.per_sec);
+        bucket.tokens += 1.0;
+        Ok(delay)
+    }
+
+    fn count(&self, window: &mut Window, now: Instant) -> Result<Duration, Duration> {
+        let length = self.window();
+        window.advance(now, length);
+        let elapsed = now.duration_since(window.start).as_secs_f64() / length.as_secs_f64();
+        let estimate = window.previous * (1.0 - elapsed) + window.current;
+        if estimate + 1.0 <= self.capacity {
+            window.current += 1.0;
+            return Ok(Duration::ZERO);
+        }
+        // When the previous window's weight will have dropped far enough,
+        // as a fraction of the current window or of the next one.
+        let retry_after = if window.current + 1.0 <= self.capacity {
+            1.0 - (self.capacity - window.current - 1.0) / window.previous - elapsed
+        } else {
+            1.0 - elapsed + 1.0 - (self.capacity - 1.0) / window.current
+        };
+        Err(length.mul_f64(retry_after.max(0.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_rate_limiter() {
+        let limiter = ClientRateLimiter::new(3, 0.5, RateLimitAlgorithm::TokenBucket);
+        let client = IpAddr::from([203, 0, 113, 7]);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(client).is_ok());
+        }
+        let retry_after = limiter.try_acquire(client).unwrap_err();
+        assert!(retry_after > Duration::from_millis(1900) && retry_after <= Duration::from_secs(2));
+        assert!(limiter.try_acquire(IpAddr::from([203, 0, 113, 8])).is_ok());
+    }
+
+    #[test]
+    fn test_client_rate_limiter_leaky_bucket() {
+        let limiter = ClientRateLimiter::new(2, 10.0, RateLimitAlgorithm::LeakyBucket);
+        let client = IpAddr::from([203, 0, 113, 7]);
+        assert_eq!(limiter.try_acquire(client), Ok(Duration::ZERO));
+        let delay = limiter.try_acquire(client).unwrap();
+        assert!(delay > Duration::from_millis(90) && delay <= Duration::from_millis(100));
+        let retry_after = limiter.try_acquire(client).unwrap_err();
+        assert!(
+            retry_after > Duration::from_millis(90) && retry_after <= Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn test_client_rate_limiter_sliding_window() {
+        let limiter = ClientRateLimiter::new(2, 1.0, RateLimitAlgorithm::SlidingWindow);
+        let client = IpAddr::from([203, 0, 113, 7]);
+        assert_eq!(limiter.try_acquire(client), Ok(Duration::ZERO));
+        assert_eq!(limiter.try_acquire(client), Ok(Duration::ZERO));
+        // They still weigh on the next window until half of it has passed.
+        let retry_after = limiter.try_acquire(client).unwrap_err();
+        assert!(retry_after > Duration::from_millis(2900) && retry_after <= Duration::from_secs(3));
+
+        assert!("sliding-window".parse::<RateLimitAlgorithm>().is_ok());
+        assert!("fixed-window".parse::<RateLimitAlgorithm>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_path_rate_limits() {
+        let limit = "/api:100rps".parse::<PathRateLimit>().unwrap();
+        assert_eq!(limit.path_prefix, "/api");
+        assert_eq!(limit.per_sec, 100);
+        assert!("/api:0rps".parse::<PathRateLimit>().is_err());
+        assert!("api:10rps".parse::<PathRateLimit>().is_err());
+        assert!("/api".parse::<PathRateLimit>().is_err());
+
+        let limiters = HashMap::from([
+            (
+                "/api".to_string(),
+                Arc::new(UpstreamRateLimiter::new(100, 0)),
+            ),
+            (
+                "/api/search".to_string(),
+                Arc::new(UpstreamRateLimiter::new(1, 0)),
+            ),
+        ]);
+        let search = find_path_limiter(&limiters, "/api/search/q").unwrap();
+        assert!(search.acquire().await.is_ok());
+        assert!(search.acquire().await.is_err());
+        let api = find_path_limiter(&limiters, "/api/users").unwrap();
+        assert!(api.acquire().await.is_ok());
+        assert!(find_path_limiter(&limiters, "/other").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_acquire_rejects_past_queue_depth() {
+        let limiter = UpstreamRateLimiter::new(2, 0);
+        assert!(limiter.acquire().await.is_ok());
+        assert!(limiter.acquire().await.is_ok());
+        assert!(limiter.acquire().await.is_err());
+
+        let limiter = UpstreamRateLimiter::new(10, 1);
+        for _ in 0..10 {
+            assert!(limiter.acquire().await.is_ok());
+        }
+        let started = Instant::now();
+        assert!(limiter.acquire().await.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,50 @@
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// Removes every object member named in `fields` from `value`, at any depth.
+pub fn remove_fields(value: &mut Value, fields: &HashSet<String>) -> usize {
+    match value {
+        Value::Object(members) => {
+            let before = members.len();
+            members.retain(|name, _| !fields.contains(name));
+            let removed = before - members.len();
+            removed
+                + members
+                    .values_mut()
+                    .map(|member| remove_fields(member, fields))
+                    .sum::<usize>()
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .map(|item| remove_fields(item, fields))
+            .sum(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_remove_fields() {
+        let fields: HashSet<String> = ["password", "ssn"].iter().map(|f| f.to_string()).collect();
+        let mut value = json!({
+            "name": "a",
+            "password": "secret",
+            "users": [{"ssn": "123", "id": 1}, {"id": 2, "password": {"old": "x"}}],
+            "nested": {"profile": {"ssn": "456", "city": "b"}},
+        });
+        assert_eq!(remove_fields(&mut value, &fields), 4);
+        assert_eq!(
+            value,
+            json!({
+                "name": "a",
+                "users": [{"id": 1}, {"id": 2}],
+                "nested": {"profile": {"city": "b"}},
+            })
+        );
+        assert_eq!(remove_fields(&mut json!("password"), &fields), 0);
+    }
+}
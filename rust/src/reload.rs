@@ -0,0 +1,55 @@
+use std::{fmt, str::FromStr};
+use tokio::signal::unix::SignalKind;
+
+/// The signal that makes the proxy read its configuration, TLS certificate
+/// and path blocklist again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReloadSignal {
+    Hangup,
+    User1,
+}
+
+impl ReloadSignal {
+    pub fn kind(self) -> SignalKind {
+        match self {
+            ReloadSignal::Hangup => SignalKind::hangup(),
+            ReloadSignal::User1 => SignalKind::user_defined1(),
+        }
+    }
+}
+
+impl FromStr for ReloadSignal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_start_matches("SIG") {
+            "HUP" => Ok(ReloadSignal::Hangup),
+            "USR1" => Ok(ReloadSignal::User1),
+            _ => Err(format!(
+                "invalid reload signal '{}', expected SIGHUP or SIGUSR1",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ReloadSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadSignal::Hangup => f.write_str("SIGHUP"),
+            ReloadSignal::User1 => f.write_str("SIGUSR1"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reload_signal() {
+        assert_eq!("SIGHUP".parse(), Ok(ReloadSignal::Hangup));
+        assert_eq!("USR1".parse(), Ok(ReloadSignal::User1));
+        assert!("SIGTERM".parse::<ReloadSignal>().is_err());
+    }
+}
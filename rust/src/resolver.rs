@@ -0,0 +1,279 @@
+use crate::mdns;
+use futures::future::BoxFuture;
+use hyper::client::connect::dns::Name;
+use hyper::service::Service;
+use ipnet::IpNet;
+use std::{
+    error::Error,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Address ranges upstreams are not allowed to resolve to, or be given as,
+/// unless configured otherwise.
+pub const DEFAULT_DENIED_RANGES: [&str; 6] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "127.0.0.0/8",
+    "::1/128",
+];
+
+pub fn default_denied_ranges() -> Vec<IpNet> {
+    DEFAULT_DENIED_RANGES
+        .iter()
+        .map(|range| range.parse().expect("default range should parse"))
+        .collect()
+}
+
+/// The IP version upstream connections are restricted to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    fn contains(self, ip: IpAddr) -> bool {
+        match self {
+            AddressFamily::V4 => ip.is_ipv4(),
+            AddressFamily::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressFamily::V4 => write!(f, "IPv4"),
+            AddressFamily::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ResolveError {
+    Io(io::Error),
+    Denied(String),
+    NoAddressInFamily(String, AddressFamily),
+    Timeout(String),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::Io(e) => write!(f, "dns lookup failed: {}", e),
+            ResolveError::Denied(host) => {
+                write!(f, "'{}' only resolves to denied addresses", host)
+            }
+            ResolveError::NoAddressInFamily(host, family) => {
+                write!(f, "'{}' has no {} addresses", host, family)
+            }
+            ResolveError::Timeout(host) => write!(f, "dns lookup for '{}' timed out", host),
+        }
+    }
+}
+
+impl Error for ResolveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ResolveError::Io(e) => Some(e),
+            ResolveError::Denied(_)
+            | ResolveError::NoAddressInFamily(..)
+            | ResolveError::Timeout(_) => None,
+        }
+    }
+}
+
+/// Returns the `ResolveError` somewhere in the source chain of `err`, if any.
+pub fn find_resolve_error<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a ResolveError> {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if let Some(resolve_err) = e.downcast_ref::<ResolveError>() {
+            return Some(resolve_err);
+        }
+        current = e.source();
+    }
+    None
+}
+
+/// Resolves upstream hostnames through the system resolver and drops any
+/// address that falls within a denied range or outside the allowed address
+/// family. `.local` hostnames are first looked up with multicast DNS.
+///
+/// Hyper only consults the resolver for hostnames, so IP literals have to
+/// be checked with `check_ip_literal` before connecting.
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamResolver {
+    denied: Arc<Vec<IpNet>>,
+    family: Option<AddressFamily>,
+    timeout: Option<Duration>,
+}
+
+impl UpstreamResolver {
+    pub fn new(denied: Vec<IpNet>, family: Option<AddressFamily>) -> UpstreamResolver {
+        UpstreamResolver {
+            denied: Arc::new(denied),
+            family,
+            timeout: None,
+        }
+    }
+
+    /// Gives up on lookups that take longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Fails with `ResolveError::Denied` if `host` is an IP literal within a
+    /// denied range. Hostnames pass, as they are checked once resolved.
+    pub fn check_ip_literal(&self, host: &str) -> Result<(), ResolveError> {
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        match literal.parse::<IpAddr>() {
+            Ok(ip) if self.is_denied(ip) => {
+                tracing::warn!("Refusing denied upstream address {}", ip);
+                Err(ResolveError::Denied(host.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn is_denied(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            v4 => v4,
+        };
+        self.denied.iter().any(|range| range.contains(&ip))
+    }
+
+    fn filter(&self, host: &str, addrs: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, ResolveError> {
+        let addrs: Vec<SocketAddr> = match self.family {
+            Some(family) => addrs
+                .into_iter()
+                .filter(|addr| family.contains(addr.ip()))
+                .collect(),
+            None => addrs,
+        };
+        if let (Some(family), true) = (self.family, addrs.is_empty()) {
+            return Err(ResolveError::NoAddressInFamily(host.to_string(), family));
+        }
+        let allowed: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|addr| {
+                let denied = self.is_denied(addr.ip());
+                if denied {
+                    tracing::warn!("Dropping denied address {} for '{}'", addr.ip(), host);
+                }
+                !denied
+            })
+            .collect();
+        if allowed.is_empty() {
+            return Err(ResolveError::Denied(host.to_string()));
+        }
+        Ok(allowed)
+    }
+}
+
+async fn lookup(host: &str) -> Result<Vec<SocketAddr>, ResolveError> {
+    let addrs = tokio::net::lookup_host((host, 0))
+        .await
+        .map_err(ResolveError::Io)?;
+    Ok(addrs.collect())
+}
+
+impl Service<Name> for UpstreamResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = ResolveError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let resolve = async {
+                if mdns::is_local(host) {
+                    match mdns::resolve(host, mdns::QUERY_TIMEOUT).await {
+                        Ok(addrs) => return Ok(mdns::with_port(addrs)),
+                        Err(e) => {
+                            tracing::debug!("mDNS lookup for '{}' failed, using DNS: {}", host, e);
+                        }
+                    }
+                }
+                lookup(host).await
+            };
+            let addrs = match resolver.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, resolve)
+                    .await
+                    .map_err(|_| ResolveError::Timeout(host.to_string()))??,
+                None => resolve.await?,
+            };
+            resolver.filter(host, addrs).map(Vec::into_iter)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_drops_denied_addresses() {
+        let resolver = UpstreamResolver::new(default_denied_ranges(), None);
+        let addrs = vec![
+            "10.1.2.3:0".parse().unwrap(),
+            "[::ffff:192.168.1.1]:0".parse().unwrap(),
+            "93.184.216.34:0".parse().unwrap(),
+        ];
+        let allowed = resolver.filter("example.com", addrs).unwrap();
+        assert_eq!(allowed, vec!["93.184.216.34:0".parse().unwrap()]);
+
+        let denied = resolver.filter("internal", vec!["127.0.0.1:0".parse().unwrap()]);
+        assert!(matches!(denied, Err(ResolveError::Denied(_))));
+    }
+
+    #[test]
+    fn test_check_ip_literal() {
+        let resolver = UpstreamResolver::new(default_denied_ranges(), None);
+        for host in ["127.0.0.1", "0.0.0.0", "[::1]", "[::ffff:10.0.0.1]"] {
+            assert!(
+                matches!(
+                    resolver.check_ip_literal(host),
+                    Err(ResolveError::Denied(_))
+                ),
+                "{}",
+                host
+            );
+        }
+        for host in ["93.184.216.34", "[2606:2800:220:1::1]", "localhost"] {
+            assert!(resolver.check_ip_literal(host).is_ok(), "{}", host);
+        }
+        let resolver = UpstreamResolver::new(Vec::new(), None);
+        assert!(resolver.check_ip_literal("127.0.0.1").is_ok());
+    }
+
+    #[test]
+    fn test_filter_keeps_address_family() {
+        let addrs = vec![
+            "93.184.216.34:0".parse().unwrap(),
+            "[2606:2800:220:1::1]:0".parse().unwrap(),
+        ];
+        let resolver = UpstreamResolver::new(Vec::new(), Some(AddressFamily::V6));
+        let allowed = resolver.filter("example.com", addrs.clone()).unwrap();
+        assert_eq!(allowed, vec!["[2606:2800:220:1::1]:0".parse().unwrap()]);
+
+        let resolver = UpstreamResolver::new(Vec::new(), Some(AddressFamily::V4));
+        let missing = resolver.filter("example.com", addrs[1..].to_vec());
+        assert!(matches!(
+            missing,
+            Err(ResolveError::NoAddressInFamily(_, AddressFamily::V4))
+        ));
+    }
+}
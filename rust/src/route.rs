@@ -0,0 +1,462 @@
+use hyper::{
+    header::{HeaderMap, HeaderName, ACCEPT, HOST},
+    http::uri::Authority,
+    Uri,
+};
+use std::str::FromStr;
+
+/// Sends requests whose path starts with `path_prefix` to `backend` instead
+/// of the default upstream. Parsed from `PREFIX=BACKEND`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteEntry {
+    pub path_prefix: String,
+    pub backend: String,
+}
+
+impl FromStr for RouteEntry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path_prefix, backend) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=BACKEND, got '{}'", s))?;
+        if !path_prefix.starts_with('/') {
+            return Err(format!("route prefix '{}' must start with /", path_prefix));
+        }
+        Ok(RouteEntry {
+            path_prefix: path_prefix.to_string(),
+            backend: parse_backend(backend)?,
+        })
+    }
+}
+
+/// Sends requests that ask for `version` of the API through a vendor media
+/// type in `Accept`, e.g. `application/vnd.example.v2+json`, to `backend`.
+/// Parsed from `VERSION:BACKEND`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionRoute {
+    pub version: String,
+    pub backend: String,
+}
+
+impl FromStr for VersionRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (version, backend) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected VERSION:BACKEND, got '{}'", s))?;
+        if version.is_empty() {
+            return Err(format!("no version in '{}'", s));
+        }
+        Ok(VersionRoute {
+            version: version.to_ascii_lowercase(),
+            backend: parse_backend(backend)?,
+        })
+    }
+}
+
+/// Sends requests for the virtual host `host`, named in `Host` or, for
+/// HTTP/2, `:authority`, to `backend`. Parsed from `HOST:BACKEND`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostRoute {
+    pub host: String,
+    pub backend: String,
+}
+
+impl FromStr for HostRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, backend) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected HOST:BACKEND, got '{}'", s))?;
+        let valid = host
+            .parse::<Authority>()
+            .is_ok_and(|authority| authority.as_str() == authority.host());
+        if host.is_empty() || !valid {
+            return Err(format!("invalid host '{}'", host));
+        }
+        Ok(HostRoute {
+            host: host.to_ascii_lowercase(),
+            backend: parse_backend(backend)?,
+        })
+    }
+}
+
+/// Finds the backend for the host the request is for, ignoring any port.
+pub fn find_host<'a>(routes: &'a [HostRoute], headers: &HeaderMap, uri: &Uri) -> Option<&'a str> {
+    let authority = match headers.get(HOST) {
+        Some(host) => host.to_str().ok()?.parse::<Authority>().ok()?,
+        None => uri.authority()?.clone(),
+    };
+    let host = authority.host().trim_end_matches('.');
+    routes
+        .iter()
+        .find(|route| route.host.eq_ignore_ascii_case(host))
+        .map(|route| route.backend.as_str())
+}
+
+/// Sends requests with a feature flag header, e.g. `X-Feature: new-ui`, to
+/// `backend`. Parsed from `HEADER:VALUE:BACKEND`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FeatureRoute {
+    pub header: HeaderName,
+    pub value: String,
+    pub backend: String,
+}
+
+impl FromStr for FeatureRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (header, rest) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected HEADER:VALUE:BACKEND, got '{}'", s))?;
+        let (value, backend) = rest
+            .split_once(':')
+            .ok_or_else(|| format!("expected HEADER:VALUE:BACKEND, got '{}'", s))?;
+        let header = header
+            .parse::<HeaderName>()
+            .map_err(|e| format!("invalid header '{}': {}", header, e))?;
+        if value.is_empty() {
+            return Err(format!("no value in '{}'", s));
+        }
+        Ok(FeatureRoute {
+            header,
+            value: value.to_string(),
+            backend: parse_backend(backend)?,
+        })
+    }
+}
+
+/// Finds the first route whose header is in `headers` with its value, or
+/// with its value among comma-separated ones.
+pub fn find_feature<'a>(
+    routes: &'a [FeatureRoute],
+    headers: &HeaderMap,
+) -> Option<&'a FeatureRoute> {
+    routes.iter().find(|route| {
+        headers
+            .get_all(&route.header)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim() == route.value)
+    })
+}
+
+fn parse_backend(backend: &str) -> Result<String, String> {
+    let uri = backend
+        .parse::<Uri>()
+        .map_err(|e| format!("invalid backend '{}': {}", backend, e))?;
+    if uri.scheme().is_none() || uri.authority().is_none() {
+        return Err(format!("backend '{}' must be an absolute URL", backend));
+    }
+    Ok(backend.trim_end_matches('/').to_string())
+}
+
+/// Finds the backend for the API version asked for in `headers`, the last
+/// dot-separated part of the first vendor media type in `Accept`, e.g. `v2`
+/// in `application/vnd.example.v2+json`.
+pub fn find_version<'a>(routes: &'a [VersionRoute], headers: &HeaderMap) -> Option<&'a str> {
+    let version = headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|media_type| {
+            let media_type = media_type.split(';').next()?.trim();
+            let subtype = media_type.strip_prefix("application/vnd.")?;
+            let name = subtype.split('+').next()?;
+            name.rsplit_once('.').map(|(_, version)| version)
+        })?;
+    routes
+        .iter()
+        .find(|route| route.version.eq_ignore_ascii_case(version))
+        .map(|route| route.backend.as_str())
+}
+
+/// Sends requests to the backend named by one of their path segments, e.g.
+/// `/tenant-a/users` to `https://tenant-a.internal/users`, with the segment
+/// taken out of the forwarded path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentRoute {
+    /// Which segment names the backend, counting from 1.
+    segment: usize,
+    /// The backend URL, with `{segment}` standing for the segment.
+    template: String,
+}
+
+const SEGMENT_PLACEHOLDER: &str = "{segment}";
+
+impl SegmentRoute {
+    pub fn new(segment: usize, template: &str) -> Result<SegmentRoute, String> {
+        if segment == 0 {
+            return Err("path segments are counted from 1".to_string());
+        }
+        if !template.contains(SEGMENT_PLACEHOLDER) {
+            return Err(format!(
+                "upstream template '{}' has no {}",
+                template, SEGMENT_PLACEHOLDER
+            ));
+        }
+        parse_backend(&template.replace(SEGMENT_PLACEHOLDER, "segment"))?;
+        Ok(SegmentRoute {
+            segment,
+            template: template.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// The backend for `path` and the path to forward to it, or `None` if
+    /// `path` doesn't have the segment. Fails if the segment can't be part
+    /// of a hostname.
+    pub fn resolve(&self, path: &str) -> Result<Option<(String, String)>, String> {
+        let mut segments: Vec<&str> = path.split('/').skip(1).collect();
+        let segment = match segments.get(self.segment - 1) {
+            Some(segment) if !segment.is_empty() => *segment,
+            _ => return Ok(None),
+        };
+        let valid = segment.len() <= 63
+            && !segment.starts_with('-')
+            && !segment.ends_with('-')
+            && segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+        if !valid {
+            return Err(format!(
+                "path segment '{}' is not a valid host label",
+                segment
+            ));
+        }
+        let backend = self.template.replace(SEGMENT_PLACEHOLDER, segment);
+        segments.remove(self.segment - 1);
+        Ok(Some((backend, format!("/{}", segments.join("/")))))
+    }
+}
+
+/// Finds the backend for `path`, the one with the longest matching prefix.
+pub fn find<'a>(routes: &'a [RouteEntry], path: &str) -> Option<&'a str> {
+    routes
+        .iter()
+        .filter(|route| path.starts_with(route.path_prefix.as_str()))
+        .max_by_key(|route| route.path_prefix.len())
+        .map(|route| route.backend.as_str())
+}
+
+/// Replaces the authority and/or path of the `base` endpoint URL, keeping
+/// the rest.
+pub fn rewrite_endpoint(
+    base: &str,
+    authority: Option<&str>,
+    path_prefix: Option<&str>,
+) -> Result<String, String> {
+    let uri = base
+        .parse::<Uri>()
+        .map_err(|e| format!("invalid endpoint '{}': {}", base, e))?;
+    let scheme = uri
+        .scheme_str()
+        .ok_or_else(|| format!("endpoint '{}' must be an absolute URL", base))?;
+    let authority = match authority {
+        Some(authority) => authority
+            .parse::<Authority>()
+            .map_err(|e| format!("invalid authority '{}': {}", authority, e))?,
+        None => uri
+            .authority()
+            .cloned()
+            .ok_or_else(|| format!("endpoint '{}' must be an absolute URL", base))?,
+    };
+    let path = match path_prefix {
+        Some(prefix) if !prefix.starts_with('/') => {
+            return Err(format!("path prefix '{}' must start with /", prefix))
+        }
+        Some(prefix) => prefix,
+        None => uri.path(),
+    };
+    Ok(format!(
+        "{}://{}{}",
+        scheme,
+        authority,
+        path.trim_end_matches('/')
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_longest_prefix() {
+        let routes: Vec<RouteEntry> = vec![
+            "/api=http://api.internal:8080".parse().unwrap(),
+            "/api/v2=http://api-v2.internal/".parse().unwrap(),
+            "/static=https://cdn.internal".parse().unwrap(),
+        ];
+        assert_eq!(
+            find(&routes, "/api/v1/users"),
+            Some("http://api.internal:8080")
+        );
+        assert_eq!(
+            find(&routes, "/api/v2/users"),
+            Some("http://api-v2.internal")
+        );
+        assert_eq!(
+            find(&routes, "/static/app.js"),
+            Some("https://cdn.internal")
+        );
+        assert_eq!(find(&routes, "/"), None);
+
+        assert!("/api".parse::<RouteEntry>().is_err());
+        assert!("api=http://api.internal".parse::<RouteEntry>().is_err());
+        assert!("/api=api.internal".parse::<RouteEntry>().is_err());
+    }
+
+    #[test]
+    fn test_find_version() {
+        let routes: Vec<VersionRoute> = vec![
+            "v1:http://api-v1.internal".parse().unwrap(),
+            "v2:https://api-v2.internal/".parse().unwrap(),
+        ];
+        let mut headers = HeaderMap::new();
+        assert_eq!(find_version(&routes, &headers), None);
+        headers.insert(
+            ACCEPT,
+            "text/html, application/vnd.example.v2+json; q=0.9"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            find_version(&routes, &headers),
+            Some("https://api-v2.internal")
+        );
+        headers.insert(ACCEPT, "application/vnd.example.v3+json".parse().unwrap());
+        assert_eq!(find_version(&routes, &headers), None);
+        headers.insert(ACCEPT, "application/json".parse().unwrap());
+        assert_eq!(find_version(&routes, &headers), None);
+
+        assert!("v2".parse::<VersionRoute>().is_err());
+        assert!(":http://api.internal".parse::<VersionRoute>().is_err());
+        assert!("v2:api.internal".parse::<VersionRoute>().is_err());
+    }
+
+    #[test]
+    fn test_find_feature() {
+        let routes: Vec<FeatureRoute> = vec![
+            "X-Feature:new-ui:https://new-ui.internal/".parse().unwrap(),
+            "X-Feature:beta:http://beta.internal:8080".parse().unwrap(),
+        ];
+        let mut headers = HeaderMap::new();
+        assert_eq!(find_feature(&routes, &headers), None);
+        headers.insert("x-feature", "dark-mode, beta".parse().unwrap());
+        assert_eq!(
+            find_feature(&routes, &headers).map(|route| route.backend.as_str()),
+            Some("http://beta.internal:8080")
+        );
+        headers.insert("x-feature", "new-ui".parse().unwrap());
+        assert_eq!(
+            find_feature(&routes, &headers).map(|route| route.backend.as_str()),
+            Some("https://new-ui.internal")
+        );
+        headers.insert("x-feature", "new-ui-v2".parse().unwrap());
+        assert_eq!(find_feature(&routes, &headers), None);
+
+        assert!("X-Feature:new-ui".parse::<FeatureRoute>().is_err());
+        assert!("X-Feature::http://new-ui.internal"
+            .parse::<FeatureRoute>()
+            .is_err());
+        assert!("X Feature:new-ui:http://new-ui.internal"
+            .parse::<FeatureRoute>()
+            .is_err());
+        assert!("X-Feature:new-ui:new-ui.internal"
+            .parse::<FeatureRoute>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_find_host() {
+        let routes: Vec<HostRoute> = vec![
+            "api.example.com:https://api-backend.internal"
+                .parse()
+                .unwrap(),
+            "Static.example.com:https://cdn-backend.internal/"
+                .parse()
+                .unwrap(),
+        ];
+        let uri = Uri::from_static("/index.html");
+        let mut headers = HeaderMap::new();
+        assert_eq!(find_host(&routes, &headers, &uri), None);
+        assert_eq!(
+            find_host(
+                &routes,
+                &headers,
+                &Uri::from_static("https://api.example.com/users")
+            ),
+            Some("https://api-backend.internal")
+        );
+        headers.insert(HOST, "static.EXAMPLE.com:8443".parse().unwrap());
+        assert_eq!(
+            find_host(&routes, &headers, &uri),
+            Some("https://cdn-backend.internal")
+        );
+        headers.insert(HOST, "other.example.com".parse().unwrap());
+        assert_eq!(find_host(&routes, &headers, &uri), None);
+
+        assert!("api.example.com".parse::<HostRoute>().is_err());
+        assert!(":https://api.internal".parse::<HostRoute>().is_err());
+        assert!("api.example.com:api.internal".parse::<HostRoute>().is_err());
+        assert!("user@api.example.com:https://api.internal"
+            .parse::<HostRoute>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_segment_route() {
+        let route = SegmentRoute::new(1, "https://{segment}.internal/").unwrap();
+        assert_eq!(
+            route.resolve("/tenantA/api/v1/users").unwrap(),
+            Some((
+                "https://tenantA.internal".to_string(),
+                "/api/v1/users".to_string()
+            ))
+        );
+        assert_eq!(
+            route.resolve("/tenantA").unwrap(),
+            Some(("https://tenantA.internal".to_string(), "/".to_string()))
+        );
+        assert_eq!(route.resolve("/").unwrap(), None);
+        assert!(route.resolve("/evil.com%2F/users").is_err());
+        assert!(route.resolve("/user@host/users").is_err());
+
+        let route = SegmentRoute::new(2, "http://{segment}.tenants:8080").unwrap();
+        assert_eq!(
+            route.resolve("/api/b/users").unwrap(),
+            Some((
+                "http://b.tenants:8080".to_string(),
+                "/api/users".to_string()
+            ))
+        );
+
+        assert!(SegmentRoute::new(0, "https://{segment}.internal").is_err());
+        assert!(SegmentRoute::new(1, "https://tenant.internal").is_err());
+        assert!(SegmentRoute::new(1, "{segment}.internal").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_endpoint() {
+        let base = "https://api.internal:8443/v1";
+        assert_eq!(
+            rewrite_endpoint(base, Some("api-2.internal:9443"), None).unwrap(),
+            "https://api-2.internal:9443/v1"
+        );
+        assert_eq!(
+            rewrite_endpoint(base, None, Some("/api")).unwrap(),
+            "https://api.internal:8443/api"
+        );
+        assert_eq!(
+            rewrite_endpoint("http://127.0.0.1:8080", Some("[::1]:80"), Some("/")).unwrap(),
+            "http://[::1]:80"
+        );
+        assert!(rewrite_endpoint(base, Some("bad host"), None).is_err());
+        assert!(rewrite_endpoint(base, None, Some("api")).is_err());
+    }
+}
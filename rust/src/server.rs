@@ -1,197 +1,5985 @@
-use hyper::{client::HttpConnector, Body, Client, Request, Response, StatusCode};
-use hyper_openssl::HttpsConnector;
-use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use crate::audit::{AuditLog, Decision};
+use crate::auth::{self, PathAuth};
+use crate::aws::AwsSigner;
+use crate::blocklist::PathBlocklist;
+use crate::body::{self, BoxBody};
+use crate::body_log::{self, BodyLogFormatRule};
+use crate::client::{
+    self, ClientOptions, HeaderCase, HttpClient, HttpVersionNegotiation, Protocol,
+    TlsRenegotiation, UpstreamProtocol,
+};
+use crate::coalesce::Coalescer;
+use crate::compression;
+use crate::config::Config;
+use crate::connector::{self, Downstream, PoolStats};
+use crate::content_type::{self, ContentTypeOverride};
+use crate::cookies::{self, CookieFilter, InsecureCookies};
+use crate::debug_headers;
+use crate::download::ForcedDownload;
+use crate::env;
+use crate::error::ProxyError;
+use crate::etag;
+use crate::filter::{Action, FilterChain, FilterRule};
+use crate::geoip::GeoIpDb;
+use crate::graphql;
+use crate::grpc;
+use crate::integrity;
+use crate::listener::{
+    ClientStream, Incoming, ListenerOptions, SessionCache, SlowClientPolicy, TlsAcceptor,
+};
+use crate::metrics::MetricsRecorder;
+use crate::minify;
+use crate::oauth::TokenSource;
+use crate::path::{self, PathCase, PathNormalization, SegmentInsertion};
+use crate::pre_connect::PreConnectHook;
+use crate::priority::{self, PathPriority};
+use crate::rate_limit::{
+    self, ClientRateLimiter, PathRateLimit, RateLimitAlgorithm, UpstreamRateLimiter,
+};
+use crate::redact;
+use crate::resolver::{self, AddressFamily, ResolveError};
+use crate::route::{self, FeatureRoute, HostRoute, RouteEntry, SegmentRoute, VersionRoute};
+use crate::status_ranges::StatusRanges;
+use crate::timeouts::{MethodTimeouts, PathTimeout};
+use crate::validation::{self, BodyType, ResponseValidation};
+use crate::vary;
+use crate::workers::WorkerPool;
+use futures::future::BoxFuture;
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, ALLOW, AUTHORIZATION, CACHE_CONTROL,
+        CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, HOST, IF_NONE_MATCH, LOCATION,
+        RETRY_AFTER, SET_COOKIE, TRANSFER_ENCODING, VIA, WWW_AUTHENTICATE,
+    },
+    http::uri::Authority,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode, Version,
+};
+use rand::Rng;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    error::Error,
+    fs::{self, OpenOptions},
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::Instrument;
+
+/// How long to wait for upstream response headers when no other timeout
+/// applies.
+const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Clone, Debug)]
 pub struct ProxyClient {
     addr: SocketAddr,
     forward_addr: String,
-    http_client: Client<HttpsConnector<HttpConnector>>,
+    routes: Vec<RouteEntry>,
+    version_routes: Vec<VersionRoute>,
+    feature_routes: Vec<FeatureRoute>,
+    host_routes: Vec<HostRoute>,
+    segment_route: Option<SegmentRoute>,
+    path_priorities: Vec<PathPriority>,
+    /// The client for configured backends, which may be at any address.
+    http_client: HttpClient,
+    pool_stats: Arc<PoolStats>,
+    client_options: ClientOptions,
+    /// The client for backends named by the request through
+    /// `segment_route`, kept away from `client_options.denied_ip_ranges`.
+    routed_client: Option<HttpClient>,
+    upstream_protocols: HashMap<String, Protocol>,
+    /// Clients for the hosts in `upstream_protocols`, sharing `pool_stats`.
+    protocol_clients: HashMap<Protocol, HttpClient>,
+    listener_options: ListenerOptions,
+    pre_connect_hook: Option<Arc<PreConnectHook>>,
+    cookie_filter: Option<CookieFilter>,
+    insecure_cookies: Option<InsecureCookies>,
+    timing_header: Option<HeaderName>,
+    token_source: Option<Arc<TokenSource>>,
+    aws_signer: Option<AwsSigner>,
+    upstream_timeout: Duration,
+    method_timeouts: Option<MethodTimeouts>,
+    path_timeouts: Vec<PathTimeout>,
+    gzip_above_bytes: Option<u64>,
+    in_flight: Arc<AtomicUsize>,
+    empty_body_timeout: Option<Duration>,
+    stall_timeout: Option<Duration>,
+    min_response_speed: Option<(u64, Duration)>,
+    coalescer: Option<Arc<Coalescer>>,
+    response_hash_header: Option<HeaderName>,
+    log_sampling_rate: Option<f64>,
+    log_body_paths: Vec<String>,
+    body_log_formats: Vec<BodyLogFormatRule>,
+    upstream_error_response: Option<(StatusCode, String)>,
+    upstream_rate_limiter: Option<Arc<UpstreamRateLimiter>>,
+    path_rate_limiters: HashMap<String, Arc<UpstreamRateLimiter>>,
+    client_rate_limiter: Option<Arc<ClientRateLimiter>>,
+    correlation_id_header: Option<HeaderName>,
+    traced_headers: Vec<HeaderName>,
+    trace_sensitive_headers: bool,
+    abort_on_tls_error: bool,
+    timeout_jitter_ms: u64,
+    path_normalization: Option<PathNormalization>,
+    path_case: PathCase,
+    base64_path: bool,
+    vary_headers: Vec<HeaderName>,
+    debug_headers: bool,
+    pool_stats_headers: bool,
+    idle_check: Option<IdleCheck>,
+    strip_header_prefix: Option<String>,
+    add_etag: bool,
+    path_segments: Vec<SegmentInsertion>,
+    valid_statuses: Option<StatusRanges>,
+    strict_response_validation: bool,
+    latency_sla: Option<Duration>,
+    response_size_sla: Option<u64>,
+    local_options_allow: Option<HeaderValue>,
+    path_blocklist: Option<Arc<PathBlocklist>>,
+    audit_log: Option<Arc<AuditLog>>,
+    filters: Option<FilterChain>,
+    expected_content_type: Option<String>,
+    draining: Arc<AtomicBool>,
+    draining_header: Option<(HeaderName, HeaderValue)>,
+    allow_get_body: bool,
+    method_override_header: Option<HeaderName>,
+    forwarding_headers: bool,
+    path_auths: Vec<PathAuth>,
+    health_path: Option<String>,
+    favicon: Option<Bytes>,
+    geoip: Option<Arc<GeoIpDb>>,
+    blocked_countries: HashSet<String>,
+    validate_json_encoding: bool,
+    body_validation_max_bytes: Option<usize>,
+    removed_body_fields: HashSet<String>,
+    body_passthrough_above_bytes: Option<u64>,
+    graphql_operation_header: bool,
+    body_size_header: Option<HeaderName>,
+    response_size_header: Option<HeaderName>,
+    strip_request_headers: HashSet<HeaderName>,
+    allowed_request_headers: Option<HashSet<HeaderName>>,
+    strip_response_headers: HashSet<HeaderName>,
+    allowed_response_headers: Option<HashSet<HeaderName>>,
+    metadata_header: Option<HeaderValue>,
+    proxy_info_header: bool,
+    metrics: Option<Arc<MetricsRecorder>>,
+    host_header: Option<HeaderValue>,
+    strip_host_port: bool,
+    max_request_body_bytes: Option<u64>,
+    max_response_body_bytes: Option<u64>,
+    grpc_max_recv_message_size: Option<u64>,
+    grpc_max_send_message_size: Option<u64>,
+    grpc_status_mapping: bool,
+    forced_download: Option<ForcedDownload>,
+    content_type_overrides: Vec<ContentTypeOverride>,
+    minify_json: bool,
 }
 
-impl ProxyClient {
-    pub fn new(addr: SocketAddr, forward_addr: String) -> ProxyClient {
-        let ssl = HttpsConnector::new().unwrap();
-        let http_client = Client::builder().build::<_, Body>(ssl);
-        ProxyClient {
-            addr,
-            forward_addr,
-            http_client,
+impl ProxyClient {
+    pub fn new(addr: SocketAddr, forward_addr: String) -> ProxyClient {
+        let (http_client, pool_stats) = client::build(&trusted(&ClientOptions::default()));
+        ProxyClient {
+            addr,
+            forward_addr,
+            routes: Vec::new(),
+            version_routes: Vec::new(),
+            feature_routes: Vec::new(),
+            host_routes: Vec::new(),
+            segment_route: None,
+            path_priorities: Vec::new(),
+            http_client,
+            pool_stats,
+            client_options: ClientOptions::default(),
+            routed_client: None,
+            upstream_protocols: HashMap::new(),
+            protocol_clients: HashMap::new(),
+            listener_options: ListenerOptions::default(),
+            pre_connect_hook: None,
+            cookie_filter: None,
+            insecure_cookies: None,
+            timing_header: None,
+            token_source: None,
+            aws_signer: None,
+            upstream_timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            method_timeouts: None,
+            path_timeouts: Vec::new(),
+            gzip_above_bytes: None,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            empty_body_timeout: None,
+            stall_timeout: None,
+            min_response_speed: None,
+            coalescer: None,
+            response_hash_header: None,
+            log_sampling_rate: None,
+            log_body_paths: Vec::new(),
+            body_log_formats: Vec::new(),
+            upstream_error_response: None,
+            upstream_rate_limiter: None,
+            path_rate_limiters: HashMap::new(),
+            client_rate_limiter: None,
+            correlation_id_header: None,
+            traced_headers: Vec::new(),
+            trace_sensitive_headers: false,
+            abort_on_tls_error: false,
+            timeout_jitter_ms: 0,
+            path_normalization: None,
+            path_case: PathCase::None,
+            base64_path: false,
+            vary_headers: Vec::new(),
+            debug_headers: false,
+            pool_stats_headers: false,
+            idle_check: None,
+            strip_header_prefix: None,
+            add_etag: false,
+            path_segments: Vec::new(),
+            valid_statuses: None,
+            strict_response_validation: false,
+            latency_sla: None,
+            response_size_sla: None,
+            local_options_allow: None,
+            path_blocklist: None,
+            audit_log: None,
+            filters: None,
+            expected_content_type: None,
+            draining: Arc::new(AtomicBool::new(false)),
+            draining_header: None,
+            allow_get_body: false,
+            method_override_header: None,
+            forwarding_headers: true,
+            path_auths: Vec::new(),
+            health_path: None,
+            favicon: None,
+            geoip: None,
+            blocked_countries: HashSet::new(),
+            validate_json_encoding: false,
+            body_validation_max_bytes: None,
+            removed_body_fields: HashSet::new(),
+            body_passthrough_above_bytes: None,
+            graphql_operation_header: false,
+            body_size_header: None,
+            response_size_header: None,
+            strip_request_headers: HashSet::new(),
+            allowed_request_headers: None,
+            strip_response_headers: HashSet::new(),
+            allowed_response_headers: None,
+            metadata_header: None,
+            proxy_info_header: false,
+            metrics: None,
+            host_header: None,
+            strip_host_port: false,
+            max_request_body_bytes: None,
+            max_response_body_bytes: None,
+            grpc_max_recv_message_size: None,
+            grpc_max_send_message_size: None,
+            grpc_status_mapping: false,
+            forced_download: None,
+            content_type_overrides: Vec::new(),
+            minify_json: false,
+        }
+    }
+
+    /// Builds a client with every option of `config`. Those that need the
+    /// runtime (the upstream auth token, blocklist reloads, metrics, pool
+    /// warm-up and the drain timeout) are left to the caller.
+    pub fn from_config(config: Config) -> Result<ProxyClient, String> {
+        let config_hash = config.hash();
+        let forward_addr = env::expand(&config.base_endpoint)
+            .map_err(|e| format!("invalid base endpoint: {}", e))?;
+        let forward_addr =
+            if config.upstream_authority.is_some() || config.upstream_path_prefix.is_some() {
+                route::rewrite_endpoint(
+                    &forward_addr,
+                    config.upstream_authority.as_deref(),
+                    config.upstream_path_prefix.as_deref(),
+                )
+                .map_err(|e| format!("invalid upstream rewrite: {}", e))?
+            } else {
+                forward_addr
+            };
+        let routes = config
+            .route
+            .iter()
+            .map(|route| env::expand(route).and_then(|route| route.parse::<RouteEntry>()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid route: {}", e))?;
+        let version_routes = config
+            .version_upstream
+            .iter()
+            .map(|route| env::expand(route).and_then(|route| route.parse::<VersionRoute>()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid version upstream: {}", e))?;
+        let host_routes = config
+            .host_route
+            .iter()
+            .map(|route| env::expand(route).and_then(|route| route.parse::<HostRoute>()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid host route: {}", e))?;
+        let feature_routes = config
+            .feature_route
+            .iter()
+            .map(|route| env::expand(route).and_then(|route| route.parse::<FeatureRoute>()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid feature route: {}", e))?;
+        let aws_signer = match (&config.aws_region, &config.aws_service) {
+            (Some(region), Some(service)) => Some(
+                AwsSigner::from_env(region, service)
+                    .map_err(|e| format!("invalid AWS signing: {}", e))?,
+            ),
+            (None, None) => None,
+            _ => return Err("--aws-region and --aws-service go together".to_string()),
+        };
+        let segment_route = match (
+            config.upstream_hostname_from_path_segment,
+            &config.upstream_template,
+        ) {
+            (Some(segment), Some(template)) => Some(
+                SegmentRoute::new(segment, template)
+                    .map_err(|e| format!("invalid upstream template: {}", e))?,
+            ),
+            (None, None) => None,
+            _ => {
+                return Err(
+                    "--upstream-hostname-from-path-segment and --upstream-template go together"
+                        .to_string(),
+                )
+            }
+        };
+
+        let tls_key_log = match config.tls_key_log_file {
+            Some(_) if !config.debug_mode => {
+                return Err("the TLS key log file requires debug mode".to_string())
+            }
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+                tracing::warn!("Writing upstream TLS secrets to {}", path.display());
+                Some(Arc::new(Mutex::new(file)))
+            }
+            None => None,
+        };
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => {
+                let cache = SessionCache {
+                    size: config.tls_session_cache_size,
+                    timeout: config.tls_session_timeout_secs.map(Duration::from_secs),
+                };
+                let tls = match &config.client_cert_optional {
+                    Some(client_ca) => {
+                        TlsAcceptor::from_pem_files_with_client_ca(cert, key, &cache, client_ca)
+                            .map_err(|e| e.to_string())
+                    }
+                    None => TlsAcceptor::from_pem_files_with_session_cache(cert, key, &cache)
+                        .map_err(|e| e.to_string()),
+                };
+                Some(tls.map_err(|e| format!("failed to load TLS certificate and key: {}", e))?)
+            }
+            (None, None) => None,
+            _ => return Err("a TLS certificate and key must be given together".to_string()),
+        };
+        if config.upstream_tls_renegotiation == TlsRenegotiation::Reject
+            && !client::REJECT_RENEGOTIATION_SUPPORTED
+        {
+            return Err("rejecting TLS renegotiation needs OpenSSL 1.1.0h or newer".to_string());
+        }
+        let client_options = ClientOptions {
+            denied_ip_ranges: if config.restrict_upstream_ip_ranges {
+                resolver::default_denied_ranges()
+            } else {
+                Vec::new()
+            },
+            disable_tls_session_tickets: config.upstream_tls_no_session_tickets,
+            tls_renegotiation: config.upstream_tls_renegotiation,
+            tls_hostname_pattern: config.upstream_tls_hostname_pattern,
+            proxy_protocol: config.upstream_proxy_protocol,
+            connect_retries: config.upstream_connect_retry,
+            max_connections_per_host: config.max_concurrent_upstream_connections_per_host,
+            max_connections: config.max_host_connections,
+            http2_keep_alive_interval: config.grpc_keepalive_interval_secs.map(Duration::from_secs),
+            http2_keep_alive_timeout: Duration::from_secs(config.grpc_keepalive_timeout_secs),
+            http2_initial_stream_window_size: config.upstream_h2_initial_stream_window,
+            http2_initial_connection_window_size: config.upstream_h2_initial_connection_window,
+            resolver_timeout: config
+                .upstream_resolver_timeout_ms
+                .map(Duration::from_millis),
+            tls_key_log,
+            tcp_fast_open: config.upstream_tcp_fast_open,
+            http1_title_case_headers: config.upstream_title_case_headers
+                || config.request_header_case == HeaderCase::HttpStandard,
+            http1_writev: config.upstream_http1_writev,
+            disable_pooling: config.force_close_upstream_connection,
+            max_connection_age: config
+                .upstream_max_idle_connection_age_secs
+                .map(Duration::from_secs),
+            tcp_keepalive_interval: config
+                .upstream_tcp_keepalive_interval
+                .map(|secs| Duration::from_secs(secs.get())),
+            tcp_keepalive_probes: config.upstream_tcp_keepalive_probes,
+            write_buffer_size: config.upstream_request_write_buf_size,
+            connect_attempt_timeout: config
+                .upstream_connection_timeout_per_attempt_ms
+                .map(Duration::from_millis),
+            connect_total_timeout: config
+                .upstream_connection_timeout_total_ms
+                .map(Duration::from_millis),
+            protocol: match config.upstream_http_version_negotiation {
+                HttpVersionNegotiation::None => Some(Protocol::Http1),
+                HttpVersionNegotiation::Alpn => None,
+            },
+            address_family: if config.upstream_ipv4_only {
+                Some(AddressFamily::V4)
+            } else if config.upstream_ipv6_only {
+                Some(AddressFamily::V6)
+            } else {
+                None
+            },
+        };
+        if let Some(size) = config.listen_h2_max_frame_size {
+            if !(16_384..=16_777_215).contains(&size) {
+                return Err(format!(
+                    "invalid HTTP/2 max frame size {}, expected 16384 to 16777215",
+                    size
+                ));
+            }
+        }
+        let listener_options = ListenerOptions {
+            slow_client: config
+                .slow_client_abort_threshold_ms
+                .map(|threshold| SlowClientPolicy {
+                    min_bytes_per_sec: config.min_client_bandwidth_bps,
+                    threshold: Duration::from_millis(threshold),
+                }),
+            proxy_protocol: config.listen_proxy_protocol,
+            recv_buffer_size: config.listen_recv_buf_size,
+            send_buffer_size: config.listen_send_buf_size,
+            defer_accept: config.listen_defer_accept,
+            tcp_quickack: config.listen_tcp_quickack,
+            h2_max_frame_size: config.listen_h2_max_frame_size,
+            reuse_port: config.workers.is_some(),
+            tls,
+        };
+        let mut proxy_client = ProxyClient::new(config.listen, forward_addr)
+            .with_client_options(client_options)
+            .with_listener_options(listener_options)
+            .with_upstream_timeout(Duration::from_secs(config.upstream_timeout));
+        if let Some(url) = config.upstream_pre_connect_hook_url {
+            let cache_ttl = Duration::from_secs(config.pre_connect_cache_secs);
+            proxy_client = proxy_client.with_pre_connect_hook(PreConnectHook::new(url, cache_ttl));
+        }
+        if config.remove_response_cookies {
+            proxy_client = proxy_client.with_cookie_filter(CookieFilter::All);
+        } else if !config.remove_cookies_matching.is_empty() {
+            proxy_client = proxy_client
+                .with_cookie_filter(CookieFilter::NamePrefixes(config.remove_cookies_matching));
+        }
+        if let Some(cookies) = config.strip_response_set_cookie_for_non_https {
+            proxy_client = proxy_client.with_insecure_cookies_stripped(cookies);
+        }
+        if let Some(header) = config.inject_response_timing_header {
+            proxy_client = proxy_client.with_timing_header(header);
+        }
+        if let Some(timeouts) = config.upstream_timeout_per_method {
+            proxy_client = proxy_client.with_method_timeouts(timeouts);
+        }
+        proxy_client = proxy_client.with_timeout_jitter_ms(config.upstream_timeout_jitter_ms);
+        for timeout in config.upstream_per_path_timeout {
+            proxy_client = proxy_client.with_path_timeout(timeout);
+        }
+        if let Some(min_bytes) = config.gzip_compress_response_above_bytes {
+            proxy_client = proxy_client.with_gzip_above_bytes(min_bytes);
+        }
+        if let Some(timeout) = config.upstream_empty_body_timeout_ms {
+            proxy_client = proxy_client.with_empty_body_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(timeout) = config.upstream_response_timeout_after_first_byte_ms {
+            proxy_client = proxy_client.with_stall_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(bytes_per_sec) = config.upstream_response_min_speed_bps {
+            proxy_client = proxy_client.with_min_response_speed(
+                bytes_per_sec,
+                Duration::from_secs(config.upstream_slow_speed_window_secs),
+            );
+        }
+        if let Some(window) = config.request_coalesce_window_ms {
+            proxy_client = proxy_client.with_coalesce_window(Duration::from_millis(window));
+        }
+        if let Some(header) = config.upstream_response_hash_header {
+            proxy_client = proxy_client.with_response_hash_header(header);
+        }
+        if let Some(rate) = config.request_log_sampling_rate {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!("log sampling rate {} is not between 0 and 1", rate));
+            }
+            proxy_client = proxy_client.with_log_sampling_rate(rate);
+        }
+        for prefix in config.log_request_body_for {
+            if !prefix.starts_with('/') {
+                return Err(format!(
+                    "body logging prefix '{}' must start with /",
+                    prefix
+                ));
+            }
+            proxy_client = proxy_client.with_body_logging_for(prefix);
+        }
+        for rule in config.request_body_log_format {
+            proxy_client = proxy_client.with_body_log_format(rule);
+        }
+        if let Some(statuses) = config.upstream_valid_status_range {
+            proxy_client = proxy_client.with_valid_statuses(statuses);
+        }
+        if config.upstream_response_validation_mode == ResponseValidation::Strict {
+            proxy_client = proxy_client.with_strict_response_validation();
+        }
+        if let Some(ms) = config.upstream_latency_sla_warn_ms {
+            proxy_client = proxy_client.with_latency_sla(Duration::from_millis(ms));
+        }
+        if let Some(bytes) = config.upstream_response_size_sla_warn_bytes {
+            proxy_client = proxy_client.with_response_size_sla(bytes);
+        }
+        if config.upstream_happy_path_only {
+            proxy_client = proxy_client
+                .with_happy_path_only(config.upstream_error_status, config.upstream_error_body);
+        }
+        match (config.rate_limit_capacity, config.rate_limit_rps) {
+            (Some(capacity), Some(per_sec)) if per_sec > 0.0 && per_sec.is_finite() => {
+                proxy_client = proxy_client.with_client_rate_limit(
+                    capacity.get(),
+                    per_sec,
+                    config.rate_limit_algorithm,
+                );
+            }
+            (None, None) => {}
+            _ => {
+                return Err(
+                    "a rate limit needs a capacity and a positive rate per second".to_string(),
+                )
+            }
+        }
+        for limit in config.rate_limit_path {
+            proxy_client = proxy_client.with_path_rate_limit(limit);
+        }
+        if let Some(limit) = config.upstream_burst_limit {
+            proxy_client =
+                proxy_client.with_upstream_burst_limit(limit.get(), config.upstream_queue_depth);
+        }
+        if let Some(header) = config.log_correlation_id_header {
+            proxy_client = proxy_client.with_correlation_id_header(header);
+        }
+        if !config.trace_request_headers.is_empty() {
+            proxy_client = proxy_client
+                .with_traced_headers(config.trace_request_headers, config.trace_sensitive_headers);
+        }
+        if config.abort_on_upstream_tls_error {
+            proxy_client = proxy_client.with_abort_on_tls_error();
+        }
+        if config.request_normalize_path_strict {
+            proxy_client = proxy_client.with_path_normalization(PathNormalization::Strict);
+        } else if config.request_normalize_path {
+            proxy_client = proxy_client.with_path_normalization(PathNormalization::Lenient);
+        }
+        proxy_client = proxy_client.with_path_case(config.request_path_case_normalization);
+        if config.upstream_base64_encode_path {
+            proxy_client = proxy_client.with_base64_path();
+        }
+        if let Some(filename) = &config.response_download_header {
+            let mut download = ForcedDownload::new(filename, config.force_download_for)?;
+            if config.force_download_override {
+                download = download.overriding();
+            }
+            proxy_client = proxy_client.with_forced_download(download);
+        }
+        for content_type in config.override_content_type {
+            proxy_client = proxy_client.with_content_type_override(content_type);
+        }
+        if config.upstream_response_minify_json {
+            proxy_client = proxy_client.with_json_minification();
+        }
+        if !config.response_vary_header.is_empty() {
+            proxy_client = proxy_client.with_vary_headers(config.response_vary_header);
+        }
+        if config.debug_headers {
+            proxy_client = proxy_client.with_debug_headers();
+        }
+        if config.upstream_connection_count_header {
+            if !config.debug_headers {
+                return Err("connection count headers require debug headers".to_string());
+            }
+            proxy_client = proxy_client.with_pool_stats_headers();
+        }
+        if config.upstream_idle_connection_check {
+            if config.pool_pre_check {
+                return Err(
+                    "upstream idle connection check and pool pre-check can't both be set"
+                        .to_string(),
+                );
+            }
+            proxy_client = proxy_client.with_idle_connection_check(Duration::from_millis(
+                config.idle_connection_revalidate_after_ms,
+            ));
+        }
+        if config.pool_pre_check {
+            if !config.upstream_pool_health_check_path.starts_with('/') {
+                return Err(format!(
+                    "pool health check path '{}' must start with /",
+                    config.upstream_pool_health_check_path
+                ));
+            }
+            proxy_client = proxy_client.with_pool_pre_check(
+                config.upstream_pool_health_check_path,
+                Duration::from_millis(config.idle_connection_revalidate_after_ms),
+            );
+        }
+        if config.upstream_metadata_header {
+            proxy_client = proxy_client.with_metadata_header(&config_hash);
+        }
+        if config.upstream_send_proxy_info_header {
+            proxy_client = proxy_client.with_proxy_info_header();
+        }
+        if config.upstream_strip_port_from_host_header {
+            proxy_client = proxy_client.with_host_port_stripped();
+        }
+        if !config.allow_request_headers.is_empty() {
+            proxy_client = proxy_client.with_allowed_request_headers(config.allow_request_headers);
+        }
+        if !config.allow_response_headers.is_empty() {
+            proxy_client =
+                proxy_client.with_allowed_response_headers(config.allow_response_headers);
+        }
+        proxy_client = proxy_client
+            .with_stripped_request_headers(config.strip_req_header)
+            .with_stripped_response_headers(config.strip_resp_header);
+        if let Some(prefix) = config.debug_headers_strip_prefix {
+            proxy_client = proxy_client.with_stripped_header_prefix(prefix);
+        }
+        if config.response_add_etag {
+            proxy_client = proxy_client.with_generated_etags();
+        }
+        if config.add_path_segment.len() != config.after_segment.len() {
+            return Err("every path segment to add needs a segment to add it after".to_string());
+        }
+        for (segment, after) in config
+            .add_path_segment
+            .into_iter()
+            .zip(config.after_segment)
+        {
+            proxy_client = proxy_client.with_path_segment(segment, after);
+        }
+        if config.handle_options_locally {
+            proxy_client = proxy_client.with_local_options(&config.allowed_methods);
+        }
+        if let Some(content_type) = config.upstream_expect_content_type {
+            proxy_client = proxy_client.with_expected_content_type(content_type);
+        }
+        if let Some((name, value)) = config.connection_draining_header {
+            proxy_client = proxy_client.with_draining_header(name, value);
+        }
+        if config.allow_get_body {
+            proxy_client = proxy_client.with_get_body();
+        }
+        if config.allow_method_override {
+            proxy_client = proxy_client.with_method_override(config.request_method_override_header);
+        }
+        if config.no_forwarding_headers {
+            proxy_client = proxy_client.without_forwarding_headers();
+        }
+        if !config.health_path.is_empty() {
+            proxy_client = proxy_client.with_health_path(config.health_path);
+        }
+        match config.geoip_db_path {
+            Some(path) => {
+                proxy_client = proxy_client
+                    .with_geoip_db(GeoIpDb::open(&path)?)
+                    .with_geo_block(config.geo_block);
+            }
+            None if !config.geo_block.is_empty() => {
+                return Err("--geo-block needs --geoip-db-path".to_string());
+            }
+            None => {}
+        }
+        if let Some(path) = config.serve_favicon_file {
+            let icon =
+                fs::read(&path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            proxy_client = proxy_client.with_favicon(icon.into());
+        }
+        if config.request_body_encoding_validation {
+            proxy_client = proxy_client.with_json_encoding_validation();
+        }
+        if config.request_body_type_validation {
+            proxy_client = proxy_client.with_body_type_validation(config.body_validation_max_bytes);
+        }
+        for field in config.request_body_transform_remove_fields {
+            proxy_client = proxy_client.with_removed_body_field(field);
+        }
+        if config.request_transform_graphql_operation_name_header {
+            proxy_client = proxy_client.with_graphql_operation_header();
+        }
+        if let Some(limit) = config.body_passthrough_above_bytes {
+            proxy_client = proxy_client.with_body_passthrough_above_bytes(limit);
+        }
+        if let Some(header) = config.body_size_reporting_header {
+            proxy_client = proxy_client.with_body_size_header(header);
+        }
+        if let Some(header) = config.response_body_size_header {
+            proxy_client = proxy_client.with_response_size_header(header);
+        }
+        if let Some(authority) = &config.upstream_authority {
+            // Validated by rewrite_endpoint.
+            let host = HeaderValue::from_str(authority).expect("authority is a valid header value");
+            proxy_client = proxy_client.with_host_header(host);
+        }
+        if let Some(signer) = aws_signer {
+            proxy_client = proxy_client.with_aws_signer(signer);
+        }
+        for route in routes {
+            proxy_client = proxy_client.with_route(route);
+        }
+        for route in version_routes {
+            proxy_client = proxy_client.with_version_route(route);
+        }
+        for route in host_routes {
+            proxy_client = proxy_client.with_host_route(route);
+        }
+        for route in feature_routes {
+            proxy_client = proxy_client.with_feature_route(route);
+        }
+        if let Some(route) = segment_route {
+            proxy_client = proxy_client.with_segment_route(route);
+        }
+        for priority in config.upstream_h2_priority {
+            proxy_client = proxy_client.with_path_priority(priority);
+        }
+        if config.upstream_proxy_protocol.is_some()
+            && config
+                .upstream_protocol
+                .iter()
+                .any(|upstream| upstream.protocol == Protocol::Http2)
+        {
+            return Err(
+                "HTTP/2 upstreams can't be used with --upstream-proxy-protocol".to_string(),
+            );
+        }
+        for upstream in config.upstream_protocol {
+            proxy_client = proxy_client.with_upstream_protocol(upstream);
+        }
+        if let Some(limit) = config.max_req_body {
+            proxy_client = proxy_client.with_max_request_body_bytes(limit);
+        }
+        if let Some(limit) = config.max_resp_body {
+            proxy_client = proxy_client.with_max_response_body_bytes(limit);
+        }
+        proxy_client =
+            proxy_client.with_grpc_max_recv_message_size(config.grpc_max_recv_message_size);
+        if let Some(limit) = config.grpc_max_send_message_size {
+            proxy_client = proxy_client.with_grpc_max_send_message_size(limit);
+        }
+        if config.upstream_grpc_status_mapping {
+            proxy_client = proxy_client.with_grpc_status_mapping();
+        }
+        for auth in config.path_auth {
+            proxy_client = proxy_client.with_path_auth(auth);
+        }
+        if !config.filter.is_empty() {
+            proxy_client = proxy_client.with_filters(config.filter);
+        }
+        if let Some(path) = config.audit_log_file {
+            let log = AuditLog::open(&path)
+                .map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+            proxy_client = proxy_client.with_audit_log(log);
+        }
+        Ok(proxy_client)
+    }
+
+    /// Sends requests under the route's path prefix to its backend instead
+    /// of `forward_addr`. When prefixes overlap the longest one applies.
+    pub fn with_route(mut self, route: RouteEntry) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Sends requests asking for the route's API version in `Accept` to its
+    /// backend, ahead of path routes.
+    pub fn with_version_route(mut self, route: VersionRoute) -> Self {
+        self.version_routes.push(route);
+        self
+    }
+
+    /// Sends requests for the route's virtual host to its backend, ahead of
+    /// version and path routes.
+    pub fn with_host_route(mut self, route: HostRoute) -> Self {
+        self.host_routes.push(route);
+        self
+    }
+
+    /// Sends requests with the route's feature flag header to its backend,
+    /// ahead of host, version and path routes. When several match the first
+    /// one added applies.
+    pub fn with_feature_route(mut self, route: FeatureRoute) -> Self {
+        self.feature_routes.push(route);
+        self
+    }
+
+    /// Sends requests to the backend named by a segment of their path, ahead
+    /// of feature, host, version and path routes.
+    pub fn with_segment_route(mut self, route: SegmentRoute) -> Self {
+        self.segment_route = Some(route);
+        self.build_routed_client();
+        self
+    }
+
+    /// Sends requests under the path prefix with an RFC 9218 `Priority`
+    /// header carrying its urgency. When prefixes overlap the longest one
+    /// applies.
+    pub fn with_path_priority(mut self, priority: PathPriority) -> Self {
+        self.path_priorities.push(priority);
+        self
+    }
+
+    pub fn with_client_options(mut self, options: ClientOptions) -> Self {
+        (self.http_client, self.pool_stats) = client::build(&trusted(&options));
+        self.client_options = options;
+        if self.routed_client.is_some() {
+            self.build_routed_client();
+        }
+        let protocols: Vec<Protocol> = self.protocol_clients.keys().copied().collect();
+        self.protocol_clients.clear();
+        for protocol in protocols {
+            self.build_protocol_client(protocol);
+        }
+        self
+    }
+
+    /// Only speaks the protocol of `upstream` to its host, e.g. HTTP/2 to
+    /// gRPC backends and HTTP/1.1 to legacy ones, rather than negotiating it.
+    pub fn with_upstream_protocol(mut self, upstream: UpstreamProtocol) -> Self {
+        if !self.protocol_clients.contains_key(&upstream.protocol) {
+            self.build_protocol_client(upstream.protocol);
+        }
+        self.upstream_protocols
+            .insert(upstream.host, upstream.protocol);
+        self
+    }
+
+    fn build_protocol_client(&mut self, protocol: Protocol) {
+        let options = ClientOptions {
+            protocol: Some(protocol),
+            ..trusted(&self.client_options)
+        };
+        let client = client::build_with_pool_stats(&options, Arc::clone(&self.pool_stats));
+        self.protocol_clients.insert(protocol, client);
+    }
+
+    fn build_routed_client(&mut self) {
+        self.routed_client = Some(client::build_with_pool_stats(
+            &self.client_options,
+            Arc::clone(&self.pool_stats),
+        ));
+    }
+
+    /// The client for requests to `uri`.
+    fn client_for(&self, uri: &hyper::Uri) -> &HttpClient {
+        uri.host()
+            .and_then(|host| self.upstream_protocols.get(&host.to_ascii_lowercase()))
+            .and_then(|protocol| self.protocol_clients.get(protocol))
+            .unwrap_or(&self.http_client)
+    }
+
+    pub fn with_listener_options(mut self, options: ListenerOptions) -> Self {
+        self.listener_options = options;
+        self
+    }
+
+    pub fn with_pre_connect_hook(mut self, hook: PreConnectHook) -> Self {
+        self.pre_connect_hook = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn with_cookie_filter(mut self, filter: CookieFilter) -> Self {
+        self.cookie_filter = Some(filter);
+        self
+    }
+
+    /// Removes `cookies` from responses unless the client connected over
+    /// TLS, so that browsers don't store them from an insecure channel.
+    pub fn with_insecure_cookies_stripped(mut self, cookies: InsecureCookies) -> Self {
+        self.insecure_cookies = Some(cookies);
+        self
+    }
+
+    /// Reports upstream latency in `header` using the `Server-Timing` syntax.
+    pub fn with_timing_header(mut self, header: HeaderName) -> Self {
+        self.timing_header = Some(header);
+        self
+    }
+
+    /// Authenticates upstream requests with bearer tokens from `source`.
+    pub fn with_token_source(mut self, source: Arc<TokenSource>) -> Self {
+        self.token_source = Some(source);
+        self
+    }
+
+    /// Signs upstream requests with AWS Signature Version 4, setting `Host`
+    /// to the upstream's unless it is replaced with `with_host_header`.
+    /// Request bodies are read in full before forwarding.
+    pub fn with_aws_signer(mut self, signer: AwsSigner) -> Self {
+        self.aws_signer = Some(signer);
+        self
+    }
+
+    /// Answers with 504 when the upstream takes longer than `timeout` to
+    /// respond, 30 seconds by default. Method timeouts take precedence.
+    pub fn with_upstream_timeout(mut self, timeout: Duration) -> Self {
+        self.upstream_timeout = timeout;
+        self
+    }
+
+    /// Answers with 504 when the upstream takes longer than the timeout for
+    /// the request's method to respond.
+    pub fn with_method_timeouts(mut self, timeouts: MethodTimeouts) -> Self {
+        self.method_timeouts = Some(timeouts);
+        self
+    }
+
+    /// Answers with 504 when the upstream takes longer than the timeout's
+    /// to respond to a request for a matching path, ahead of method
+    /// timeouts. Of several matching timeouts, the most specific applies.
+    pub fn with_path_timeout(mut self, timeout: PathTimeout) -> Self {
+        let at = self
+            .path_timeouts
+            .partition_point(|other| other.specificity() >= timeout.specificity());
+        self.path_timeouts.insert(at, timeout);
+        self
+    }
+
+    /// Adds a random delay of up to `max_ms` milliseconds to each upstream
+    /// timeout, whether global, per method or per path, so requests that
+    /// started together don't all time out at once.
+    pub fn with_timeout_jitter_ms(mut self, max_ms: u64) -> Self {
+        self.timeout_jitter_ms = max_ms;
+        self
+    }
+
+    /// Gzips responses larger than `min_bytes` for clients that accept it.
+    pub fn with_gzip_above_bytes(mut self, min_bytes: u64) -> Self {
+        self.gzip_above_bytes = Some(min_bytes);
+        self
+    }
+
+    /// Ends response bodies that yield nothing at all within `timeout`.
+    pub fn with_empty_body_timeout(mut self, timeout: Duration) -> Self {
+        self.empty_body_timeout = Some(timeout);
+        self
+    }
+
+    /// Aborts response bodies that yield nothing for `timeout` after their
+    /// first chunk, closing the connection to the client.
+    pub fn with_stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = Some(timeout);
+        self
+    }
+
+    /// Aborts response bodies that yield fewer than `bytes_per_sec` bytes per
+    /// second over any `window`, closing the connection to the client.
+    pub fn with_min_response_speed(mut self, bytes_per_sec: u64, window: Duration) -> Self {
+        self.min_response_speed = Some((bytes_per_sec, window));
+        self
+    }
+
+    /// Sends identical GET requests arriving within `window` upstream once.
+    pub fn with_coalesce_window(mut self, window: Duration) -> Self {
+        self.coalescer = Some(Arc::new(Coalescer::new(window)));
+        self
+    }
+
+    /// Answers with 502 when an upstream response carrying `header` does not
+    /// match the SHA-256 digest it gives. Such responses are buffered in full.
+    pub fn with_response_hash_header(mut self, header: HeaderName) -> Self {
+        self.response_hash_header = Some(header);
+        self
+    }
+
+    /// Only logs a `rate` fraction of successful requests. Error responses
+    /// and denied requests are always logged.
+    pub fn with_log_sampling_rate(mut self, rate: f64) -> Self {
+        self.log_sampling_rate = Some(rate);
+        self
+    }
+
+    /// Logs the bodies of requests to paths starting with `prefix`, up to
+    /// `LOGGED_BODY_LIMIT` bytes of each.
+    pub fn with_body_logging_for(mut self, prefix: impl Into<String>) -> Self {
+        self.log_body_paths.push(prefix.into());
+        self
+    }
+
+    /// Writes logged bodies in `rule.format`, for bodies of the content type
+    /// `rule` applies to. Bodies no rule applies to are logged as UTF-8.
+    pub fn with_body_log_format(mut self, rule: BodyLogFormatRule) -> Self {
+        self.body_log_formats.push(rule);
+        self
+    }
+
+    /// Answers every invalid upstream response, by default any non-2xx one,
+    /// with `status` and `body` instead, so upstream error details never
+    /// reach clients.
+    pub fn with_happy_path_only(mut self, status: StatusCode, body: String) -> Self {
+        self.upstream_error_response = Some((status, body));
+        self
+    }
+
+    /// Sends at most `per_sec` requests a second upstream across all
+    /// clients. Up to `queue_depth` requests over the limit wait for their
+    /// turn, the rest are answered with 503.
+    pub fn with_upstream_burst_limit(mut self, per_sec: u32, queue_depth: usize) -> Self {
+        self.upstream_rate_limiter = Some(Arc::new(UpstreamRateLimiter::new(per_sec, queue_depth)));
+        self
+    }
+
+    /// Sends at most `limit.per_sec` requests a second upstream for paths
+    /// starting with `limit.path_prefix`, across all clients. Where several
+    /// prefixes match, the longest one's limit applies. Requests over the
+    /// limit are answered with 429 and a `Retry-After`, before their body
+    /// is read.
+    pub fn with_path_rate_limit(mut self, limit: PathRateLimit) -> Self {
+        self.path_rate_limiters.insert(
+            limit.path_prefix,
+            Arc::new(UpstreamRateLimiter::new(limit.per_sec, 0)),
+        );
+        self
+    }
+
+    /// Lets each client IP address send bursts of up to `capacity` requests,
+    /// refilled at `per_sec` requests a second. Requests over the limit are
+    /// answered with 429 and a `Retry-After`.
+    pub fn with_client_rate_limit(
+        mut self,
+        capacity: u32,
+        per_sec: f64,
+        algorithm: RateLimitAlgorithm,
+    ) -> Self {
+        self.client_rate_limiter = Some(Arc::new(ClientRateLimiter::new(
+            capacity, per_sec, algorithm,
+        )));
+        self
+    }
+
+    /// Adds the value of the request's `header` to every log line for the
+    /// request as `correlation_id`.
+    pub fn with_correlation_id_header(mut self, header: HeaderName) -> Self {
+        self.correlation_id_header = Some(header);
+        self
+    }
+
+    /// Adds the values of the request's `headers` to every log line for the
+    /// request as `headers`. Values of sensitive headers are redacted unless
+    /// `include_sensitive` is set.
+    pub fn with_traced_headers(
+        mut self,
+        headers: Vec<HeaderName>,
+        include_sensitive: bool,
+    ) -> Self {
+        self.traced_headers = headers;
+        self.trace_sensitive_headers = include_sensitive;
+        self
+    }
+
+    /// Closes the client connection without a response when the upstream
+    /// request fails with a TLS error, rather than answering with 502.
+    pub fn with_abort_on_tls_error(mut self) -> Self {
+        self.abort_on_tls_error = true;
+        self
+    }
+
+    /// Removes repeated slashes and `.` and `..` components from request
+    /// paths before forwarding them.
+    pub fn with_path_normalization(mut self, normalization: PathNormalization) -> Self {
+        self.path_normalization = Some(normalization);
+        self
+    }
+
+    /// Converts request paths, but not their query, to `case` before
+    /// forwarding them.
+    pub fn with_path_case(mut self, case: PathCase) -> Self {
+        self.path_case = case;
+        self
+    }
+
+    /// Sends the path and query of requests upstream base64url-encoded as a
+    /// single path segment, after any other changes to the path.
+    pub fn with_base64_path(mut self) -> Self {
+        self.base64_path = true;
+        self
+    }
+
+    /// Adds `headers` to the `Vary` header of every response, merging them
+    /// with the upstream's.
+    pub fn with_vary_headers(mut self, headers: Vec<HeaderName>) -> Self {
+        self.vary_headers = headers;
+        self
+    }
+
+    /// Adds `X-Proxy-*` headers with the upstream URL, upstream latency, a
+    /// request ID and the proxy version to every response.
+    pub fn with_debug_headers(mut self) -> Self {
+        self.debug_headers = true;
+        self
+    }
+
+    /// Adds the number of active and idle connections to the upstream that
+    /// served the response to the debug headers.
+    pub fn with_pool_stats_headers(mut self) -> Self {
+        self.pool_stats_headers = true;
+        self
+    }
+
+    /// Sends `OPTIONS *` before a request that would reuse a pooled
+    /// connection idle for at least `after`, so that a connection dropped
+    /// while idle is discarded by the check rather than failing the request.
+    /// Best-effort, like `with_pool_pre_check`.
+    pub fn with_idle_connection_check(mut self, after: Duration) -> Self {
+        self.idle_check = Some(IdleCheck {
+            method: Method::OPTIONS,
+            path: "*".to_string(),
+            after,
+        });
+        self
+    }
+
+    /// Sends `HEAD <path>` before a request that would reuse a pooled
+    /// connection idle for at least `after`, so that a connection dropped
+    /// while idle is discarded by the check rather than failing the request.
+    ///
+    /// The check is best-effort: the checked connection isn't reserved, so
+    /// under concurrency another request may take it first, and this one
+    /// goes over another connection.
+    pub fn with_pool_pre_check(mut self, path: String, after: Duration) -> Self {
+        self.idle_check = Some(IdleCheck {
+            method: Method::HEAD,
+            path,
+            after,
+        });
+        self
+    }
+
+    /// Removes response headers whose name starts with `prefix`.
+    pub fn with_stripped_header_prefix(mut self, prefix: String) -> Self {
+        self.strip_header_prefix = Some(prefix);
+        self
+    }
+
+    /// Removes the `names` headers from requests before they are sent
+    /// upstream.
+    pub fn with_stripped_request_headers(
+        mut self,
+        names: impl IntoIterator<Item = HeaderName>,
+    ) -> Self {
+        self.strip_request_headers.extend(names);
+        self
+    }
+
+    /// Sends only the `names` headers of requests upstream, dropping every
+    /// other header the client sent. Headers the proxy adds itself, such as
+    /// `X-Forwarded-For`, are still sent.
+    pub fn with_allowed_request_headers(
+        mut self,
+        names: impl IntoIterator<Item = HeaderName>,
+    ) -> Self {
+        self.allowed_request_headers = Some(names.into_iter().collect());
+        self
+    }
+
+    /// Returns only the `names` headers of upstream responses, dropping
+    /// every other header the upstream sent. Headers the proxy adds itself,
+    /// such as the debug headers, are still returned.
+    pub fn with_allowed_response_headers(
+        mut self,
+        names: impl IntoIterator<Item = HeaderName>,
+    ) -> Self {
+        self.allowed_response_headers = Some(names.into_iter().collect());
+        self
+    }
+
+    /// Removes the `names` headers from responses before they are returned.
+    pub fn with_stripped_response_headers(
+        mut self,
+        names: impl IntoIterator<Item = HeaderName>,
+    ) -> Self {
+        self.strip_response_headers.extend(names);
+        self
+    }
+
+    /// Adds an `ETag` to successful GET responses that lack one, answering
+    /// 304 when it matches the request's `If-None-Match`. Such responses are
+    /// buffered in full.
+    pub fn with_generated_etags(mut self) -> Self {
+        self.add_etag = true;
+        self
+    }
+
+    /// Inserts `segment` into request paths after their first `after`
+    /// segments. Insertions are applied in the order they are added.
+    pub fn with_path_segment(mut self, segment: String, after: usize) -> Self {
+        self.path_segments.push(SegmentInsertion { segment, after });
+        self
+    }
+
+    /// Logs an error for upstream responses whose status is not in
+    /// `statuses`, and treats only those as valid instead of any 2xx status.
+    pub fn with_valid_statuses(mut self, statuses: StatusRanges) -> Self {
+        self.valid_statuses = Some(statuses);
+        self
+    }
+
+    /// Answers with 502 in place of upstream responses with a status outside
+    /// 100-599, several `Content-Length` headers, both `Content-Length` and
+    /// `Transfer-Encoding`, or header values that aren't visible ASCII.
+    pub fn with_strict_response_validation(mut self) -> Self {
+        self.strict_response_validation = true;
+        self
+    }
+
+    /// Logs a warning, and records an SLA violation in the metrics, for
+    /// every upstream response whose headers take longer than `sla`.
+    pub fn with_latency_sla(mut self, sla: Duration) -> Self {
+        self.latency_sla = Some(sla);
+        self
+    }
+
+    /// Logs a warning for every upstream response body larger than `bytes`,
+    /// as soon as `Content-Length` declares it or otherwise once it's read.
+    pub fn with_response_size_sla(mut self, bytes: u64) -> Self {
+        self.response_size_sla = Some(bytes);
+        self
+    }
+
+    /// Answers OPTIONS requests with 200 and an `Allow` header listing
+    /// `methods` instead of forwarding them.
+    pub fn with_local_options(mut self, methods: &[Method]) -> Self {
+        let allow = methods
+            .iter()
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.local_options_allow =
+            Some(HeaderValue::from_str(&allow).expect("methods are valid header values"));
+        self
+    }
+
+    /// Answers with 403 to requests whose path matches `blocklist`.
+    pub fn with_path_blocklist(mut self, blocklist: Arc<PathBlocklist>) -> Self {
+        self.path_blocklist = Some(blocklist);
+        self
+    }
+
+    /// Records the decisions of the filter rules, path blocklist and
+    /// pre-connect hook in `log`.
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.audit_log = Some(Arc::new(log));
+        self
+    }
+
+    /// Evaluates `rules` in order before forwarding each request, the first
+    /// rule matching it decides whether it's forwarded, blocked or
+    /// redirected.
+    pub fn with_filters(mut self, rules: Vec<FilterRule>) -> Self {
+        self.filters = Some(FilterChain::new(rules));
+        self
+    }
+
+    /// Answers with 502 when an upstream response's media type isn't
+    /// `content_type`, e.g. an HTML error page when JSON is expected.
+    /// Parameters such as `charset` are ignored.
+    pub fn with_expected_content_type(mut self, content_type: String) -> Self {
+        self.expected_content_type = Some(content_type);
+        self
+    }
+
+    /// Adds `name: value` to every upstream request sent once the proxy is
+    /// draining, so upstreams can start closing their own connections.
+    pub fn with_draining_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.draining_header = Some((name, value));
+        self
+    }
+
+    /// Forwards the bodies of GET requests, which are dropped by default.
+    pub fn with_get_body(mut self) -> Self {
+        self.allow_get_body = true;
+        self
+    }
+
+    /// Forwards POST requests with the method given in `header`, e.g.
+    /// `X-HTTP-Method-Override: DELETE`, for clients that can only send GET
+    /// and POST.
+    pub fn with_method_override(mut self, header: HeaderName) -> Self {
+        self.method_override_header = Some(header);
+        self
+    }
+
+    /// Sends requests upstream without adding `X-Forwarded-For`,
+    /// `X-Forwarded-Host`, `X-Forwarded-Proto` and `Via`, for when a layer in
+    /// front of the proxy already does.
+    pub fn without_forwarding_headers(mut self) -> Self {
+        self.forwarding_headers = false;
+        self
+    }
+
+    /// Answers with 401 to requests under the path prefix of `auth` that
+    /// don't carry its credentials. The credentials are not sent upstream.
+    /// When prefixes overlap the longest one applies.
+    pub fn with_path_auth(mut self, auth: PathAuth) -> Self {
+        self.path_auths.push(auth);
+        self
+    }
+
+    /// Answers requests for `path` with 200 and the proxy's version instead
+    /// of forwarding them, for orchestrators probing the proxy itself.
+    pub fn with_health_path(mut self, path: String) -> Self {
+        self.health_path = Some(path);
+        self
+    }
+
+    /// Looks up the country of clients in `db`, logging it with each request.
+    pub fn with_geoip_db(mut self, db: GeoIpDb) -> Self {
+        self.geoip = Some(Arc::new(db));
+        self
+    }
+
+    /// Answers with 403 to requests from clients in `countries`, ISO 3166-1
+    /// codes looked up in the database given to `with_geoip_db`.
+    pub fn with_geo_block(mut self, countries: impl IntoIterator<Item = String>) -> Self {
+        self.blocked_countries.extend(
+            countries
+                .into_iter()
+                .map(|country| country.to_ascii_uppercase()),
+        );
+        self
+    }
+
+    /// Answers GET and HEAD requests for `/favicon.ico` with `icon`, an ICO
+    /// or PNG image, instead of forwarding them.
+    pub fn with_favicon(mut self, icon: Bytes) -> Self {
+        self.favicon = Some(icon);
+        self
+    }
+
+    /// Answers with 400 to requests with an `application/json` body that
+    /// isn't valid UTF-8. Such bodies are read in full before forwarding.
+    pub fn with_json_encoding_validation(mut self) -> Self {
+        self.validate_json_encoding = true;
+        self
+    }
+
+    /// Answers with 400 to requests with a malformed JSON or URL-encoded form
+    /// body. Bodies up to `max_bytes` long are read in full before
+    /// forwarding, longer ones are forwarded unchecked.
+    pub fn with_body_type_validation(mut self, max_bytes: usize) -> Self {
+        self.body_validation_max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Removes members named `field`, at any depth, from JSON request bodies.
+    /// Such bodies are read in full before forwarding, and answered with 400
+    /// if they aren't valid JSON.
+    pub fn with_removed_body_field(mut self, field: impl Into<String>) -> Self {
+        self.removed_body_fields.insert(field.into());
+        self
+    }
+
+    /// Sends the `operationName` of GraphQL requests POSTed as JSON upstream
+    /// in `X-GraphQL-Operation-Name`, replacing any sent by the client, and
+    /// matches path routes against the path followed by the operation name,
+    /// e.g. `/graphql/GetUser`. Such bodies are read in full before
+    /// forwarding.
+    pub fn with_graphql_operation_header(mut self) -> Self {
+        self.graphql_operation_header = true;
+        self
+    }
+
+    /// Forwards request bodies larger than `limit` bytes as they are, without
+    /// the validation, transformation, logging or size reporting that read
+    /// bodies in full. Bodies of unknown length are read up to `limit` to
+    /// tell.
+    pub fn with_body_passthrough_above_bytes(mut self, limit: u64) -> Self {
+        self.body_passthrough_above_bytes = Some(limit);
+        self
+    }
+
+    /// Whether anything reads request bodies in full before forwarding them.
+    fn reads_request_bodies(&self) -> bool {
+        self.validate_json_encoding
+            || self.body_validation_max_bytes.is_some()
+            || !self.removed_body_fields.is_empty()
+            || self.graphql_operation_header
+            || self.body_size_header.is_some()
+            || !self.log_body_paths.is_empty()
+    }
+
+    /// Reports the size of request bodies to upstreams in `header`, e.g.
+    /// `X-Request-Body-Bytes: 512`, replacing any sent by the client. Bodies
+    /// are read in full before forwarding. The size of response bodies is
+    /// logged once they have been sent.
+    pub fn with_body_size_header(mut self, header: HeaderName) -> Self {
+        self.body_size_header = Some(header);
+        self
+    }
+
+    /// Reports the size of response bodies to clients in `header`, e.g.
+    /// `X-Response-Size: 512`. When the size isn't known up front, it is
+    /// sent in a trailer to HTTP/2 clients. The size is logged once the body
+    /// has been sent.
+    pub fn with_response_size_header(mut self, header: HeaderName) -> Self {
+        self.response_size_header = Some(header);
+        self
+    }
+
+    /// Adds `X-Proxy-Meta: version=<version>;config-hash=<config_hash>` to
+    /// upstream requests, so upstreams can tell proxy deployments apart.
+    pub fn with_metadata_header(mut self, config_hash: &str) -> Self {
+        let meta = format!(
+            "version={};config-hash={}",
+            env!("CARGO_PKG_VERSION"),
+            config_hash
+        );
+        self.metadata_header =
+            Some(HeaderValue::from_str(&meta).expect("config hash is a valid header value"));
+        self
+    }
+
+    /// Adds `X-Forwarded-By: proxy-filter/<version>` to upstream requests,
+    /// replacing any sent by the client.
+    pub fn with_proxy_info_header(mut self) -> Self {
+        self.proxy_info_header = true;
+        self
+    }
+
+    /// Counts requests and upstream errors and observes request durations in
+    /// `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<MetricsRecorder>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Sends `host` as the `Host` header of upstream requests instead of
+    /// the client's.
+    pub fn with_host_header(mut self, host: HeaderValue) -> Self {
+        self.host_header = Some(host);
+        self
+    }
+
+    /// Drops the port from the `Host` header of upstream requests, filling
+    /// it in from the upstream URL when the client sent none.
+    pub fn with_host_port_stripped(mut self) -> Self {
+        self.strip_host_port = true;
+        self
+    }
+
+    /// Answers with 413 when a request body is larger than `limit` bytes.
+    /// Bodies without a `Content-Length` are counted as they are forwarded.
+    pub fn with_max_request_body_bytes(mut self, limit: u64) -> Self {
+        self.max_request_body_bytes = Some(limit);
+        self
+    }
+
+    /// Answers with 502 when an upstream response declares a body larger
+    /// than `limit` bytes. Bodies without a `Content-Length` are counted as
+    /// they are streamed and the connection is closed once one goes over.
+    pub fn with_max_response_body_bytes(mut self, limit: u64) -> Self {
+        self.max_response_body_bytes = Some(limit);
+        self
+    }
+
+    /// Answers gRPC requests with a message longer than `limit` bytes with
+    /// `RESOURCE_EXHAUSTED`.
+    pub fn with_grpc_max_recv_message_size(mut self, limit: u64) -> Self {
+        self.grpc_max_recv_message_size = Some(limit);
+        self
+    }
+
+    /// Ends gRPC calls with `RESOURCE_EXHAUSTED` once the upstream responds
+    /// with a message longer than `limit` bytes.
+    pub fn with_grpc_max_send_message_size(mut self, limit: u64) -> Self {
+        self.grpc_max_send_message_size = Some(limit);
+        self
+    }
+
+    /// Logs the `grpc-status` of gRPC responses with its HTTP equivalent,
+    /// also recording both in the metrics.
+    pub fn with_grpc_status_mapping(mut self) -> Self {
+        self.grpc_status_mapping = true;
+        self
+    }
+
+    /// Sets `Content-Disposition: attachment` on responses of the download's
+    /// media types.
+    pub fn with_forced_download(mut self, download: ForcedDownload) -> Self {
+        self.forced_download = Some(download);
+        self
+    }
+
+    /// Replaces a missing or generic `Content-Type`, `text/plain` or
+    /// `application/octet-stream`, on responses to requests for paths with
+    /// the override's extension.
+    pub fn with_content_type_override(mut self, content_type: ContentTypeOverride) -> Self {
+        self.content_type_overrides.push(content_type);
+        self
+    }
+
+    /// Removes the whitespace from uncompressed `application/json` response
+    /// bodies, which are read in full to do so.
+    pub fn with_json_minification(mut self) -> Self {
+        self.minify_json = true;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn listener_options(&self) -> &ListenerOptions {
+        &self.listener_options
+    }
+
+    /// The number of requests currently being handled.
+    pub fn in_flight(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.in_flight)
+    }
+
+    /// The number of connections open to the upstream, in use or idle.
+    pub fn open_connections(&self) -> usize {
+        self.pool_stats.open()
+    }
+
+    /// Sends `connections` concurrent HEAD requests to the upstream so the
+    /// connection pool holds that many connections before real traffic
+    /// arrives. Upstreams speaking HTTP/2 share a single connection.
+    pub fn warm_up(&self, connections: usize) -> impl Future<Output = ()> + Send + 'static {
+        let uri = self.forward_addr.parse::<hyper::Uri>();
+        let client = match &uri {
+            Ok(uri) => self.client_for(uri),
+            Err(_) => &self.http_client,
+        }
+        .clone();
+        let pool_stats = Arc::clone(&self.pool_stats);
+        let forward_addr = self.forward_addr.clone();
+        async move {
+            let uri = match uri {
+                Ok(uri) => uri,
+                Err(e) => {
+                    tracing::warn!("Not warming up connections to {}: {}", forward_addr, e);
+                    return;
+                }
+            };
+            tracing::info!(
+                "Warming up {} connections to {}, pool size {}",
+                connections,
+                uri,
+                pool_stats.open()
+            );
+            send_heads(&client, &uri, connections).await;
+            tracing::info!(
+                "Warmed up connections to {}, pool size {}",
+                uri,
+                pool_stats.open()
+            );
+        }
+    }
+
+    /// Opens a new connection to each upstream whose newest connection is
+    /// three quarters of the way to the maximum connection age, so one is
+    /// ready once the older ones are closed. Does nothing without a maximum
+    /// age.
+    pub async fn refresh_connections(&self) {
+        let max_age = match self.client_options.max_connection_age {
+            Some(max_age) => max_age,
+            None => return,
+        };
+        for (uri, stats) in self.pool_stats.aging_hosts(max_age * 3 / 4, max_age) {
+            tracing::debug!(
+                "Opening a connection to {} before its {} open expire",
+                uri,
+                stats.open
+            );
+            // Every idle connection is taken first, so the last request
+            // needs a new one.
+            send_heads(self.client_for(&uri), &uri, stats.idle() + 1).await;
+        }
+    }
+
+    /// Set when the proxy starts shutting down.
+    pub fn draining(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.draining)
+    }
+}
+
+/// Sends `count` concurrent HEAD requests to `uri`, releasing each
+/// connection as soon as its response arrives.
+async fn send_heads(client: &HttpClient, uri: &hyper::Uri, count: usize) {
+    let requests = (0..count).map(|_| {
+        let req = Request::head(uri.clone())
+            .body(body::boxed(Body::empty()))
+            .expect("HEAD request");
+        client.request(req)
+    });
+    for result in futures::future::join_all(requests).await {
+        match result {
+            Ok(response) => drop(connector::release_after(response)),
+            Err(e) => tracing::warn!("HEAD request to {} failed: {}", uri, e),
+        }
+    }
+}
+
+/// Counts a request as in flight until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn start(counter: &Arc<AtomicUsize>) -> InFlight {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight(Arc::clone(counter))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves a `ProxyClient` on its address.
+pub struct ServerBuilder {
+    proxy: ReloadHandle,
+    shutdown: Option<BoxFuture<'static, ()>>,
+    workers: Option<WorkerPool>,
+}
+
+impl ServerBuilder {
+    pub fn new(proxy_client: ProxyClient) -> ServerBuilder {
+        ServerBuilder {
+            proxy: ReloadHandle(Arc::new(RwLock::new(Arc::new(proxy_client)))),
+            shutdown: None,
+            workers: None,
+        }
+    }
+
+    /// A handle to replace the proxy's client while it serves.
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.proxy.clone()
+    }
+
+    /// Stops accepting connections once `signal` completes, `serve` then
+    /// returns when the open connections are done.
+    pub fn with_graceful_shutdown(
+        mut self,
+        signal: impl Future<Output = ()> + Send + 'static,
+    ) -> Self {
+        self.shutdown = Some(Box::pin(signal));
+        self
+    }
+
+    /// Serves on the worker threads of `workers` instead of on the runtime
+    /// `serve` is awaited on. Each worker binds its own listening socket,
+    /// so the listener options should have `reuse_port` set.
+    pub fn with_workers(mut self, workers: WorkerPool) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    /// Accepts connections and proxies their requests until shut down.
+    ///
+    /// # Panics
+    ///
+    /// If the proxy's address can't be bound.
+    pub async fn serve(self) -> Result<(), hyper::Error> {
+        let workers = match self.workers {
+            Some(workers) => workers,
+            None => return serve_connections(self.proxy, self.shutdown).await,
+        };
+        let proxy = self.proxy;
+        // Every worker stops accepting once the one shutdown signal fires.
+        let (stop_tx, stop_rx) = tokio::sync::watch::channel(false);
+        let graceful = self.shutdown.is_some();
+        if let Some(signal) = self.shutdown {
+            tokio::spawn(async move {
+                signal.await;
+                let _ = stop_tx.send(true);
+            });
+        }
+        workers
+            .run(move |_| {
+                let mut stop = stop_rx.clone();
+                let shutdown = graceful.then(|| -> BoxFuture<'static, ()> {
+                    Box::pin(async move {
+                        let _ = stop.wait_for(|stop| *stop).await;
+                    })
+                });
+                serve_connections(proxy.clone(), shutdown)
+            })
+            .await
+    }
+}
+
+/// Accepts connections on the proxy's address and serves them on the
+/// current runtime until `shutdown` completes, if given.
+async fn serve_connections(
+    proxy: ReloadHandle,
+    shutdown: Option<BoxFuture<'static, ()>>,
+) -> Result<(), hyper::Error> {
+    let proxy_client = proxy.current();
+    let proxy_addr = proxy_client.addr();
+    let incoming = Incoming::bind(&proxy_addr, proxy_client.listener_options().clone())
+        .unwrap_or_else(|e| panic!("error binding to {}: {}", proxy_addr, e));
+    let new_service = make_service_fn(move |conn: &ClientStream| {
+        let proxy_client = proxy.current();
+        let client_certs = proxy_client
+            .listener_options()
+            .tls
+            .as_ref()
+            .is_some_and(TlsAcceptor::requests_client_certs);
+        let remote_addr = conn.remote_addr();
+        if let (Some(metrics), Some(resumed)) = (&proxy_client.metrics, conn.tls_session_reused()) {
+            metrics.record_tls_handshake(resumed);
+        }
+        let client_cert_subject = conn
+            .client_cert_subject()
+            .and_then(|subject| HeaderValue::from_str(&subject).ok());
+        let svc = service_fn(move |mut req: Request<Body>| {
+            if client_certs {
+                let headers = req.headers_mut();
+                headers.remove(X_CLIENT_CERT_SUBJECT);
+                if let Some(subject) = &client_cert_subject {
+                    headers.insert(X_CLIENT_CERT_SUBJECT, subject.clone());
+                }
+            }
+            // Clone again to ensure that client outlives this closure.
+            let proxy_client = Arc::clone(&proxy_client);
+            handle(req, proxy_client, remote_addr)
+        });
+        async move { Ok::<_, Infallible>(svc) }
+    });
+    let server = Server::builder(incoming)
+        .http2_max_frame_size(proxy_client.listener_options().h2_max_frame_size)
+        .serve(new_service);
+    match shutdown {
+        Some(signal) => server.with_graceful_shutdown(signal).await,
+        None => server.await,
+    }
+}
+
+/// Shares the client of a server so it can be replaced while serving. Each
+/// connection is served by the client that was current when it was accepted.
+#[derive(Clone)]
+pub struct ReloadHandle(Arc<RwLock<Arc<ProxyClient>>>);
+
+impl ReloadHandle {
+    /// The client new connections are served by.
+    pub fn current(&self) -> Arc<ProxyClient> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+
+    /// Replaces the client with one built from `config`, with its TLS
+    /// certificate read again. The runtime parts `ProxyClient::from_config`
+    /// leaves out carry over, and so do the listen address and the other
+    /// listener settings, which only change on restart. Rate limits whose
+    /// settings are unchanged keep counting where they were.
+    pub fn reload(&self, config: Config) -> Result<(), String> {
+        let current = self.current();
+        let mut proxy_client = ProxyClient::from_config(config)?;
+        let tls = match (
+            &current.listener_options.tls,
+            proxy_client.listener_options.tls.take(),
+        ) {
+            // The listener holds a clone of the current acceptor.
+            (Some(bound), Some(tls)) => {
+                bound.replace(&tls);
+                Some(bound.clone())
+            }
+            (None, None) => None,
+            _ => return Err("turning TLS on or off needs a restart".to_string()),
+        };
+        proxy_client.addr = current.addr;
+        proxy_client.listener_options = ListenerOptions {
+            tls,
+            ..current.listener_options.clone()
+        };
+        proxy_client.token_source = current.token_source.clone();
+        proxy_client.metrics = current.metrics.clone();
+        proxy_client.path_blocklist = current.path_blocklist.clone();
+        // Limiters with unchanged settings keep what they have counted, so
+        // a reload doesn't hand every client a fresh burst.
+        if let (Some(limiter), Some(new)) = (
+            &current.upstream_rate_limiter,
+            &proxy_client.upstream_rate_limiter,
+        ) {
+            if limiter.same_limit(new) {
+                proxy_client.upstream_rate_limiter = Some(limiter.clone());
+            }
+        }
+        for (prefix, new) in proxy_client.path_rate_limiters.iter_mut() {
+            match current.path_rate_limiters.get(prefix) {
+                Some(limiter) if limiter.same_limit(new) => *new = limiter.clone(),
+                _ => {}
+            }
+        }
+        if let (Some(limiter), Some(new)) = (
+            &current.client_rate_limiter,
+            &proxy_client.client_rate_limiter,
+        ) {
+            if limiter.same_limit(new) {
+                proxy_client.client_rate_limiter = Some(limiter.clone());
+            }
+        }
+        proxy_client.in_flight = current.in_flight();
+        proxy_client.draining = current.draining();
+        *self.0.write().unwrap() = Arc::new(proxy_client);
+        Ok(())
+    }
+
+    /// Runs `ProxyClient::refresh_connections` on the current client every
+    /// eighth of the maximum connection age, for as long as the proxy runs.
+    pub async fn refresh_connections(self) {
+        loop {
+            let proxy = self.current();
+            let interval = proxy
+                .client_options
+                .max_connection_age
+                .map_or(Duration::from_secs(1), |max_age| max_age / 8);
+            proxy.refresh_connections().await;
+            drop(proxy);
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[deprecated(note = "use `ServerBuilder::new(proxy_client).serve()`")]
+#[macro_export]
+macro_rules! new {
+    ($e:expr) => {
+        $crate::server::ServerBuilder::new($e).serve()
+    };
+}
+
+#[allow(deprecated)]
+pub use new;
+
+/// Headers whose values are redacted from traces unless asked for.
+const SENSITIVE_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+];
+
+/// Formats the `names` headers of `headers` as `name=value` pairs.
+fn traced_headers(headers: &HeaderMap, names: &[HeaderName], include_sensitive: bool) -> String {
+    names
+        .iter()
+        .flat_map(|name| {
+            headers.get_all(name).iter().map(move |value| {
+                if !include_sensitive && SENSITIVE_HEADERS.contains(&name.as_str()) {
+                    format!("{}=[REDACTED]", name)
+                } else {
+                    format!("{}={}", name, value.to_str().unwrap_or("NO VALUE"))
+                }
+            })
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn audit(
+    proxy: &ProxyClient,
+    req: &Request<Body>,
+    remote_addr: SocketAddr,
+    result: Decision,
+    decided_by: &str,
+) {
+    if let Some(log) = &proxy.audit_log {
+        log.record(
+            remote_addr.ip(),
+            req.method(),
+            req.uri().path(),
+            req.headers(),
+            result,
+            decided_by,
+        );
+    }
+}
+
+/// Compares the media type of a `Content-Type` value, ignoring parameters
+/// and case.
+pub(crate) fn media_type_matches(content_type: &str, expected: &str) -> bool {
+    let media_type = |value: &str| value.split(';').next().unwrap_or("").trim().to_string();
+    media_type(content_type).eq_ignore_ascii_case(&media_type(expected))
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// `options` without denied address ranges, for clients of configured
+/// backends.
+fn trusted(options: &ClientOptions) -> ClientOptions {
+    ClientOptions {
+        denied_ip_ranges: Vec::new(),
+        ..options.clone()
+    }
+}
+
+/// A request sent over a pooled connection idle for at least `after` before
+/// a request reuses it.
+#[derive(Clone, Debug)]
+struct IdleCheck {
+    method: Method,
+    path: String,
+    after: Duration,
+}
+
+/// Checks that the idle pooled connection a request to `upstream_uri` would
+/// reuse is still alive with `check`. A connection that fails the check is
+/// closed by the client, so the request gets another one.
+async fn pre_check(
+    client: &HttpClient,
+    upstream_uri: &hyper::Uri,
+    check: &IdleCheck,
+    timeout: Duration,
+) {
+    let mut parts = upstream_uri.clone().into_parts();
+    parts.path_and_query = check.path.parse().ok();
+    let uri = match hyper::Uri::from_parts(parts) {
+        Ok(uri) => uri,
+        Err(e) => {
+            tracing::warn!("Not checking connection to {}: {}", upstream_uri, e);
+            return;
+        }
+    };
+    let req = Request::builder()
+        .method(check.method.clone())
+        .uri(uri.clone())
+        .body(body::boxed(Body::empty()))
+        .expect("check request");
+    let check = client.request(req);
+    match tokio::time::timeout(timeout, check).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => tracing::info!("Pooled connection check {} failed: {}", uri, e),
+        Err(_) => tracing::info!("Pooled connection check {} timed out", uri),
+    }
+}
+
+/// Most bytes of a request body logged for `--log-request-body-for`.
+const LOGGED_BODY_LIMIT: usize = 4096;
+
+/// Reads the whole request `body`.
+async fn read_request_body(
+    proxy: &ProxyClient,
+    body: BoxBody,
+    remote_addr: SocketAddr,
+) -> Result<Bytes, ProxyError> {
+    hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| request_body_error(proxy, e, remote_addr))
+}
+
+fn request_body_error(
+    proxy: &ProxyClient,
+    e: Box<dyn Error + Send + Sync>,
+    remote_addr: SocketAddr,
+) -> ProxyError {
+    tracing::info!("Failed to read request body from {}: {}", remote_addr, e);
+    match proxy
+        .max_request_body_bytes
+        .filter(|_| body::is_too_large(e.as_ref()))
+    {
+        Some(limit) => ProxyError::RequestBodyTooLarge { limit },
+        None => ProxyError::BadRequest,
+    }
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_PROXY_META: &str = "x-proxy-meta";
+const X_FORWARDED_BY: &str = "x-forwarded-by";
+const X_GRAPHQL_OPERATION_NAME: &str = "x-graphql-operation-name";
+const X_CLIENT_CERT_SUBJECT: &str = "x-client-cert-subject";
+const PROXY_INFO: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Replaces the method of a POST `req` with the one in its `header`, removing
+/// the header. Returns the new method, or an error if it isn't valid.
+fn override_method<B>(req: &mut Request<B>, header: &HeaderName) -> Result<Option<Method>, String> {
+    if req.method() != Method::POST {
+        return Ok(None);
+    }
+    let method = match req.headers_mut().remove(header) {
+        Some(value) => Method::from_bytes(value.as_bytes()).map_err(|_| {
+            format!(
+                "invalid {} '{}'",
+                header,
+                String::from_utf8_lossy(value.as_bytes())
+            )
+        })?,
+        None => return Ok(None),
+    };
+    *req.method_mut() = method.clone();
+    Ok(Some(method))
+}
+
+/// The host of `authority` without its port, keeping the brackets of IPv6
+/// addresses.
+fn host_without_port(authority: &str) -> Option<HeaderValue> {
+    let authority = authority.parse::<Authority>().ok()?;
+    HeaderValue::from_str(authority.host()).ok()
+}
+
+/// Adds the client's address to `X-Forwarded-For` and the proxy to `Via` in
+/// the headers sent upstream for `req`, and sets `X-Forwarded-Host` and
+/// `X-Forwarded-Proto` to the host and `scheme` it was received with.
+fn inject_forwarding_headers<B>(
+    headers: &mut HeaderMap,
+    req: &Request<B>,
+    remote_addr: SocketAddr,
+    scheme: &'static str,
+) {
+    let client_ip = remote_addr.ip().to_string();
+    let forwarded_for = req
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .chain(std::iter::once(client_ip.as_str()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(forwarded_for) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, forwarded_for);
+    }
+
+    let host = req.headers().get(HOST).cloned().or_else(|| {
+        req.uri()
+            .authority()
+            .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+    });
+    if let Some(host) = host {
+        headers.insert(X_FORWARDED_HOST, host);
+    }
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(scheme));
+
+    let protocol = match req.version() {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    let via = req
+        .headers()
+        .get_all(VIA)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(String::from)
+        .chain(std::iter::once(format!("{} proxy-filter", protocol)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(via) = HeaderValue::from_str(&via) {
+        headers.insert(VIA, via);
+    }
+}
+
+fn health_response() -> Response<Body> {
+    let body = serde_json::json!({ "status": "ok", "version": env!("CARGO_PKG_VERSION") });
+    let mut response = Response::new(Body::from(body.to_string()));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+fn favicon_response(icon: &Bytes, head: bool) -> Response<Body> {
+    const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    let content_type = if icon.starts_with(PNG_SIGNATURE) {
+        "image/png"
+    } else {
+        "image/x-icon"
+    };
+    let body = if head {
+        Body::empty()
+    } else {
+        Body::from(icon.clone())
+    };
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(icon.len()));
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=86400"),
+    );
+    response
+}
+
+pub async fn handle(
+    req: Request<Body>,
+    proxy: Arc<ProxyClient>,
+    remote_addr: SocketAddr,
+) -> Result<Response<BoxBody>, Arc<hyper::Error>> {
+    let span = tracing::info_span!(
+        "request",
+        correlation_id = tracing::field::Empty,
+        headers = tracing::field::Empty,
+        country = tracing::field::Empty
+    );
+    if let Some(correlation_id) = proxy
+        .correlation_id_header
+        .as_ref()
+        .and_then(|header| req.headers().get(header))
+        .and_then(|value| value.to_str().ok())
+    {
+        span.record("correlation_id", &correlation_id);
+    }
+    if !proxy.traced_headers.is_empty() {
+        let headers = traced_headers(
+            req.headers(),
+            &proxy.traced_headers,
+            proxy.trace_sensitive_headers,
+        );
+        span.record("headers", &headers.as_str());
+    }
+    let country = proxy
+        .geoip
+        .as_ref()
+        .and_then(|db| db.country(remote_addr.ip()));
+    if let Some(country) = &country {
+        span.record("country", &country.as_str());
+    }
+    let started = Instant::now();
+    let method = req.method().clone();
+    let metrics = proxy.metrics.clone();
+    let result = match country.filter(|country| proxy.blocked_countries.contains(country)) {
+        Some(country) => {
+            span.in_scope(|| {
+                tracing::info!(
+                    "Blocking {} {} from {} in {}",
+                    req.method(),
+                    req.uri(),
+                    remote_addr,
+                    country
+                )
+            });
+            audit(&proxy, &req, remote_addr, Decision::Denied, "geo_block");
+            Ok(status_response(StatusCode::FORBIDDEN).map(body::boxed))
+        }
+        None => match forward(req, proxy, remote_addr).instrument(span).await {
+            Ok(response) => Ok(response),
+            Err(ProxyError::Aborted(e)) => Err(e),
+            Err(e) => Ok(Response::from(e).map(body::boxed)),
+        },
+    };
+    if let (Some(metrics), Ok(response)) = (metrics, &result) {
+        metrics.record_request(&method, response.status(), started.elapsed());
+    }
+    result
+}
+
+async fn forward(
+    mut req: Request<Body>,
+    proxy: Arc<ProxyClient>,
+    remote_addr: SocketAddr,
+) -> Result<Response<BoxBody>, ProxyError> {
+    let _in_flight = InFlight::start(&proxy.in_flight);
+    if let Some(header) = &proxy.method_override_header {
+        match override_method(&mut req, header) {
+            Ok(Some(method)) => tracing::info!(
+                "Overriding POST {} from {} with {}",
+                req.uri(),
+                remote_addr,
+                method
+            ),
+            Ok(None) => {}
+            Err(e) => return Err(ProxyError::InvalidMethodOverride(e)),
+        }
+    }
+    let sampled = proxy
+        .log_sampling_rate
+        .is_none_or(|rate| rand::random::<f64>() < rate);
+    if proxy.health_path.as_deref() == Some(req.uri().path()) {
+        return Ok(health_response().map(body::boxed));
+    }
+    if let Some(icon) = &proxy.favicon {
+        if req.uri().path() == "/favicon.ico"
+            && (req.method() == Method::GET || req.method() == Method::HEAD)
+        {
+            return Ok(favicon_response(icon, req.method() == Method::HEAD).map(body::boxed));
+        }
+    }
+    if let Some(allow) = &proxy.local_options_allow {
+        if req.method() == Method::OPTIONS {
+            let mut response = status_response(StatusCode::OK);
+            response.headers_mut().insert(ALLOW, allow.clone());
+            return Ok(response.map(body::boxed));
+        }
+    }
+    if let Some(limiter) = &proxy.client_rate_limiter {
+        match limiter.try_acquire(remote_addr.ip()) {
+            Ok(Duration::ZERO) => {}
+            Ok(delay) => tokio::time::sleep(delay).await,
+            Err(retry_after) => {
+                tracing::info!(
+                    "Rate limit exceeded, rejecting {} {} from {}",
+                    req.method(),
+                    req.uri(),
+                    remote_addr
+                );
+                let mut response = status_response(StatusCode::TOO_MANY_REQUESTS);
+                response.headers_mut().insert(
+                    RETRY_AFTER,
+                    HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+                );
+                return Ok(response.map(body::boxed));
+            }
+        }
+    }
+    // Checked before the body is read for validation or logging, so requests
+    // over the limit are turned away without being buffered.
+    if let Some(limiter) =
+        rate_limit::find_path_limiter(&proxy.path_rate_limiters, req.uri().path())
+    {
+        if let Err(retry_after) = limiter.acquire().await {
+            tracing::info!(
+                "Path rate limit exceeded, rejecting {} {} from {}",
+                req.method(),
+                req.uri(),
+                remote_addr
+            );
+            let mut response = status_response(StatusCode::TOO_MANY_REQUESTS);
+            response.headers_mut().insert(
+                RETRY_AFTER,
+                HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+            );
+            return Ok(response.map(body::boxed));
+        }
+    }
+    let path_auth = auth::find(&proxy.path_auths, req.uri().path());
+    if let Some(path_auth) = path_auth {
+        if !path_auth.is_authorized(req.headers()) {
+            tracing::info!(
+                "Unauthorized {} {} from {}",
+                req.method(),
+                req.uri(),
+                remote_addr
+            );
+            audit(&proxy, &req, remote_addr, Decision::Denied, "path_auth");
+            let mut response = status_response(StatusCode::UNAUTHORIZED);
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, path_auth.challenge());
+            return Ok(response.map(body::boxed));
+        }
+    }
+    if let Some(action) = proxy.filters.as_ref().and_then(|f| f.evaluate(&req)) {
+        match action {
+            Action::Allow => audit(&proxy, &req, remote_addr, Decision::Allowed, "filter"),
+            Action::Block(status, body) => {
+                tracing::info!(
+                    "Filter blocked {} {} from {} with {}",
+                    req.method(),
+                    req.uri(),
+                    remote_addr,
+                    status
+                );
+                audit(&proxy, &req, remote_addr, Decision::Denied, "filter");
+                let mut response = status_response(*status);
+                if let Some(body) = body {
+                    *response.body_mut() = Body::from(body.clone());
+                }
+                return Ok(response.map(body::boxed));
+            }
+            Action::Redirect(location) => {
+                tracing::info!(
+                    "Filter redirected {} {} from {} to {}",
+                    req.method(),
+                    req.uri(),
+                    remote_addr,
+                    location
+                );
+                audit(&proxy, &req, remote_addr, Decision::Denied, "filter");
+                let mut response = status_response(StatusCode::FOUND);
+                match HeaderValue::from_str(location) {
+                    Ok(location) => {
+                        response.headers_mut().insert(LOCATION, location);
+                    }
+                    Err(_) => *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR,
+                }
+                return Ok(response.map(body::boxed));
+            }
+        }
+    }
+    if let Some(blocklist) = &proxy.path_blocklist {
+        if blocklist.is_blocked(req.uri().path()) {
+            tracing::info!(
+                "Blocked {} {} from {}",
+                req.method(),
+                req.uri(),
+                remote_addr
+            );
+            audit(
+                &proxy,
+                &req,
+                remote_addr,
+                Decision::Denied,
+                "path_blocklist",
+            );
+            return Ok(status_response(StatusCode::FORBIDDEN).map(body::boxed));
+        }
+    }
+    if let Some(hook) = &proxy.pre_connect_hook {
+        if !hook.is_allowed(&req).await {
+            tracing::info!(
+                "Pre-connect hook denied {} {} from {}",
+                req.method(),
+                req.uri(),
+                remote_addr
+            );
+            audit(
+                &proxy,
+                &req,
+                remote_addr,
+                Decision::Denied,
+                "pre_connect_hook",
+            );
+            return Ok(status_response(StatusCode::FORBIDDEN).map(body::boxed));
+        }
+        audit(
+            &proxy,
+            &req,
+            remote_addr,
+            Decision::Allowed,
+            "pre_connect_hook",
+        );
+    }
+    let is_grpc = grpc::is_grpc(req.headers());
+    let req = match proxy.grpc_max_recv_message_size.filter(|_| is_grpc) {
+        Some(limit) => req.map(|body| grpc::with_message_limit(body, limit)),
+        None => req,
+    };
+    // Boxed so that limits can wrap the body without losing its trailers.
+    let req = req.map(body::boxed);
+    let req = match proxy.max_request_body_bytes {
+        Some(limit) => match content_length(req.headers()) {
+            Some(length) if length > limit => {
+                tracing::info!(
+                    "Rejecting {} byte body of {} {} from {}",
+                    length,
+                    req.method(),
+                    req.uri(),
+                    remote_addr
+                );
+                return Err(ProxyError::RequestBodyTooLarge { limit });
+            }
+            Some(_) => req,
+            None if req.body().is_end_stream() => req,
+            None => req.map(|body| body::with_size_limit(body, limit)),
+        },
+        None => req,
+    };
+    let passthrough_limit = proxy
+        .body_passthrough_above_bytes
+        .filter(|_| proxy.reads_request_bodies() && !req.body().is_end_stream());
+    let (req, passthrough) = match passthrough_limit {
+        Some(limit) => match content_length(req.headers()) {
+            Some(length) => (req, length > limit),
+            None => {
+                let (parts, body) = req.into_parts();
+                let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+                let (prefix, rest) = body::read_prefix(body, limit)
+                    .await
+                    .map_err(|e| request_body_error(&proxy, e, remote_addr))?;
+                match rest {
+                    Some(rest) => (Request::from_parts(parts, rest), true),
+                    None => (
+                        Request::from_parts(parts, body::boxed(Body::from(prefix))),
+                        false,
+                    ),
+                }
+            }
+        },
+        None => (req, false),
+    };
+    if passthrough {
+        tracing::warn!(
+            "Passing through body of {} {} from {} larger than {} bytes untransformed",
+            req.method(),
+            req.uri(),
+            remote_addr,
+            passthrough_limit.unwrap_or_default()
+        );
+    }
+    let is_json = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| media_type_matches(ct, "application/json"));
+    let req = if proxy.validate_json_encoding && is_json && !passthrough {
+        let (parts, body) = req.into_parts();
+        let bytes = read_request_body(&proxy, body, remote_addr).await?;
+        if let Err(e) = std::str::from_utf8(&bytes) {
+            tracing::info!(
+                "Rejecting JSON body of {} {} from {}: {}",
+                parts.method,
+                parts.uri,
+                remote_addr,
+                e
+            );
+            return Err(ProxyError::InvalidEncoding(e.to_string()));
+        }
+        Request::from_parts(parts, body::boxed(Body::from(bytes)))
+    } else {
+        req
+    };
+    let body_type = proxy
+        .body_validation_max_bytes
+        .filter(|_| !passthrough)
+        .and_then(|max_bytes| Some((BodyType::of(req.headers())?, max_bytes)));
+    let req = match body_type {
+        Some((body_type, max_bytes)) if !req.body().is_end_stream() => {
+            let (parts, body) = req.into_parts();
+            let (prefix, rest) = body::read_prefix(body, max_bytes)
+                .await
+                .map_err(|e| request_body_error(&proxy, e, remote_addr))?;
+            match rest {
+                Some(rest) => {
+                    tracing::debug!(
+                        "Not checking body of {} {} larger than {} bytes",
+                        parts.method,
+                        parts.uri,
+                        max_bytes
+                    );
+                    Request::from_parts(parts, rest)
+                }
+                None => {
+                    if let Err(e) = body_type.check(&prefix) {
+                        tracing::info!(
+                            "Rejecting body of {} {} from {}: {}",
+                            parts.method,
+                            parts.uri,
+                            remote_addr,
+                            e
+                        );
+                        return Err(ProxyError::InvalidBody(e));
+                    }
+                    Request::from_parts(parts, body::boxed(Body::from(prefix)))
+                }
+            }
+        }
+        _ => req,
+    };
+    let req = if !proxy.removed_body_fields.is_empty()
+        && !passthrough
+        && BodyType::of(req.headers()) == Some(BodyType::Json)
+        && !req.body().is_end_stream()
+    {
+        let (mut parts, body) = req.into_parts();
+        let bytes = read_request_body(&proxy, body, remote_addr).await?;
+        let mut value: serde_json::Value = serde_json::from_slice(&bytes).map_err(|e| {
+            tracing::info!(
+                "Rejecting body of {} {} from {}: {}",
+                parts.method,
+                parts.uri,
+                remote_addr,
+                e
+            );
+            ProxyError::InvalidBody(format!("invalid JSON body: {}", e))
+        })?;
+        let body = match redact::remove_fields(&mut value, &proxy.removed_body_fields) {
+            0 => bytes,
+            removed => {
+                tracing::debug!("Removed {} fields from body of {}", removed, parts.uri);
+                let body = Bytes::from(value.to_string());
+                parts.headers.remove(TRANSFER_ENCODING);
+                parts
+                    .headers
+                    .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+                body
+            }
+        };
+        Request::from_parts(parts, body::boxed(Body::from(body)))
+    } else {
+        req
+    };
+    let (req, request_body_bytes) = match &proxy.body_size_header {
+        Some(_) if !passthrough => {
+            let (parts, body) = req.into_parts();
+            let bytes = read_request_body(&proxy, body, remote_addr).await?;
+            let length = bytes.len() as u64;
+            (
+                Request::from_parts(parts, body::boxed(Body::from(bytes))),
+                Some(length),
+            )
+        }
+        _ => (req, None),
+    };
+    let log_body = proxy
+        .log_body_paths
+        .iter()
+        .any(|prefix| req.uri().path().starts_with(prefix.as_str()));
+    let req = if log_body && !passthrough && !req.body().is_end_stream() {
+        let (parts, body) = req.into_parts();
+        let bytes = read_request_body(&proxy, body, remote_addr).await?;
+        let logged = &bytes[..bytes.len().min(LOGGED_BODY_LIMIT)];
+        let content_type = parts
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        let format = body_log::format_for(&proxy.body_log_formats, content_type);
+        tracing::info!(
+            "Request body of {} {} ({} bytes): {}",
+            parts.method,
+            parts.uri.path(),
+            bytes.len(),
+            format.encode(logged)
+        );
+        Request::from_parts(parts, body::boxed(Body::from(bytes)))
+    } else {
+        req
+    };
+    let (req, graphql_operation) = if proxy.graphql_operation_header {
+        let mut req = req;
+        req.headers_mut().remove(X_GRAPHQL_OPERATION_NAME);
+        if req.method() == Method::POST
+            && !passthrough
+            && BodyType::of(req.headers()) == Some(BodyType::Json)
+            && !req.body().is_end_stream()
+        {
+            let (mut parts, body) = req.into_parts();
+            let bytes = read_request_body(&proxy, body, remote_addr).await?;
+            let operation = graphql::operation_name(&bytes);
+            if let Some(value) = operation
+                .as_deref()
+                .and_then(|name| HeaderValue::from_str(name).ok())
+            {
+                parts.headers.insert(X_GRAPHQL_OPERATION_NAME, value);
+            }
+            (
+                Request::from_parts(parts, body::boxed(Body::from(bytes))),
+                operation,
+            )
+        } else {
+            (req, None)
+        }
+    } else {
+        (req, None)
+    };
+    if let Some(limiter) = &proxy.upstream_rate_limiter {
+        if limiter.acquire().await.is_err() {
+            tracing::warn!(
+                "Upstream burst limit exceeded, rejecting {} {} from {}",
+                req.method(),
+                req.uri(),
+                remote_addr
+            );
+            return Ok(status_response(StatusCode::SERVICE_UNAVAILABLE).map(body::boxed));
+        }
+    }
+    let tenant = match &proxy.segment_route {
+        Some(route) => route.resolve(req.uri().path()).map_err(|e| {
+            tracing::info!("Not routing {} from {}: {}", req.uri(), remote_addr, e);
+            ProxyError::BadRequest
+        })?,
+        None => None,
+    };
+    let feature = match tenant {
+        Some(_) => None,
+        None => route::find_feature(&proxy.feature_routes, req.headers()),
+    };
+    if let Some(feature) = feature {
+        tracing::info!(
+            "Routing {} from {} to {} for {}: {}",
+            req.uri(),
+            remote_addr,
+            feature.backend,
+            feature.header,
+            feature.value
+        );
+    }
+    let backend = match (&tenant, feature) {
+        (Some((backend, _)), _) => backend.as_str(),
+        (None, Some(feature)) => feature.backend.as_str(),
+        (None, None) => route::find_host(&proxy.host_routes, req.headers(), req.uri())
+            .or_else(|| route::find_version(&proxy.version_routes, req.headers()))
+            .or_else(|| match &graphql_operation {
+                Some(operation) => {
+                    let path = format!("{}/{}", req.uri().path().trim_end_matches('/'), operation);
+                    route::find(&proxy.routes, &path)
+                }
+                None => route::find(&proxy.routes, req.uri().path()),
+            })
+            .unwrap_or(&proxy.forward_addr),
+    };
+    let uri_string = match req.uri().path_and_query() {
+        Some(path_query) => {
+            let mut path = match &tenant {
+                Some((_, path)) => Cow::Borrowed(path.as_str()),
+                None => Cow::Borrowed(path_query.path()),
+            };
+            if let Some(normalization) = proxy.path_normalization {
+                let (normalized, above_root) = path::normalize(&path);
+                if above_root && normalization == PathNormalization::Strict {
+                    tracing::info!("Rejecting path above the root: {}", path);
+                    return Err(ProxyError::BadRequest);
+                }
+                tracing::debug!("Normalized path {} to {}", path, normalized);
+                path = Cow::Owned(normalized);
+            }
+            if proxy.path_case != PathCase::None {
+                path = Cow::Owned(proxy.path_case.apply(&path));
+            }
+            if !proxy.path_segments.is_empty() {
+                path = Cow::Owned(path::insert_segments(&path, &proxy.path_segments));
+            }
+            let path_query = match path_query.query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path.into_owned(),
+            };
+            if proxy.base64_path {
+                let encoded = base64::encode_config(&path_query, base64::URL_SAFE_NO_PAD);
+                format!("{}/{}", backend, encoded)
+            } else {
+                format!("{}{}", backend, path_query)
+            }
+        }
+        None => backend.to_string(),
+    };
+    if sampled {
+        tracing::info!("uri_string: {}", uri_string);
+    }
+    let uri = uri_string
+        .parse::<hyper::Uri>()
+        .expect("proxy addr should parse");
+    let drop_body =
+        req.method() == Method::GET && !proxy.allow_get_body && !req.body().is_end_stream();
+    if drop_body {
+        tracing::debug!("Dropping body of GET {}", req.uri());
+    }
+    let mut http_req_builder = Request::builder();
+    {
+        let headers = http_req_builder.headers_mut().unwrap();
+        for (key, value) in req.headers() {
+            if drop_body && (key == CONTENT_LENGTH || key == TRANSFER_ENCODING) {
+                continue;
+            }
+            if path_auth.is_some() && key == AUTHORIZATION {
+                continue;
+            }
+            if let Some(allowed) = &proxy.allowed_request_headers {
+                if !allowed.contains(key) {
+                    continue;
+                }
+            }
+            if sampled {
+                tracing::info!("Sending: {}: {}", key, value.to_str().unwrap_or("NO VALUE"));
+            }
+            headers.append(key, value.into());
+        }
+        if proxy.forwarding_headers {
+            let scheme = if proxy.listener_options.tls.is_some() {
+                "https"
+            } else {
+                "http"
+            };
+            inject_forwarding_headers(headers, &req, remote_addr, scheme);
+        }
+        if let Some(token) = proxy.token_source.as_ref().and_then(|s| s.authorization()) {
+            headers.insert(AUTHORIZATION, token);
+        }
+        if let Some(host) = &proxy.host_header {
+            headers.insert(HOST, host.clone());
+        }
+        if let Some(meta) = &proxy.metadata_header {
+            headers.insert(X_PROXY_META, meta.clone());
+        }
+        if proxy.proxy_info_header {
+            headers.insert(X_FORWARDED_BY, HeaderValue::from_static(PROXY_INFO));
+        }
+        if let (Some(name), Some(length)) = (&proxy.body_size_header, request_body_bytes) {
+            headers.insert(name, HeaderValue::from(if drop_body { 0 } else { length }));
+        }
+        if let Some(priority) = priority::find(&proxy.path_priorities, req.uri().path()) {
+            headers.insert(priority::PRIORITY, priority);
+        }
+        if let Some((name, value)) = &proxy.draining_header {
+            if proxy.draining.load(Ordering::SeqCst) {
+                headers.insert(name, value.clone());
+            }
+        }
+        if req.version() == Version::HTTP_10 {
+            tracing::debug!("Received HTTP/1.0 request");
+            if !headers.contains_key(HOST) {
+                // HTTP/1.0 clients may omit Host, fall back to the upstream's.
+                if let Some(authority) = uri.authority() {
+                    if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                        headers.insert(HOST, host);
+                    }
+                }
+            }
+        }
+        if proxy.strip_host_port {
+            let host = match headers.get(HOST) {
+                Some(host) => host.to_str().ok().map(str::to_string),
+                None => uri.authority().map(|authority| authority.to_string()),
+            };
+            if let Some(host) = host.and_then(|host| host_without_port(&host)) {
+                headers.insert(HOST, host);
+            }
+        }
+        for name in &proxy.strip_request_headers {
+            headers.remove(name);
+        }
+    }
+    let path_timeout = proxy
+        .path_timeouts
+        .iter()
+        .find(|timeout| timeout.matches(req.uri().path()));
+    let timeout = match path_timeout {
+        Some(path_timeout) => path_timeout.timeout,
+        None => proxy
+            .method_timeouts
+            .as_ref()
+            .and_then(|timeouts| timeouts.get(req.method()))
+            .unwrap_or(proxy.upstream_timeout),
+    };
+    let timeout = match proxy.timeout_jitter_ms {
+        0 => timeout,
+        jitter => timeout + Duration::from_millis(rand::thread_rng().gen_range(0..jitter)),
+    };
+    let accepts_gzip = proxy.gzip_above_bytes.is_some()
+        && req.method() != Method::HEAD
+        && compression::accepts_gzip(req.headers());
+    let wants_etag = proxy.add_etag && req.method() == Method::GET;
+    let downstream_version = req.version();
+    let request_path = req.uri().path().to_string();
+    let if_none_match = req.headers().get(IF_NONE_MATCH).cloned();
+    let method = req.method().clone();
+    let body = if drop_body {
+        body::boxed(Body::empty())
+    } else {
+        req.into_body()
+    };
+    let body = match &proxy.aws_signer {
+        Some(signer) => {
+            let bytes = read_request_body(&proxy, body, remote_addr).await?;
+            let headers = http_req_builder.headers_mut().unwrap();
+            if proxy.host_header.is_none() {
+                headers.remove(HOST);
+            }
+            signer.sign(&method, &uri, headers, &bytes, SystemTime::now());
+            body::boxed(Body::from(bytes))
+        }
+        None => body,
+    };
+    let http_req = http_req_builder.method(method).uri(uri).body(body);
+
+    match http_req {
+        Err(_) => Err(ProxyError::BadRequest),
+        Ok(http_req) => {
+            let started = Instant::now();
+            let downstream = Downstream {
+                remote_addr,
+                local_addr: proxy.addr,
+            };
+            let upstream_uri = http_req.uri().clone();
+            let client = match (&tenant, &proxy.routed_client) {
+                (Some(_), Some(routed_client)) => routed_client,
+                _ => proxy.client_for(&upstream_uri),
+            };
+            if let Some(check) = &proxy.idle_check {
+                let idle_for = proxy.pool_stats.idle_for(&upstream_uri);
+                if idle_for.is_some_and(|idle_for| idle_for >= check.after) {
+                    pre_check(client, &upstream_uri, check, timeout).await;
+                }
+            }
+            let _active_request = (proxy.pool_stats_headers || proxy.idle_check.is_some())
+                .then(|| proxy.pool_stats.start_request(&upstream_uri));
+            let coalesce_key = proxy
+                .coalescer
+                .as_ref()
+                .and_then(|_| Coalescer::key(&http_req));
+            let upstream_request = connector::scope(downstream, client.request(http_req));
+            let upstream_request = async {
+                match (&proxy.coalescer, coalesce_key) {
+                    (Some(coalescer), Some(key)) => coalescer.request(key, upstream_request).await,
+                    _ => upstream_request.await.map_err(Arc::new),
+                }
+            };
+            let upstream_result = match tokio::time::timeout(timeout, upstream_request).await {
+                Ok(result) => result,
+                Err(_) => {
+                    tracing::warn!("{} timed out after {:?}", uri_string, timeout);
+                    if let Some(metrics) = &proxy.metrics {
+                        metrics.record_upstream_error();
+                    }
+                    return Err(ProxyError::UpstreamTimeout(timeout));
+                }
+            };
+            if let (Some(metrics), Err(_)) = (&proxy.metrics, &upstream_result) {
+                metrics.record_upstream_error();
+            }
+            let http_resp = match upstream_result {
+                Ok(http_resp) => http_resp,
+                Err(e)
+                    if matches!(
+                        resolver::find_resolve_error(e.as_ref()),
+                        Some(ResolveError::Denied(_))
+                    ) =>
+                {
+                    tracing::warn!("Refusing to connect to {}: {}", uri_string, e);
+                    return Err(ProxyError::UpstreamDenied);
+                }
+                Err(e)
+                    if matches!(
+                        resolver::find_resolve_error(e.as_ref()),
+                        Some(ResolveError::Timeout(_))
+                    ) =>
+                {
+                    tracing::warn!("Not connecting to {}: {}", uri_string, e);
+                    return Err(ProxyError::UpstreamResolveTimeout);
+                }
+                Err(e) if proxy.abort_on_tls_error && client::is_tls_error(e.as_ref()) => {
+                    tracing::warn!("TLS error from {}, closing connection: {}", uri_string, e);
+                    return Err(ProxyError::Aborted(e));
+                }
+                Err(e) if grpc::is_message_too_large(e.as_ref()) => {
+                    tracing::info!("gRPC request to {} too large: {}", uri_string, e);
+                    return Ok(grpc::message_too_large_response().map(body::boxed));
+                }
+                Err(e) if body::is_too_large(e.as_ref()) => {
+                    tracing::info!("Request body to {} too large: {}", uri_string, e);
+                    return Err(ProxyError::RequestBodyTooLarge {
+                        limit: proxy.max_request_body_bytes.unwrap_or_default(),
+                    });
+                }
+                Err(e) if connector::is_connection_limit_reached(e.as_ref()) => {
+                    tracing::warn!("Not connecting to {}: {}", uri_string, e);
+                    return Err(ProxyError::UpstreamConnectionLimit);
+                }
+                Err(e) if client::is_tls_error(e.as_ref()) => {
+                    tracing::error!("TLS error from {}: {}", uri_string, e);
+                    return Err(ProxyError::UpstreamTlsError(e.to_string()));
+                }
+                Err(e) => {
+                    tracing::error!("Request to {} failed: {}", uri_string, e);
+                    return Err(ProxyError::UpstreamConnectFailed(e.to_string()));
+                }
+            };
+            let http_resp = connector::release_after(http_resp);
+            let upstream_latency = started.elapsed();
+            let status_code = http_resp.status();
+            if sampled || status_code.is_client_error() || status_code.is_server_error() {
+                tracing::info!("Sent request to {}, response {}", uri_string, status_code);
+            }
+            if let Some(sla) = proxy.latency_sla.filter(|&sla| upstream_latency > sla) {
+                let upstream = upstream_uri
+                    .authority()
+                    .map_or("", |authority| authority.as_str());
+                tracing::warn!(
+                    "SLA violation for {} from {}: took {:?}, over {:?}",
+                    request_path,
+                    upstream,
+                    upstream_latency,
+                    sla
+                );
+                if let Some(metrics) = &proxy.metrics {
+                    metrics.record_sla_violation(upstream);
+                }
+            }
+            if proxy.strict_response_validation {
+                if let Err(e) = validation::check_response(status_code, http_resp.headers()) {
+                    tracing::error!("Malformed response from {}: {}", uri_string, e);
+                    return Err(ProxyError::InvalidUpstreamResponse(format!(
+                        "malformed upstream response: {}",
+                        e
+                    )));
+                }
+            }
+            let valid = match &proxy.valid_statuses {
+                Some(statuses) => statuses.contains(status_code),
+                None => status_code.is_success(),
+            };
+            if !valid && proxy.valid_statuses.is_some() {
+                tracing::error!("Invalid status {} from {}", status_code, uri_string);
+            }
+            if let Some((status, body)) = &proxy.upstream_error_response {
+                if !valid {
+                    let mut response = Response::new(body::boxed(Body::from(body.clone())));
+                    *response.status_mut() = *status;
+                    return Ok(response);
+                }
+            }
+            if let Some(expected) = &proxy.expected_content_type {
+                let content_type = http_resp
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok());
+                let has_body = !matches!(
+                    status_code,
+                    StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+                );
+                if has_body && !content_type.is_some_and(|ct| media_type_matches(ct, expected)) {
+                    tracing::error!(
+                        "Unexpected content type {} from {}, expected {}",
+                        content_type.unwrap_or("(none)"),
+                        uri_string,
+                        expected
+                    );
+                    return Err(ProxyError::UpstreamVerificationFailed);
+                }
+            }
+            let expected_hash = proxy
+                .response_hash_header
+                .as_ref()
+                .and_then(|header| http_resp.headers().get(header).cloned());
+            let compress = accepts_gzip
+                && !matches!(
+                    status_code,
+                    StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+                )
+                && proxy.gzip_above_bytes.is_some_and(|min_bytes| {
+                    compression::should_compress(http_resp.headers(), min_bytes)
+                });
+            let minify_json = proxy.minify_json
+                && !matches!(
+                    status_code,
+                    StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+                )
+                && !http_resp.headers().contains_key(CONTENT_ENCODING)
+                && http_resp
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|ct| media_type_matches(ct, "application/json"));
+            let insecure_cookies = proxy
+                .insecure_cookies
+                .filter(|_| proxy.listener_options.tls.is_none());
+            let mut response_builder = Response::builder().status(status_code);
+            {
+                let headers = response_builder.headers_mut().unwrap();
+                for (key, value) in http_resp.headers() {
+                    if let Some(allowed) = &proxy.allowed_response_headers {
+                        if !allowed.contains(key) {
+                            continue;
+                        }
+                    }
+                    if key == SET_COOKIE {
+                        if insecure_cookies.is_some_and(|cookies| cookies.removes(value)) {
+                            tracing::debug!(
+                                "Removing cookie '{}' sent over plain HTTP",
+                                cookies::cookie_name(value)
+                            );
+                            continue;
+                        }
+                        if let Some(filter) = &proxy.cookie_filter {
+                            if filter.removes(value) {
+                                tracing::debug!(
+                                    "Removing cookie '{}'",
+                                    cookies::cookie_name(value)
+                                );
+                                continue;
+                            }
+                        }
+                    }
+                    headers.append(key, value.into());
+                }
+                if let Some(timing_header) = &proxy.timing_header {
+                    let metric = format!(
+                        "upstream;dur={:.3}",
+                        upstream_latency.as_secs_f64() * 1000.0
+                    );
+                    let timing = headers
+                        .get_all(timing_header)
+                        .iter()
+                        .filter_map(|value| value.to_str().ok())
+                        .chain(std::iter::once(metric.as_str()))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    if let Ok(timing) = HeaderValue::from_str(&timing) {
+                        headers.insert(timing_header, timing);
+                    }
+                }
+                if compress {
+                    // The compressed length is only known once it is sent.
+                    headers.remove(CONTENT_LENGTH);
+                    headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+                    vary::extend(headers, &[ACCEPT_ENCODING]);
+                }
+                if !proxy.vary_headers.is_empty() {
+                    vary::extend(headers, &proxy.vary_headers);
+                }
+                if proxy.debug_headers {
+                    debug_headers::insert(headers, &uri_string, upstream_latency);
+                    if proxy.pool_stats_headers {
+                        let stats = proxy.pool_stats.host(&upstream_uri);
+                        debug_headers::insert_pool_stats(headers, stats.active, stats.idle());
+                    }
+                }
+                if let Some(prefix) = &proxy.strip_header_prefix {
+                    debug_headers::strip_prefix(headers, prefix);
+                }
+                if !proxy.content_type_overrides.is_empty() {
+                    content_type::apply(&proxy.content_type_overrides, &request_path, headers);
+                }
+                if let Some(download) = &proxy.forced_download {
+                    download.apply(headers);
+                }
+                for name in &proxy.strip_response_headers {
+                    headers.remove(name);
+                }
+            }
+            let generate_etag = wants_etag
+                && status_code == StatusCode::OK
+                && !http_resp.headers().contains_key(ETAG);
+            let declared_length = content_length(http_resp.headers());
+            if let Some(limit) = proxy.max_response_body_bytes {
+                if declared_length.is_some_and(|length| length > limit) {
+                    tracing::error!(
+                        "Response from {} is larger than {} bytes",
+                        uri_string,
+                        limit
+                    );
+                    return Err(ProxyError::UpstreamResponseTooLarge { limit });
+                }
+            }
+            // Set in the headers of trailers-only responses, which end the
+            // call without a body.
+            let header_grpc_status = grpc::status_code(http_resp.headers());
+            let mut body = body::boxed(http_resp.into_body());
+            if let Some(timeout) = proxy.stall_timeout {
+                body = body::with_stall_timeout(body, timeout);
+            }
+            if let Some((bytes_per_sec, window)) = proxy.min_response_speed {
+                body = body::with_min_speed(body, bytes_per_sec, window);
+            }
+            if let Some(limit) = proxy.max_response_body_bytes {
+                if declared_length.is_none() {
+                    body = body::with_size_limit(body, limit);
+                }
+            }
+            if let Some(sla) = proxy.response_size_sla {
+                let upstream = upstream_uri
+                    .authority()
+                    .map_or_else(String::new, |authority| authority.to_string());
+                let path = request_path.clone();
+                let warn = move |length: u64| {
+                    if length > sla {
+                        tracing::warn!(
+                            "Response size SLA violation for {} from {}: {} bytes, over {}",
+                            path,
+                            upstream,
+                            length,
+                            sla
+                        );
+                    }
+                };
+                match declared_length {
+                    Some(length) => warn(length),
+                    None => body = body::boxed(body::on_complete(body, warn)),
+                }
+            }
+            if let Some(limit) = proxy.grpc_max_send_message_size.filter(|_| is_grpc) {
+                body = body::boxed(grpc::with_message_limit_status(body, limit));
+            }
+            if proxy.grpc_status_mapping && is_grpc {
+                let record = {
+                    let uri_string = uri_string.clone();
+                    let metrics = proxy.metrics.clone();
+                    move |code: Option<u32>| {
+                        let Some(code) = code else {
+                            tracing::warn!("gRPC response from {} has no status", uri_string);
+                            return;
+                        };
+                        let status = grpc::http_status(code);
+                        tracing::info!(
+                            "gRPC response from {}: grpc-status {}, HTTP {}",
+                            uri_string,
+                            code,
+                            status.as_u16()
+                        );
+                        if let Some(metrics) = metrics {
+                            metrics.record_grpc_status(code, status);
+                        }
+                    }
+                };
+                match header_grpc_status {
+                    Some(code) => record(Some(code)),
+                    None => body = body::boxed(grpc::on_status(body, record)),
+                }
+            }
+            if let Some(expected) = expected_hash {
+                let verified = match hyper::body::to_bytes(body).await {
+                    Ok(bytes) => integrity::verify(&expected, &bytes).map(|()| bytes),
+                    Err(e) => Err(e.to_string()),
+                };
+                match verified {
+                    Ok(bytes) => body = body::boxed(Body::from(bytes)),
+                    Err(e) => {
+                        tracing::error!("Response from {} failed verification: {}", uri_string, e);
+                        return Err(ProxyError::UpstreamVerificationFailed);
+                    }
+                }
+            }
+            if minify_json {
+                let bytes = match hyper::body::to_bytes(body).await {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        tracing::error!("Failed to read response from {}: {}", uri_string, e);
+                        return Err(ProxyError::UpstreamBodyFailed);
+                    }
+                };
+                body = body::boxed(match minify::json(&bytes) {
+                    Ok(minified) => {
+                        if !compress {
+                            let headers = response_builder.headers_mut().unwrap();
+                            headers.insert(CONTENT_LENGTH, HeaderValue::from(minified.len()));
+                        }
+                        Body::from(minified)
+                    }
+                    Err(e) => {
+                        tracing::warn!("Not minifying response from {}: {}", uri_string, e);
+                        Body::from(bytes)
+                    }
+                });
+            }
+            if generate_etag {
+                let bytes = match hyper::body::to_bytes(body).await {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        tracing::error!("Failed to read response from {}: {}", uri_string, e);
+                        return Err(ProxyError::UpstreamBodyFailed);
+                    }
+                };
+                let tag = etag::generate(&bytes, compress);
+                let headers = response_builder.headers_mut().unwrap();
+                if if_none_match
+                    .as_ref()
+                    .is_some_and(|if_none_match| etag::matches(if_none_match, &tag))
+                {
+                    headers.remove(CONTENT_LENGTH);
+                    if compress {
+                        headers.remove(CONTENT_ENCODING);
+                    }
+                    headers.insert(ETAG, tag);
+                    let response = response_builder
+                        .status(StatusCode::NOT_MODIFIED)
+                        .body(body::boxed(Body::empty()))
+                        .unwrap();
+                    return Ok(response);
+                }
+                headers.insert(ETAG, tag);
+                body = body::boxed(Body::from(bytes));
+            }
+            if let Some(timeout) = proxy.empty_body_timeout {
+                body = body::with_empty_body_timeout(body, timeout);
+            }
+            if compress {
+                body = body::boxed(compression::gzip(body));
+            }
+            if proxy.body_size_header.is_some() {
+                let uri_string = uri_string.clone();
+                body = body::boxed(body::on_complete(body, move |length| {
+                    tracing::info!(
+                        "Response from {}: X-Response-Body-Bytes: {}",
+                        uri_string,
+                        length
+                    )
+                }));
+            }
+            if let Some(name) = &proxy.response_size_header {
+                match body.size_hint().exact() {
+                    Some(length) => {
+                        let headers = response_builder.headers_mut().unwrap();
+                        headers.insert(name.clone(), HeaderValue::from(length));
+                    }
+                    None if downstream_version == Version::HTTP_2 => {
+                        body = body::boxed(body::with_length_trailer(body, name.clone()));
+                    }
+                    None => {}
+                }
+                let uri_string = uri_string.clone();
+                body = body::boxed(body::on_complete(body, move |length| {
+                    tracing::info!(
+                        "Sent response from {} to {}: {} bytes",
+                        uri_string,
+                        remote_addr,
+                        length
+                    )
+                }));
+            }
+            match response_builder.body(body) {
+                Ok(response) => Ok(response),
+                Err(e) => {
+                    tracing::error!("Invalid response from {}: {}", uri_string, e);
+                    Err(ProxyError::InvalidUpstreamResponse(e.to_string()))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy_protocol;
+    use futures_channel::oneshot;
+    use hyper::{Client, Method, Request};
+    use mockito::{mock, server_address, Matcher};
+    use std::{
+        borrow::Borrow,
+        net::{SocketAddr, TcpListener as StdTcpListener},
+        str, thread,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn test_inject_forwarding_headers() {
+        let req = Request::builder()
+            .uri("/some/test/path")
+            .header(HOST, "proxy.example.com")
+            .header(X_FORWARDED_FOR, "203.0.113.7")
+            .header(VIA, "1.1 edge")
+            .body(())
+            .unwrap();
+        let mut headers = req.headers().clone();
+        inject_forwarding_headers(
+            &mut headers,
+            &req,
+            SocketAddr::from(([10, 0, 0, 1], 5000)),
+            "http",
+        );
+        assert_eq!(headers[X_FORWARDED_FOR], "203.0.113.7, 10.0.0.1");
+        assert_eq!(headers[X_FORWARDED_HOST], "proxy.example.com");
+        assert_eq!(headers[X_FORWARDED_PROTO], "http");
+        assert_eq!(headers[VIA], "1.1 edge, 1.1 proxy-filter");
+
+        let req = Request::builder()
+            .version(Version::HTTP_2)
+            .uri("http://[::1]:3000/")
+            .body(())
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        inject_forwarding_headers(&mut headers, &req, "[::1]:5000".parse().unwrap(), "https");
+        assert_eq!(headers[X_FORWARDED_FOR], "::1");
+        assert_eq!(headers[X_FORWARDED_PROTO], "https");
+        assert_eq!(headers[X_FORWARDED_HOST], "[::1]:3000");
+        assert_eq!(headers[VIA], "2 proxy-filter");
+    }
+
+    #[test]
+    fn test_traced_headers_redacts_sensitive_values() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        let names = [
+            AUTHORIZATION,
+            HeaderName::from_static("x-api-key"),
+            HeaderName::from_static("content-type"),
+        ];
+        assert_eq!(
+            traced_headers(&headers, &names, false),
+            "authorization=[REDACTED] content-type=application/json"
+        );
+        assert_eq!(
+            traced_headers(&headers, &names, true),
+            "authorization=Bearer secret content-type=application/json"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle() {
+        let mock = mock("POST", "/some/test/path")
+            .match_body("{expected payload}")
+            .match_header("content-type", "application/test")
+            .match_header("DD-API-KEY", "SECRET API KEY")
+            .match_header("user-agent", "unit-test")
+            .with_body("{expected response}")
+            .with_status(418)
+            .expect(1)
+            .create();
+        let server = TestServer::serve(server_address());
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri_string = format!("http://{}/some/test/path", server.addr);
+        let uri = uri_string
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let client = Client::new();
+        let req = Request::builder()
+            .method(Method::POST)
+            .header("content-type", "application/test")
+            .header("DD-API-KEY", "SECRET API KEY")
+            .header("user-agent", "unit-test")
+            .uri(uri)
+            .body(Body::from("{expected payload}"))
+            .expect("request builder");
+        let mut resp = client.request(req).await.unwrap();
+        assert_eq!(resp.status(), 418);
+
+        let body = resp.data().await;
+        assert!(body.is_some(), "body must not be empty");
+        assert_eq!(
+            str::from_utf8(body.unwrap().unwrap().borrow()),
+            Ok("{expected response}")
+        );
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_get_request() {
+        let mock = mock("GET", "/some/test/path")
+            .match_header("user-agent", "unit-test")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("key_one".into(), "value_one".into()),
+                Matcher::UrlEncoded("key_two".into(), "value_two".into()),
+            ]))
+            .with_body("{expected response}")
+            .with_status(418)
+            .expect(1)
+            .create();
+        let server = TestServer::serve(server_address());
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri_string = format!(
+            "http://{}/some/test/path?key_one=value_one&key_two=value_two",
+            server.addr
+        );
+        let uri = uri_string
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let client = Client::new();
+        let req = Request::builder()
+            .method(Method::GET)
+            .header("user-agent", "unit-test")
+            .uri(uri)
+            .body(Body::empty())
+            .expect("request builder");
+        let mut resp = client.request(req).await.unwrap();
+        assert_eq!(resp.status(), 418);
+
+        let body = resp.data().await;
+        assert!(body.is_some(), "body must not be empty");
+        assert_eq!(
+            str::from_utf8(body.unwrap().unwrap().borrow()),
+            Ok("{expected response}")
+        );
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_denied_upstream_address() {
+        let mock = mock("GET", "/some/test/path").expect(0).create();
+        let port = server_address().port();
+        for (template, segment) in [
+            ("http://{segment}", "localhost"),
+            ("http://127.0.0.{segment}", "1"),
+            ("http://0.0.0.{segment}", "0"),
+        ] {
+            let template = format!("{}:{}", template, port);
+            let server =
+                TestServer::serve_with(format!("http://{}", server_address()), move |proxy| {
+                    proxy.with_segment_route(SegmentRoute::new(1, &template).unwrap())
+                });
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            let uri = format!("http://{}/{}/some/test/path", server.addr, segment)
+                .parse::<hyper::Uri>()
+                .expect("server addr should parse");
+            let resp = Client::new().get(uri).await.unwrap();
+            assert_eq!(resp.status(), 403);
+        }
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_loopback_base_endpoint_by_default() {
+        let mock = mock("GET", "/some/test/path").expect(1).create();
+        let config = Config {
+            base_endpoint: format!("http://{}", server_address()),
+            ..Config::default()
+        };
+        assert!(config.restrict_upstream_ip_ranges);
+        let proxy = ProxyClient::from_config(config).unwrap();
+        let req = Request::get("/some/test/path").body(Body::empty()).unwrap();
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let resp = handle(req, Arc::new(proxy), remote_addr).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_pre_connect_hook_denies() {
+        let hook = mock("GET", "/auth")
+            .match_header("x-original-method", "DELETE")
+            .match_header("x-original-uri", "/some/test/path")
+            .match_header("authorization", "Bearer token")
+            .with_status(401)
+            .expect(1)
+            .create();
+        let upstream = mock("DELETE", "/some/test/path").expect(0).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_pre_connect_hook(PreConnectHook::new(
+                format!("http://{}/auth", server_address()),
+                std::time::Duration::from_secs(60),
+            ))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        for _ in 0..2 {
+            let req = Request::builder()
+                .method(Method::DELETE)
+                .header("authorization", "Bearer token")
+                .uri(format!("http://{}/some/test/path", server.addr))
+                .body(Body::empty())
+                .expect("request builder");
+            let resp = client.request(req).await.unwrap();
+            assert_eq!(resp.status(), 403);
+        }
+        // The second request is answered from the cache.
+        hook.assert();
+        upstream.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_http10_without_host() {
+        let mock = mock("GET", "/some/test/path")
+            .match_header("host", server_address().to_string().as_str())
+            .with_body("{expected response}")
+            .with_status(418)
+            .expect(1)
+            .create();
+        let server = TestServer::serve(server_address());
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let mut stream = tokio::net::TcpStream::connect(server.addr).await.unwrap();
+        stream
+            .write_all(b"GET /some/test/path HTTP/1.0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.contains(" 418 "), "unexpected response: {}", resp);
+        assert!(resp.ends_with("{expected response}"));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_timing_header() {
+        let mock = mock("GET", "/some/test/path")
+            .with_header("server-timing", "db;dur=5")
+            .with_status(200)
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_timing_header(HeaderName::from_static("server-timing"))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let resp = Client::new().get(uri).await.unwrap();
+        let timing = resp.headers()["server-timing"].to_str().unwrap();
+        assert!(
+            timing.starts_with("db;dur=5, upstream;dur="),
+            "unexpected Server-Timing: {}",
+            timing
+        );
+        mock.assert();
+    }
+
+    #[test]
+    fn test_override_method() {
+        let header = HeaderName::from_static("x-http-method-override");
+        let mut req = Request::post("/")
+            .header(&header, "DELETE")
+            .body(())
+            .unwrap();
+        assert_eq!(override_method(&mut req, &header), Ok(Some(Method::DELETE)));
+        assert_eq!(req.method(), Method::DELETE);
+        assert!(!req.headers().contains_key(&header));
+
+        let mut req = Request::get("/")
+            .header(&header, "DELETE")
+            .body(())
+            .unwrap();
+        assert_eq!(override_method(&mut req, &header), Ok(None));
+        assert_eq!(req.method(), Method::GET);
+
+        let mut req = Request::post("/")
+            .header(&header, "BAD METHOD")
+            .body(())
+            .unwrap();
+        assert!(override_method(&mut req, &header).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_method_override() {
+        let mock = mock("DELETE", "/method/override").expect(1).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_method_override(HeaderName::from_static("x-http-method-override"))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let req = Request::post(format!("http://{}/method/override", server.addr))
+            .header("X-HTTP-Method-Override", "DELETE")
+            .body(Body::empty())
+            .unwrap();
+        let resp = Client::new().request(req).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_pool_stats_headers() {
+        let mock = mock("GET", "/pool/stats").expect(2).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_debug_headers().with_pool_stats_headers()
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/pool/stats", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let client = Client::new();
+        for _ in 0..2 {
+            let resp = client.get(uri.clone()).await.unwrap();
+            assert_eq!(resp.headers()["x-proxy-pool-active"], "1");
+            assert_eq!(resp.headers()["x-proxy-pool-idle"], "0");
+        }
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_insecure_cookies() {
+        let mock = mock("GET", "/insecure/cookies")
+            .with_header("set-cookie", "session=abc; Secure; HttpOnly")
+            .with_header("set-cookie", "theme=dark; Path=/")
+            .with_status(200)
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_insecure_cookies_stripped(InsecureCookies::Secure)
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/insecure/cookies", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let resp = Client::new().get(uri).await.unwrap();
+        let cookies = resp
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(cookies, ["theme=dark; Path=/"]);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_signs_aws_requests() {
+        let upstream = mock("POST", "/prod/items")
+            .match_header("host", server_address().to_string().as_str())
+            .match_header("x-amz-security-token", "token")
+            .match_header(
+                "authorization",
+                Matcher::Regex(
+                    "^AWS4-HMAC-SHA256 Credential=AKID/[0-9]{8}/us-east-1/execute-api/aws4_request, \
+                     SignedHeaders=host;x-amz-date;x-amz-security-token, Signature=[0-9a-f]{64}$"
+                        .to_string(),
+                ),
+            )
+            .match_body("{}")
+            .expect(1)
+            .create();
+        let signer =
+            AwsSigner::new("us-east-1", "execute-api", "AKID", "secret", Some("token")).unwrap();
+        let server = TestServer::serve_with(format!("http://{}/prod", server_address()), |proxy| {
+            proxy.with_aws_signer(signer)
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let req = Request::post(format!("http://{}/items", server.addr))
+            .header("authorization", "Bearer client")
+            .body(Body::from("{}"))
+            .unwrap();
+        assert_eq!(Client::new().request(req).await.unwrap().status(), 200);
+        upstream.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_injects_oauth_token() {
+        let token = mock("POST", "/token")
+            .match_header("authorization", "Basic aWQ6c2VjcmV0")
+            .match_body("grant_type=client_credentials")
+            .with_body(r#"{"access_token":"abc","token_type":"bearer","expires_in":3600}"#)
+            .expect(1)
+            .create();
+        let upstream = mock("GET", "/some/test/path")
+            .match_header("authorization", "Bearer abc")
+            .with_status(200)
+            .expect(1)
+            .create();
+        let source = TokenSource::start(format!("http://{}/token", server_address()), "id:secret")
+            .await
+            .unwrap();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_token_source(source)
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        token.assert();
+        upstream.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_injects_google_id_token() {
+        let claims = base64::encode_config(r#"{"exp":4102444800}"#, base64::URL_SAFE_NO_PAD);
+        let id_token = format!("eyJhbGciOiJSUzI1NiJ9.{}.c2ln", claims);
+        let token = mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/default/identity\
+             ?audience=https%3A%2F%2Fapi.example.com",
+        )
+        .match_header("metadata-flavor", "Google")
+        .with_body(&id_token)
+        .expect(1)
+        .create();
+        let upstream = mock("GET", "/google/path")
+            .match_header("authorization", format!("Bearer {}", id_token).as_str())
+            .expect(1)
+            .create();
+        let source =
+            TokenSource::start_google_iam(&server_address().to_string(), "https://api.example.com")
+                .await
+                .unwrap();
+        let proxy = ProxyClient::new(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            format!("http://{}", server_address()),
+        )
+        .with_token_source(source);
+        let req = Request::get("/google/path").body(Body::empty()).unwrap();
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let resp = handle(req, Arc::new(proxy), remote_addr).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        token.assert();
+        upstream.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_upstream_proxy_protocol() {
+        use std::io::{Read, Write};
+
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream_thread = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0; 1024];
+            while !received.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            String::from_utf8(received).unwrap()
+        });
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy.with_client_options(ClientOptions {
+                proxy_protocol: Some(proxy_protocol::Version::V1),
+                ..ClientOptions::default()
+            })
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 204);
+
+        let received = upstream_thread.join().unwrap();
+        let (proxy_header, request) = received.split_once("\r\n").unwrap();
+        assert!(proxy_header.starts_with("PROXY TCP4 127.0.0.1 127.0.0.1 "));
+        assert!(proxy_header.ends_with(&format!(" {}", server.addr.port())));
+        assert!(request.starts_with("GET /some/test/path HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_method_timeout() {
+        // Accepts connections but never answers.
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy.with_method_timeouts("GET:200".parse().unwrap())
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 504);
+        drop(upstream);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_path_timeout() {
+        // Accepts connections but never answers.
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy
+                .with_method_timeouts("GET:10000".parse().unwrap())
+                .with_path_timeout("/*=5000".parse().unwrap())
+                .with_path_timeout("/slow/*=200".parse().unwrap())
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/slow/report", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let started = Instant::now();
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 504);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        drop(upstream);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_upstream_timeout_jitter() {
+        // Accepts connections but never answers.
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let proxy = Arc::new(
+            ProxyClient::new(
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                format!("http://{}", upstream_addr),
+            )
+            .with_upstream_timeout(Duration::from_millis(50))
+            .with_timeout_jitter_ms(50),
+        );
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let mut timeouts = Vec::new();
+        for _ in 0..5 {
+            let req = Request::get("/slow").body(Body::empty()).unwrap();
+            match forward(req, Arc::clone(&proxy), remote_addr).await {
+                Err(ProxyError::UpstreamTimeout(timeout)) => timeouts.push(timeout),
+                other => panic!("expected an upstream timeout, got {:?}", other.map(|_| ())),
+            }
+        }
+        // The global timeout is jittered too, not only per-method ones.
+        assert!(timeouts.iter().all(|&timeout| (Duration::from_millis(50)
+            ..Duration::from_millis(100))
+            .contains(&timeout)));
+        assert!(timeouts
+            .iter()
+            .any(|&timeout| timeout > Duration::from_millis(50)));
+        drop(upstream);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_upstream_timeout() {
+        // Accepts connections but never answers.
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy.with_upstream_timeout(Duration::from_millis(200))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let started = Instant::now();
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 504);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "upstream_timeout");
+        drop(upstream);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_gzip_response() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let body = "a".repeat(2048);
+        let mock = mock("GET", "/some/test/path")
+            .with_body(&body)
+            .with_status(200)
+            .expect(2)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_gzip_above_bytes(1024)
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr);
+        let client = Client::new();
+
+        let req = Request::get(&uri)
+            .header("accept-encoding", "gzip")
+            .body(Body::empty())
+            .expect("request builder");
+        let resp = client.request(req).await.unwrap();
+        assert_eq!(resp.headers()["content-encoding"], "gzip");
+        assert!(!resp.headers().contains_key("content-length"));
+        let compressed = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let mut decompressed = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+
+        let resp = client.get(uri.parse().unwrap()).await.unwrap();
+        assert!(!resp.headers().contains_key("content-encoding"));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_empty_body_timeout() {
+        use std::io::{Read, Write};
+
+        // Sends the headers of a chunked response but never finishes it.
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n")
+                .unwrap();
+            thread::sleep(std::time::Duration::from_secs(10));
+        });
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy.with_empty_body_timeout(std::time::Duration::from_millis(200))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = tokio::time::timeout(
+            std::time::Duration::from_secs(2),
+            hyper::body::to_bytes(resp.into_body()),
+        );
+        assert!(body.await.expect("body should end").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_stall_timeout() {
+        use std::io::{Read, Write};
+
+        // Sends the first chunk of a response but never finishes it.
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n")
+                .unwrap();
+            thread::sleep(std::time::Duration::from_secs(10));
+        });
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy.with_stall_timeout(std::time::Duration::from_millis(200))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = tokio::time::timeout(
+            std::time::Duration::from_secs(2),
+            hyper::body::to_bytes(resp.into_body()),
+        );
+        assert!(body.await.expect("body should be aborted").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_min_response_speed() {
+        use std::io::{Read, Write};
+
+        // Trickles a response out a byte every 100ms.
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n")
+                .unwrap();
+            for _ in 0..50 {
+                if stream.write_all(b"1\r\na\r\n").is_err() {
+                    return;
+                }
+                thread::sleep(std::time::Duration::from_millis(100));
+            }
+        });
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy.with_min_response_speed(100, std::time::Duration::from_millis(500))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = tokio::time::timeout(
+            std::time::Duration::from_secs(2),
+            hyper::body::to_bytes(resp.into_body()),
+        );
+        assert!(body.await.expect("body should be aborted").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_coalesces_identical_gets() {
+        let mock = mock("GET", "/some/test/path")
+            .with_body("{expected response}")
+            .with_status(200)
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_coalesce_window(Duration::from_millis(500))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let client = Client::new();
+        let (first, second) = tokio::join!(client.get(uri.clone()), client.get(uri));
+        for resp in [first.unwrap(), second.unwrap()] {
+            assert_eq!(resp.status(), 200);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"{expected response}");
+        }
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_does_not_coalesce_different_headers() {
+        let mock = mock("GET", "/coalesce/headers")
+            .with_body("{expected response}")
+            .expect(3)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_coalesce_window(Duration::from_millis(500))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/coalesce/headers", server.addr);
+        let get = |header: Option<(&'static str, &'static str)>| {
+            let mut req = Request::get(&uri);
+            if let Some((name, value)) = header {
+                req = req.header(name, value);
+            }
+            Client::new().request(req.body(Body::empty()).unwrap())
+        };
+        let (plain, gzip, api_key) = tokio::join!(
+            get(None),
+            get(Some(("accept-encoding", "gzip"))),
+            get(Some(("x-api-key", "secret")))
+        );
+        for resp in [plain, gzip, api_key] {
+            assert_eq!(resp.unwrap().status(), 200);
+        }
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_response_hash_mismatch() {
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let valid = mock("GET", "/valid")
+            .with_header("x-content-hash", hash)
+            .with_body("hello")
+            .create();
+        let tampered = mock("GET", "/tampered")
+            .with_header("x-content-hash", hash)
+            .with_body("goodbye")
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_response_hash_header(HeaderName::from_static("x-content-hash"))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+
+        let uri = format!("http://{}/valid", server.addr).parse().unwrap();
+        let resp = client.get(uri).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+
+        let uri = format!("http://{}/tampered", server.addr).parse().unwrap();
+        let resp = client.get(uri).await.unwrap();
+        assert_eq!(resp.status(), 502);
+        valid.assert();
+        tampered.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_happy_path_only() {
+        let mock = mock("GET", "/some/test/path")
+            .with_body("stack trace")
+            .with_status(500)
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_happy_path_only(StatusCode::BAD_GATEWAY, "unavailable".to_string())
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 502);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"unavailable");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_upstream_burst_limit() {
+        let mock = mock("GET", "/some/test/path")
+            .with_status(200)
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_upstream_burst_limit(1, 0)
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let client = Client::new();
+        let resp = client.get(uri.clone()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let resp = client.get(uri).await.unwrap();
+        assert_eq!(resp.status(), 503);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_upstream_tls_error() {
+        use std::io::Write;
+
+        // Answers the TLS handshake with plain HTTP.
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in upstream.incoming() {
+                let _ = stream
+                    .unwrap()
+                    .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+            }
+        });
+        let client = Client::new();
+
+        let server = TestServer::serve_with(format!("https://{}", upstream_addr), |proxy| proxy);
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr);
+        let resp = client.get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 502);
+
+        let server = TestServer::serve_with(format!("https://{}", upstream_addr), |proxy| {
+            proxy.with_abort_on_tls_error()
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr);
+        assert!(client.get(uri.parse().unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_normalizes_path() {
+        let mock = mock("GET", "/api/v2/users?id=1")
+            .with_status(200)
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_path_normalization(PathNormalization::Strict)
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let uri = format!("http://{}//api/v1/../v2/./users?id=1", server.addr);
+        let resp = client.get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let uri = format!("http://{}/api/../../etc/passwd", server.addr);
+        let resp = client.get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 400);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_tcp_fast_open() {
+        let mock = mock("GET", "/some/test/path").expect(1).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_client_options(ClientOptions {
+                tcp_fast_open: true,
+                ..ClientOptions::default()
+            })
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        assert_eq!(Client::new().get(uri).await.unwrap().status(), 200);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_title_case_headers() {
+        use std::io::{Read, Write};
+
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let upstream = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let mut buf = [0; 4096];
+            let n = stream.read(&mut buf).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).into_owned()
+        });
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy.with_client_options(ClientOptions {
+                http1_title_case_headers: true,
+                http1_writev: false,
+                ..ClientOptions::default()
+            })
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let req = Request::get(format!("http://{}/some/test/path", server.addr))
+            .header("x-custom-header", "value")
+            .body(Body::empty())
+            .unwrap();
+        let resp = Client::new().request(req).await.unwrap();
+        assert_eq!(resp.status(), 204);
+        let received = upstream.join().unwrap();
+        assert!(
+            received.contains("\r\nX-Custom-Header: value\r\n"),
+            "{}",
+            received
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_retries_upstream_connect() {
+        use std::io::{Read, Write};
+
+        // Nothing listens on the upstream port until after the first attempt.
+        let upstream_addr = StdTcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy.with_client_options(ClientOptions {
+                connect_retries: 3,
+                ..ClientOptions::default()
+            })
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let upstream = thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(150));
+            let upstream = StdTcpListener::bind(upstream_addr).unwrap();
+            let (mut stream, _) = upstream.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+        });
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 204);
+        upstream.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_max_connections_per_host() {
+        use std::io::Read;
+
+        // Accepts connections but never answers.
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]);
+            thread::sleep(std::time::Duration::from_secs(10));
+        });
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy.with_client_options(ClientOptions {
+                max_connections_per_host: Some(1),
+                ..ClientOptions::default()
+            })
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse::<hyper::Uri>()
+            .expect("server addr should parse");
+        let client = Client::new();
+        let hanging = tokio::spawn(client.get(uri.clone()));
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let resp = client.get(uri).await.unwrap();
+        assert_eq!(resp.status(), 503);
+        hanging.abort();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_max_connections() {
+        use std::io::Read;
+
+        // Accepts connections but never answers.
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]);
+            thread::sleep(std::time::Duration::from_secs(10));
+        });
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy
+                .with_client_options(ClientOptions {
+                    max_connections: Some(1),
+                    ..ClientOptions::default()
+                })
+                .with_route(
+                    format!("/other=http://{}", server_address())
+                        .parse()
+                        .unwrap(),
+                )
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let hanging = tokio::spawn(
+            client.get(
+                format!("http://{}/some/test/path", server.addr)
+                    .parse()
+                    .unwrap(),
+            ),
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        // Another upstream, over the limit for all of them.
+        let uri = format!("http://{}/other/path", server.addr);
+        let resp = client.get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 503);
+        hanging.abort();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_generates_etag() {
+        let mock = mock("GET", "/some/test/path")
+            .with_body("hello")
+            .with_status(200)
+            .expect(2)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_generated_etags()
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr);
+        let client = Client::new();
+        let resp = client.get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let etag = resp.headers()["etag"].clone();
+        assert_eq!(
+            etag,
+            "\"2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824\""
+        );
+
+        let req = Request::get(&uri)
+            .header("if-none-match", etag)
+            .body(Body::empty())
+            .expect("request builder");
+        let resp = client.request(req).await.unwrap();
+        assert_eq!(resp.status(), 304);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_valid_status_range() {
+        let redirect = mock("GET", "/redirect").with_status(302).expect(1).create();
+        let created = mock("GET", "/created").with_status(201).expect(1).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy
+                .with_valid_statuses("200,302".parse().unwrap())
+                .with_happy_path_only(StatusCode::BAD_GATEWAY, String::new())
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let uri = format!("http://{}/redirect", server.addr).parse().unwrap();
+        assert_eq!(client.get(uri).await.unwrap().status(), 302);
+        let uri = format!("http://{}/created", server.addr).parse().unwrap();
+        assert_eq!(client.get(uri).await.unwrap().status(), 502);
+        redirect.assert();
+        created.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_strict_response_validation() {
+        let valid = mock("GET", "/valid")
+            .with_header("x-city", "Paris")
+            .create();
+        let malformed = mock("GET", "/malformed")
+            .with_header("x-city", "Zürich")
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_strict_response_validation()
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let uri = format!("http://{}/valid", server.addr).parse().unwrap();
+        assert_eq!(client.get(uri).await.unwrap().status(), 200);
+        let uri = format!("http://{}/malformed", server.addr).parse().unwrap();
+        assert_eq!(client.get(uri).await.unwrap().status(), 502);
+        valid.assert();
+        malformed.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_options_locally() {
+        let mock = mock("OPTIONS", "/some/test/path").expect(0).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_local_options(&[Method::GET, Method::OPTIONS])
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri(format!("http://{}/some/test/path", server.addr))
+            .body(Body::empty())
+            .expect("request builder");
+        let resp = Client::new().request(req).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["allow"], "GET, OPTIONS");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_filter_blocks_before_upstream() {
+        let mock = mock("GET", "/admin/users").expect(0).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_filters(vec!["path-prefix=/admin;action=block:451:not here"
+                .parse()
+                .unwrap()])
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/admin/users", server.addr)
+            .parse()
+            .unwrap();
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 451);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "not here");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_expected_content_type() {
+        let json = mock("GET", "/json")
+            .with_header("content-type", "Application/JSON; charset=utf-8")
+            .with_body("{}")
+            .expect(1)
+            .create();
+        let html = mock("GET", "/html")
+            .with_header("content-type", "text/html")
+            .with_body("<h1>Oops</h1>")
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_expected_content_type("application/json".to_string())
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let uri = format!("http://{}/json", server.addr).parse().unwrap();
+        assert_eq!(client.get(uri).await.unwrap().status(), 200);
+        let uri = format!("http://{}/html", server.addr).parse().unwrap();
+        assert_eq!(client.get(uri).await.unwrap().status(), 502);
+        json.assert();
+        html.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_upstream_not_listening() {
+        let upstream_addr = StdTcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| proxy);
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr);
+        let resp = Client::new().get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 502);
+        assert_eq!(resp.headers()["content-type"], "application/json");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "upstream_unavailable");
+        assert!(body["detail"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_draining_header() {
+        let before = mock("GET", "/before")
+            .match_header("x-draining", Matcher::Missing)
+            .expect(1)
+            .create();
+        let after = mock("GET", "/after")
+            .match_header("x-draining", "true")
+            .expect(1)
+            .create();
+        let proxy = ProxyClient::new(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            format!("http://{}", server_address()),
+        )
+        .with_draining_header(
+            HeaderName::from_static("x-draining"),
+            HeaderValue::from_static("true"),
+        );
+        let draining = proxy.draining();
+        let proxy = Arc::new(proxy);
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let req = Request::get("/before").body(Body::empty()).unwrap();
+        handle(req, Arc::clone(&proxy), remote_addr).await.unwrap();
+        draining.store(true, Ordering::SeqCst);
+        let req = Request::get("/after").body(Body::empty()).unwrap();
+        handle(req, proxy, remote_addr).await.unwrap();
+        before.assert();
+        after.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_servers_on_different_ports() {
+        let first = mock("GET", "/first/ping")
+            .with_body("first")
+            .expect(1)
+            .create();
+        let second = mock("GET", "/second/ping")
+            .with_body("second")
+            .expect(1)
+            .create();
+        let first_server =
+            TestServer::serve_with(format!("http://{}/first", server_address()), |proxy| proxy);
+        let second_server =
+            TestServer::serve_with(format!("http://{}/second", server_address()), |proxy| proxy);
+        assert_ne!(first_server.addr.port(), second_server.addr.port());
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        for (server, expected) in [(&second_server, "second"), (&first_server, "first")] {
+            let uri = format!("http://{}/ping", server.addr).parse().unwrap();
+            let resp = client.get(uri).await.unwrap();
+            assert_eq!(resp.status(), 200);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(body, expected);
+        }
+        first.assert();
+        second.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_get_body() {
+        let dropped = mock("GET", "/dropped")
+            .match_header("content-length", Matcher::Missing)
+            .match_body("")
+            .expect(1)
+            .create();
+        let forwarded = mock("GET", "/forwarded")
+            .match_body("query")
+            .expect(1)
+            .create();
+        let server = TestServer::serve(server_address());
+        let get_body_server =
+            TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+                proxy.with_get_body()
+            });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        for (server, path) in [(&server, "dropped"), (&get_body_server, "forwarded")] {
+            let req = Request::builder()
+                .method(Method::GET)
+                .uri(format!("http://{}/{}", server.addr, path))
+                .body(Body::from("query"))
+                .expect("request builder");
+            assert_eq!(client.request(req).await.unwrap().status(), 200);
+        }
+        dropped.assert();
+        forwarded.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_warm_up() {
+        use std::io::{Read, Write};
+
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in upstream.incoming() {
+                let mut stream = stream.unwrap();
+                // Answer one request and keep the connection open so it stays
+                // in the pool.
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf);
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n");
+                    let _ = stream.read(&mut buf);
+                });
+            }
+        });
+        let proxy = ProxyClient::new(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            format!("http://{}", upstream_addr),
+        );
+        assert_eq!(proxy.open_connections(), 0);
+        proxy.warm_up(3).await;
+        assert_eq!(proxy.open_connections(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_max_connection_age() {
+        use std::io::{Read, Write};
+
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in upstream.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    while let Ok(n) = stream.read(&mut buf) {
+                        if n == 0 {
+                            break;
+                        }
+                        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n");
+                    }
+                });
+            }
+        });
+        let proxy = ProxyClient::new(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            format!("http://{}", upstream_addr),
+        )
+        .with_client_options(ClientOptions {
+            max_connection_age: Some(std::time::Duration::from_millis(400)),
+            ..ClientOptions::default()
+        });
+        // Reused while it is young enough, then closed once idle.
+        for _ in 0..3 {
+            proxy.warm_up(1).await;
+            assert_eq!(proxy.open_connections(), 1);
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        assert_eq!(proxy.open_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_refresh_connections() {
+        use std::io::{Read, Write};
+
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in upstream.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    while let Ok(n) = stream.read(&mut buf) {
+                        if n == 0 {
+                            break;
+                        }
+                        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n");
+                    }
+                });
+            }
+        });
+        let proxy = ProxyClient::new(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            format!("http://{}", upstream_addr),
+        )
+        .with_client_options(ClientOptions {
+            max_connection_age: Some(std::time::Duration::from_millis(400)),
+            ..ClientOptions::default()
+        });
+        proxy.warm_up(1).await;
+        // Too young to need a replacement.
+        proxy.refresh_connections().await;
+        assert_eq!(proxy.open_connections(), 1);
+        tokio::time::sleep(std::time::Duration::from_millis(320)).await;
+        proxy.refresh_connections().await;
+        assert_eq!(proxy.open_connections(), 2);
+        // The old connection expires, the new one stays.
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert_eq!(proxy.open_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_disable_pooling() {
+        use std::io::{Read, Write};
+
+        let upstream = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in upstream.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf);
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n");
+                    let _ = stream.read(&mut buf);
+                });
+            }
+        });
+        let proxy = ProxyClient::new(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            format!("http://{}", upstream_addr),
+        )
+        .with_client_options(ClientOptions {
+            disable_pooling: true,
+            ..ClientOptions::default()
+        });
+        proxy.warm_up(3).await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(proxy.open_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_grpc_max_recv_message_size() {
+        let mock = mock("POST", "/grpc.Service/Method").expect(0).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_grpc_max_recv_message_size(4)
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let mut message = vec![0, 0, 0, 0, 10];
+        message.extend_from_slice(b"0123456789");
+        let req = Request::post(format!("http://{}/grpc.Service/Method", server.addr))
+            .header(CONTENT_TYPE, "application/grpc")
+            .body(Body::wrap_stream(futures::stream::iter([Ok::<
+                _,
+                Infallible,
+            >(
+                message
+            )])))
+            .unwrap();
+        let resp = Client::new().request(req).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["grpc-status"], "8");
+        assert_eq!(resp.headers()["grpc-message"], "message too large");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_max_request_body() {
+        let mock = mock("POST", "/upload").expect(2).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_max_request_body_bytes(1024)
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let uri = format!("http://{}/upload", server.addr);
+        let chunked = |chunks: usize| {
+            Body::wrap_stream(futures::stream::iter(
+                (0..chunks).map(|_| Ok::<_, std::io::Error>(vec![b'a'; 400])),
+            ))
+        };
+        for (body, status) in [
+            (Body::from(vec![b'a'; 1024]), 200),
+            (Body::from(vec![b'a'; 1025]), 413),
+            (chunked(2), 200),
+            (chunked(3), 413),
+        ] {
+            let req = Request::post(&uri).body(body).unwrap();
+            assert_eq!(client.request(req).await.unwrap().status(), status);
+        }
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_max_response_body() {
+        let _declared = mock("GET", "/declared")
+            .with_body(vec![b'a'; 2048])
+            .create();
+        let _chunked = mock("GET", "/chunked")
+            .with_body_from_fn(|w| w.write_all(&[b'a'; 2048]))
+            .create();
+        let _small = mock("GET", "/small").with_body("ok").create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_max_response_body_bytes(1024)
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+
+        let uri = format!("http://{}/small", server.addr);
+        let resp = client.get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let uri = format!("http://{}/declared", server.addr);
+        let resp = client.get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 502);
+        // The status may already be sent when the limit is hit, so the
+        // connection is closed instead.
+        let uri = format!("http://{}/chunked", server.addr);
+        let cut_off = match client.get(uri.parse().unwrap()).await {
+            Ok(resp) => hyper::body::to_bytes(resp.into_body()).await.is_err(),
+            Err(_) => true,
+        };
+        assert!(cut_off);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_client_rate_limit() {
+        let mock = mock("GET", "/rate/limited").expect(4).create();
+        let proxy = Arc::new(
+            ProxyClient::new(
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                format!("http://{}", server_address()),
+            )
+            .with_client_rate_limit(3, 0.1, RateLimitAlgorithm::TokenBucket),
+        );
+        let request = |remote_addr: SocketAddr| {
+            let req = Request::get("/rate/limited").body(Body::empty()).unwrap();
+            handle(req, Arc::clone(&proxy), remote_addr)
+        };
+        let client = SocketAddr::from(([203, 0, 113, 7], 40000));
+        for _ in 0..3 {
+            assert_eq!(request(client).await.unwrap().status(), 200);
+        }
+        let resp = request(client).await.unwrap();
+        assert_eq!(resp.status(), 429);
+        assert_eq!(resp.headers()[RETRY_AFTER], "10");
+        // Other ports of the same address share its bucket.
+        let resp = request(SocketAddr::from(([203, 0, 113, 7], 40001)))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 429);
+
+        let other = SocketAddr::from(([203, 0, 113, 8], 40000));
+        assert_eq!(request(other).await.unwrap().status(), 200);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_path_rate_limit() {
+        let mock = mock("POST", "/limited/upload").expect(1).create();
+        let proxy = Arc::new(
+            ProxyClient::new(
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                format!("http://{}", server_address()),
+            )
+            .with_path_rate_limit("/limited:1rps".parse().unwrap())
+            .with_body_logging_for("/limited"),
+        );
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let req = Request::post("/limited/upload")
+            .body(Body::from("first"))
+            .unwrap();
+        let resp = handle(req, Arc::clone(&proxy), remote_addr).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        // Rejected without waiting for a body that never arrives.
+        let (_sender, body) = Body::channel();
+        let req = Request::post("/limited/upload").body(body).unwrap();
+        let resp = tokio::time::timeout(
+            Duration::from_secs(2),
+            handle(req, Arc::clone(&proxy), remote_addr),
+        )
+        .await
+        .expect("rejected before reading the body")
+        .unwrap();
+        assert_eq!(resp.status(), 429);
+        assert_eq!(resp.headers()[RETRY_AFTER], "1");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_path_auth() {
+        let mock = mock("GET", "/admin/users")
+            .match_header("authorization", Matcher::Missing)
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_path_auth("/admin:admin-realm:adminuser:adminpass".parse().unwrap())
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let uri = format!("http://{}/admin/users", server.addr);
+        let resp = client.get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 401);
+        assert_eq!(
+            resp.headers()["www-authenticate"],
+            "Basic realm=\"admin-realm\""
+        );
+        let req = Request::builder()
+            .uri(uri)
+            .header(
+                "authorization",
+                format!("Basic {}", base64::encode("adminuser:adminpass")),
+            )
+            .body(Body::empty())
+            .expect("request builder");
+        assert_eq!(client.request(req).await.unwrap().status(), 200);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_health_path() {
+        let mock = mock("GET", "/_proxy/health").expect(0).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_health_path("/_proxy/health".to_string())
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/_proxy/health", server.addr);
+        let resp = Client::new().get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_favicon() {
+        let mock = mock("GET", "/favicon.ico").expect(0).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_favicon(Bytes::from_static(b"\x00\x00\x01\x00icon"))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/favicon.ico", server.addr);
+        let resp = Client::new().get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "image/x-icon");
+        assert_eq!(resp.headers()[CACHE_CONTROL], "public, max-age=86400");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, &b"\x00\x00\x01\x00icon"[..]);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_json_encoding_validation() {
+        let mock = mock("POST", "/json")
+            .match_body("{\"name\":\"caf\u{e9}\"}")
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_json_encoding_validation()
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let bodies: [&[u8]; 2] = [
+            "{\"name\":\"caf\u{e9}\"}".as_bytes(),
+            b"{\"name\":\"caf\xe9\"}",
+        ];
+        let mut statuses = Vec::new();
+        for body in bodies {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(format!("http://{}/json", server.addr))
+                .header("content-type", "application/json; charset=utf-8")
+                .body(Body::from(body))
+                .expect("request builder");
+            statuses.push(client.request(req).await.unwrap().status());
+        }
+        assert_eq!(statuses, [200, 400]);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_body_type_validation() {
+        let mock = mock("POST", "/form")
+            .match_body(Matcher::Regex("^(name=a%20b|[x]{32})$".to_string()))
+            .expect(2)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_body_type_validation(16)
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let bodies = [
+            ("application/x-www-form-urlencoded", "name=a%20b"),
+            ("application/x-www-form-urlencoded", "name=%zz"),
+            ("application/json", "{\"name\":"),
+            // Too long to be checked.
+            ("application/json", "xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx"),
+        ];
+        let mut statuses = Vec::new();
+        for (content_type, body) in bodies {
+            let req = Request::post(format!("http://{}/form", server.addr))
+                .header("content-type", content_type)
+                .body(Body::from(body))
+                .unwrap();
+            statuses.push(client.request(req).await.unwrap().status());
+        }
+        assert_eq!(statuses, [200, 400, 400, 200]);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_graphql_operation() {
+        let default = mock("POST", "/graphql")
+            .match_header("x-graphql-operation-name", "ListUsers")
+            .expect(1)
+            .create();
+        let routed = mock("POST", "/users/graphql")
+            .match_header("x-graphql-operation-name", "GetUser")
+            .match_body(r#"{"operationName":"GetUser","query":"query GetUser { user }"}"#)
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_graphql_operation_header().with_route(
+                format!("/graphql/GetUser=http://{}/users", server_address())
+                    .parse()
+                    .unwrap(),
+            )
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        for operation in ["ListUsers", "GetUser"] {
+            let body = format!(
+                r#"{{"operationName":"{}","query":"query {} {{ user }}"}}"#,
+                operation, operation
+            );
+            let req = Request::post(format!("http://{}/graphql", server.addr))
+                .header("content-type", "application/json")
+                .header("x-graphql-operation-name", "Spoofed")
+                .body(Body::from(body))
+                .unwrap();
+            assert_eq!(client.request(req).await.unwrap().status(), 200);
+        }
+        default.assert();
+        routed.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_removes_body_fields() {
+        let mock = mock("POST", "/users")
+            .match_header("content-length", "35")
+            .match_body(Matcher::Json(serde_json::json!({
+                "name": "a",
+                "profile": {"city": "b"}
+            })))
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy
+                .with_removed_body_field("password")
+                .with_removed_body_field("ssn")
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let bodies = [
+            r#"{"name":"a","password":"x","profile":{"ssn":"1","city":"b"}}"#,
+            r#"{"name":"#,
+        ];
+        let mut statuses = Vec::new();
+        for body in bodies {
+            let req = Request::post(format!("http://{}/users", server.addr))
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            statuses.push(client.request(req).await.unwrap().status());
+        }
+        assert_eq!(statuses, [200, 400]);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_body_passthrough() {
+        let large = r#"{"name":"a","password":"x","bio":"a long biography"}"#;
+        let stripped = mock("POST", "/users")
+            .match_body(r#"{"name":"a"}"#)
+            .expect(1)
+            .create();
+        let passed = mock("POST", "/users").match_body(large).expect(2).create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy
+                .with_removed_body_field("password")
+                .with_body_passthrough_above_bytes(40)
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let chunks: Vec<Result<_, std::io::Error>> = vec![Ok(&large[..20]), Ok(&large[20..])];
+        let bodies = [
+            Body::from(r#"{"name":"a","password":"x"}"#),
+            Body::from(large),
+            Body::wrap_stream(futures::stream::iter(chunks)),
+        ];
+        for body in bodies {
+            let req = Request::post(format!("http://{}/users", server.addr))
+                .header("content-type", "application/json")
+                .body(body)
+                .unwrap();
+            assert_eq!(client.request(req).await.unwrap().status(), 200);
+        }
+        stripped.assert();
+        passed.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_allowed_request_headers() {
+        let mock = mock("GET", "/allowed/request/headers")
+            .match_header("x-api-key", "secret")
+            .match_header("cookie", Matcher::Missing)
+            .match_header("user-agent", Matcher::Missing)
+            .match_header("x-forwarded-for", "127.0.0.1")
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_allowed_request_headers([HeaderName::from_static("x-api-key")])
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let req = Request::get(format!("http://{}/allowed/request/headers", server.addr))
+            .header("x-api-key", "secret")
+            .header("cookie", "session=abc")
+            .header("user-agent", "test")
+            .body(Body::empty())
+            .unwrap();
+        let resp = Client::new().request(req).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_body_size_header() {
+        let mock = mock("POST", "/body/size")
+            .match_header("x-request-body-bytes", "11")
+            .match_body("hello world")
+            .with_body("ok")
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_body_size_header(HeaderName::from_static("x-request-body-bytes"))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let (mut sender, body) = Body::channel();
+        let req = Request::post(format!("http://{}/body/size", server.addr))
+            .header("x-request-body-bytes", "1")
+            .body(body)
+            .unwrap();
+        let resp = tokio::spawn(Client::new().request(req));
+        sender.send_data("hello ".into()).await.unwrap();
+        sender.send_data("world".into()).await.unwrap();
+        drop(sender);
+        let resp = resp.await.unwrap().unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "ok");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_response_size_header() {
+        let sized = mock("GET", "/sized").with_body("hello").create();
+        let streamed = mock("GET", "/streamed")
+            .with_body_from_fn(|w| w.write_all(b"hello world"))
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_response_size_header(HeaderName::from_static("x-response-size"))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let resp = Client::new()
+            .get(format!("http://{}/sized", server.addr).parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()["x-response-size"], "5");
+        assert_eq!(
+            hyper::body::to_bytes(resp.into_body()).await.unwrap(),
+            "hello"
+        );
+
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+        let mut resp = client
+            .get(format!("http://{}/streamed", server.addr).parse().unwrap())
+            .await
+            .unwrap();
+        assert!(!resp.headers().contains_key("x-response-size"));
+        let body = resp.body_mut();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(data, b"hello world");
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers["x-response-size"], "11");
+        sized.assert();
+        streamed.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_upstream_protocol() {
+        // Only speaks HTTP/2, which plain-text upstreams aren't sent by default.
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = upstream.accept().await.unwrap();
+                let service = service_fn(|req: Request<Body>| async move {
+                    Ok::<_, hyper::Error>(Response::new(Body::from(format!("{:?}", req.version()))))
+                });
+                tokio::spawn(
+                    hyper::server::conn::Http::new()
+                        .http2_only(true)
+                        .serve_connection(stream, service),
+                );
+            }
+        });
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy.with_upstream_protocol("127.0.0.1:h2".parse().unwrap())
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/protocol", server.addr)
+            .parse::<hyper::Uri>()
+            .unwrap();
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "HTTP/2.0");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_path_priority() {
+        let mock = mock("GET", "/assets/app.js")
+            .match_header("priority", "u=5")
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_path_priority("/assets:low".parse().unwrap())
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/assets/app.js", server.addr)
+            .parse::<hyper::Uri>()
+            .unwrap();
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_allowed_response_headers() {
+        let mock = mock("GET", "/allowed/response/headers")
+            .with_header("content-type", "text/plain")
+            .with_header("server", "internal/1.2.3")
+            .with_header("x-backend-host", "10.0.0.7")
+            .with_body("ok")
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_allowed_response_headers([CONTENT_TYPE])
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/allowed/response/headers", server.addr)
+            .parse::<hyper::Uri>()
+            .unwrap();
+        let resp = Client::new().get(uri).await.unwrap();
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/plain");
+        assert!(!resp.headers().contains_key("server"));
+        assert!(!resp.headers().contains_key("x-backend-host"));
+        assert_eq!(hyper::body::to_bytes(resp.into_body()).await.unwrap(), "ok");
+        mock.assert();
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config: Config = toml::from_str(
+            r#"
+            base-endpoint = "http://127.0.0.1:8080/base"
+            listen = "127.0.0.1:4000"
+            upstream-timeout = 10
+            route = ["/api=http://127.0.0.1:9000"]
+            strip-req-header = ["cookie"]
+            upstream-happy-path-only = true
+            upstream-error-status = "503"
+            "#,
+        )
+        .unwrap();
+        let proxy = ProxyClient::from_config(config).unwrap();
+        assert_eq!(proxy.addr, SocketAddr::from(([127, 0, 0, 1], 4000)));
+        assert_eq!(proxy.forward_addr, "http://127.0.0.1:8080/base");
+        assert_eq!(proxy.upstream_timeout, Duration::from_secs(10));
+        assert_eq!(proxy.routes.len(), 1);
+        assert!(proxy
+            .strip_request_headers
+            .contains(&HeaderName::from_static("cookie")));
+        assert_eq!(
+            proxy.upstream_error_response.as_ref().unwrap().0,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(proxy.health_path.as_deref(), Some("/_proxy/health"));
+
+        let config = toml::from_str("add-path-segment = [\"v1\"]").unwrap();
+        assert!(ProxyClient::from_config(config).is_err());
+        let config = toml::from_str("listen-h2-max-frame-size = 1024").unwrap();
+        assert!(ProxyClient::from_config(config).is_err());
+        let config = toml::from_str("upstream-tls-renegotiation = \"reject\"").unwrap();
+        assert_eq!(
+            ProxyClient::from_config(config).is_ok(),
+            client::REJECT_RENEGOTIATION_SUPPORTED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_routes() {
+        let default = mock("GET", "/default/other").expect(1).create();
+        let api = mock("GET", "/v1/api/users").expect(1).create();
+        let api_v2 = mock("GET", "/v2/api/v2/users").expect(1).create();
+        let server =
+            TestServer::serve_with(format!("http://{}/default", server_address()), |proxy| {
+                proxy
+                    .with_route(
+                        format!("/api=http://{}/v1", server_address())
+                            .parse()
+                            .unwrap(),
+                    )
+                    .with_route(
+                        format!("/api/v2=http://{}/v2", server_address())
+                            .parse()
+                            .unwrap(),
+                    )
+            });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        for path in ["/other", "/api/users", "/api/v2/users"] {
+            let uri = format!("http://{}{}", server.addr, path).parse().unwrap();
+            assert_eq!(client.get(uri).await.unwrap().status(), 200);
+        }
+        default.assert();
+        api.assert();
+        api_v2.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_base64_path() {
+        // "/some/test/path?a=1"
+        let mock = mock("GET", "/base/L3NvbWUvdGVzdC9wYXRoP2E9MQ")
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}/base", server_address()), |proxy| {
+            proxy.with_base64_path()
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path?a=1", server.addr);
+        let resp = Client::new().get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_segment_route() {
+        let mock = mock("GET", "/api/users?page=2").expect(1).create();
+        let port = server_address().port();
+        let server = TestServer::serve_with("http://127.0.0.1:1".to_string(), move |proxy| {
+            // localhost resolves to a loopback address, denied by default.
+            proxy
+                .with_client_options(ClientOptions {
+                    denied_ip_ranges: Vec::new(),
+                    ..ClientOptions::default()
+                })
+                .with_segment_route(
+                    SegmentRoute::new(1, &format!("http://{{segment}}:{}", port)).unwrap(),
+                )
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        let uri = format!("http://{}/localhost/api/users?page=2", server.addr);
+        assert_eq!(
+            client.get(uri.parse().unwrap()).await.unwrap().status(),
+            200
+        );
+        let uri = format!("http://{}/local.host/api/users", server.addr);
+        assert_eq!(
+            client.get(uri.parse().unwrap()).await.unwrap().status(),
+            400
+        );
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_version_routes() {
+        let default = mock("GET", "/default/users").expect(1).create();
+        let v2 = mock("GET", "/v2/users").expect(1).create();
+        let server =
+            TestServer::serve_with(format!("http://{}/default", server_address()), |proxy| {
+                proxy.with_version_route(
+                    format!("v2:http://{}/v2", server_address())
+                        .parse()
+                        .unwrap(),
+                )
+            });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        for accept in ["application/json", "application/vnd.example.v2+json"] {
+            let req = Request::get(format!("http://{}/users", server.addr))
+                .header("accept", accept)
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(client.request(req).await.unwrap().status(), 200);
+        }
+        default.assert();
+        v2.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_feature_routes() {
+        let default = mock("GET", "/default/users").expect(1).create();
+        let new_ui = mock("GET", "/new-ui/users").expect(1).create();
+        let server =
+            TestServer::serve_with(format!("http://{}/default", server_address()), |proxy| {
+                proxy.with_feature_route(
+                    format!("X-Feature:new-ui:http://{}/new-ui", server_address())
+                        .parse()
+                        .unwrap(),
+                )
+            });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        for feature in ["old-ui", "new-ui"] {
+            let req = Request::get(format!("http://{}/users", server.addr))
+                .header("x-feature", feature)
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(client.request(req).await.unwrap().status(), 200);
+        }
+        default.assert();
+        new_ui.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_host_routes() {
+        let default = mock("GET", "/default/users").expect(1).create();
+        let api = mock("GET", "/api/users").expect(1).create();
+        let server =
+            TestServer::serve_with(format!("http://{}/default", server_address()), |proxy| {
+                proxy.with_host_route(
+                    format!("api.example.com:http://{}/api", server_address())
+                        .parse()
+                        .unwrap(),
+                )
+            });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        for host in ["www.example.com", "api.example.com:8080"] {
+            let req = Request::get(format!("http://{}/users", server.addr))
+                .header("host", host)
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(client.request(req).await.unwrap().status(), 200);
         }
+        default.assert();
+        api.assert();
     }
-    pub fn addr(&self) -> SocketAddr {
-        self.addr
+
+    #[tokio::test]
+    async fn test_proxy_handle_stripped_headers() {
+        let mock = mock("GET", "/some/test/path")
+            .match_header("x-internal-token", Matcher::Missing)
+            .match_header("x-kept", "yes")
+            .with_header("x-backend-server", "backend-3")
+            .with_header("x-request-cost", "12")
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy
+                .with_stripped_request_headers([HeaderName::from_static("x-internal-token")])
+                .with_stripped_response_headers([HeaderName::from_static("x-backend-server")])
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let req = Request::builder()
+            .uri(format!("http://{}/some/test/path", server.addr))
+            .header("X-Internal-Token", "secret")
+            .header("x-kept", "yes")
+            .body(Body::empty())
+            .expect("request builder");
+        let resp = Client::new().request(req).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(!resp.headers().contains_key("x-backend-server"));
+        assert_eq!(resp.headers()["x-request-cost"], "12");
+        mock.assert();
     }
-}
 
-#[macro_export]
-macro_rules! new {
-    ($e:expr) => {{
-        use crate::server::handle;
-        use hyper::{
-            service::{make_service_fn, service_fn},
-            Server,
-        };
-        use std::{convert::Infallible, sync::Arc};
+    #[tokio::test]
+    async fn test_proxy_handle_metadata_header() {
+        let mock = mock("GET", "/some/test/path")
+            .match_header(
+                "x-proxy-meta",
+                format!("version={};config-hash=abc123", env!("CARGO_PKG_VERSION")).as_str(),
+            )
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_metadata_header("abc123")
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse()
+            .unwrap();
+        assert_eq!(Client::new().get(uri).await.unwrap().status(), 200);
+        mock.assert();
+    }
 
-        let proxy_client: Arc<ProxyClient> = Arc::new($e);
-        let proxy_addr = proxy_client.addr();
-        let new_service = make_service_fn(move |_conn| {
-            let proxy_client = Arc::clone(&proxy_client);
-            let svc = service_fn(move |req| {
-                // Clone again to ensure that client outlives this closure.
-                let proxy_client = Arc::clone(&proxy_client);
-                handle(req, proxy_client)
-            });
-            async move { Ok::<_, Infallible>(svc) }
+    #[tokio::test]
+    async fn test_proxy_handle_proxy_info_header() {
+        let mock = mock("GET", "/some/test/path")
+            .match_header(
+                "x-forwarded-by",
+                format!("proxy-filter/{}", env!("CARGO_PKG_VERSION")).as_str(),
+            )
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_proxy_info_header()
         });
-        let builder = Server::bind(&proxy_addr);
-        builder.serve(new_service)
-    }};
-}
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let req = Request::get(format!("http://{}/some/test/path", server.addr))
+            .header("x-forwarded-by", "spoofed/1.0")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(Client::new().request(req).await.unwrap().status(), 200);
+        mock.assert();
+    }
 
-pub(crate) use new;
+    #[tokio::test]
+    async fn test_proxy_handle_pool_pre_check() {
+        // Unlike mockito, keeps connections open between requests.
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let upstream_received = Arc::clone(&received);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = upstream.accept().await.unwrap();
+                let received = Arc::clone(&upstream_received);
+                let service = service_fn(move |req: Request<Body>| {
+                    received
+                        .lock()
+                        .unwrap()
+                        .push(format!("{} {}", req.method(), req.uri()));
+                    async { Ok::<_, hyper::Error>(Response::new(Body::empty())) }
+                });
+                tokio::spawn(hyper::server::conn::Http::new().serve_connection(stream, service));
+            }
+        });
+        let server = TestServer::serve_with(format!("http://{}", upstream_addr), |proxy| {
+            proxy.with_pool_pre_check("/healthz".to_string(), Duration::from_millis(300))
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let client = Client::new();
+        // The first request has no idle connection to check, the second's
+        // hasn't been idle long enough, the third's has.
+        for pause in [0, 0, 500] {
+            tokio::time::sleep(Duration::from_millis(pause)).await;
+            let uri = format!("http://{}/pre/check", server.addr).parse().unwrap();
+            let resp = client.get(uri).await.unwrap();
+            assert_eq!(resp.status(), 200);
+            hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        }
+        assert_eq!(
+            *received.lock().unwrap(),
+            [
+                "GET /pre/check",
+                "GET /pre/check",
+                "HEAD /healthz",
+                "GET /pre/check"
+            ]
+        );
+    }
 
-pub async fn handle(
-    req: Request<Body>,
-    proxy: Arc<ProxyClient>,
-) -> Result<Response<Body>, Infallible> {
-    let uri_string = if let Some(path_query) = req.uri().path_and_query() {
-        format!("{}{}", proxy.forward_addr, path_query)
-    } else {
-        proxy.forward_addr.clone()
-    };
-    tracing::info!("uri_string: {}", uri_string);
-    let uri = uri_string
-        .parse::<hyper::Uri>()
-        .expect("proxy addr should parse");
-    let mut http_req_builder = Request::builder();
-    {
-        let headers = http_req_builder.headers_mut().unwrap();
-        for (key, value) in req.headers() {
-            tracing::info!("Sending: {}: {}", key, value.to_str().unwrap_or("NO VALUE"));
-            headers.append(key, value.into());
+    #[tokio::test]
+    async fn test_proxy_handle_idle_connection_check() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let upstream_received = Arc::clone(&received);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = upstream.accept().await.unwrap();
+                let received = Arc::clone(&upstream_received);
+                let service = service_fn(move |req: Request<Body>| {
+                    received
+                        .lock()
+                        .unwrap()
+                        .push(format!("{} {}", req.method(), req.uri()));
+                    async { Ok::<_, hyper::Error>(Response::new(Body::empty())) }
+                });
+                tokio::spawn(hyper::server::conn::Http::new().serve_connection(stream, service));
+            }
+        });
+        let proxy = Arc::new(
+            ProxyClient::new(
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                format!("http://{}", upstream_addr),
+            )
+            .with_idle_connection_check(Duration::from_millis(300)),
+        );
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        for pause in [0, 0, 500] {
+            tokio::time::sleep(Duration::from_millis(pause)).await;
+            let req = Request::get("/idle/check").body(Body::empty()).unwrap();
+            let resp = handle(req, Arc::clone(&proxy), remote_addr).await.unwrap();
+            assert_eq!(resp.status(), 200);
+            hyper::body::to_bytes(resp.into_body()).await.unwrap();
         }
+        assert_eq!(
+            *received.lock().unwrap(),
+            [
+                "GET /idle/check",
+                "GET /idle/check",
+                "OPTIONS *",
+                "GET /idle/check"
+            ]
+        );
     }
-    let http_req = http_req_builder
-        .method(req.method())
-        .uri(uri)
-        .body(req.into_body());
 
-    match http_req {
-        Err(_) => {
-            let mut response = Response::new(Body::empty());
-            *response.status_mut() = StatusCode::BAD_REQUEST;
-            Ok(response)
+    #[tokio::test]
+    async fn test_proxy_handle_records_metrics() {
+        let mock = mock("GET", "/some/test/path").expect(1).create();
+        let metrics = Arc::new(MetricsRecorder::new());
+        let upstream_addr = StdTcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        for forward_addr in [
+            format!("http://{}", server_address()),
+            format!("http://{}", upstream_addr),
+        ] {
+            let proxy = ProxyClient::new(SocketAddr::from(([127, 0, 0, 1], 0)), forward_addr)
+                .with_metrics(Arc::clone(&metrics));
+            let req = Request::get("/some/test/path").body(Body::empty()).unwrap();
+            handle(req, Arc::new(proxy), remote_addr).await.unwrap();
         }
-        Ok(http_req) => {
-            let http_resp = proxy.http_client.request(http_req).await.unwrap();
-            let status_code = http_resp.status();
-            tracing::info!("Sent request to {}, response {}", uri_string, status_code);
-            let mut response_builder = Response::builder().status(status_code);
-            {
-                let headers = response_builder.headers_mut().unwrap();
-                for (key, value) in http_resp.headers() {
-                    headers.append(key, value.into());
-                }
-            }
-            let response = response_builder.body(http_resp.into_body()).unwrap();
-            Ok(response)
+        let rendered = metrics.render();
+        assert!(rendered.contains("proxy_requests_total{method=\"GET\",status=\"200\"} 1\n"));
+        assert!(rendered.contains("proxy_requests_total{method=\"GET\",status=\"502\"} 1\n"));
+        assert!(rendered.contains("proxy_request_duration_seconds_count 2\n"));
+        assert!(rendered.contains("proxy_upstream_errors_total 1\n"));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_records_sla_violations() {
+        let mock = mock("GET", "/sla/path").expect(2).create();
+        let metrics = Arc::new(MetricsRecorder::new());
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        for sla in [Duration::from_secs(60), Duration::ZERO] {
+            let proxy = ProxyClient::new(
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                format!("http://{}", server_address()),
+            )
+            .with_metrics(Arc::clone(&metrics))
+            .with_latency_sla(sla);
+            let req = Request::get("/sla/path").body(Body::empty()).unwrap();
+            handle(req, Arc::new(proxy), remote_addr).await.unwrap();
         }
+        let rendered = metrics.render();
+        assert!(rendered.contains(&format!(
+            "proxy_sla_violation_total{{upstream=\"{}\"}} 1\n",
+            server_address()
+        )));
+        mock.assert();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use futures_channel::oneshot;
-    use hyper::{body::HttpBody, Client, Method, Request};
-    use mockito::{mock, server_address, Matcher};
-    use std::{
-        borrow::Borrow,
-        net::{SocketAddr, TcpListener as StdTcpListener},
-        str, thread,
-    };
-    use tokio::net::TcpListener;
+    #[tokio::test]
+    async fn test_proxy_handle_response_size_sla() {
+        let mock = mock("GET", "/size/sla")
+            .with_body("x".repeat(64))
+            .expect(1)
+            .create();
+        let proxy = ProxyClient::new(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            format!("http://{}", server_address()),
+        )
+        .with_response_size_sla(16);
+        let req = Request::get("/size/sla").body(Body::empty()).unwrap();
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let resp = handle(req, Arc::new(proxy), remote_addr).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body.len(), 64);
+        mock.assert();
+    }
 
     #[tokio::test]
-    async fn test_proxy_handle() {
-        let mock = mock("POST", "/some/test/path")
-            .match_body("{expected payload}")
-            .match_header("content-type", "application/test")
-            .match_header("DD-API-KEY", "SECRET API KEY")
-            .match_header("user-agent", "unit-test")
-            .with_body("{expected response}")
-            .with_status(418)
+    async fn test_reload_handle() {
+        let mock = mock("GET", "/reloaded").expect(1).create();
+        let metrics = Arc::new(MetricsRecorder::new());
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let proxy = ProxyClient::new(addr, "http://127.0.0.1:1".to_string())
+            .with_metrics(Arc::clone(&metrics));
+        let reload = ServerBuilder::new(proxy).reload_handle();
+        let config = Config {
+            base_endpoint: format!("http://{}", server_address()),
+            listen: "127.0.0.1:1".parse().unwrap(),
+            ..Config::default()
+        };
+        reload.reload(config).unwrap();
+        let proxy = reload.current();
+        assert_eq!(proxy.addr(), addr);
+        let req = Request::get("/reloaded").body(Body::empty()).unwrap();
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let resp = handle(req, proxy, remote_addr).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(metrics
+            .render()
+            .contains("proxy_requests_total{method=\"GET\",status=\"200\"} 1\n"));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_reload_handle_keeps_rate_limits() {
+        let mock = mock("GET", "/reload/limited").expect(3).create();
+        let config = || Config {
+            base_endpoint: format!("http://{}", server_address()),
+            listen: "127.0.0.1:1".parse().unwrap(),
+            rate_limit_capacity: std::num::NonZeroU32::new(1),
+            rate_limit_rps: Some(0.1),
+            ..Config::default()
+        };
+        let reload =
+            ServerBuilder::new(ProxyClient::from_config(config()).unwrap()).reload_handle();
+        let request = |proxy: Arc<ProxyClient>, remote_addr: SocketAddr| {
+            let req = Request::get("/reload/limited").body(Body::empty()).unwrap();
+            handle(req, proxy, remote_addr)
+        };
+        let limited = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let other = SocketAddr::from(([127, 0, 0, 2], 4000));
+        let resp = request(reload.current(), limited).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let resp = request(reload.current(), limited).await.unwrap();
+        assert_eq!(resp.status(), 429);
+
+        reload.reload(config()).unwrap();
+        let resp = request(reload.current(), limited).await.unwrap();
+        assert_eq!(resp.status(), 429);
+        let resp = request(reload.current(), other).await.unwrap();
+        assert_eq!(resp.status(), 200);
+
+        // New settings start a new limiter.
+        reload
+            .reload(Config {
+                rate_limit_rps: Some(0.2),
+                ..config()
+            })
+            .unwrap();
+        let resp = request(reload.current(), limited).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_serve_on_workers() {
+        let mock = mock("GET", "/workers").expect(4).create();
+        let addr = tcp_bind(&"127.0.0.1:0".parse().unwrap())
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let proxy = ProxyClient::new(addr, format!("http://{}", server_address()))
+            .with_listener_options(ListenerOptions {
+                reuse_port: true,
+                ..ListenerOptions::default()
+            });
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(
+            ServerBuilder::new(proxy)
+                .with_workers(WorkerPool::new(std::num::NonZeroUsize::new(2).unwrap()))
+                .with_graceful_shutdown(async {
+                    let _ = shutdown_rx.await;
+                })
+                .serve(),
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        for _ in 0..4 {
+            // A new connection each time, for the kernel to spread.
+            let client = Client::new();
+            let uri = format!("http://{}/workers", addr).parse().unwrap();
+            assert_eq!(client.get(uri).await.unwrap().status(), 200);
+        }
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_minifies_json() {
+        let mock = mock("GET", "/pretty.json")
+            .with_header("content-type", "application/json")
+            .with_body("{\n  \"name\": \"a b\",\n  \"ids\": [1, 2]\n}\n")
             .expect(1)
             .create();
-        let server = TestServer::serve(server_address());
+        let proxy = ProxyClient::new(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            format!("http://{}", server_address()),
+        )
+        .with_json_minification();
+        let req = Request::get("/pretty.json").body(Body::empty()).unwrap();
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let resp = handle(req, Arc::new(proxy), remote_addr).await.unwrap();
+        assert_eq!(resp.headers()["content-length"], "26");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "{\"name\":\"a b\",\"ids\":[1,2]}");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_grpc_status_mapping() {
+        let mock = mock("POST", "/grpc.health.v1.Health/Check")
+            .with_header("content-type", "application/grpc")
+            .with_header("grpc-status", "14")
+            .expect(1)
+            .create();
+        let metrics = Arc::new(MetricsRecorder::new());
+        let proxy = ProxyClient::new(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            format!("http://{}", server_address()),
+        )
+        .with_metrics(Arc::clone(&metrics))
+        .with_grpc_status_mapping();
+        let req = Request::post("/grpc.health.v1.Health/Check")
+            .header("content-type", "application/grpc")
+            .body(Body::empty())
+            .unwrap();
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 4000));
+        let resp = handle(req, Arc::new(proxy), remote_addr).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(metrics
+            .render()
+            .contains("proxy_grpc_responses_total{grpc_status=\"14\",http_status=\"503\"} 1\n"));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_handle_host_header() {
+        let mock = mock("GET", "/some/test/path")
+            .match_header("host", "api.internal:8443")
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_host_header(HeaderValue::from_static("api.internal:8443"))
+        });
         std::thread::sleep(std::time::Duration::from_secs(1));
-        let uri_string = format!("http://{}/some/test/path", server.addr);
-        let uri = uri_string
-            .parse::<hyper::Uri>()
-            .expect("server addr should parse");
-        let client = Client::new();
-        let req = Request::builder()
-            .method(Method::POST)
-            .header("content-type", "application/test")
-            .header("DD-API-KEY", "SECRET API KEY")
-            .header("user-agent", "unit-test")
-            .uri(uri)
-            .body(Body::from("{expected payload}"))
-            .expect("request builder");
-        let mut resp = client.request(req).await.unwrap();
-        assert_eq!(resp.status(), 418);
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse()
+            .unwrap();
+        assert_eq!(Client::new().get(uri).await.unwrap().status(), 200);
+        mock.assert();
+    }
 
-        let body = resp.data().await;
-        assert!(body.is_some(), "body must not be empty");
-        assert_eq!(
-            str::from_utf8(body.unwrap().unwrap().borrow()),
-            Ok("{expected response}")
-        );
+    #[tokio::test]
+    async fn test_proxy_handle_logs_request_body() {
+        let mock = mock("POST", "/debug/echo")
+            .match_body("{\"debug\":true}")
+            .expect(1)
+            .create();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_body_logging_for("/debug")
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let req = Request::post(format!("http://{}/debug/echo", server.addr))
+            .body(Body::from("{\"debug\":true}"))
+            .unwrap();
+        assert_eq!(Client::new().request(req).await.unwrap().status(), 200);
         mock.assert();
     }
 
     #[tokio::test]
-    async fn test_proxy_handle_get_request() {
+    async fn test_proxy_handle_host_port_stripped() {
         let mock = mock("GET", "/some/test/path")
-            .match_header("user-agent", "unit-test")
-            .match_query(Matcher::AllOf(vec![
-                Matcher::UrlEncoded("key_one".into(), "value_one".into()),
-                Matcher::UrlEncoded("key_two".into(), "value_two".into()),
-            ]))
-            .with_body("{expected response}")
-            .with_status(418)
+            .match_header("host", "127.0.0.1")
             .expect(1)
             .create();
-        let server = TestServer::serve(server_address());
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_host_port_stripped()
+        });
         std::thread::sleep(std::time::Duration::from_secs(1));
-        let uri_string = format!(
-            "http://{}/some/test/path?key_one=value_one&key_two=value_two",
-            server.addr
-        );
-        let uri = uri_string
-            .parse::<hyper::Uri>()
-            .expect("server addr should parse");
-        let client = Client::new();
-        let req = Request::builder()
-            .method(Method::GET)
-            .header("user-agent", "unit-test")
-            .uri(uri)
-            .body(Body::empty())
-            .expect("request builder");
-        let mut resp = client.request(req).await.unwrap();
-        assert_eq!(resp.status(), 418);
+        let uri = format!("http://{}/some/test/path", server.addr)
+            .parse()
+            .unwrap();
+        assert_eq!(Client::new().get(uri).await.unwrap().status(), 200);
+        mock.assert();
+        assert_eq!(host_without_port("[::1]:8443").unwrap(), "[::1]");
+        assert_eq!(host_without_port("example.com").unwrap(), "example.com");
+    }
 
-        let body = resp.data().await;
-        assert!(body.is_some(), "body must not be empty");
-        assert_eq!(
-            str::from_utf8(body.unwrap().unwrap().borrow()),
-            Ok("{expected response}")
-        );
+    #[tokio::test]
+    async fn test_proxy_handle_tls_listener() {
+        use crate::listener::TlsAcceptor;
+        use hyper::client::HttpConnector;
+        use hyper_openssl::HttpsConnector;
+        use openssl::{
+            asn1::Asn1Time,
+            ec::{EcGroup, EcKey},
+            hash::MessageDigest,
+            nid::Nid,
+            pkey::PKey,
+            ssl::{SslConnector, SslMethod},
+            x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+        };
+
+        let key = EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap())
+            .and_then(PKey::from_ec_key)
+            .unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "localhost")
+            .unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        let mock = mock("GET", "/some/test/path")
+            .match_header("x-forwarded-proto", "https")
+            .with_body("hello")
+            .expect(1)
+            .create();
+        let tls = TlsAcceptor::new(&cert, &key).unwrap();
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_listener_options(ListenerOptions {
+                tls: Some(tls),
+                ..Default::default()
+            })
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+
+        let mut ssl = SslConnector::builder(SslMethod::tls()).unwrap();
+        ssl.cert_store_mut().add_cert(cert).unwrap();
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let client =
+            Client::builder().build::<_, Body>(HttpsConnector::with_connector(http, ssl).unwrap());
+        let uri = format!("https://localhost:{}/some/test/path", server.addr.port());
+        let resp = client.get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+
+        // Plain HTTP is not accepted.
+        let uri = format!("http://{}/some/test/path", server.addr);
+        assert!(Client::new().get(uri.parse().unwrap()).await.is_err());
         mock.assert();
     }
 
+    #[tokio::test]
+    async fn test_proxy_handle_optional_client_certs() {
+        use crate::listener::TlsAcceptor;
+        use hyper::client::HttpConnector;
+        use hyper_openssl::HttpsConnector;
+        use openssl::{
+            asn1::Asn1Time,
+            ec::{EcGroup, EcKey},
+            hash::MessageDigest,
+            nid::Nid,
+            pkey::{PKey, Private},
+            ssl::{SslConnector, SslMethod},
+            x509::{extension::BasicConstraints, X509NameBuilder, X509},
+        };
+
+        // A certificate for `subject`, issued by `issuer` or self-signed.
+        fn issue(
+            subject: &[(Nid, &str)],
+            issuer: Option<(&X509, &PKey<Private>)>,
+        ) -> (X509, PKey<Private>) {
+            let key = EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap())
+                .and_then(PKey::from_ec_key)
+                .unwrap();
+            let mut name = X509NameBuilder::new().unwrap();
+            for (nid, value) in subject {
+                name.append_entry_by_nid(*nid, value).unwrap();
+            }
+            let name = name.build();
+            let mut cert = X509::builder().unwrap();
+            cert.set_version(2).unwrap();
+            cert.set_subject_name(&name).unwrap();
+            cert.set_pubkey(&key).unwrap();
+            cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+                .unwrap();
+            cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+                .unwrap();
+            match issuer {
+                Some((issuer, issuer_key)) => {
+                    cert.set_issuer_name(issuer.subject_name()).unwrap();
+                    cert.sign(issuer_key, MessageDigest::sha256()).unwrap();
+                }
+                None => {
+                    let ca = BasicConstraints::new().critical().ca().build().unwrap();
+                    cert.append_extension(ca).unwrap();
+                    cert.set_issuer_name(&name).unwrap();
+                    cert.sign(&key, MessageDigest::sha256()).unwrap();
+                }
+            }
+            (cert.build(), key)
+        }
+
+        let (ca, ca_key) = issue(&[(Nid::COMMONNAME, "Test CA")], None);
+        let (server_cert, server_key) = issue(&[(Nid::COMMONNAME, "localhost")], None);
+        let (client_cert, client_key) = issue(
+            &[
+                (Nid::ORGANIZATIONNAME, "Example"),
+                (Nid::COMMONNAME, "client"),
+            ],
+            Some((&ca, &ca_key)),
+        );
+
+        let with_cert = mock("GET", "/with/cert")
+            .match_header("x-client-cert-subject", "CN=client,O=Example")
+            .expect(1)
+            .create();
+        let without_cert = mock("GET", "/without/cert")
+            .match_header("x-client-cert-subject", Matcher::Missing)
+            .expect(1)
+            .create();
+        let tls =
+            TlsAcceptor::new_with_optional_client_certs(&server_cert, &server_key, &[ca]).unwrap();
+        assert!(tls.requests_client_certs());
+        let server = TestServer::serve_with(format!("http://{}", server_address()), |proxy| {
+            proxy.with_listener_options(ListenerOptions {
+                tls: Some(tls),
+                ..Default::default()
+            })
+        });
+        std::thread::sleep(std::time::Duration::from_secs(1));
+
+        for (path, cert) in [("/with/cert", true), ("/without/cert", false)] {
+            let mut ssl = SslConnector::builder(SslMethod::tls()).unwrap();
+            ssl.cert_store_mut().add_cert(server_cert.clone()).unwrap();
+            if cert {
+                ssl.set_certificate(&client_cert).unwrap();
+                ssl.set_private_key(&client_key).unwrap();
+            }
+            let mut http = HttpConnector::new();
+            http.enforce_http(false);
+            let client = Client::builder()
+                .build::<_, Body>(HttpsConnector::with_connector(http, ssl).unwrap());
+            let req = Request::get(format!("https://localhost:{}{}", server.addr.port(), path))
+                .header("x-client-cert-subject", "CN=spoofed")
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(client.request(req).await.unwrap().status(), 200);
+        }
+        with_cert.assert();
+        without_cert.assert();
+    }
+
     pub fn runtime() -> tokio::runtime::Runtime {
         tokio::runtime::Builder::new_current_thread()
             .enable_all()
@@ -213,6 +6001,15 @@ mod tests {
 
     impl TestServer {
         fn serve(proxy_addr: SocketAddr) -> TestServer {
+            TestServer::serve_with(format!("http://{}", proxy_addr), |proxy_client| {
+                proxy_client
+            })
+        }
+
+        fn serve_with<F>(forward_addr: String, configure: F) -> TestServer
+        where
+            F: FnOnce(ProxyClient) -> ProxyClient + Send + 'static,
+        {
             let (shutdown_tx, shutdown_rx) = oneshot::channel();
             let listener = tcp_bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
             let addr = listener.local_addr().unwrap();
@@ -220,23 +6017,22 @@ mod tests {
                 .spawn(move || {
                     runtime()
                         .block_on(async move {
-                            let proxy_client =
-                                ProxyClient::new(addr, format!("http://{}", proxy_addr));
-                            let server = new!(proxy_client);
-                            server
+                            let proxy_client = configure(ProxyClient::new(addr, forward_addr));
+                            ServerBuilder::new(proxy_client)
                                 .with_graceful_shutdown(async {
                                     let _ = shutdown_rx.await;
                                 })
+                                .serve()
                                 .await
                         })
                         .expect("serve()");
                 })
                 .expect("thread spawn");
-            return TestServer {
+            TestServer {
                 _shutdown_signal: Some(shutdown_tx),
                 _thread: Some(thread),
                 addr,
-            };
+            }
         }
     }
 }
@@ -0,0 +1,61 @@
+use hyper::StatusCode;
+use std::{ops::RangeInclusive, str::FromStr};
+
+/// Sets of response statuses, parsed from a list of statuses and ranges like
+/// `200-299,302`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatusRanges(Vec<RangeInclusive<u16>>);
+
+impl StatusRanges {
+    pub fn contains(&self, status: StatusCode) -> bool {
+        self.0.iter().any(|range| range.contains(&status.as_u16()))
+    }
+}
+
+impl FromStr for StatusRanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |status: &str| {
+            status
+                .trim()
+                .parse::<StatusCode>()
+                .map(|status| status.as_u16())
+                .map_err(|_| format!("invalid status '{}'", status))
+        };
+        let mut ranges = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let range = match entry.split_once('-') {
+                Some((start, end)) => parse(start)?..=parse(end)?,
+                None => parse(entry)?..=parse(entry)?,
+            };
+            if range.is_empty() {
+                return Err(format!("empty status range '{}'", entry));
+            }
+            ranges.push(range);
+        }
+        if ranges.is_empty() {
+            return Err("expected at least one status".to_string());
+        }
+        Ok(StatusRanges(ranges))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status_ranges() {
+        let ranges: StatusRanges = "200-299, 302".parse().unwrap();
+        assert!(ranges.contains(StatusCode::OK));
+        assert!(ranges.contains(StatusCode::NO_CONTENT));
+        assert!(ranges.contains(StatusCode::FOUND));
+        assert!(!ranges.contains(StatusCode::MOVED_PERMANENTLY));
+        assert!(!ranges.contains(StatusCode::INTERNAL_SERVER_ERROR));
+
+        assert!("".parse::<StatusRanges>().is_err());
+        assert!("299-200".parse::<StatusRanges>().is_err());
+        assert!("2xx".parse::<StatusRanges>().is_err());
+    }
+}
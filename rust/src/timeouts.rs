@@ -0,0 +1,115 @@
+use crate::blocklist;
+use hyper::Method;
+use std::{collections::BTreeMap, str::FromStr, time::Duration};
+
+/// Upstream response timeouts keyed by request method, parsed from a list like
+/// `POST:5000,GET:500` in milliseconds. Methods without an entry have no
+/// timeout. Kept sorted by method, so the `Debug` output that configuration
+/// hashes and diffs are computed from is the same for equal timeouts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MethodTimeouts(BTreeMap<String, Duration>);
+
+impl MethodTimeouts {
+    pub fn get(&self, method: &Method) -> Option<Duration> {
+        self.0.get(method.as_str()).copied()
+    }
+}
+
+impl FromStr for MethodTimeouts {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut timeouts = BTreeMap::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (method, millis) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("expected METHOD:MILLIS, got '{}'", entry))?;
+            let method = Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes())
+                .map_err(|_| format!("invalid method '{}'", method))?;
+            let millis: u64 = millis
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid timeout '{}' for {}", millis, method))?;
+            timeouts.insert(method.to_string(), Duration::from_millis(millis));
+        }
+        Ok(MethodTimeouts(timeouts))
+    }
+}
+
+/// An upstream response timeout for request paths matching a glob, where
+/// `*` matches any run of characters and `?` any single one. Parsed from
+/// `GLOB=MILLIS`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PathTimeout {
+    pub pattern: String,
+    pub timeout: Duration,
+}
+
+impl PathTimeout {
+    pub fn new(pattern: &str, timeout: Duration) -> Result<PathTimeout, String> {
+        if !pattern.starts_with('/') && !pattern.starts_with('*') {
+            return Err(format!("path pattern '{}' must start with / or *", pattern));
+        }
+        Ok(PathTimeout {
+            pattern: pattern.to_string(),
+            timeout,
+        })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        blocklist::matches(&self.pattern, path)
+    }
+
+    /// How many characters of a path the pattern pins down. Of the patterns
+    /// matching a path, the most specific applies.
+    pub fn specificity(&self) -> usize {
+        self.pattern
+            .bytes()
+            .filter(|&c| c != b'*' && c != b'?')
+            .count()
+    }
+}
+
+impl FromStr for PathTimeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, millis) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected GLOB=MILLIS, got '{}'", s))?;
+        let millis: u64 = millis
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid timeout '{}' for {}", millis, pattern))?;
+        PathTimeout::new(pattern.trim(), Duration::from_millis(millis))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path_timeout() {
+        let timeout: PathTimeout = "/reports/*=30000".parse().unwrap();
+        assert_eq!(timeout.timeout, Duration::from_secs(30));
+        assert!(timeout.matches("/reports/2024/q1"));
+        assert!(!timeout.matches("/api/reports/x"));
+        assert_eq!(timeout.specificity(), 9);
+
+        assert!("/reports/*".parse::<PathTimeout>().is_err());
+        assert!("/reports/*=soon".parse::<PathTimeout>().is_err());
+        assert!("reports=100".parse::<PathTimeout>().is_err());
+    }
+
+    #[test]
+    fn test_parse_method_timeouts() {
+        let timeouts: MethodTimeouts = "POST:5000, get:500".parse().unwrap();
+        assert_eq!(timeouts.get(&Method::POST), Some(Duration::from_secs(5)));
+        assert_eq!(timeouts.get(&Method::GET), Some(Duration::from_millis(500)));
+        assert_eq!(timeouts.get(&Method::PUT), None);
+
+        assert!("POST".parse::<MethodTimeouts>().is_err());
+        assert!("POST:soon".parse::<MethodTimeouts>().is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use hyper::{
+    header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+    StatusCode,
+};
+use std::str::FromStr;
+
+/// Request body types whose structure can be checked before forwarding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyType {
+    Json,
+    Form,
+}
+
+impl BodyType {
+    /// The checkable type of a body sent with `headers`, if any.
+    pub fn of(headers: &HeaderMap) -> Option<BodyType> {
+        let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        let media_type = content_type.split(';').next()?.trim();
+        if media_type.eq_ignore_ascii_case("application/json") {
+            Some(BodyType::Json)
+        } else if media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            Some(BodyType::Form)
+        } else {
+            None
+        }
+    }
+
+    /// Checks that `body` is well-formed, without looking at what it holds.
+    pub fn check(self, body: &[u8]) -> Result<(), String> {
+        match self {
+            BodyType::Json => serde_json::from_slice::<serde::de::IgnoredAny>(body)
+                .map(|_| ())
+                .map_err(|e| format!("invalid JSON body: {}", e)),
+            BodyType::Form => check_form(body),
+        }
+    }
+}
+
+/// Checks that `body` only has the characters of URL-encoded `key=value`
+/// pairs joined by `&`, with every `%` starting an escape.
+fn check_form(body: &[u8]) -> Result<(), String> {
+    let mut bytes = body.iter().enumerate();
+    while let Some((i, &b)) = bytes.next() {
+        match b {
+            b'%' => {
+                let escape = body.get(i + 1..i + 3);
+                if !escape.is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit)) {
+                    return Err(format!("invalid escape at byte {} of form body", i));
+                }
+                bytes.nth(1);
+            }
+            b'&' | b'=' | b'+' | b'*' | b'-' | b'.' | b'_' | b'~' => {}
+            b if b.is_ascii_alphanumeric() => {}
+            b => {
+                return Err(format!(
+                    "unexpected byte 0x{:02x} at byte {} of form body",
+                    b, i
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How closely upstream responses are checked before being passed on. Status
+/// lines and header names that aren't valid HTTP fail either way, as hyper
+/// can't parse them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseValidation {
+    /// Also refuse responses `check_response` finds malformed.
+    Strict,
+    /// Pass on whatever hyper could parse.
+    Lenient,
+}
+
+impl FromStr for ResponseValidation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(ResponseValidation::Strict),
+            "lenient" => Ok(ResponseValidation::Lenient),
+            _ => Err(format!(
+                "invalid response validation mode '{}', expected strict or lenient",
+                s
+            )),
+        }
+    }
+}
+
+/// Checks that an upstream response has a status from 100 to 599, at most one
+/// `Content-Length`, not along with `Transfer-Encoding`, and header values of
+/// visible ASCII only.
+pub fn check_response(status: StatusCode, headers: &HeaderMap) -> Result<(), String> {
+    if !(100..600).contains(&status.as_u16()) {
+        return Err(format!("invalid status {}", status.as_u16()));
+    }
+    let content_lengths = headers.get_all(CONTENT_LENGTH).iter().count();
+    if content_lengths > 1 {
+        return Err(format!("{} Content-Length headers", content_lengths));
+    }
+    if content_lengths == 1 && headers.contains_key(TRANSFER_ENCODING) {
+        return Err("both Content-Length and Transfer-Encoding".to_string());
+    }
+    match headers.iter().find(|(_, value)| value.to_str().is_err()) {
+        Some((name, _)) => Err(format!("invalid value of header {}", name)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_body_type() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(BodyType::of(&headers), None);
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+        assert_eq!(BodyType::of(&headers), Some(BodyType::Json));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        assert_eq!(BodyType::of(&headers), Some(BodyType::Form));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        assert_eq!(BodyType::of(&headers), None);
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(BodyType::Json.check(b"{\"a\": [1, 2]}").is_ok());
+        assert!(BodyType::Json.check(b"{\"a\": ").is_err());
+        assert!(BodyType::Form.check(b"name=caf%C3%A9&q=a+b").is_ok());
+        assert!(BodyType::Form.check(b"").is_ok());
+        assert!(BodyType::Form.check(b"name=%zz").is_err());
+        assert!(BodyType::Form.check(b"name=%4").is_err());
+        assert!(BodyType::Form.check(b"{\"name\":1}").is_err());
+    }
+
+    #[test]
+    fn test_check_response() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("5"));
+        assert!(check_response(StatusCode::OK, &headers).is_ok());
+        assert!(check_response(StatusCode::from_u16(600).unwrap(), &headers).is_err());
+
+        headers.append(CONTENT_LENGTH, HeaderValue::from_static("5"));
+        assert!(check_response(StatusCode::OK, &headers).is_err());
+
+        headers.remove(CONTENT_LENGTH);
+        headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        assert!(check_response(StatusCode::OK, &headers).is_ok());
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("5"));
+        assert!(check_response(StatusCode::OK, &headers).is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-name", HeaderValue::from_bytes(b"caf\xc3\xa9").unwrap());
+        assert!(check_response(StatusCode::OK, &headers).is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, VARY};
+
+/// Adds `names` to the `Vary` header of `headers`, merging them into any
+/// values already there. A `Vary: *` is left as is.
+pub fn extend(headers: &mut HeaderMap, names: &[HeaderName]) {
+    let mut values: Vec<String> = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+        .collect();
+    if values.iter().any(|value| value == "*") {
+        return;
+    }
+    for name in names {
+        if !values
+            .iter()
+            .any(|value| value.eq_ignore_ascii_case(name.as_str()))
+        {
+            values.push(name.to_string());
+        }
+    }
+    if let Ok(vary) = HeaderValue::from_str(&values.join(", ")) {
+        headers.insert(VARY, vary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{ACCEPT_ENCODING, ACCEPT_LANGUAGE, ORIGIN};
+
+    #[test]
+    fn test_extend() {
+        let mut headers = HeaderMap::new();
+        extend(&mut headers, &[ACCEPT_ENCODING]);
+        assert_eq!(headers[VARY], "accept-encoding");
+
+        headers.insert(VARY, HeaderValue::from_static("Origin, Accept-Encoding"));
+        headers.append(VARY, HeaderValue::from_static("Cookie"));
+        extend(&mut headers, &[ACCEPT_ENCODING, ACCEPT_LANGUAGE, ORIGIN]);
+        assert_eq!(
+            headers[VARY],
+            "Origin, Accept-Encoding, Cookie, accept-language"
+        );
+
+        headers.insert(VARY, HeaderValue::from_static("*"));
+        extend(&mut headers, &[ACCEPT_ENCODING]);
+        assert_eq!(headers[VARY], "*");
+    }
+}
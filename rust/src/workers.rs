@@ -0,0 +1,108 @@
+use std::{future::Future, io, num::NonZeroUsize, sync::Arc, thread};
+
+/// Worker threads each running a single-threaded Tokio runtime. A server
+/// run on them binds one listening socket per worker with SO_REUSEPORT, so
+/// the kernel spreads connections across the workers and every connection
+/// stays on the thread that accepted it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorkerPool {
+    workers: NonZeroUsize,
+}
+
+impl WorkerPool {
+    pub fn new(workers: NonZeroUsize) -> WorkerPool {
+        WorkerPool { workers }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.get()
+    }
+
+    /// Runs the future `worker(index)` returns to completion on each
+    /// worker's runtime, and returns once every worker has finished, with
+    /// the first error any of them returned.
+    ///
+    /// # Panics
+    ///
+    /// If a worker panics, or its thread or runtime can't be started.
+    pub async fn run<F, Fut, E>(&self, worker: F) -> Result<(), E>
+    where
+        F: Fn(usize) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>>,
+        E: Send + 'static,
+    {
+        let worker = Arc::new(worker);
+        let threads = (0..self.workers())
+            .map(|index| {
+                let worker = Arc::clone(&worker);
+                thread::Builder::new()
+                    .name(format!("proxy-worker-{}", index))
+                    .spawn(move || {
+                        let runtime = tokio::runtime::Builder::new_current_thread()
+                            .enable_all()
+                            .build()?;
+                        Ok(runtime.block_on(worker(index)))
+                    })
+                    .expect("spawning worker thread")
+            })
+            .collect::<Vec<_>>();
+        tracing::info!("Serving on {} worker threads", threads.len());
+        let results = tokio::task::spawn_blocking(move || {
+            threads
+                .into_iter()
+                .map(|thread| thread.join())
+                .collect::<Vec<thread::Result<io::Result<Result<(), E>>>>>()
+        })
+        .await
+        .expect("joining worker threads");
+        let mut first_error = None;
+        for result in results {
+            match result {
+                Ok(Ok(Ok(()))) => {}
+                Ok(Ok(Err(e))) => {
+                    first_error.get_or_insert(e);
+                }
+                Ok(Err(e)) => panic!("error starting worker runtime: {}", e),
+                Err(panic) => std::panic::resume_unwind(panic),
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_run() {
+        let pool = WorkerPool::new(NonZeroUsize::new(3).unwrap());
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&threads);
+        let result = pool
+            .run(move |index| {
+                let seen = Arc::clone(&seen);
+                async move {
+                    tokio::task::yield_now().await;
+                    seen.lock()
+                        .unwrap()
+                        .push(thread::current().name().map(str::to_string));
+                    if index == 1 {
+                        Err(index)
+                    } else {
+                        Ok(())
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result, Err(1));
+        let mut threads = threads.lock().unwrap().clone();
+        threads.sort();
+        assert_eq!(
+            threads,
+            ["proxy-worker-0", "proxy-worker-1", "proxy-worker-2"]
+                .map(|name| Some(name.to_string()))
+        );
+    }
+}